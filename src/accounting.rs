@@ -0,0 +1,248 @@
+use crate::config::Config;
+use crate::tags::{RequestTags, TagLimiter};
+use axum::http::{HeaderMap, HeaderValue};
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Days of usage history kept in memory
+const RETENTION_DAYS: u64 = 31;
+
+/// Upstream price in USD per million tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl ModelPrice {
+    /// Parse `input/output`, e.g. `2.5/10`
+    pub fn parse(raw: &str) -> Option<Self> {
+        let (input, output) = raw.split_once('/')?;
+        Some(Self {
+            input: input.trim().parse().ok()?,
+            output: output.trim().parse().ok()?,
+        })
+    }
+
+    pub fn cost(&self, input_tokens: u32, output_tokens: u32) -> f64 {
+        (input_tokens as f64 * self.input + output_tokens as f64 * self.output) / 1_000_000.0
+    }
+}
+
+/// Usage and estimated cost of one response, as reported back to the client
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageReport {
+    pub upstream_model: String,
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub estimated_cost: Option<f64>,
+}
+
+impl UsageReport {
+    /// `x-proxy-*` response headers describing this usage
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-proxy-input-tokens", HeaderValue::from(self.input_tokens));
+        headers.insert(
+            "x-proxy-output-tokens",
+            HeaderValue::from(self.output_tokens),
+        );
+        if let Some(cost) = self.estimated_cost {
+            if let Ok(value) = HeaderValue::from_str(&format!("{:.6}", cost)) {
+                headers.insert("x-proxy-estimated-cost", value);
+            }
+        }
+        if let Ok(value) = HeaderValue::from_str(&self.upstream_model) {
+            headers.insert("x-proxy-upstream-model", value);
+        }
+        headers
+    }
+
+    /// Fold another upstream call made for the same response into this report
+    pub fn add(&mut self, other: &UsageReport) {
+        self.upstream_model = other.upstream_model.clone();
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.estimated_cost = match (self.estimated_cost, other.estimated_cost) {
+            (Some(a), Some(b)) => Some(a + b),
+            _ => None,
+        };
+    }
+
+    /// The same information for the final event of a streamed response
+    pub fn to_json(&self) -> Value {
+        json!({
+            "input_tokens": self.input_tokens,
+            "output_tokens": self.output_tokens,
+            "estimated_cost": self.estimated_cost,
+            "upstream_model": self.upstream_model,
+        })
+    }
+}
+
+/// Aggregation key: one row per day, upstream model, client and tag set
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct UsageKey {
+    /// Days since the Unix epoch (UTC)
+    pub day: u64,
+    pub model: String,
+    /// Fingerprint of the client's API key, never the key itself
+    pub client: Option<String>,
+    /// Request tags as `key=value,...`, empty when untagged
+    pub tags: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: f64,
+}
+
+/// In-memory usage and spend accounting shared by all requests
+#[derive(Debug, Default)]
+pub struct Accounting {
+    records: Mutex<HashMap<UsageKey, UsageTotals>>,
+    tags: TagLimiter,
+}
+
+impl Accounting {
+    /// Record one upstream call, returning its estimated cost when the model is priced
+    pub fn record(
+        &self,
+        config: &Config,
+        model: &str,
+        client: Option<&str>,
+        tags: &RequestTags,
+        input_tokens: u32,
+        output_tokens: u32,
+    ) -> Option<f64> {
+        let cost = config
+            .price_for(model)
+            .map(|price| price.cost(input_tokens, output_tokens));
+        let today = current_day();
+        let key = UsageKey {
+            day: today,
+            model: model.to_string(),
+            client: client.map(client_fingerprint),
+            tags: self
+                .tags
+                .bound(tags, config.request_tag_max_values)
+                .to_string(),
+        };
+
+        let mut records = self.records.lock().expect("accounting lock poisoned");
+        records.retain(|k, _| k.day + RETENTION_DAYS > today);
+        let totals = records.entry(key).or_default();
+        totals.requests += 1;
+        totals.input_tokens += input_tokens as u64;
+        totals.output_tokens += output_tokens as u64;
+        totals.cost += cost.unwrap_or(0.0);
+
+        cost
+    }
+
+    /// Total spend today, optionally limited to one client key
+    pub fn spend_today(&self, client: Option<&str>) -> f64 {
+        let today = current_day();
+        let client = client.map(client_fingerprint);
+        let records = self.records.lock().expect("accounting lock poisoned");
+        records
+            .iter()
+            .filter(|(k, _)| k.day == today && (client.is_none() || k.client == client))
+            .map(|(_, totals)| totals.cost)
+            .sum()
+    }
+
+    /// Copy of every retained aggregate, ordered by day, model and client
+    pub fn snapshot(&self) -> Vec<(UsageKey, UsageTotals)> {
+        let records = self.records.lock().expect("accounting lock poisoned");
+        let mut rows: Vec<_> = records
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        rows.sort_by(|a, b| a.0.cmp(&b.0));
+        rows
+    }
+
+    /// Explain why a request should be downgraded to the cheaper model, if it should
+    pub fn budget_pressure(&self, config: &Config, client: Option<&str>) -> Option<String> {
+        config.budget_downgrade_model.as_ref()?;
+        let threshold = config.budget_downgrade_threshold;
+
+        if let Some(budget) = client.and_then(|key| config.client_budget_for(key)) {
+            let spent = self.spend_today(client);
+            if spent >= budget * threshold {
+                return Some(format!(
+                    "client key spent ${:.2} of ${:.2} daily budget",
+                    spent, budget
+                ));
+            }
+        }
+
+        if let Some(cap) = config.daily_spend_cap {
+            let spent = self.spend_today(None);
+            if spent >= cap * threshold {
+                return Some(format!("spent ${:.2} of ${:.2} daily cap", spent, cap));
+            }
+        }
+
+        None
+    }
+}
+
+/// Stable, non-reversible label for a client API key
+pub fn client_fingerprint(key: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    format!("key-{:08x}", hasher.finish() as u32)
+}
+
+pub fn current_day() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86_400)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_fingerprint, ModelPrice, UsageReport};
+
+    #[test]
+    fn price_parses_and_costs_per_million_tokens() {
+        let price = ModelPrice::parse("2.5/10").unwrap();
+        assert_eq!(price.cost(1_000_000, 100_000), 3.5);
+        assert!(ModelPrice::parse("2.5").is_none());
+    }
+
+    #[test]
+    fn usage_report_headers_include_cost_only_when_priced() {
+        let mut report = UsageReport {
+            upstream_model: "gpt-4o".to_string(),
+            input_tokens: 10,
+            output_tokens: 2,
+            estimated_cost: None,
+        };
+        let headers = report.headers();
+        assert_eq!(headers["x-proxy-input-tokens"], "10");
+        assert_eq!(headers["x-proxy-upstream-model"], "gpt-4o");
+        assert!(!headers.contains_key("x-proxy-estimated-cost"));
+
+        report.estimated_cost = Some(0.00125);
+        assert_eq!(report.headers()["x-proxy-estimated-cost"], "0.001250");
+    }
+
+    #[test]
+    fn fingerprint_hides_the_key() {
+        let fingerprint = client_fingerprint("sk-team-a-secret");
+        assert!(fingerprint.starts_with("key-"));
+        assert!(!fingerprint.contains("secret"));
+        assert_eq!(fingerprint, client_fingerprint("sk-team-a-secret"));
+    }
+}
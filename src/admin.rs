@@ -0,0 +1,728 @@
+use crate::config::Config;
+use crate::error::{ErrorMessage, ProxyError, ProxyResult};
+use crate::export::format_timestamp;
+use crate::model_rules::{ModelRules, RuleFile, RuleSpec};
+use crate::upstream::{UpstreamPool, UpstreamRegistry};
+use axum::{
+    body::Body,
+    extract::{Path, Request},
+    http::{header, HeaderMap},
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, put},
+    Extension, Json, Router,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// How many recent error responses the admin API keeps
+const RECENT_ERRORS: usize = 50;
+
+/// Swaps the log filter installed at startup
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Admin routes, all requiring `Authorization: Bearer <ADMIN_TOKEN>`
+pub fn router() -> Router {
+    Router::new()
+        .route(
+            "/admin/upstreams",
+            get(list_upstreams)
+                .put(replace_upstreams)
+                .post(add_upstream),
+        )
+        .route(
+            "/admin/upstreams/:index",
+            put(update_upstream).delete(remove_upstream),
+        )
+        .route(
+            "/admin/routing-rules",
+            get(list_rules).put(replace_rules).post(add_rule),
+        )
+        .route(
+            "/admin/routing-rules/:index",
+            put(update_rule).delete(remove_rule),
+        )
+        .route("/admin/config", get(effective_config))
+        .route("/admin/stats", get(request_stats))
+        .route("/admin/errors", get(recent_errors))
+        .route("/admin/logging", get(log_level).put(set_log_level))
+        .route_layer(middleware::from_fn(require_admin_token))
+}
+
+async fn require_admin_token(
+    Extension(config): Extension<Arc<Config>>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> ProxyResult<Response> {
+    let presented = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    match (config.admin_token.as_deref(), presented) {
+        (Some(expected), Some(presented)) if constant_time_eq(expected, presented) => {
+            Ok(next.run(request).await)
+        }
+        _ => Err(ProxyError::Unauthorized(
+            "admin endpoints require a valid bearer token".to_string(),
+        )),
+    }
+}
+
+/// Live request counters and recent errors, plus control of the log level
+pub struct AdminState {
+    started: Instant,
+    requests: AtomicU64,
+    in_flight: AtomicU64,
+    /// Streamed responses the client disconnected from before the end
+    cancelled_streams: AtomicU64,
+    /// Finished responses by status class, 1xx through 5xx
+    statuses: [AtomicU64; 5],
+    errors: Mutex<VecDeque<Value>>,
+    log_handle: LogHandle,
+    log_level: Mutex<LogLevel>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogLevel {
+    pub debug: bool,
+    pub verbose: bool,
+}
+
+impl LogLevel {
+    pub fn filter(&self) -> EnvFilter {
+        let level = if self.verbose {
+            tracing::Level::TRACE
+        } else if self.debug {
+            tracing::Level::DEBUG
+        } else {
+            tracing::Level::INFO
+        };
+        EnvFilter::new(format!("anthropic_proxy={}", level))
+    }
+}
+
+impl AdminState {
+    pub fn new(log_handle: LogHandle, log_level: LogLevel) -> Self {
+        Self {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+            cancelled_streams: AtomicU64::new(0),
+            statuses: Default::default(),
+            errors: Mutex::new(VecDeque::with_capacity(RECENT_ERRORS)),
+            log_handle,
+            log_level: Mutex::new(log_level),
+        }
+    }
+
+    fn record(&self, method: &str, path: &str, response: &Response) {
+        let status = response.status();
+        if let Some(counter) = self.statuses.get((status.as_u16() / 100) as usize - 1) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        if status.is_client_error() || status.is_server_error() {
+            let message = response.extensions().get::<ErrorMessage>().map(|m| &m.0);
+            let mut errors = self.errors.lock().expect("admin errors lock poisoned");
+            if errors.len() == RECENT_ERRORS {
+                errors.pop_front();
+            }
+            errors.push_back(json!({
+                "at": format_timestamp(now()),
+                "method": method,
+                "path": path,
+                "status": status.as_u16(),
+                "message": message,
+            }));
+        }
+    }
+}
+
+/// Count every proxied request and remember the ones that failed
+pub async fn track_requests(
+    Extension(state): Extension<Arc<AdminState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    state.requests.fetch_add(1, Ordering::Relaxed);
+    state.in_flight.fetch_add(1, Ordering::Relaxed);
+    let response = next.run(request).await;
+    state.in_flight.fetch_sub(1, Ordering::Relaxed);
+    state.record(&method, &path, &response);
+    watch_disconnect(state, response)
+}
+
+/// Count event streams whose body is dropped before the end, which happens
+/// when the client disconnects. The translated stream owns the upstream
+/// response, so dropping it also cancels the upstream request.
+fn watch_disconnect(state: Arc<AdminState>, response: Response) -> Response {
+    let is_stream = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"text/event-stream"));
+    if !is_stream {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = Body::from_stream(async_stream::stream! {
+        let mut watch = DisconnectWatch { state, finished: false };
+        let mut data = body.into_data_stream();
+        while let Some(chunk) = data.next().await {
+            yield chunk;
+        }
+        watch.finished = true;
+    });
+    Response::from_parts(parts, body)
+}
+
+struct DisconnectWatch {
+    state: Arc<AdminState>,
+    finished: bool,
+}
+
+impl Drop for DisconnectWatch {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.state.cancelled_streams.fetch_add(1, Ordering::Relaxed);
+            tracing::info!("Client disconnected mid-stream, upstream request cancelled");
+        }
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[derive(Debug, Deserialize)]
+struct UpstreamList {
+    urls: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpstreamUrl {
+    url: String,
+}
+
+async fn list_upstreams(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(registry): Extension<Arc<UpstreamRegistry>>,
+) -> Json<Value> {
+    let mut body = describe(&registry.current());
+    if let Some(routes) = &config.upstream_routes {
+        let named: serde_json::Map<String, Value> = routes
+            .upstreams()
+            .iter()
+            .map(|routed| {
+                let pool = routed.registry.current();
+                let mut upstream = describe(&pool);
+                upstream["health"] = json!(pool.health());
+                (routed.name.clone(), upstream)
+            })
+            .collect();
+        body["named"] = Value::Object(named);
+    }
+    Json(body)
+}
+
+async fn replace_upstreams(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(registry): Extension<Arc<UpstreamRegistry>>,
+    Json(body): Json<UpstreamList>,
+) -> ProxyResult<Json<Value>> {
+    apply(&registry, "replaced", |_| {
+        body.urls.iter().map(|url| resolve(&config, url)).collect()
+    })
+}
+
+async fn add_upstream(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(registry): Extension<Arc<UpstreamRegistry>>,
+    Json(body): Json<UpstreamUrl>,
+) -> ProxyResult<Json<Value>> {
+    let url = resolve(&config, &body.url)?;
+    apply(&registry, "added", |mut urls| {
+        urls.push(url);
+        Ok(urls)
+    })
+}
+
+async fn update_upstream(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(registry): Extension<Arc<UpstreamRegistry>>,
+    Path(index): Path<usize>,
+    Json(body): Json<UpstreamUrl>,
+) -> ProxyResult<Json<Value>> {
+    let url = resolve(&config, &body.url)?;
+    apply(&registry, "updated", |mut urls| {
+        *urls.get_mut(index).ok_or_else(|| no_such_target(index))? = url;
+        Ok(urls)
+    })
+}
+
+async fn remove_upstream(
+    Extension(registry): Extension<Arc<UpstreamRegistry>>,
+    Path(index): Path<usize>,
+) -> ProxyResult<Json<Value>> {
+    apply(&registry, "removed", |mut urls| {
+        if index >= urls.len() {
+            return Err(no_such_target(index));
+        }
+        urls.remove(index);
+        Ok(urls)
+    })
+}
+
+/// Validate the changed target list and swap it in
+fn apply(
+    registry: &UpstreamRegistry,
+    action: &str,
+    change: impl FnOnce(Vec<String>) -> ProxyResult<Vec<String>>,
+) -> ProxyResult<Json<Value>> {
+    let pool = registry.update(|urls| {
+        let urls = change(urls)?;
+        validate(&urls)?;
+        Ok::<_, ProxyError>(urls)
+    })?;
+    tracing::info!(
+        "Admin: upstream targets {}, now {}",
+        action,
+        pool.urls().join(", ")
+    );
+    Ok(Json(describe(&pool)))
+}
+
+fn validate(urls: &[String]) -> ProxyResult<()> {
+    if urls.is_empty() {
+        return Err(ProxyError::InvalidRequest(
+            "at least one upstream target is required".to_string(),
+        ));
+    }
+    if let Some(duplicate) = urls
+        .iter()
+        .enumerate()
+        .find_map(|(i, url)| urls[..i].contains(url).then_some(url))
+    {
+        return Err(ProxyError::InvalidRequest(format!(
+            "duplicate upstream target: {}",
+            duplicate
+        )));
+    }
+    Ok(())
+}
+
+/// Accept the same URL forms as UPSTREAM_BASE_URL
+fn resolve(config: &Config, url: &str) -> ProxyResult<String> {
+    config
+        .upstream_url(url)
+        .map_err(|err| ProxyError::InvalidRequest(format!("{}: {}", url, err)))
+}
+
+fn no_such_target(index: usize) -> ProxyError {
+    ProxyError::InvalidRequest(format!("no upstream target at index {}", index))
+}
+
+async fn list_rules(Extension(config): Extension<Arc<Config>>) -> Json<Value> {
+    Json(json!({ "rules": config.model_rules.current().specs() }))
+}
+
+async fn replace_rules(
+    Extension(config): Extension<Arc<Config>>,
+    Json(body): Json<RuleFile>,
+) -> ProxyResult<Json<Value>> {
+    apply_rules(&config, "replaced", |_| Ok(body.rules))
+}
+
+async fn add_rule(
+    Extension(config): Extension<Arc<Config>>,
+    Json(rule): Json<RuleSpec>,
+) -> ProxyResult<Json<Value>> {
+    apply_rules(&config, "added", |mut rules| {
+        rules.push(rule);
+        Ok(rules)
+    })
+}
+
+async fn update_rule(
+    Extension(config): Extension<Arc<Config>>,
+    Path(index): Path<usize>,
+    Json(rule): Json<RuleSpec>,
+) -> ProxyResult<Json<Value>> {
+    apply_rules(&config, "updated", |mut rules| {
+        *rules.get_mut(index).ok_or_else(|| no_such_rule(index))? = rule;
+        Ok(rules)
+    })
+}
+
+async fn remove_rule(
+    Extension(config): Extension<Arc<Config>>,
+    Path(index): Path<usize>,
+) -> ProxyResult<Json<Value>> {
+    apply_rules(&config, "removed", |mut rules| {
+        if index >= rules.len() {
+            return Err(no_such_rule(index));
+        }
+        rules.remove(index);
+        Ok(rules)
+    })
+}
+
+/// Validate the changed routing rules and swap them in
+fn apply_rules(
+    config: &Config,
+    action: &str,
+    change: impl FnOnce(Vec<RuleSpec>) -> ProxyResult<Vec<RuleSpec>>,
+) -> ProxyResult<Json<Value>> {
+    let rules = config.model_rules.update(|rules| {
+        let rules = ModelRules::new(change(rules)?)
+            .and_then(|rules| config.check_rule_upstreams(&rules).map(|_| rules))
+            .map_err(|err| ProxyError::InvalidRequest(format!("{:#}", err)))?;
+        Ok::<_, ProxyError>(rules)
+    })?;
+    tracing::info!(
+        "Admin: routing rule {}, now {} rule(s)",
+        action,
+        rules.specs().len()
+    );
+    Ok(Json(json!({ "rules": rules.specs() })))
+}
+
+fn no_such_rule(index: usize) -> ProxyError {
+    ProxyError::InvalidRequest(format!("no routing rule at index {}", index))
+}
+
+/// The configuration in effect, with credentials replaced by whether they are set
+async fn effective_config(Extension(config): Extension<Arc<Config>>) -> Json<Value> {
+    Json(redacted_config(&config))
+}
+
+fn redacted_config(config: &Config) -> Value {
+    let redact = |secret: &Option<String>| secret.as_ref().map(|_| "[redacted]");
+    json!({
+        "server": {
+            "port": config.port,
+            "admin_port": config.admin_port,
+            "http2": format!("{:?}", config.http2),
+            "admin_token": redact(&config.admin_token),
+            "debug_endpoints": config.debug_endpoints,
+        },
+        "upstream": {
+            "base_url": config.base_url,
+            "format": format!("{:?}", config.upstream_format),
+            "api_key": redact(&config.api_key),
+            "headers": config
+                .upstream_headers
+                .iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>(),
+            "routes": config.upstream_routes.as_ref().map(|routes| routes.describe()),
+            "vertex": config.vertex.as_ref().map(|vertex| json!({
+                "project": vertex.project,
+                "location": vertex.location,
+            })),
+            "affinity": config.upstream_affinity,
+            "affinity_session_key": config.affinity_session_key,
+            "affinity_ttl_secs": config.affinity_ttl_secs,
+            "empty_response_retries": config.empty_response_retries,
+            "upstream_retries": config.upstream_retries,
+            "upstream_retry_backoff_ms": config.upstream_retry_backoff_ms,
+            "upstream_retry_jitter": config.upstream_retry_jitter,
+            "upstream_retry_statuses": config.upstream_retry_statuses,
+            "rate_limit_retry_secs": config.rate_limit_retry_secs,
+            "error_rules": config.error_rules.is_some(),
+            "routing_rules": config.model_rules.current().specs().len(),
+            "circuit_breaker_failures": config.circuit_breaker_failures,
+            "circuit_breaker_cooldown_secs": config.circuit_breaker_cooldown_secs,
+            "stream_idle_timeout_secs": config.stream_idle_timeout_secs,
+            "stream_block_max_bytes": config.stream_block_max_bytes,
+            "stream_coalesce_ms": config.stream_coalesce_ms,
+            "stream_coalesce_bytes": config.stream_coalesce_bytes,
+        },
+        "models": {
+            "reasoning_model": config.reasoning_model,
+            "completion_model": config.completion_model,
+            "haiku_model": config.haiku_model,
+            "sonnet_model": config.sonnet_model,
+            "opus_model": config.opus_model,
+            "small_model": config.small_model,
+            "small_model_max_tokens": config.small_model_max_tokens,
+            "fallback_models": config.fallback_models,
+            "model_splits": config.model_splits.as_ref().map(|splits| splits.to_json()),
+            "routing_overrides": if config.routing_override_keys.iter().any(|k| k == "*") {
+                json!("all clients")
+            } else {
+                json!(config.routing_override_keys.len())
+            },
+            "ensemble_models": config.ensemble_models,
+            "ensemble_judge_model": config.ensemble_judge_model,
+            "ensemble_record_file": config.ensemble_record_file,
+            "context_limits": config.context_limits,
+            "default_context_limit": config.default_context_limit,
+            "text_only_models": config.text_only_models,
+            "image_caption_model": config.image_caption_model,
+        },
+        "requests": {
+            "top_k": format!("{:?}", config.top_k),
+            "content_filter": format!("{:?}", config.content_filter),
+            "tool_input_streaming": format!("{:?}", config.tool_input_streaming),
+            "cache_control": format!("{:?}", config.cache_control),
+            "quirks": format!("{:?}", config.quirks()),
+            "vllm_repetition_penalty": config.vllm_repetition_penalty,
+            "vllm_min_p": config.vllm_min_p,
+            "model_capabilities": config.model_capabilities,
+            "openrouter": {
+                "enabled": config.is_openrouter(),
+                "provider": config.openrouter_provider,
+                "models": config.openrouter_models,
+                "transforms": config.openrouter_transforms,
+                "referer": config.openrouter_referer,
+                "title": config.openrouter_title,
+            },
+            "thinking_budget_params": format!("{:?}", config.thinking_budget_params),
+            "thinking_history": format!("{:?}", config.thinking_history),
+            "tool_result_max_chars": config.tool_result_max_chars,
+            "tool_schema_max_chars": config.tool_schema_max_chars,
+            "tools_max_chars": config.tools_max_chars,
+            "inline_image_urls": config.inline_image_urls,
+            "structured_output_tools": config.structured_output_tools,
+            "extra_params": config.extra_params,
+            "extra_params_deny": config.extra_params_deny,
+            "stop_sequences_max": config.stop_sequences_max(),
+            "emulate_stop_sequences": config.emulate_stop_sequences,
+            "report_requested_model": config.report_requested_model,
+            "schema_profile": format!("{:?}", config.schema_profile),
+            "strict_tools": config.strict_tools,
+            "secret_masking": config.secret_scanner.is_some(),
+            "batch_concurrency": config.batch_concurrency,
+            "batch_db_path": config.batch_db_path,
+            "batch_retention_secs": config.batch_retention_secs,
+        },
+        "responses": {
+            "output_guardrails": config.output_guardrails.is_some(),
+            "hide_thinking_models": config.hide_thinking_models,
+            "strip_thinking": config.strip_thinking,
+            "think_tag_models": config.think_tag_models,
+            "think_tags": config
+                .think_tags
+                .iter()
+                .map(|(model, tags)| format!("{}={}|{}", model, tags.open, tags.close))
+                .collect::<Vec<_>>(),
+            "reasoning_limits": config.reasoning_limits,
+            "reasoning_limit_upstream": config.reasoning_limit_upstream,
+            "auto_continue_max": config.auto_continue_max,
+            "ping_interval_secs": config.ping_interval_secs,
+            "heartbeat_interval_secs": config.heartbeat_interval_secs,
+            "early_message_start": config.early_message_start,
+            "stream_upstream": config.stream_upstream,
+        },
+        "usage": {
+            "model_pricing": config
+                .model_pricing
+                .iter()
+                .map(|(model, price)| json!({ "model": model, "input": price.input, "output": price.output }))
+                .collect::<Vec<_>>(),
+            "daily_spend_cap": config.daily_spend_cap,
+            "client_budgets": config.client_budgets,
+            "budget_downgrade_model": config.budget_downgrade_model,
+            "budget_downgrade_threshold": config.budget_downgrade_threshold,
+            "export_dir": config.usage_export_dir,
+            "export_interval_secs": config.usage_export_interval_secs,
+            "export_format": config.usage_export_format.extension(),
+            "export_s3_url": config.usage_export_s3.as_ref().map(|target| target.url.as_str()),
+            "tag_keys": config.request_tag_keys,
+            "tag_header": config.request_tag_header,
+            "tag_max_values": config.request_tag_max_values,
+        },
+    })
+}
+
+async fn request_stats(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(state): Extension<Arc<AdminState>>,
+) -> Json<Value> {
+    let status = |class: usize| state.statuses[class - 1].load(Ordering::Relaxed);
+    Json(json!({
+        "uptime_secs": state.started.elapsed().as_secs(),
+        "requests": state.requests.load(Ordering::Relaxed),
+        "in_flight": state.in_flight.load(Ordering::Relaxed),
+        "cancelled_streams": state.cancelled_streams.load(Ordering::Relaxed),
+        "responses": {
+            "2xx": status(2),
+            "3xx": status(3),
+            "4xx": status(4),
+            "5xx": status(5),
+        },
+        "splits": config.model_splits.as_ref().map(|splits| splits.to_json()),
+    }))
+}
+
+/// The most recent error responses, newest first
+async fn recent_errors(Extension(state): Extension<Arc<AdminState>>) -> Json<Value> {
+    let errors = state.errors.lock().expect("admin errors lock poisoned");
+    Json(json!({ "errors": errors.iter().rev().collect::<Vec<_>>() }))
+}
+
+async fn log_level(Extension(state): Extension<Arc<AdminState>>) -> Json<LogLevel> {
+    Json(*state.log_level.lock().expect("log level lock poisoned"))
+}
+
+async fn set_log_level(
+    Extension(state): Extension<Arc<AdminState>>,
+    Json(level): Json<LogLevel>,
+) -> ProxyResult<Json<LogLevel>> {
+    state
+        .log_handle
+        .reload(level.filter())
+        .map_err(|err| ProxyError::Internal(format!("failed to change log level: {}", err)))?;
+    *state.log_level.lock().expect("log level lock poisoned") = level;
+    tracing::info!(
+        "Admin: logging set to debug={} verbose={}",
+        level.debug,
+        level.verbose
+    );
+    Ok(Json(level))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn describe(pool: &UpstreamPool) -> Value {
+    let targets: Vec<Value> = pool
+        .stats()
+        .into_iter()
+        .enumerate()
+        .map(|(index, (url, stats))| {
+            json!({
+                "index": index,
+                "url": url,
+                "latency_ms": stats.latency_ms,
+                "error_rate": stats.error_rate,
+                "samples": stats.samples,
+                "consecutive_failures": stats.consecutive_failures,
+                "circuit_open": stats.open_until.is_some(),
+            })
+        })
+        .collect();
+    json!({
+        "upstreams": targets,
+        "sticky_conversations": pool.sticky_table().map(|sticky| sticky.conversations()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_rules, constant_time_eq, redacted_config, validate, watch_disconnect, AdminState,
+        LogLevel,
+    };
+    use crate::config::Config;
+    use axum::body::Body;
+    use axum::response::Response;
+    use futures::StreamExt;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tracing_subscriber::{reload, EnvFilter};
+
+    #[test]
+    fn target_lists_must_be_non_empty_and_unique() {
+        assert!(validate(&["https://a/v1/chat/completions".to_string()]).is_ok());
+        assert!(validate(&[]).is_err());
+        let dup = "https://a/v1/chat/completions".to_string();
+        assert!(validate(&[dup.clone(), dup]).is_err());
+    }
+
+    #[test]
+    fn routing_rule_changes_are_validated_before_the_swap() {
+        let config = Config::for_tests();
+        let rule = |json: &str| serde_json::from_str(json).unwrap();
+
+        let added = apply_rules(&config, "added", |mut rules| {
+            rules.push(rule(r#"{"match": "claude-*", "map_model": "gpt-4.1"}"#));
+            Ok(rules)
+        })
+        .unwrap();
+        assert_eq!(added["rules"][0]["map_model"], "gpt-4.1");
+        let unknown_upstream = apply_rules(&config, "added", |mut rules| {
+            rules.push(rule(r#"{"match": "gpt-*", "upstream": "openai"}"#));
+            Ok(rules)
+        });
+        assert!(unknown_upstream.is_err());
+        let invalid = apply_rules(&config, "replaced", |_| {
+            Ok(vec![rule(r#"{"match": "gpt-*"}"#)])
+        });
+        assert!(invalid.is_err());
+
+        let rules = config.model_rules.current();
+        assert_eq!(rules.specs().len(), 1);
+        let rule = rules.find("claude-sonnet-4", false).unwrap();
+        assert_eq!(rule.map_model.as_deref(), Some("gpt-4.1"));
+    }
+
+    #[test]
+    fn token_comparison_requires_exact_match() {
+        assert!(constant_time_eq("s3cret", "s3cret"));
+        assert!(!constant_time_eq("s3cret", "s3creT"));
+        assert!(!constant_time_eq("s3cret", "s3cre"));
+    }
+
+    #[test]
+    fn effective_config_never_shows_credentials() {
+        let mut config = Config::for_tests();
+        config.api_key = Some("sk-upstream".to_string());
+        config.admin_token = Some("admin-s3cret".to_string());
+
+        let shown = redacted_config(&config);
+        assert_eq!(shown["upstream"]["api_key"], "[redacted]");
+        assert_eq!(shown["server"]["admin_token"], "[redacted]");
+        let text = shown.to_string();
+        assert!(!text.contains("sk-upstream") && !text.contains("admin-s3cret"));
+    }
+
+    #[tokio::test]
+    async fn streams_dropped_early_count_as_cancelled() {
+        let (_, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let level = LogLevel {
+            debug: false,
+            verbose: false,
+        };
+        let state = Arc::new(AdminState::new(handle, level));
+        let stream = || {
+            let chunks = ["event: ping\n\n", "event: ping\n\n"];
+            Response::builder()
+                .header("content-type", "text/event-stream")
+                .body(Body::from_stream(futures::stream::iter(
+                    chunks.map(Ok::<_, std::io::Error>),
+                )))
+                .unwrap()
+        };
+
+        let body = watch_disconnect(state.clone(), stream()).into_body();
+        assert_eq!(body.into_data_stream().count().await, 2);
+        assert_eq!(state.cancelled_streams.load(Ordering::Relaxed), 0);
+
+        let mut body = watch_disconnect(state.clone(), stream())
+            .into_body()
+            .into_data_stream();
+        body.next().await;
+        drop(body);
+        assert_eq!(state.cancelled_streams.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::error::ProxyResult;
+use crate::models::openai;
+use crate::sse::SseParser;
+use bytes::Bytes;
+use futures::stream::{Stream, StreamExt};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Builds the response a non-streaming request would have received from the
+/// chunks of a streamed one
+#[derive(Debug, Default)]
+pub struct ResponseAssembler {
+    id: Option<String>,
+    model: Option<String>,
+    created: Option<u64>,
+    content: Option<String>,
+    reasoning: Option<String>,
+    /// Tool calls by index: id, type, name and arguments
+    tool_calls: BTreeMap<usize, (String, String, String, String)>,
+    function_call: Option<openai::FunctionCall>,
+    finish_reason: Option<String>,
+    stop_reason: Option<Value>,
+    content_filter_results: Option<Value>,
+    usage: Option<openai::Usage>,
+}
+
+impl ResponseAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, chunk: openai::StreamChunk) {
+        self.id = self.id.take().or(chunk.id);
+        self.model = self.model.take().or(chunk.model);
+        self.created = self.created.or(chunk.created);
+        if let Some(chunk_usage) = &chunk.usage {
+            match self.usage.as_mut() {
+                Some(usage) => usage.merge(chunk_usage),
+                None => self.usage = Some(chunk_usage.clone()),
+            }
+        }
+
+        let Some(choice) = chunk.choices.into_iter().next() else {
+            return;
+        };
+        let delta = choice.delta;
+        if let Some(reasoning) = delta.reasoning_text() {
+            self.reasoning
+                .get_or_insert_with(String::new)
+                .push_str(&reasoning);
+        }
+        if let Some(content) = &delta.content {
+            self.content
+                .get_or_insert_with(String::new)
+                .push_str(content);
+        }
+        for call in delta.tool_calls.into_iter().flatten() {
+            let entry = self.tool_calls.entry(call.index).or_default();
+            if let Some(id) = call.id {
+                entry.0 = id;
+            }
+            if let Some(call_type) = call.call_type {
+                entry.1 = call_type;
+            }
+            if let Some(function) = call.function {
+                if let Some(name) = function.name {
+                    entry.2 = name;
+                }
+                if let Some(arguments) = function.arguments {
+                    entry.3.push_str(&arguments);
+                }
+            }
+        }
+        if let Some(function) = delta.function_call {
+            let call = self
+                .function_call
+                .get_or_insert_with(|| openai::FunctionCall {
+                    name: String::new(),
+                    arguments: String::new(),
+                });
+            if let Some(name) = function.name {
+                call.name = name;
+            }
+            if let Some(arguments) = function.arguments {
+                call.arguments.push_str(&arguments);
+            }
+        }
+        if choice.finish_reason.is_some() {
+            self.finish_reason = choice.finish_reason;
+            self.stop_reason = choice.stop_reason;
+            self.content_filter_results = choice.content_filter_results;
+        }
+    }
+
+    pub fn finish(self) -> openai::OpenAIResponse {
+        let tool_calls: Vec<openai::ToolCall> = self
+            .tool_calls
+            .into_values()
+            .map(|(id, call_type, name, arguments)| openai::ToolCall {
+                id,
+                call_type: if call_type.is_empty() {
+                    "function".to_string()
+                } else {
+                    call_type
+                },
+                function: openai::FunctionCall { name, arguments },
+            })
+            .collect();
+        openai::OpenAIResponse {
+            id: self.id,
+            object: Some("chat.completion".to_string()),
+            created: self.created,
+            model: self.model,
+            choices: vec![openai::Choice {
+                index: 0,
+                message: openai::ChoiceMessage {
+                    role: "assistant".to_string(),
+                    content: self.content,
+                    tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                    function_call: self.function_call,
+                    reasoning: self.reasoning,
+                    reasoning_content: None,
+                    reasoning_details: None,
+                },
+                finish_reason: self.finish_reason,
+                stop_reason: self.stop_reason,
+                content_filter_results: self.content_filter_results,
+            }],
+            usage: self.usage.unwrap_or(openai::Usage {
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                total_tokens: 0,
+                prompt_tokens_details: None,
+            }),
+            system_fingerprint: None,
+        }
+    }
+}
+
+/// Read a whole upstream event stream into a single response
+pub async fn collect(
+    stream: impl Stream<Item = Result<Bytes, reqwest::Error>>,
+) -> ProxyResult<openai::OpenAIResponse> {
+    let mut parser = SseParser::new();
+    let mut assembler = ResponseAssembler::new();
+    futures::pin_mut!(stream);
+    while let Some(bytes) = stream.next().await {
+        for event in parser.push(&bytes?) {
+            push_event(&mut assembler, &event.data);
+        }
+    }
+    if let Some(event) = parser.finish() {
+        push_event(&mut assembler, &event.data);
+    }
+    Ok(assembler.finish())
+}
+
+fn push_event(assembler: &mut ResponseAssembler, data: &str) {
+    if data.trim() == "[DONE]" {
+        return;
+    }
+    match serde_json::from_str::<openai::StreamChunk>(data) {
+        Ok(chunk) => assembler.push(chunk),
+        Err(_) => tracing::debug!("Ignoring unrecognized upstream stream chunk: {}", data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::collect;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn streamed_chunks_become_one_response() {
+        let body = [
+            r#"{"id":"chatcmpl-1","model":"r1","choices":[{"index":0,"delta":{"reasoning":"think"}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":"Hel"}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":"lo","tool_calls":[{"index":0,"id":"c1","type":"function","function":{"name":"f","arguments":"{\"a\""}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":":1}"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}]}"#,
+            r#"{"choices":[],"usage":{"prompt_tokens":3,"completion_tokens":9,"total_tokens":12}}"#,
+            "[DONE]",
+        ]
+        .map(|data| format!("data: {}\n\n", data))
+        .concat();
+        let (head, tail) = body.split_at(40);
+        let stream = futures::stream::iter([
+            Ok(Bytes::from(head.to_string())),
+            Ok(Bytes::from(tail.to_string())),
+        ]);
+
+        let resp = collect(stream).await.unwrap();
+        let choice = &resp.choices[0];
+        assert_eq!(resp.id.as_deref(), Some("chatcmpl-1"));
+        assert_eq!(choice.message.reasoning.as_deref(), Some("think"));
+        assert_eq!(choice.message.content.as_deref(), Some("Hello"));
+        let calls = choice.message.tool_calls.as_ref().unwrap();
+        assert_eq!(calls[0].function.arguments, r#"{"a":1}"#);
+        assert_eq!(choice.finish_reason.as_deref(), Some("tool_calls"));
+        assert_eq!(resp.usage.completion_tokens, 9);
+    }
+}
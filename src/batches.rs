@@ -0,0 +1,805 @@
+use crate::accounting::Accounting;
+use crate::config::Config;
+use crate::error::{ProxyError, ProxyResult};
+use crate::export::format_timestamp;
+use crate::models::anthropic;
+use crate::proxy;
+use crate::upstream::UpstreamRegistry;
+use crate::vision::CaptionCache;
+use axum::{
+    extract::Path,
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use futures::StreamExt;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Anthropic's own per-batch request limit
+const MAX_BATCH_REQUESTS: usize = 100_000;
+/// Requests that haven't started this long after creation expire, like Anthropic's
+const BATCH_EXPIRY_SECS: u64 = 24 * 3600;
+const MAX_RESULT_BYTES: usize = 16 * 1024 * 1024;
+
+/// Message Batches API routes
+pub fn router() -> Router {
+    Router::new()
+        .route("/v1/messages/batches", get(list_batches).post(create_batch))
+        .route("/v1/messages/batches/:id", get(retrieve_batch))
+        .route("/v1/messages/batches/:id/cancel", post(cancel_batch))
+        .route("/v1/messages/batches/:id/results", get(batch_results))
+}
+
+/// Batch state, kept in memory or in a SQLite database (BATCH_DB_PATH).
+/// Every batch belongs to the API key that created it, and is deleted
+/// BATCH_RETENTION_SECS after creation.
+#[derive(Debug)]
+pub struct BatchStore {
+    backend: Backend,
+    retention_secs: u64,
+}
+
+#[derive(Debug)]
+enum Backend {
+    Memory(Mutex<HashMap<String, (Batch, Vec<Value>)>>),
+    Sqlite(Mutex<rusqlite::Connection>),
+}
+
+#[derive(Debug, Clone)]
+struct Batch {
+    id: String,
+    /// Hash of the API key that created the batch
+    owner: Option<String>,
+    created_at: u64,
+    ended_at: Option<u64>,
+    cancel_initiated_at: Option<u64>,
+    total: usize,
+    succeeded: usize,
+    errored: usize,
+    canceled: usize,
+    expired: usize,
+}
+
+impl Batch {
+    fn finished(&self) -> usize {
+        self.succeeded + self.errored + self.canceled + self.expired
+    }
+
+    /// Count a finished request by its result type, ending the batch with the last one
+    fn record(&mut self, kind: &str) {
+        match kind {
+            "succeeded" => self.succeeded += 1,
+            "canceled" => self.canceled += 1,
+            "expired" => self.expired += 1,
+            _ => self.errored += 1,
+        }
+        if self.finished() >= self.total {
+            self.ended_at.get_or_insert_with(now);
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let status = match (self.ended_at, self.cancel_initiated_at) {
+            (Some(_), _) => "ended",
+            (None, Some(_)) => "canceling",
+            (None, None) => "in_progress",
+        };
+        json!({
+            "id": self.id,
+            "type": "message_batch",
+            "processing_status": status,
+            "request_counts": {
+                "processing": self.total.saturating_sub(self.finished()),
+                "succeeded": self.succeeded,
+                "errored": self.errored,
+                "canceled": self.canceled,
+                "expired": self.expired,
+            },
+            "created_at": format_timestamp(self.created_at),
+            "expires_at": format_timestamp(self.created_at + BATCH_EXPIRY_SECS),
+            "ended_at": self.ended_at.map(format_timestamp),
+            "cancel_initiated_at": self.cancel_initiated_at.map(format_timestamp),
+            "archived_at": null,
+            "results_url": self.ended_at.map(|_| format!("/v1/messages/batches/{}/results", self.id)),
+        })
+    }
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        let count = |idx| row.get::<_, i64>(idx).map(|n| n as usize);
+        Ok(Self {
+            id: row.get(0)?,
+            owner: row.get(1)?,
+            created_at: row.get::<_, i64>(2)? as u64,
+            ended_at: row.get::<_, Option<i64>>(3)?.map(|t| t as u64),
+            cancel_initiated_at: row.get::<_, Option<i64>>(4)?.map(|t| t as u64),
+            total: count(5)?,
+            succeeded: count(6)?,
+            errored: count(7)?,
+            canceled: count(8)?,
+            expired: count(9)?,
+        })
+    }
+}
+
+const BATCH_COLUMNS: &str = "id, owner, created_at, ended_at, cancel_initiated_at, total, \
+     succeeded, errored, canceled, expired";
+
+impl BatchStore {
+    pub fn in_memory(retention_secs: u64) -> Self {
+        Self {
+            backend: Backend::Memory(Mutex::default()),
+            retention_secs,
+        }
+    }
+
+    /// Open (or create) a SQLite batch database. Batches that were still
+    /// running when the proxy stopped end, with their unfinished requests
+    /// reported as errored.
+    pub fn open(path: &std::path::Path, retention_secs: u64) -> anyhow::Result<Self> {
+        let db = rusqlite::Connection::open(path)?;
+        db.execute_batch(
+            "PRAGMA foreign_keys = ON;
+             CREATE TABLE IF NOT EXISTS batches (
+                 id TEXT PRIMARY KEY,
+                 owner TEXT,
+                 created_at INTEGER NOT NULL,
+                 ended_at INTEGER,
+                 cancel_initiated_at INTEGER,
+                 total INTEGER NOT NULL,
+                 succeeded INTEGER NOT NULL DEFAULT 0,
+                 errored INTEGER NOT NULL DEFAULT 0,
+                 canceled INTEGER NOT NULL DEFAULT 0,
+                 expired INTEGER NOT NULL DEFAULT 0
+             );
+             CREATE TABLE IF NOT EXISTS batch_requests (
+                 batch_id TEXT NOT NULL REFERENCES batches(id) ON DELETE CASCADE,
+                 custom_id TEXT NOT NULL,
+                 result TEXT,
+                 finished INTEGER,
+                 PRIMARY KEY (batch_id, custom_id)
+             );",
+        )?;
+
+        let interrupted = json!({
+            "type": "errored",
+            "error": {
+                "type": "error",
+                "error": {
+                    "type": "api_error",
+                    "message": "the proxy restarted before this request finished",
+                },
+            },
+        });
+        let tx = db.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE batches SET errored = errored + (SELECT count(*) FROM batch_requests
+                 WHERE batch_id = batches.id AND result IS NULL), ended_at = ?1
+             WHERE ended_at IS NULL",
+            [now() as i64],
+        )?;
+        tx.execute(
+            "UPDATE batch_requests SET result = json_object('custom_id', custom_id, 'result', json(?1)),
+                 finished = (SELECT coalesce(max(finished), 0) FROM batch_requests) + rowid
+             WHERE result IS NULL",
+            [interrupted.to_string()],
+        )?;
+        tx.commit()?;
+
+        Ok(Self {
+            backend: Backend::Sqlite(Mutex::new(db)),
+            retention_secs,
+        })
+    }
+
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        match &config.batch_db_path {
+            Some(path) => {
+                tracing::info!("Message batches: stored in {}", path.display());
+                Self::open(path, config.batch_retention_secs)
+            }
+            None => Ok(Self::in_memory(config.batch_retention_secs)),
+        }
+    }
+
+    fn insert(&self, batch: Batch, custom_ids: &[&str]) -> ProxyResult<()> {
+        self.prune();
+        match &self.backend {
+            Backend::Memory(batches) => {
+                batches
+                    .lock()
+                    .expect("batch store lock poisoned")
+                    .insert(batch.id.clone(), (batch, Vec::new()));
+            }
+            Backend::Sqlite(db) => {
+                let db = db.lock().expect("batch store lock poisoned");
+                let tx = db.unchecked_transaction().map_err(database_error)?;
+                tx.execute(
+                    "INSERT INTO batches (id, owner, created_at, total) VALUES (?1, ?2, ?3, ?4)",
+                    rusqlite::params![
+                        batch.id,
+                        batch.owner,
+                        batch.created_at as i64,
+                        batch.total as i64
+                    ],
+                )
+                .map_err(database_error)?;
+                let mut insert = tx
+                    .prepare("INSERT INTO batch_requests (batch_id, custom_id) VALUES (?1, ?2)")
+                    .map_err(database_error)?;
+                for custom_id in custom_ids {
+                    insert
+                        .execute([batch.id.as_str(), custom_id])
+                        .map_err(database_error)?;
+                }
+                drop(insert);
+                tx.commit().map_err(database_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The batch with this id, if it belongs to `owner`
+    fn get(&self, id: &str, owner: Option<&str>) -> ProxyResult<Batch> {
+        self.prune();
+        let batch = match &self.backend {
+            Backend::Memory(batches) => batches
+                .lock()
+                .expect("batch store lock poisoned")
+                .get(id)
+                .map(|(batch, _)| batch.clone()),
+            Backend::Sqlite(db) => {
+                use rusqlite::OptionalExtension;
+                db.lock()
+                    .expect("batch store lock poisoned")
+                    .query_row(
+                        &format!("SELECT {} FROM batches WHERE id = ?1", BATCH_COLUMNS),
+                        [id],
+                        Batch::from_row,
+                    )
+                    .optional()
+                    .map_err(database_error)?
+            }
+        };
+        batch
+            .filter(|batch| batch.owner.as_deref() == owner)
+            .ok_or_else(|| ProxyError::NotFound(format!("no message batch with id {}", id)))
+    }
+
+    /// Every batch belonging to `owner`, newest first
+    fn list(&self, owner: Option<&str>) -> ProxyResult<Vec<Batch>> {
+        self.prune();
+        let mut batches: Vec<Batch> = match &self.backend {
+            Backend::Memory(batches) => batches
+                .lock()
+                .expect("batch store lock poisoned")
+                .values()
+                .map(|(batch, _)| batch)
+                .filter(|batch| batch.owner.as_deref() == owner)
+                .cloned()
+                .collect(),
+            Backend::Sqlite(db) => {
+                let db = db.lock().expect("batch store lock poisoned");
+                let mut query = db
+                    .prepare(&format!(
+                        "SELECT {} FROM batches WHERE owner IS ?1",
+                        BATCH_COLUMNS
+                    ))
+                    .map_err(database_error)?;
+                let rows = query
+                    .query_map([owner], Batch::from_row)
+                    .map_err(database_error)?;
+                rows.collect::<rusqlite::Result<_>>()
+                    .map_err(database_error)?
+            }
+        };
+        batches.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(b.id.cmp(&a.id)));
+        Ok(batches)
+    }
+
+    /// Start canceling a batch that belongs to `owner` and hasn't ended
+    fn cancel(&self, id: &str, owner: Option<&str>) -> ProxyResult<Batch> {
+        let mut batch = self.get(id, owner)?;
+        if batch.ended_at.is_some() || batch.cancel_initiated_at.is_some() {
+            return Ok(batch);
+        }
+        tracing::info!("Message batch {} canceling", id);
+        let canceled_at = now();
+        batch.cancel_initiated_at = Some(canceled_at);
+        match &self.backend {
+            Backend::Memory(batches) => {
+                let mut batches = batches.lock().expect("batch store lock poisoned");
+                if let Some((stored, _)) = batches.get_mut(id) {
+                    stored.cancel_initiated_at.get_or_insert(canceled_at);
+                }
+            }
+            Backend::Sqlite(db) => {
+                db.lock()
+                    .expect("batch store lock poisoned")
+                    .execute(
+                        "UPDATE batches SET cancel_initiated_at = ?2
+                         WHERE id = ?1 AND cancel_initiated_at IS NULL",
+                        rusqlite::params![id, canceled_at as i64],
+                    )
+                    .map_err(database_error)?;
+            }
+        }
+        Ok(batch)
+    }
+
+    fn is_canceled(&self, id: &str) -> bool {
+        match &self.backend {
+            Backend::Memory(batches) => batches
+                .lock()
+                .expect("batch store lock poisoned")
+                .get(id)
+                .is_none_or(|(batch, _)| batch.cancel_initiated_at.is_some()),
+            Backend::Sqlite(db) => db
+                .lock()
+                .expect("batch store lock poisoned")
+                .query_row(
+                    "SELECT cancel_initiated_at IS NOT NULL FROM batches WHERE id = ?1",
+                    [id],
+                    |row| row.get(0),
+                )
+                .unwrap_or(true),
+        }
+    }
+
+    /// Record one request's result line, `{"custom_id": .., "result": ..}`
+    fn push_result(&self, id: &str, result: Value) {
+        let kind = result["result"]["type"].as_str().unwrap_or_default();
+        match &self.backend {
+            Backend::Memory(batches) => {
+                let mut batches = batches.lock().expect("batch store lock poisoned");
+                if let Some((batch, results)) = batches.get_mut(id) {
+                    batch.record(kind);
+                    results.push(result);
+                }
+            }
+            Backend::Sqlite(db) => {
+                let db = db.lock().expect("batch store lock poisoned");
+                let stored = (|| {
+                    let tx = db.unchecked_transaction()?;
+                    tx.execute(
+                        "UPDATE batch_requests SET result = ?3,
+                             finished = (SELECT coalesce(max(finished), 0) + 1 FROM batch_requests)
+                         WHERE batch_id = ?1 AND custom_id = ?2",
+                        rusqlite::params![id, result["custom_id"].as_str(), result.to_string()],
+                    )?;
+                    let column = match kind {
+                        "succeeded" | "canceled" | "expired" => kind,
+                        _ => "errored",
+                    };
+                    tx.execute(
+                        &format!(
+                            "UPDATE batches SET {column} = {column} + 1,
+                                 ended_at = CASE WHEN succeeded + errored + canceled + expired + 1
+                                     >= total THEN ?2 ELSE ended_at END
+                             WHERE id = ?1"
+                        ),
+                        rusqlite::params![id, now() as i64],
+                    )?;
+                    tx.commit()
+                })();
+                if let Err(err) = stored {
+                    tracing::error!("Failed to store a result of message batch {}: {}", id, err);
+                }
+            }
+        }
+    }
+
+    /// The results of a batch belonging to `owner` as JSONL, or None while
+    /// it is still processing
+    fn results(&self, id: &str, owner: Option<&str>) -> ProxyResult<Option<String>> {
+        if self.get(id, owner)?.ended_at.is_none() {
+            return Ok(None);
+        }
+        let lines = match &self.backend {
+            Backend::Memory(batches) => batches
+                .lock()
+                .expect("batch store lock poisoned")
+                .get(id)
+                .map(|(_, results)| results.iter().map(|r| format!("{}\n", r)).collect())
+                .unwrap_or_default(),
+            Backend::Sqlite(db) => {
+                let db = db.lock().expect("batch store lock poisoned");
+                let mut query = db
+                    .prepare(
+                        "SELECT result FROM batch_requests
+                         WHERE batch_id = ?1 AND result IS NOT NULL ORDER BY finished",
+                    )
+                    .map_err(database_error)?;
+                let rows = query
+                    .query_map([id], |row| row.get::<_, String>(0))
+                    .map_err(database_error)?;
+                let mut jsonl = String::new();
+                for line in rows {
+                    jsonl.push_str(&line.map_err(database_error)?);
+                    jsonl.push('\n');
+                }
+                jsonl
+            }
+        };
+        Ok(Some(lines))
+    }
+
+    /// Delete batches older than the retention period
+    fn prune(&self) {
+        let cutoff = now().saturating_sub(self.retention_secs);
+        match &self.backend {
+            Backend::Memory(batches) => batches
+                .lock()
+                .expect("batch store lock poisoned")
+                .retain(|_, (batch, _)| batch.created_at >= cutoff),
+            Backend::Sqlite(db) => {
+                let deleted = db
+                    .lock()
+                    .expect("batch store lock poisoned")
+                    .execute("DELETE FROM batches WHERE created_at < ?1", [cutoff as i64]);
+                if let Err(err) = deleted {
+                    tracing::error!("Failed to delete expired message batches: {}", err);
+                }
+            }
+        }
+    }
+}
+
+fn database_error(err: rusqlite::Error) -> ProxyError {
+    ProxyError::Internal(format!("message batch store: {}", err))
+}
+
+/// The batch owner for a request: a hash of its API key, so the store never
+/// holds the key itself
+fn owner(headers: &HeaderMap) -> Option<String> {
+    proxy::client_key(headers).map(|key| {
+        ring::digest::digest(&ring::digest::SHA256, key.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateBatch {
+    requests: Vec<BatchRequest>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    custom_id: String,
+    params: anthropic::AnthropicRequest,
+}
+
+/// Everything a batch entry needs to run through the Messages pipeline
+#[derive(Clone)]
+struct Pipeline {
+    config: Arc<Config>,
+    client: Client,
+    accounting: Arc<Accounting>,
+    upstreams: Arc<UpstreamRegistry>,
+    captions: Arc<CaptionCache>,
+    headers: HeaderMap,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn create_batch(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<Client>,
+    Extension(accounting): Extension<Arc<Accounting>>,
+    Extension(upstreams): Extension<Arc<UpstreamRegistry>>,
+    Extension(captions): Extension<Arc<CaptionCache>>,
+    Extension(store): Extension<Arc<BatchStore>>,
+    headers: HeaderMap,
+    Json(body): Json<CreateBatch>,
+) -> ProxyResult<Json<Value>> {
+    validate(&body.requests)?;
+
+    let id = format!(
+        "msgbatch_{:016x}",
+        RandomState::new().hash_one(SystemTime::now())
+    );
+    let batch = Batch {
+        id: id.clone(),
+        owner: owner(&headers),
+        created_at: now(),
+        ended_at: None,
+        cancel_initiated_at: None,
+        total: body.requests.len(),
+        succeeded: 0,
+        errored: 0,
+        canceled: 0,
+        expired: 0,
+    };
+    let created = batch.to_json();
+    let expires_at = batch.created_at + BATCH_EXPIRY_SECS;
+    let custom_ids: Vec<&str> = body.requests.iter().map(|r| r.custom_id.as_str()).collect();
+    store.insert(batch, &custom_ids)?;
+    tracing::info!(
+        "Message batch {} created with {} request(s)",
+        id,
+        body.requests.len()
+    );
+
+    let concurrency = config.batch_concurrency;
+    let pipeline = Pipeline {
+        config,
+        client,
+        accounting,
+        upstreams,
+        captions,
+        headers,
+    };
+    tokio::spawn(async move {
+        futures::stream::iter(body.requests)
+            .for_each_concurrent(concurrency, |entry| {
+                let pipeline = pipeline.clone();
+                let store = store.clone();
+                let id = id.clone();
+                async move {
+                    let result = if store.is_canceled(&id) {
+                        json!({ "type": "canceled" })
+                    } else if now() >= expires_at {
+                        json!({ "type": "expired" })
+                    } else {
+                        run_entry(pipeline, entry.params).await
+                    };
+                    store.push_result(
+                        &id,
+                        json!({ "custom_id": entry.custom_id, "result": result }),
+                    );
+                }
+            })
+            .await;
+        tracing::info!("Message batch {} ended", id);
+    });
+
+    Ok(Json(created))
+}
+
+fn validate(requests: &[BatchRequest]) -> ProxyResult<()> {
+    if requests.is_empty() || requests.len() > MAX_BATCH_REQUESTS {
+        return Err(ProxyError::InvalidRequest(format!(
+            "a batch must contain between 1 and {} requests",
+            MAX_BATCH_REQUESTS
+        )));
+    }
+    let mut custom_ids = HashSet::new();
+    for request in requests {
+        if !custom_ids.insert(request.custom_id.as_str()) {
+            return Err(ProxyError::InvalidRequest(format!(
+                "duplicate custom_id: {}",
+                request.custom_id
+            )));
+        }
+        if request.params.stream == Some(true) {
+            return Err(ProxyError::InvalidRequest(format!(
+                "{}: streaming is not supported in batches",
+                request.custom_id
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Run one batch entry as a regular non-streaming Messages request
+async fn run_entry(pipeline: Pipeline, params: anthropic::AnthropicRequest) -> Value {
+    let response = match proxy::proxy_handler(
+        Extension(pipeline.config),
+        Extension(pipeline.client),
+        Extension(pipeline.accounting),
+        Extension(pipeline.upstreams),
+        Extension(pipeline.captions),
+        pipeline.headers,
+        Ok(Json(json!(params))),
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(err) => err.into_response(),
+    };
+
+    let succeeded = response.status().is_success();
+    let body = axum::body::to_bytes(response.into_body(), MAX_RESULT_BYTES)
+        .await
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<Value>(&bytes).ok())
+        .unwrap_or(Value::Null);
+
+    if succeeded {
+        json!({ "type": "succeeded", "message": body })
+    } else {
+        let error = match body.get("error") {
+            Some(error) => error.clone(),
+            None => json!({ "type": "api_error", "message": "request failed" }),
+        };
+        json!({ "type": "errored", "error": { "type": "error", "error": error } })
+    }
+}
+
+async fn list_batches(
+    Extension(store): Extension<Arc<BatchStore>>,
+    headers: HeaderMap,
+) -> ProxyResult<Json<Value>> {
+    let data: Vec<Value> = store
+        .list(owner(&headers).as_deref())?
+        .iter()
+        .map(Batch::to_json)
+        .collect();
+    Ok(Json(json!({
+        "data": data,
+        "has_more": false,
+        "first_id": data.first().map(|b| b["id"].clone()),
+        "last_id": data.last().map(|b| b["id"].clone()),
+    })))
+}
+
+async fn retrieve_batch(
+    Extension(store): Extension<Arc<BatchStore>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ProxyResult<Json<Value>> {
+    let batch = store.get(&id, owner(&headers).as_deref())?;
+    Ok(Json(batch.to_json()))
+}
+
+async fn cancel_batch(
+    Extension(store): Extension<Arc<BatchStore>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ProxyResult<Json<Value>> {
+    let batch = store.cancel(&id, owner(&headers).as_deref())?;
+    Ok(Json(batch.to_json()))
+}
+
+async fn batch_results(
+    Extension(store): Extension<Arc<BatchStore>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ProxyResult<Response> {
+    let jsonl = store
+        .results(&id, owner(&headers).as_deref())?
+        .ok_or_else(|| {
+            ProxyError::InvalidRequest(format!(
+                "message batch {} is still processing; results are available once it has ended",
+                id
+            ))
+        })?;
+    Ok(([(header::CONTENT_TYPE, "application/binary")], jsonl).into_response())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Batch, BatchStore};
+    use serde_json::json;
+
+    fn batch(id: &str, owner: &str, created_at: u64) -> Batch {
+        Batch {
+            id: id.to_string(),
+            owner: Some(owner.to_string()),
+            created_at,
+            ended_at: None,
+            cancel_initiated_at: None,
+            total: 2,
+            succeeded: 0,
+            errored: 0,
+            canceled: 0,
+            expired: 0,
+        }
+    }
+
+    fn batches_end_once_every_request_has_a_result(store: BatchStore) {
+        store
+            .insert(batch("msgbatch_1", "a", super::now()), &["a", "b"])
+            .unwrap();
+
+        store.push_result(
+            "msgbatch_1",
+            json!({"custom_id": "a", "result": {"type": "succeeded"}}),
+        );
+        let batch = store.get("msgbatch_1", Some("a")).unwrap().to_json();
+        assert_eq!(batch["processing_status"], "in_progress");
+        assert_eq!(batch["request_counts"]["processing"], 1);
+        assert_eq!(store.results("msgbatch_1", Some("a")).unwrap(), None);
+
+        store.push_result(
+            "msgbatch_1",
+            json!({"custom_id": "b", "result": {"type": "expired"}}),
+        );
+        let batch = store.get("msgbatch_1", Some("a")).unwrap().to_json();
+        assert_eq!(batch["processing_status"], "ended");
+        assert_eq!(batch["request_counts"]["succeeded"], 1);
+        assert_eq!(batch["request_counts"]["expired"], 1);
+        assert_eq!(
+            batch["results_url"],
+            "/v1/messages/batches/msgbatch_1/results"
+        );
+        let results = store.results("msgbatch_1", Some("a")).unwrap().unwrap();
+        assert_eq!(results.lines().count(), 2);
+        assert!(results.starts_with(r#"{"custom_id":"a""#));
+        assert!(store.get("missing", Some("a")).is_err());
+    }
+
+    #[test]
+    fn memory_batches_end_once_every_request_has_a_result() {
+        batches_end_once_every_request_has_a_result(BatchStore::in_memory(3600));
+    }
+
+    #[test]
+    fn sqlite_batches_end_once_every_request_has_a_result() {
+        let path = std::env::temp_dir().join(format!("batches-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        batches_end_once_every_request_has_a_result(BatchStore::open(&path, 3600).unwrap());
+
+        // Batches survive a restart; ones still running end as errored
+        let store = BatchStore::open(&path, 3600).unwrap();
+        store
+            .insert(batch("msgbatch_2", "a", super::now()), &["a", "b"])
+            .unwrap();
+        store.push_result(
+            "msgbatch_2",
+            json!({"custom_id": "a", "result": {"type": "succeeded"}}),
+        );
+        drop(store);
+        let store = BatchStore::open(&path, 3600).unwrap();
+        assert!(store
+            .get("msgbatch_1", Some("a"))
+            .unwrap()
+            .ended_at
+            .is_some());
+        let batch = store.get("msgbatch_2", Some("a")).unwrap();
+        assert!(batch.ended_at.is_some());
+        assert_eq!((batch.succeeded, batch.errored), (1, 1));
+        let results = store.results("msgbatch_2", Some("a")).unwrap().unwrap();
+        assert!(results.lines().nth(1).unwrap().contains("proxy restarted"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn batches_are_only_visible_to_their_owner() {
+        let store = BatchStore::in_memory(3600);
+        store
+            .insert(batch("msgbatch_1", "a", super::now()), &["a", "b"])
+            .unwrap();
+        store
+            .insert(batch("msgbatch_2", "b", super::now()), &["a", "b"])
+            .unwrap();
+
+        assert!(store.get("msgbatch_1", Some("b")).is_err());
+        assert!(store.get("msgbatch_1", None).is_err());
+        assert!(store.results("msgbatch_1", Some("b")).is_err());
+        assert!(store.cancel("msgbatch_1", Some("b")).is_err());
+        let listed = store.list(Some("a")).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, "msgbatch_1");
+    }
+
+    #[test]
+    fn batches_are_deleted_after_the_retention_period() {
+        let store = BatchStore::in_memory(3600);
+        store
+            .insert(batch("msgbatch_old", "a", super::now() - 7200), &["a"])
+            .unwrap();
+        store
+            .insert(batch("msgbatch_new", "a", super::now()), &["a"])
+            .unwrap();
+
+        assert!(store.get("msgbatch_old", Some("a")).is_err());
+        assert_eq!(store.list(Some("a")).unwrap().len(), 1);
+    }
+}
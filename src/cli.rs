@@ -53,4 +53,18 @@ pub enum Command {
         #[arg(long, value_name = "FILE", default_value = "/tmp/anthropic-proxy.pid")]
         pid_file: PathBuf,
     },
+    /// Run the built-in response translation fixtures against this build and configuration
+    Selftest,
+    /// Probe a running proxy and exit non-zero if it is unhealthy (for container healthchecks)
+    Health {
+        /// Base URL of the proxy
+        #[arg(long, value_name = "URL", default_value = "http://127.0.0.1:3000")]
+        url: String,
+        /// Also require /readyz to report ready
+        #[arg(long)]
+        ready: bool,
+        /// Seconds to wait for each probe
+        #[arg(long, value_name = "SECS", default_value_t = 5)]
+        timeout: u64,
+    },
 }
@@ -0,0 +1,159 @@
+use crate::config::Config;
+use crate::proxy::EventStream;
+use crate::translator;
+use bytes::Bytes;
+use futures::stream::StreamExt;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+/// A text, thinking or tool input delta held back to be merged with the next
+struct PendingDelta {
+    index: u64,
+    block_type: &'static str,
+    text: String,
+    since: Instant,
+}
+
+impl PendingDelta {
+    fn event(self) -> Bytes {
+        translator::delta_event(self.index as usize, self.block_type, &self.text)
+    }
+}
+
+/// Pass translated events on as they come, logging the time to the first
+/// token. With STREAM_COALESCE_MS set, consecutive deltas of a block are
+/// merged for up to that long, or until they reach STREAM_COALESCE_BYTES.
+pub fn pace(events: EventStream, config: &Config, started: Instant) -> EventStream {
+    let max_delay = config.stream_coalesce_ms.map(Duration::from_millis);
+    let max_bytes = config.stream_coalesce_bytes;
+    Box::pin(async_stream::stream! {
+        let mut events = events;
+        let mut first_token = true;
+        let mut pending: Option<PendingDelta> = None;
+        loop {
+            let next = match (&pending, max_delay) {
+                (Some(held), Some(delay)) => {
+                    let wait = (held.since + delay).saturating_duration_since(Instant::now());
+                    match tokio::time::timeout(wait, events.next()).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            if let Some(held) = pending.take() {
+                                yield Ok(held.event());
+                            }
+                            continue;
+                        }
+                    }
+                }
+                _ => events.next().await,
+            };
+            let Some(item) = next else {
+                break;
+            };
+            let bytes = match item {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    if let Some(held) = pending.take() {
+                        yield Ok(held.event());
+                    }
+                    yield Err(err);
+                    continue;
+                }
+            };
+
+            if first_token && bytes.starts_with(b"event: content_block_delta\n") {
+                first_token = false;
+                tracing::debug!("Time to first token: {:?}", started.elapsed());
+            }
+
+            let delta = max_delay.and_then(|_| parse_delta(&bytes));
+            let Some((index, block_type, text)) = delta else {
+                if let Some(held) = pending.take() {
+                    yield Ok(held.event());
+                }
+                yield Ok(bytes);
+                continue;
+            };
+            match pending.as_mut() {
+                Some(held) if held.index == index && held.block_type == block_type => {
+                    held.text.push_str(&text);
+                }
+                _ => {
+                    if let Some(held) = pending.take() {
+                        yield Ok(held.event());
+                    }
+                    pending = Some(PendingDelta {
+                        index,
+                        block_type,
+                        text,
+                        since: Instant::now(),
+                    });
+                }
+            }
+            if pending.as_ref().is_some_and(|held| held.text.len() >= max_bytes) {
+                if let Some(held) = pending.take() {
+                    yield Ok(held.event());
+                }
+            }
+        }
+        if let Some(held) = pending.take() {
+            yield Ok(held.event());
+        }
+    })
+}
+
+/// The index, block type and text of a content_block_delta event that can
+/// be merged with its neighbours
+fn parse_delta(bytes: &[u8]) -> Option<(u64, &'static str, String)> {
+    let data = bytes.strip_prefix(b"event: content_block_delta\ndata: ")?;
+    let event: Value = serde_json::from_slice(data).ok()?;
+    let index = event["index"].as_u64()?;
+    let (block_type, field) = match event["delta"]["type"].as_str()? {
+        "text_delta" => ("text", "text"),
+        "thinking_delta" => ("thinking", "thinking"),
+        "input_json_delta" => ("tool_use", "partial_json"),
+        _ => return None,
+    };
+    let text = event["delta"][field].as_str()?.to_string();
+    Some((index, block_type, text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pace;
+    use crate::config::Config;
+    use crate::translator;
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn consecutive_deltas_are_merged_up_to_the_byte_limit() {
+        let mut config = Config::for_tests();
+        config.stream_coalesce_ms = Some(1000);
+        config.stream_coalesce_bytes = 6;
+        let events = vec![
+            translator::delta_event(0, "text", "Hel"),
+            translator::delta_event(0, "text", "lo, "),
+            translator::delta_event(0, "text", "wor"),
+            Bytes::from("event: ping\ndata: {\"type\": \"ping\"}\n\n"),
+            translator::delta_event(0, "text", "ld"),
+            translator::delta_event(1, "tool_use", "{}"),
+        ];
+        let stream = Box::pin(futures::stream::iter(events.into_iter().map(Ok)));
+
+        let output: Vec<Bytes> = pace(stream, &config, Instant::now())
+            .map(|item| item.unwrap())
+            .collect()
+            .await;
+        assert_eq!(
+            output,
+            [
+                translator::delta_event(0, "text", "Hello, "),
+                translator::delta_event(0, "text", "wor"),
+                Bytes::from("event: ping\ndata: {\"type\": \"ping\"}\n\n"),
+                translator::delta_event(0, "text", "ld"),
+                translator::delta_event(1, "tool_use", "{}"),
+            ]
+        );
+    }
+}
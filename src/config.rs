@@ -1,14 +1,283 @@
-use anyhow::{bail, Result};
+use crate::accounting::ModelPrice;
+use crate::error_rules::ErrorRules;
+use crate::export::S3Target;
+use crate::guardrails::Guardrails;
+use crate::model_rules::{ModelRules, RuleRegistry};
+use crate::quirks::{self, ModelCapabilities};
+use crate::routing::{ModelRoutes, DEFAULT_UPSTREAM};
+use crate::secrets::SecretScanner;
+use crate::server::Http2Mode;
+use crate::splits::ModelSplits;
+use crate::think_tags::Delimiters;
+use crate::vertex::Vertex;
+use anyhow::{bail, Context, Result};
 use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::{env, path::PathBuf};
 
+/// Largest content block the stream translator holds in memory by default
+const DEFAULT_STREAM_BLOCK_MAX_BYTES: usize = 8 * 1024 * 1024;
+
+/// How long message batches are kept by default, as long as Anthropic keeps results
+const DEFAULT_BATCH_RETENTION_SECS: u64 = 29 * 24 * 3600;
+
+/// API spoken by the upstream at UPSTREAM_BASE_URL
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamFormat {
+    /// OpenAI-compatible chat completions
+    #[serde(rename = "openai")]
+    OpenAI,
+    /// Anthropic Messages API
+    Anthropic,
+    /// Ollama's native chat API
+    Ollama,
+}
+
+/// What to do with a request's `top_k`, which OpenAI itself rejects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TopKMode {
+    /// Forward it unless the upstream is api.openai.com
+    Auto,
+    /// Always forward it (OpenRouter, vLLM, Ollama, ...)
+    Pass,
+    /// Never forward it
+    Drop,
+}
+
+/// How a response the upstream's content filter stopped reaches the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentFilterMode {
+    /// End the message with `stop_reason: "refusal"`
+    Refusal,
+    /// Fail the request with an error
+    Error,
+    /// Pretend the message ended normally
+    EndTurn,
+}
+
+/// When streamed tool input is sent to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolInputStreaming {
+    /// Whole once the call completes, unless the client opted into
+    /// fine-grained tool streaming
+    Auto,
+    /// Always whole once the call completes, repaired if needed
+    Buffered,
+    /// Always as the upstream produces it
+    Streamed,
+}
+
+/// How a request's `thinking.budget_tokens` is passed to the upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThinkingBudgetParams {
+    /// `reasoning_effort` for api.openai.com, `reasoning.max_tokens` for
+    /// openrouter.ai, nothing for other upstreams
+    Auto,
+    /// OpenAI's `reasoning_effort: low | medium | high`
+    Effort,
+    /// OpenRouter's `reasoning: {"max_tokens": N}`
+    MaxTokens,
+    /// Only used to pick REASONING_MODEL
+    Off,
+}
+
+/// What happens to thinking blocks in earlier assistant turns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThinkingHistory {
+    /// Leave them out
+    Drop,
+    /// Send them as the message's `reasoning_content`
+    Reasoning,
+    /// Fold them into the message text inside `<thinking>` tags
+    Text,
+}
+
+/// What to do with Anthropic `cache_control` prompt caching breakpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheControlMode {
+    /// Forward them to openrouter.ai, strip them for other upstreams
+    Auto,
+    /// Forward them on text content parts
+    Pass,
+    /// Never forward them
+    Strip,
+}
+
+/// Request and response adjustments for an upstream's known deviations
+/// from OpenAI's API
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamQuirks {
+    /// `gemini` for Google's OpenAI endpoints, none elsewhere; vLLM has to
+    /// be named
+    Auto,
+    None,
+    /// Google's OpenAI-compatible Gemini API
+    Gemini,
+    /// vLLM's OpenAI-compatible server
+    Vllm,
+}
+
+/// File format of the usage export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// How tool input schemas are cleaned before they go upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SchemaProfile {
+    /// `gemini` for Google's OpenAI endpoint, `openai` for api.openai.com,
+    /// `standard` elsewhere
+    Auto,
+    /// Inline `$ref`s, drop unsupported formats and schema metadata
+    Standard,
+    /// Standard, with `oneOf` turned into `anyOf` and `allOf` merged
+    #[serde(rename = "openai")]
+    OpenAI,
+    /// OpenAI, also without keywords Gemini rejects and with string-only enums
+    Gemini,
+    /// Forward schemas unchanged
+    Off,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub port: u16,
+    pub http2: Http2Mode,
     pub base_url: String,
+    pub upstream_format: UpstreamFormat,
     pub api_key: Option<String>,
+    pub upstream_headers: Vec<(String, String)>,
+    /// Model options sent with every request to an Ollama upstream
+    pub ollama_options: Map<String, Value>,
+    /// How long Ollama keeps the model loaded, a duration or seconds
+    pub ollama_keep_alive: Option<Value>,
+    pub upstream_routes: Option<Arc<ModelRoutes>>,
+    /// Routing rules from ROUTING_RULES_FILE, replaceable through the admin API
+    pub model_rules: Arc<RuleRegistry>,
+    /// Vertex AI project whose models are reached through UPSTREAM_BASE_URL
+    pub vertex: Option<Arc<Vertex>>,
     pub reasoning_model: Option<String>,
     pub completion_model: Option<String>,
+    pub haiku_model: Option<String>,
+    pub sonnet_model: Option<String>,
+    pub opus_model: Option<String>,
+    pub small_model: Option<String>,
+    /// Requests asking for at most this many tokens count as background work
+    pub small_model_max_tokens: u32,
+    pub fallback_models: Vec<String>,
+    pub model_splits: Option<Arc<ModelSplits>>,
+    /// Client API keys allowed to send routing override headers, `*` for all
+    pub routing_override_keys: Vec<String>,
+    pub ensemble_models: Vec<String>,
+    pub ensemble_judge_model: Option<String>,
+    pub ensemble_record_file: Option<PathBuf>,
+    pub output_guardrails: Option<Guardrails>,
+    pub error_rules: Option<ErrorRules>,
+    pub secret_scanner: Option<SecretScanner>,
+    pub context_limits: Vec<(String, u32)>,
+    pub default_context_limit: Option<u32>,
+    pub model_pricing: Vec<(String, ModelPrice)>,
+    pub daily_spend_cap: Option<f64>,
+    pub client_budgets: Vec<(String, f64)>,
+    pub budget_downgrade_model: Option<String>,
+    pub budget_downgrade_threshold: f64,
+    pub usage_export_dir: Option<PathBuf>,
+    pub usage_export_interval_secs: u64,
+    pub usage_export_format: ExportFormat,
+    pub usage_export_s3: Option<S3Target>,
+    pub request_tag_keys: Vec<String>,
+    pub request_tag_header: String,
+    pub request_tag_max_values: usize,
+    pub admin_token: Option<String>,
+    pub admin_port: Option<u16>,
+    pub upstream_affinity: bool,
+    /// Request header or `metadata.<field>` identifying a conversation
+    pub affinity_session_key: Option<String>,
+    pub affinity_ttl_secs: u64,
+    pub text_only_models: Vec<String>,
+    pub hide_thinking_models: Vec<String>,
+    pub strip_thinking: bool,
+    pub think_tag_models: Vec<String>,
+    pub think_tags: Vec<(String, Delimiters)>,
+    pub tool_result_max_chars: Option<usize>,
+    pub tool_schema_max_chars: Option<usize>,
+    pub tools_max_chars: Option<usize>,
+    pub empty_response_retries: u32,
+    pub upstream_retries: u32,
+    pub upstream_retry_backoff_ms: u64,
+    pub upstream_retry_jitter: f64,
+    pub upstream_retry_statuses: Vec<u16>,
+    pub rate_limit_retry_secs: Option<u64>,
+    pub circuit_breaker_failures: Option<u32>,
+    pub circuit_breaker_cooldown_secs: u64,
+    pub stream_idle_timeout_secs: Option<u64>,
+    pub stream_block_max_bytes: Option<usize>,
+    pub stream_coalesce_ms: Option<u64>,
+    pub stream_coalesce_bytes: usize,
+    pub batch_concurrency: usize,
+    pub batch_db_path: Option<PathBuf>,
+    pub batch_retention_secs: u64,
+    pub debug_endpoints: bool,
+    pub image_caption_model: Option<String>,
+    pub image_caption_prompt: Option<String>,
+    pub inline_image_urls: bool,
+    pub structured_output_tools: bool,
+    pub extra_params: Vec<String>,
+    pub extra_params_deny: Vec<String>,
+    pub stop_sequences_max: Option<usize>,
+    pub emulate_stop_sequences: bool,
+    pub report_requested_model: bool,
+    pub ping_interval_secs: Option<u64>,
+    pub heartbeat_interval_secs: Option<u64>,
+    pub early_message_start: bool,
+    pub stream_upstream: bool,
+    pub schema_profile: SchemaProfile,
+    pub quirks: UpstreamQuirks,
+    /// Sampling extras sent to vLLM unless the client set them
+    pub vllm_repetition_penalty: Option<f64>,
+    pub vllm_min_p: Option<f64>,
+    pub model_capabilities: Vec<(String, ModelCapabilities)>,
+    pub strict_tools: bool,
+    pub auto_continue_max: u32,
+    pub reasoning_limits: Vec<(String, u32)>,
+    pub reasoning_limit_upstream: bool,
+    pub top_k: TopKMode,
+    pub content_filter: ContentFilterMode,
+    pub tool_input_streaming: ToolInputStreaming,
+    pub cache_control: CacheControlMode,
+    /// Whether the upstream is OpenRouter; None decides by the base URL
+    pub openrouter: Option<bool>,
+    /// OpenRouter provider routing preferences, sent as `provider`
+    pub openrouter_provider: Option<Value>,
+    /// Models OpenRouter falls back to, sent as `models`
+    pub openrouter_models: Vec<String>,
+    /// OpenRouter message transforms such as `middle-out`
+    pub openrouter_transforms: Vec<String>,
+    /// App attribution sent to OpenRouter as HTTP-Referer and X-Title
+    pub openrouter_referer: Option<String>,
+    pub openrouter_title: Option<String>,
+    pub thinking_budget_params: ThinkingBudgetParams,
+    pub thinking_history: ThinkingHistory,
     pub debug: bool,
     pub verbose: bool,
 }
@@ -16,10 +285,8 @@ pub struct Config {
 impl Config {
     fn load_dotenv(custom_path: Option<PathBuf>) -> Option<PathBuf> {
         if let Some(path) = custom_path {
-            if path.exists() {
-                if let Ok(_) = dotenvy::from_path(&path) {
-                    return Some(path);
-                }
+            if path.exists() && dotenvy::from_path(&path).is_ok() {
+                return Some(path);
             }
             eprintln!(
                 "⚠️  WARNING: Custom config file not found: {}",
@@ -31,29 +298,21 @@ impl Config {
             return Some(path);
         }
 
-        if let Some(home) = env::var("HOME").ok() {
+        if let Ok(home) = env::var("HOME") {
             let home_config = PathBuf::from(home).join(".anthropic-proxy.env");
-            if home_config.exists() {
-                if let Ok(_) = dotenvy::from_path(&home_config) {
-                    return Some(home_config);
-                }
+            if home_config.exists() && dotenvy::from_path(&home_config).is_ok() {
+                return Some(home_config);
             }
         }
 
         let etc_config = PathBuf::from("/etc/anthropic-proxy/.env");
-        if etc_config.exists() {
-            if let Ok(_) = dotenvy::from_path(&etc_config) {
-                return Some(etc_config);
-            }
+        if etc_config.exists() && dotenvy::from_path(&etc_config).is_ok() {
+            return Some(etc_config);
         }
 
         None
     }
 
-    pub fn from_env() -> Result<Self> {
-        Self::from_env_with_path(None)
-    }
-
     pub fn from_env_with_path(custom_path: Option<PathBuf>) -> Result<Self> {
         if let Some(path) = Self::load_dotenv(custom_path) {
             eprintln!("📄 Loaded config from: {}", path.display());
@@ -61,13 +320,38 @@ impl Config {
             eprintln!("ℹ️  No .env file found, using environment variables only");
         }
 
-        let port = env::var("PORT")
-            .ok()
-            .and_then(|p| p.parse().ok())
-            .unwrap_or(3000);
+        let port = Self::parse_number("PORT")?.unwrap_or(3000);
+
+        let http2 = match env::var("HTTP2").ok().filter(|v| !v.is_empty()) {
+            Some(raw) => Http2Mode::parse(&raw)
+                .ok_or_else(|| anyhow::anyhow!("HTTP2 must be auto, off or only"))?,
+            None => Http2Mode::Auto,
+        };
+
+        let vertex = match env::var("VERTEX_PROJECT").ok().filter(|p| !p.is_empty()) {
+            Some(project) => {
+                let location = env::var("VERTEX_LOCATION")
+                    .ok()
+                    .filter(|l| !l.is_empty())
+                    .unwrap_or_else(|| "global".to_string());
+                let credentials = env::var("VERTEX_CREDENTIALS_FILE")
+                    .ok()
+                    .filter(|p| !p.is_empty())
+                    .map(PathBuf::from);
+                Some(Arc::new(Vertex::new(project, location, credentials)?))
+            }
+            None => None,
+        };
 
+        // Vertex AI only needs a base URL to reach it through a private endpoint
         let base_url = env::var("UPSTREAM_BASE_URL")
             .or_else(|_| env::var("ANTHROPIC_PROXY_BASE_URL"))
+            .or_else(|err| {
+                vertex
+                    .as_ref()
+                    .map(|vertex| Vertex::default_base_url(&vertex.location))
+                    .ok_or(err)
+            })
             .map_err(|_| {
                 anyhow::anyhow!(
                     "UPSTREAM_BASE_URL is required. Set it to your OpenAI-compatible endpoint.\n\
@@ -79,67 +363,1051 @@ impl Config {
                 )
             })?;
 
-        Self::validate_base_url(&base_url)?;
+        let upstream_format = match env::var("UPSTREAM_FORMAT").ok().filter(|v| !v.is_empty()) {
+            Some(raw) => match raw.trim().to_lowercase().as_str() {
+                "openai" => UpstreamFormat::OpenAI,
+                "anthropic" => UpstreamFormat::Anthropic,
+                "ollama" => UpstreamFormat::Ollama,
+                _ => bail!("UPSTREAM_FORMAT must be openai, anthropic or ollama"),
+            },
+            None => UpstreamFormat::OpenAI,
+        };
+
+        Self::validate_base_url(&base_url, upstream_format)?;
+        if vertex.is_some() && upstream_format == UpstreamFormat::Ollama {
+            bail!("VERTEX_PROJECT can't be combined with UPSTREAM_FORMAT=ollama");
+        }
 
         let api_key = env::var("UPSTREAM_API_KEY")
             .or_else(|_| env::var("OPENROUTER_API_KEY"))
             .ok()
             .filter(|k| !k.is_empty());
 
+        let upstream_headers = Self::parse_pairs("UPSTREAM_HEADERS")?;
+        Self::validate_headers(&upstream_headers).context("UPSTREAM_HEADERS")?;
+
+        // Numbers and booleans are sent as such, anything else as a string
+        let ollama_options = Self::parse_pairs("OLLAMA_OPTIONS")?
+            .into_iter()
+            .map(|(name, value)| {
+                let value = serde_json::from_str(&value).unwrap_or(Value::String(value));
+                (name, value)
+            })
+            .collect();
+        let ollama_keep_alive = env::var("OLLAMA_KEEP_ALIVE")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(|v| v.parse::<i64>().map_or(Value::String(v), Value::from));
+
         let reasoning_model = env::var("REASONING_MODEL").ok();
         let completion_model = env::var("COMPLETION_MODEL").ok();
+        let haiku_model = env::var("HAIKU_MODEL").ok().filter(|m| !m.is_empty());
+        let sonnet_model = env::var("SONNET_MODEL").ok().filter(|m| !m.is_empty());
+        let opus_model = env::var("OPUS_MODEL").ok().filter(|m| !m.is_empty());
+        let small_model = env::var("SMALL_MODEL").ok().filter(|m| !m.is_empty());
+        let small_model_max_tokens = Self::parse_number("SMALL_MODEL_MAX_TOKENS")?.unwrap_or(512);
+        let fallback_models = Self::parse_list("FALLBACK_MODELS");
+        let routing_override_keys = Self::parse_list("ROUTING_OVERRIDE_KEYS");
+        let model_splits = Self::parse_pairs("MODEL_SPLITS")?;
+        let model_splits = if model_splits.is_empty() {
+            None
+        } else {
+            Some(Arc::new(ModelSplits::parse(model_splits)?))
+        };
 
-        let debug = env::var("DEBUG")
-            .map(|v| v == "1" || v.to_lowercase() == "true")
-            .unwrap_or(false);
+        let ensemble_models = Self::parse_list("ENSEMBLE_MODELS");
+        if ensemble_models.len() == 1 {
+            bail!("ENSEMBLE_MODELS must list at least two models");
+        }
+        let ensemble_judge_model = env::var("ENSEMBLE_JUDGE_MODEL")
+            .ok()
+            .filter(|m| !m.is_empty());
+        let ensemble_record_file = env::var("ENSEMBLE_RECORD_FILE")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from);
 
-        let verbose = env::var("VERBOSE")
-            .map(|v| v == "1" || v.to_lowercase() == "true")
-            .unwrap_or(false);
+        let output_guardrails = env::var("OUTPUT_GUARDRAILS_FILE")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .map(|p| Guardrails::load(&PathBuf::from(p)))
+            .transpose()?;
+        let error_rules = env::var("ERROR_RULES_FILE")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .map(|p| ErrorRules::load(&PathBuf::from(p)))
+            .transpose()?;
+        let model_rules = env::var("ROUTING_RULES_FILE")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .map(|p| ModelRules::load(&PathBuf::from(p)))
+            .transpose()?
+            .unwrap_or_default();
+        let model_rules = Arc::new(RuleRegistry::new(model_rules));
+
+        let mask_secrets = Self::parse_flag("MASK_SECRETS")?.unwrap_or(false);
+        let secret_scanner = if mask_secrets {
+            let patterns_file = env::var("SECRET_PATTERNS_FILE")
+                .ok()
+                .filter(|p| !p.is_empty())
+                .map(PathBuf::from);
+            Some(SecretScanner::new(patterns_file.as_deref())?)
+        } else {
+            None
+        };
+
+        let context_limits = Self::parse_pairs("MODEL_CONTEXT_LIMITS")?
+            .into_iter()
+            .map(|(model, limit)| {
+                limit
+                    .parse()
+                    .map(|limit| (model.clone(), limit))
+                    .map_err(|_| {
+                        anyhow::anyhow!("MODEL_CONTEXT_LIMITS has an invalid limit for {}", model)
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let default_context_limit = Self::parse_number("DEFAULT_CONTEXT_LIMIT")?;
+
+        let model_pricing = Self::parse_pairs("MODEL_PRICING")?
+            .into_iter()
+            .map(|(model, price)| {
+                ModelPrice::parse(&price)
+                    .map(|price| (model.clone(), price))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "MODEL_PRICING for {} must look like input/output USD per million tokens, e.g. 2.5/10",
+                            model
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let daily_spend_cap = Self::parse_number("DAILY_SPEND_CAP")?;
+        let client_budgets = Self::parse_pairs("CLIENT_BUDGETS")?
+            .into_iter()
+            .map(|(key, budget)| {
+                budget
+                    .parse()
+                    .map(|budget| (key, budget))
+                    .map_err(|_| anyhow::anyhow!("CLIENT_BUDGETS values must be USD amounts"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let budget_downgrade_model = env::var("BUDGET_DOWNGRADE_MODEL")
+            .ok()
+            .filter(|m| !m.is_empty());
+        let budget_downgrade_threshold =
+            Self::parse_number("BUDGET_DOWNGRADE_THRESHOLD")?.unwrap_or(0.8);
+        if (daily_spend_cap.is_some() || !client_budgets.is_empty()) && model_pricing.is_empty() {
+            bail!("DAILY_SPEND_CAP and CLIENT_BUDGETS need MODEL_PRICING to track spend");
+        }
 
-        Ok(Config {
+        let usage_export_dir = env::var("USAGE_EXPORT_DIR")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from);
+        let usage_export_interval_secs = match Self::parse_number("USAGE_EXPORT_INTERVAL_SECS")? {
+            Some(0) => bail!("USAGE_EXPORT_INTERVAL_SECS must be at least 1"),
+            Some(secs) => secs,
+            None => 3600,
+        };
+        let usage_export_format = match env::var("USAGE_EXPORT_FORMAT")
+            .ok()
+            .filter(|v| !v.is_empty())
+        {
+            Some(raw) => match raw.trim().to_lowercase().as_str() {
+                "csv" => ExportFormat::Csv,
+                "parquet" => ExportFormat::Parquet,
+                _ => bail!("USAGE_EXPORT_FORMAT must be csv or parquet"),
+            },
+            None => ExportFormat::Csv,
+        };
+        let usage_export_s3 = match env::var("USAGE_EXPORT_S3_URL")
+            .ok()
+            .filter(|v| !v.is_empty())
+        {
+            Some(url) => {
+                let url = Url::parse(&url).context("USAGE_EXPORT_S3_URL is not a URL")?;
+                if url
+                    .path_segments()
+                    .is_none_or(|mut path| path.next().unwrap_or("").is_empty())
+                {
+                    bail!("USAGE_EXPORT_S3_URL must name a bucket: https://<endpoint>/<bucket>");
+                }
+                let credential = |var: &str| {
+                    env::var(var)
+                        .ok()
+                        .filter(|v| !v.is_empty())
+                        .ok_or_else(|| anyhow::anyhow!("USAGE_EXPORT_S3_URL needs {}", var))
+                };
+                Some(S3Target {
+                    url,
+                    region: env::var("USAGE_EXPORT_S3_REGION")
+                        .ok()
+                        .filter(|v| !v.is_empty())
+                        .unwrap_or_else(|| "us-east-1".to_string()),
+                    access_key_id: credential("AWS_ACCESS_KEY_ID")?,
+                    secret_access_key: credential("AWS_SECRET_ACCESS_KEY")?,
+                    session_token: env::var("AWS_SESSION_TOKEN").ok().filter(|v| !v.is_empty()),
+                })
+            }
+            None => None,
+        };
+
+        let request_tag_keys = Self::parse_list("REQUEST_TAG_KEYS")
+            .into_iter()
+            .map(|key| key.to_lowercase())
+            .collect();
+        let request_tag_header = env::var("REQUEST_TAG_HEADER")
+            .ok()
+            .filter(|h| !h.is_empty())
+            .unwrap_or_else(|| "x-proxy-tags".to_string())
+            .to_lowercase();
+        let request_tag_max_values = Self::parse_number("REQUEST_TAG_MAX_VALUES")?
+            .filter(|&n| n > 0)
+            .unwrap_or(50);
+
+        let admin_token = env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+        let admin_port = Self::parse_number("ADMIN_PORT")?;
+
+        let upstream_affinity = Self::parse_flag("UPSTREAM_AFFINITY")?.unwrap_or(false);
+        let affinity_session_key = env::var("AFFINITY_SESSION_KEY")
+            .ok()
+            .map(|k| k.trim().to_string())
+            .filter(|k| !k.is_empty());
+        let affinity_ttl_secs = Self::parse_number("AFFINITY_TTL_SECS")?
+            .filter(|&secs| secs > 0)
+            .unwrap_or(3600);
+
+        let text_only_models = Self::parse_list("TEXT_ONLY_MODELS");
+        let hide_thinking_models = Self::parse_list("HIDE_THINKING_MODELS");
+        let strip_thinking = Self::parse_flag("STRIP_THINKING")?.unwrap_or(false);
+        let think_tag_models = Self::parse_list("THINK_TAG_MODELS");
+        let think_tags = Self::parse_pairs("THINK_TAGS")?
+            .into_iter()
+            .map(|(model, tags)| {
+                Delimiters::parse(&tags)
+                    .map(|tags| (model.clone(), tags))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "THINK_TAGS entries must look like model=open|close: {}",
+                            model
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let tool_result_max_chars =
+            Self::parse_number("TOOL_RESULT_MAX_CHARS")?.filter(|&max| max > 0);
+        let tool_schema_max_chars =
+            Self::parse_number("TOOL_SCHEMA_MAX_CHARS")?.filter(|&max| max > 0);
+        let tools_max_chars = Self::parse_number("TOOLS_MAX_CHARS")?.filter(|&max| max > 0);
+        let empty_response_retries = Self::parse_number("EMPTY_RESPONSE_RETRIES")?.unwrap_or(1);
+        let upstream_retries = Self::parse_number("UPSTREAM_RETRIES")?.unwrap_or(2);
+        let upstream_retry_backoff_ms =
+            Self::parse_number("UPSTREAM_RETRY_BACKOFF_MS")?.unwrap_or(250);
+        let upstream_retry_jitter = Self::parse_number("UPSTREAM_RETRY_JITTER")?.unwrap_or(0.2);
+        if !(0.0..=1.0).contains(&upstream_retry_jitter) {
+            bail!("UPSTREAM_RETRY_JITTER must be between 0 and 1");
+        }
+        let upstream_retry_statuses = match Self::parse_list("UPSTREAM_RETRY_STATUSES") {
+            statuses if statuses.is_empty() => vec![502, 503, 504],
+            statuses => statuses
+                .iter()
+                .map(|status| {
+                    status
+                        .parse()
+                        .ok()
+                        .filter(|status| (100..600).contains(status))
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "UPSTREAM_RETRY_STATUSES has an invalid status: {}",
+                                status
+                            )
+                        })
+                })
+                .collect::<Result<Vec<u16>>>()?,
+        };
+        let rate_limit_retry_secs =
+            Self::parse_number("RATE_LIMIT_RETRY_SECS")?.filter(|&secs| secs > 0);
+        let circuit_breaker_failures =
+            Self::parse_number("CIRCUIT_BREAKER_FAILURES")?.filter(|&failures| failures > 0);
+        let circuit_breaker_cooldown_secs =
+            Self::parse_number("CIRCUIT_BREAKER_COOLDOWN_SECS")?.unwrap_or(30);
+        let stream_idle_timeout_secs =
+            Self::parse_number("STREAM_IDLE_TIMEOUT_SECS")?.filter(|&secs| secs > 0);
+        let stream_block_max_bytes = Self::parse_number("STREAM_BLOCK_MAX_BYTES")?
+            .or(Some(DEFAULT_STREAM_BLOCK_MAX_BYTES))
+            .filter(|&max| max > 0);
+        let stream_coalesce_ms = Self::parse_number("STREAM_COALESCE_MS")?.filter(|&ms| ms > 0);
+        let stream_coalesce_bytes = Self::parse_number("STREAM_COALESCE_BYTES")?
+            .filter(|&max| max > 0)
+            .unwrap_or(1024);
+        let batch_concurrency = Self::parse_number("BATCH_CONCURRENCY")?
+            .filter(|&n| n > 0)
+            .unwrap_or(4);
+        let batch_db_path = env::var("BATCH_DB_PATH")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from);
+        let batch_retention_secs =
+            Self::parse_number("BATCH_RETENTION_SECS")?.unwrap_or(DEFAULT_BATCH_RETENTION_SECS);
+        let debug_endpoints = Self::parse_flag("DEBUG_ENDPOINTS")?.unwrap_or(false);
+        let image_caption_model = env::var("IMAGE_CAPTION_MODEL")
+            .ok()
+            .filter(|m| !m.is_empty());
+        let image_caption_prompt = env::var("IMAGE_CAPTION_PROMPT")
+            .ok()
+            .filter(|p| !p.is_empty());
+        let inline_image_urls = Self::parse_flag("INLINE_IMAGE_URLS")?.unwrap_or(false);
+        let structured_output_tools = Self::parse_flag("STRUCTURED_OUTPUT_TOOLS")?.unwrap_or(false);
+        let extra_params = Self::parse_list("EXTRA_PARAMS");
+        let extra_params_deny = Self::parse_list("EXTRA_PARAMS_DENY");
+        let stop_sequences_max = Self::parse_number("STOP_SEQUENCES_MAX")?;
+        let emulate_stop_sequences = Self::parse_flag("EMULATE_STOP_SEQUENCES")?.unwrap_or(false);
+        let report_requested_model = Self::parse_flag("REPORT_REQUESTED_MODEL")?.unwrap_or(false);
+        let ping_interval_secs = Self::parse_number("PING_INTERVAL_SECS")?.filter(|&secs| secs > 0);
+        let heartbeat_interval_secs =
+            Self::parse_number("HEARTBEAT_INTERVAL_SECS")?.filter(|&secs| secs > 0);
+        let early_message_start = Self::parse_flag("EARLY_MESSAGE_START")?.unwrap_or(false);
+        let stream_upstream = Self::parse_flag("STREAM_UPSTREAM")?.unwrap_or(false);
+        let strict_tools = Self::parse_flag("STRICT_TOOLS")?.unwrap_or(false);
+
+        let auto_continue_max = Self::parse_number("AUTO_CONTINUE_MAX")?.unwrap_or(0);
+
+        let reasoning_limits = Self::parse_pairs("REASONING_TOKEN_LIMITS")?
+            .into_iter()
+            .map(|(model, limit)| {
+                limit
+                    .parse()
+                    .map(|limit| (model.clone(), limit))
+                    .map_err(|_| {
+                        anyhow::anyhow!("REASONING_TOKEN_LIMITS has an invalid limit for {}", model)
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let reasoning_limit_upstream =
+            Self::parse_flag("REASONING_LIMIT_UPSTREAM")?.unwrap_or(false);
+        let top_k = match env::var("TOP_K").ok().filter(|v| !v.is_empty()) {
+            Some(raw) => match raw.trim().to_lowercase().as_str() {
+                "auto" => TopKMode::Auto,
+                "pass" => TopKMode::Pass,
+                "drop" => TopKMode::Drop,
+                _ => bail!("TOP_K must be auto, pass or drop"),
+            },
+            None => TopKMode::Auto,
+        };
+        let content_filter = match env::var("CONTENT_FILTER").ok().filter(|v| !v.is_empty()) {
+            Some(raw) => match raw.trim().to_lowercase().as_str() {
+                "refusal" => ContentFilterMode::Refusal,
+                "error" => ContentFilterMode::Error,
+                "end_turn" => ContentFilterMode::EndTurn,
+                _ => bail!("CONTENT_FILTER must be refusal, error or end_turn"),
+            },
+            None => ContentFilterMode::Refusal,
+        };
+        let tool_input_streaming = match env::var("TOOL_INPUT_STREAMING")
+            .ok()
+            .filter(|v| !v.is_empty())
+        {
+            Some(raw) => match raw.trim().to_lowercase().as_str() {
+                "auto" => ToolInputStreaming::Auto,
+                "buffered" => ToolInputStreaming::Buffered,
+                "streamed" => ToolInputStreaming::Streamed,
+                _ => bail!("TOOL_INPUT_STREAMING must be auto, buffered or streamed"),
+            },
+            None => ToolInputStreaming::Auto,
+        };
+        let cache_control = match env::var("CACHE_CONTROL").ok().filter(|v| !v.is_empty()) {
+            Some(raw) => match raw.trim().to_lowercase().as_str() {
+                "auto" => CacheControlMode::Auto,
+                "pass" => CacheControlMode::Pass,
+                "strip" => CacheControlMode::Strip,
+                _ => bail!("CACHE_CONTROL must be auto, pass or strip"),
+            },
+            None => CacheControlMode::Auto,
+        };
+        let openrouter = Self::parse_flag("OPENROUTER")?;
+        let openrouter_provider = match env::var("OPENROUTER_PROVIDER")
+            .ok()
+            .filter(|v| !v.is_empty())
+        {
+            Some(raw) => match serde_json::from_str::<Value>(&raw) {
+                Ok(provider @ Value::Object(_)) => Some(provider),
+                _ => bail!("OPENROUTER_PROVIDER must be a JSON object of provider preferences"),
+            },
+            None => None,
+        };
+        let openrouter_models = Self::parse_list("OPENROUTER_MODELS");
+        let openrouter_transforms = Self::parse_list("OPENROUTER_TRANSFORMS");
+        let openrouter_referer = env::var("OPENROUTER_REFERER")
+            .ok()
+            .filter(|v| !v.is_empty());
+        let openrouter_title = env::var("OPENROUTER_TITLE").ok().filter(|v| !v.is_empty());
+        Self::validate_headers(
+            &openrouter_referer
+                .iter()
+                .map(|v| ("HTTP-Referer".to_string(), v.clone()))
+                .chain(
+                    openrouter_title
+                        .iter()
+                        .map(|v| ("X-Title".to_string(), v.clone())),
+                )
+                .collect::<Vec<_>>(),
+        )
+        .context("OPENROUTER_REFERER/OPENROUTER_TITLE")?;
+        let quirks = match env::var("UPSTREAM_QUIRKS").ok().filter(|v| !v.is_empty()) {
+            Some(raw) => match raw.trim().to_lowercase().as_str() {
+                "auto" => UpstreamQuirks::Auto,
+                "none" => UpstreamQuirks::None,
+                "gemini" => UpstreamQuirks::Gemini,
+                "vllm" => UpstreamQuirks::Vllm,
+                _ => bail!("UPSTREAM_QUIRKS must be auto, none, gemini or vllm"),
+            },
+            None => UpstreamQuirks::Auto,
+        };
+        let vllm_repetition_penalty = Self::parse_number("VLLM_REPETITION_PENALTY")?;
+        let vllm_min_p = Self::parse_number("VLLM_MIN_P")?;
+        let model_capabilities = Self::parse_pairs("MODEL_CAPABILITIES")?
+            .into_iter()
+            .map(|(model, flags)| {
+                ModelCapabilities::parse(&flags)
+                    .map(|caps| (model.clone(), caps))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "MODEL_CAPABILITIES for {} must be none or max_completion_tokens, no_sampling and developer_role joined by +",
+                            model
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let schema_profile = match env::var("SCHEMA_PROFILE").ok().filter(|v| !v.is_empty()) {
+            Some(raw) => match raw.trim().to_lowercase().as_str() {
+                "auto" => SchemaProfile::Auto,
+                "standard" => SchemaProfile::Standard,
+                "openai" => SchemaProfile::OpenAI,
+                "gemini" => SchemaProfile::Gemini,
+                "off" => SchemaProfile::Off,
+                _ => bail!("SCHEMA_PROFILE must be auto, standard, openai, gemini or off"),
+            },
+            None => SchemaProfile::Auto,
+        };
+        let thinking_budget_params = match env::var("THINKING_BUDGET_PARAMS")
+            .ok()
+            .filter(|v| !v.is_empty())
+        {
+            Some(raw) => match raw.trim().to_lowercase().as_str() {
+                "auto" => ThinkingBudgetParams::Auto,
+                "effort" => ThinkingBudgetParams::Effort,
+                "max_tokens" => ThinkingBudgetParams::MaxTokens,
+                "off" => ThinkingBudgetParams::Off,
+                _ => bail!("THINKING_BUDGET_PARAMS must be auto, effort, max_tokens or off"),
+            },
+            None => ThinkingBudgetParams::Auto,
+        };
+        let thinking_history = match env::var("THINKING_HISTORY").ok().filter(|v| !v.is_empty()) {
+            Some(raw) => match raw.trim().to_lowercase().as_str() {
+                "drop" => ThinkingHistory::Drop,
+                "reasoning" => ThinkingHistory::Reasoning,
+                "text" => ThinkingHistory::Text,
+                _ => bail!("THINKING_HISTORY must be drop, reasoning or text"),
+            },
+            None => ThinkingHistory::Drop,
+        };
+
+        let debug = Self::parse_flag("DEBUG")?.unwrap_or(false);
+
+        let verbose = Self::parse_flag("VERBOSE")?.unwrap_or(false);
+
+        let mut config = Config {
             port,
+            http2,
             base_url,
+            upstream_format,
             api_key,
+            upstream_headers,
+            ollama_options,
+            ollama_keep_alive,
+            upstream_routes: None,
+            model_rules,
+            vertex,
             reasoning_model,
             completion_model,
+            haiku_model,
+            sonnet_model,
+            opus_model,
+            small_model,
+            small_model_max_tokens,
+            fallback_models,
+            model_splits,
+            routing_override_keys,
+            ensemble_models,
+            ensemble_judge_model,
+            ensemble_record_file,
+            output_guardrails,
+            error_rules,
+            secret_scanner,
+            context_limits,
+            default_context_limit,
+            model_pricing,
+            daily_spend_cap,
+            client_budgets,
+            budget_downgrade_model,
+            budget_downgrade_threshold,
+            usage_export_dir,
+            usage_export_interval_secs,
+            usage_export_format,
+            usage_export_s3,
+            request_tag_keys,
+            request_tag_header,
+            request_tag_max_values,
+            admin_token,
+            admin_port,
+            upstream_affinity,
+            affinity_session_key,
+            affinity_ttl_secs,
+            text_only_models,
+            hide_thinking_models,
+            strip_thinking,
+            think_tag_models,
+            think_tags,
+            tool_result_max_chars,
+            tool_schema_max_chars,
+            tools_max_chars,
+            empty_response_retries,
+            upstream_retries,
+            upstream_retry_backoff_ms,
+            upstream_retry_jitter,
+            upstream_retry_statuses,
+            rate_limit_retry_secs,
+            circuit_breaker_failures,
+            circuit_breaker_cooldown_secs,
+            stream_idle_timeout_secs,
+            stream_block_max_bytes,
+            stream_coalesce_ms,
+            stream_coalesce_bytes,
+            batch_concurrency,
+            batch_db_path,
+            batch_retention_secs,
+            debug_endpoints,
+            image_caption_model,
+            image_caption_prompt,
+            inline_image_urls,
+            structured_output_tools,
+            extra_params,
+            extra_params_deny,
+            stop_sequences_max,
+            emulate_stop_sequences,
+            report_requested_model,
+            ping_interval_secs,
+            heartbeat_interval_secs,
+            early_message_start,
+            stream_upstream,
+            schema_profile,
+            quirks,
+            vllm_repetition_penalty,
+            vllm_min_p,
+            model_capabilities,
+            strict_tools,
+            auto_continue_max,
+            reasoning_limits,
+            reasoning_limit_upstream,
+            top_k,
+            content_filter,
+            tool_input_streaming,
+            cache_control,
+            openrouter,
+            openrouter_provider,
+            openrouter_models,
+            openrouter_transforms,
+            openrouter_referer,
+            openrouter_title,
+            thinking_budget_params,
+            thinking_history,
             debug,
             verbose,
-        })
+        };
+        // Profiles start from everything above, so they are built last
+        if let Some(path) = env::var("UPSTREAMS_FILE").ok().filter(|p| !p.is_empty()) {
+            let routes = ModelRoutes::load(&PathBuf::from(path), &config)?;
+            config.upstream_routes = Some(Arc::new(routes));
+        }
+        config
+            .check_rule_upstreams(&config.model_rules.current())
+            .context("ROUTING_RULES_FILE")?;
+        Ok(config)
     }
 
-    pub fn chat_completions_url(&self) -> String {
-        Self::resolve_chat_completions_url(&self.base_url)
-            .expect("UPSTREAM_BASE_URL should be validated during configuration loading")
+    /// Configuration with every optional feature off, as the server would
+    /// run with only UPSTREAM_BASE_URL set
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        Config {
+            port: 3000,
+            http2: Http2Mode::Auto,
+            base_url: "http://localhost:11434".to_string(),
+            upstream_format: UpstreamFormat::OpenAI,
+            api_key: None,
+            upstream_headers: Vec::new(),
+            ollama_options: Map::new(),
+            ollama_keep_alive: None,
+            upstream_routes: None,
+            model_rules: Arc::default(),
+            vertex: None,
+            reasoning_model: None,
+            completion_model: None,
+            haiku_model: None,
+            sonnet_model: None,
+            opus_model: None,
+            small_model: None,
+            small_model_max_tokens: 512,
+            fallback_models: Vec::new(),
+            model_splits: None,
+            routing_override_keys: Vec::new(),
+            ensemble_models: Vec::new(),
+            ensemble_judge_model: None,
+            ensemble_record_file: None,
+            output_guardrails: None,
+            error_rules: None,
+            secret_scanner: None,
+            context_limits: Vec::new(),
+            default_context_limit: None,
+            model_pricing: Vec::new(),
+            daily_spend_cap: None,
+            client_budgets: Vec::new(),
+            budget_downgrade_model: None,
+            budget_downgrade_threshold: 0.8,
+            usage_export_dir: None,
+            usage_export_interval_secs: 3600,
+            usage_export_format: ExportFormat::Csv,
+            usage_export_s3: None,
+            request_tag_keys: Vec::new(),
+            request_tag_header: "x-proxy-tags".to_string(),
+            request_tag_max_values: 50,
+            admin_token: None,
+            admin_port: None,
+            upstream_affinity: false,
+            affinity_session_key: None,
+            affinity_ttl_secs: 3600,
+            text_only_models: Vec::new(),
+            hide_thinking_models: Vec::new(),
+            strip_thinking: false,
+            think_tag_models: Vec::new(),
+            think_tags: Vec::new(),
+            tool_result_max_chars: None,
+            tool_schema_max_chars: None,
+            tools_max_chars: None,
+            empty_response_retries: 1,
+            upstream_retries: 0,
+            upstream_retry_backoff_ms: 0,
+            upstream_retry_jitter: 0.0,
+            upstream_retry_statuses: vec![502, 503, 504],
+            rate_limit_retry_secs: None,
+            circuit_breaker_failures: None,
+            circuit_breaker_cooldown_secs: 30,
+            stream_idle_timeout_secs: None,
+            stream_block_max_bytes: Some(DEFAULT_STREAM_BLOCK_MAX_BYTES),
+            stream_coalesce_ms: None,
+            stream_coalesce_bytes: 1024,
+            batch_concurrency: 4,
+            batch_db_path: None,
+            batch_retention_secs: DEFAULT_BATCH_RETENTION_SECS,
+            debug_endpoints: false,
+            image_caption_model: None,
+            image_caption_prompt: None,
+            inline_image_urls: false,
+            structured_output_tools: false,
+            extra_params: Vec::new(),
+            extra_params_deny: Vec::new(),
+            stop_sequences_max: None,
+            emulate_stop_sequences: false,
+            report_requested_model: false,
+            ping_interval_secs: None,
+            heartbeat_interval_secs: None,
+            early_message_start: false,
+            stream_upstream: false,
+            schema_profile: SchemaProfile::Auto,
+            quirks: UpstreamQuirks::Auto,
+            vllm_repetition_penalty: None,
+            vllm_min_p: None,
+            model_capabilities: Vec::new(),
+            strict_tools: false,
+            auto_continue_max: 0,
+            reasoning_limits: Vec::new(),
+            reasoning_limit_upstream: false,
+            top_k: TopKMode::Auto,
+            content_filter: ContentFilterMode::Refusal,
+            tool_input_streaming: ToolInputStreaming::Auto,
+            cache_control: CacheControlMode::Auto,
+            openrouter: None,
+            openrouter_provider: None,
+            openrouter_models: Vec::new(),
+            openrouter_transforms: Vec::new(),
+            openrouter_referer: None,
+            openrouter_title: None,
+            thinking_budget_params: ThinkingBudgetParams::Auto,
+            thinking_history: ThinkingHistory::Drop,
+            debug: false,
+            verbose: false,
+        }
     }
 
-    fn validate_base_url(base_url: &str) -> Result<()> {
-        Self::resolve_chat_completions_url(base_url).map(|_| ())
+    pub fn ensemble_enabled(&self) -> bool {
+        !self.ensemble_models.is_empty()
     }
 
-    fn resolve_chat_completions_url(base_url: &str) -> Result<String> {
-        let normalized = base_url.trim();
+    /// Context window of the target model, if known
+    pub fn context_limit_for(&self, model: &str) -> Option<u32> {
+        self.context_limits
+            .iter()
+            .find(|(pattern, _)| Self::model_matches(pattern, model))
+            .map(|(_, limit)| *limit)
+            .or(self.default_context_limit)
+    }
 
-        if normalized.is_empty() {
-            bail!("UPSTREAM_BASE_URL must not be empty");
+    /// Cap on the reasoning tokens of a model, if configured
+    pub fn reasoning_limit_for(&self, model: &str) -> Option<u32> {
+        self.reasoning_limits
+            .iter()
+            .find(|(pattern, _)| Self::model_matches(pattern, model))
+            .map(|(_, limit)| *limit)
+    }
+
+    /// Upstream price of a model, if configured
+    pub fn price_for(&self, model: &str) -> Option<ModelPrice> {
+        self.model_pricing
+            .iter()
+            .find(|(pattern, _)| Self::model_matches(pattern, model))
+            .map(|(_, price)| *price)
+    }
+
+    /// Whether the model is configured as unable to view images
+    pub fn is_text_only(&self, model: &str) -> bool {
+        self.text_only_models
+            .iter()
+            .any(|pattern| Self::model_matches(pattern, model))
+    }
+
+    /// Request adjustments for a model: the first MODEL_CAPABILITIES entry
+    /// matching it, else those of OpenAI's o-series for o1, o3 and o4 models
+    pub fn capabilities_for(&self, model: &str) -> ModelCapabilities {
+        if let Some((_, caps)) = self
+            .model_capabilities
+            .iter()
+            .find(|(pattern, _)| Self::model_matches(pattern, model))
+        {
+            return *caps;
+        }
+        let bare = model.strip_prefix("openai/").unwrap_or(model);
+        if ["o1", "o3", "o4"]
+            .iter()
+            .any(|series| bare.starts_with(series))
+        {
+            ModelCapabilities::REASONING
+        } else {
+            ModelCapabilities::default()
         }
+    }
 
-        let parsed = Url::parse(normalized).map_err(|err| {
-            anyhow::anyhow!("UPSTREAM_BASE_URL must be a valid http(s) URL: {}", err)
-        })?;
+    /// The quirks profile used for this upstream, with Auto resolved
+    pub fn quirks(&self) -> UpstreamQuirks {
+        match self.quirks {
+            UpstreamQuirks::Auto
+                if self.base_url.contains("generativelanguage.googleapis.com")
+                    || (self.vertex.is_some()
+                        && self.upstream_format == UpstreamFormat::OpenAI) =>
+            {
+                UpstreamQuirks::Gemini
+            }
+            UpstreamQuirks::Auto => UpstreamQuirks::None,
+            quirks => quirks,
+        }
+    }
 
-        if !matches!(parsed.scheme(), "http" | "https") {
-            bail!("UPSTREAM_BASE_URL must use http or https");
+    /// Whether `top_k` is sent to the upstream
+    pub fn forwards_top_k(&self) -> bool {
+        match self.top_k {
+            TopKMode::Auto => {
+                !self.base_url.contains("api.openai.com") && self.quirks() != UpstreamQuirks::Gemini
+            }
+            TopKMode::Pass => true,
+            TopKMode::Drop => false,
         }
+    }
 
-        if parsed.query().is_some() || parsed.fragment().is_some() {
-            bail!("UPSTREAM_BASE_URL must not include query parameters or fragments");
+    /// Most stop sequences the upstream accepts: the configured number (0 for
+    /// no limit), or else OpenAI's limit of 4 for api.openai.com and Gemini's
+    /// of 5
+    pub fn stop_sequences_max(&self) -> Option<usize> {
+        match self.stop_sequences_max {
+            Some(0) => None,
+            Some(max) => Some(max),
+            None if self.quirks() == UpstreamQuirks::Gemini => Some(5),
+            None => self.base_url.contains("api.openai.com").then_some(4),
+        }
+    }
+
+    /// Whether `cache_control` breakpoints are sent to the upstream
+    pub fn forwards_cache_control(&self) -> bool {
+        match self.cache_control {
+            CacheControlMode::Auto => self.base_url.contains("openrouter.ai"),
+            CacheControlMode::Pass => true,
+            CacheControlMode::Strip => false,
         }
+    }
 
-        let path_segments: Vec<_> = parsed
-            .path_segments()
-            .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
-            .unwrap_or_default();
+    /// Whether OpenRouter's request extensions and attribution headers are sent
+    pub fn is_openrouter(&self) -> bool {
+        self.openrouter
+            .unwrap_or_else(|| self.base_url.contains("openrouter.ai"))
+    }
+
+    /// The configured OpenRouter extensions as request parameters
+    pub fn openrouter_params(&self) -> Vec<(String, Value)> {
+        let mut params = Vec::new();
+        if let Some(provider) = &self.openrouter_provider {
+            params.push(("provider".to_string(), provider.clone()));
+        }
+        if !self.openrouter_models.is_empty() {
+            params.push(("models".to_string(), json!(self.openrouter_models)));
+        }
+        if !self.openrouter_transforms.is_empty() {
+            params.push(("transforms".to_string(), json!(self.openrouter_transforms)));
+        }
+        params
+    }
+
+    /// HTTP-Referer and X-Title headers attributing requests to an app
+    pub fn openrouter_headers(&self) -> Vec<(&'static str, &str)> {
+        let referer = self.openrouter_referer.as_deref();
+        let title = self.openrouter_title.as_deref();
+        [("HTTP-Referer", referer), ("X-Title", title)]
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value?)))
+            .collect()
+    }
+
+    /// The schema cleaning profile used for this upstream, with Auto resolved
+    pub fn schema_profile(&self) -> SchemaProfile {
+        match self.schema_profile {
+            SchemaProfile::Auto if self.quirks() == UpstreamQuirks::Gemini => SchemaProfile::Gemini,
+            SchemaProfile::Auto if self.base_url.contains("api.openai.com") => {
+                SchemaProfile::OpenAI
+            }
+            SchemaProfile::Auto => SchemaProfile::Standard,
+            profile => profile,
+        }
+    }
+
+    /// The reasoning parameter style used for this upstream, with Auto resolved
+    pub fn thinking_budget_params(&self) -> ThinkingBudgetParams {
+        match self.thinking_budget_params {
+            ThinkingBudgetParams::Auto if self.base_url.contains("api.openai.com") => {
+                ThinkingBudgetParams::Effort
+            }
+            // Gemini takes the budget as a thinking config, set by its quirks
+            ThinkingBudgetParams::Auto
+                if self.base_url.contains("openrouter.ai")
+                    || self.quirks() == UpstreamQuirks::Gemini =>
+            {
+                ThinkingBudgetParams::MaxTokens
+            }
+            ThinkingBudgetParams::Auto => ThinkingBudgetParams::Off,
+            params => params,
+        }
+    }
+
+    /// Whether reasoning from the model is withheld from clients
+    pub fn hides_thinking(&self, model: &str) -> bool {
+        self.strip_thinking
+            || self
+                .hide_thinking_models
+                .iter()
+                .any(|pattern| Self::model_matches(pattern, model))
+    }
+
+    /// Tags to extract inline reasoning from, for models that emit it in their
+    /// text: the model's own tags if configured, `<think>` if it is only listed,
+    /// and Gemini's `<thought>` for its upstreams
+    pub fn think_tags_for(&self, model: &str) -> Option<Delimiters> {
+        self.think_tags
+            .iter()
+            .find(|(pattern, _)| Self::model_matches(pattern, model))
+            .map(|(_, tags)| tags.clone())
+            .or_else(|| {
+                self.think_tag_models
+                    .iter()
+                    .any(|pattern| Self::model_matches(pattern, model))
+                    .then(Delimiters::default)
+            })
+            .or_else(|| (self.quirks() == UpstreamQuirks::Gemini).then(quirks::gemini_thought_tags))
+    }
+
+    /// Whether an unrecognized request parameter is forwarded to the upstream:
+    /// it must be allowed by name or `*`, and not denied
+    pub fn forwards_extra_param(&self, name: &str) -> bool {
+        let listed = |list: &[String]| list.iter().any(|p| p == "*" || p == name);
+        listed(&self.extra_params) && !listed(&self.extra_params_deny)
+    }
+
+    /// Daily budget configured for a client API key
+    pub fn client_budget_for(&self, key: &str) -> Option<f64> {
+        self.client_budgets
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, budget)| *budget)
+    }
+
+    /// The model configured for the Claude tier (haiku, sonnet or opus) a
+    /// requested model belongs to
+    pub fn tier_model(&self, model: &str) -> Option<&str> {
+        let model = model.to_ascii_lowercase();
+        let tier = if model.contains("haiku") {
+            &self.haiku_model
+        } else if model.contains("sonnet") {
+            &self.sonnet_model
+        } else if model.contains("opus") {
+            &self.opus_model
+        } else {
+            return None;
+        };
+        tier.as_deref()
+    }
+
+    /// Match a model name against an exact name or a `prefix*` pattern
+    pub fn model_matches(pattern: &str, model: &str) -> bool {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => model.starts_with(prefix),
+            None => pattern == model,
+        }
+    }
+
+    fn parse_number<T: FromStr>(var: &str) -> Result<Option<T>> {
+        env::var(var)
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .map(|v| {
+                v.trim()
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("{} must be a number", var))
+            })
+            .transpose()
+    }
+
+    /// Read a boolean flag: `1`/`true` or `0`/`false`, in any case
+    fn parse_flag(var: &str) -> Result<Option<bool>> {
+        match env::var(var).ok().filter(|v| !v.trim().is_empty()) {
+            Some(raw) => match raw.trim().to_lowercase().as_str() {
+                "1" | "true" => Ok(Some(true)),
+                "0" | "false" => Ok(Some(false)),
+                _ => bail!("{} must be true or false", var),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Routing rules may only send requests to upstreams UPSTREAMS_FILE names
+    pub fn check_rule_upstreams(&self, rules: &ModelRules) -> Result<()> {
+        for name in rules.upstreams() {
+            let known = self
+                .upstream_routes
+                .as_ref()
+                .is_some_and(|routes| routes.upstream(name).is_some());
+            if name != DEFAULT_UPSTREAM && !known {
+                bail!(
+                    "routes to upstream {}, which UPSTREAMS_FILE doesn't name",
+                    name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Extra upstream headers must be valid HTTP header names and values
+    pub fn validate_headers(headers: &[(String, String)]) -> Result<()> {
+        for (name, value) in headers {
+            reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header name {}", name))?;
+            reqwest::header::HeaderValue::from_str(value)
+                .with_context(|| format!("invalid value for header {}", name))?;
+        }
+        Ok(())
+    }
+
+    /// Read a comma-separated list of `key=value` pairs from the environment
+    fn parse_pairs(var: &str) -> Result<Vec<(String, String)>> {
+        Self::parse_list(var)
+            .into_iter()
+            .map(|item| {
+                item.rsplit_once('=')
+                    .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("{} entries must look like key=value: {}", var, item)
+                    })
+            })
+            .collect()
+    }
+
+    /// Read a comma-separated list from the environment, dropping empty entries
+    fn parse_list(var: &str) -> Vec<String> {
+        env::var(var)
+            .map(|v| {
+                v.split(',')
+                    .map(|item| item.trim().to_string())
+                    .filter(|item| !item.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Endpoint URLs of every equivalent upstream, in configured order
+    pub fn upstream_urls(&self) -> Vec<String> {
+        Self::split_base_urls(&self.base_url)
+            .map(|url| {
+                self.upstream_url(url)
+                    .expect("UPSTREAM_BASE_URL should be validated during configuration loading")
+            })
+            .collect()
+    }
+
+    /// The endpoint for one of this configuration's base URLs, which are API
+    /// hosts when the upstream is Vertex AI
+    pub fn upstream_url(&self, base_url: &str) -> Result<String> {
+        let url = Self::resolve_upstream_url(base_url, self.upstream_format)?;
+        Ok(match &self.vertex {
+            Some(vertex) => vertex.endpoint(base_url, self.upstream_format),
+            None => url,
+        })
+    }
+
+    /// The endpoint requests are sent to for a base URL: chat completions, or
+    /// the Messages API for Anthropic upstreams
+    pub fn resolve_upstream_url(base_url: &str, format: UpstreamFormat) -> Result<String> {
+        match format {
+            UpstreamFormat::OpenAI => Self::resolve_chat_completions_url(base_url),
+            UpstreamFormat::Anthropic => Self::resolve_messages_url(base_url),
+            UpstreamFormat::Ollama => Self::resolve_ollama_url(base_url),
+        }
+    }
+
+    pub fn validate_base_url(base_url: &str, format: UpstreamFormat) -> Result<()> {
+        if Self::split_base_urls(base_url).next().is_none() {
+            bail!("UPSTREAM_BASE_URL must not be empty");
+        }
+        Self::split_base_urls(base_url)
+            .try_for_each(|url| Self::resolve_upstream_url(url, format).map(|_| ()))
+    }
+
+    /// UPSTREAM_BASE_URL may list several equivalent endpoints separated by commas
+    fn split_base_urls(base_url: &str) -> impl Iterator<Item = &str> {
+        base_url
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+    }
+
+    pub fn resolve_chat_completions_url(base_url: &str) -> Result<String> {
+        let normalized = base_url.trim();
+        let segments = Self::base_url_path(normalized)?;
+        let path_segments: Vec<&str> = segments.iter().map(String::as_str).collect();
 
         if Self::is_chat_completions_path(&path_segments) {
             return Ok(normalized.trim_end_matches('/').to_string());
@@ -160,6 +1428,70 @@ impl Config {
         Ok(format!("{}/v1/chat/completions", normalized))
     }
 
+    /// Resolve a base URL to an Anthropic-style `/v1/messages` endpoint
+    pub fn resolve_messages_url(base_url: &str) -> Result<String> {
+        let normalized = base_url.trim();
+        let segments = Self::base_url_path(normalized)?;
+        let path_segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        let normalized = normalized.trim_end_matches('/');
+
+        match path_segments.last().copied() {
+            Some("messages") => Ok(normalized.to_string()),
+            Some(segment) if Self::is_version_segment(segment) => {
+                Ok(format!("{}/messages", normalized))
+            }
+            _ => Ok(format!("{}/v1/messages", normalized)),
+        }
+    }
+
+    /// Resolve a base URL to Ollama's native `/api/chat` endpoint. A version
+    /// segment is taken for the OpenAI-compatible API's and replaced.
+    pub fn resolve_ollama_url(base_url: &str) -> Result<String> {
+        let normalized = base_url.trim();
+        let segments = Self::base_url_path(normalized)?;
+        let path_segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        let normalized = normalized.trim_end_matches('/');
+
+        match path_segments.as_slice() {
+            [.., "api", "chat"] => Ok(normalized.to_string()),
+            [.., "api"] => Ok(format!("{}/chat", normalized)),
+            [.., last] if Self::is_version_segment(last) => Ok(format!(
+                "{}/api/chat",
+                normalized.trim_end_matches(last).trim_end_matches('/')
+            )),
+            _ => Ok(format!("{}/api/chat", normalized)),
+        }
+    }
+
+    /// Validate a base URL and return its non-empty path segments
+    fn base_url_path(normalized: &str) -> Result<Vec<String>> {
+        if normalized.is_empty() {
+            bail!("UPSTREAM_BASE_URL must not be empty");
+        }
+
+        let parsed = Url::parse(normalized).map_err(|err| {
+            anyhow::anyhow!("UPSTREAM_BASE_URL must be a valid http(s) URL: {}", err)
+        })?;
+
+        if !matches!(parsed.scheme(), "http" | "https") {
+            bail!("UPSTREAM_BASE_URL must use http or https");
+        }
+
+        if parsed.query().is_some() || parsed.fragment().is_some() {
+            bail!("UPSTREAM_BASE_URL must not include query parameters or fragments");
+        }
+
+        Ok(parsed
+            .path_segments()
+            .map(|segments| {
+                segments
+                    .filter(|segment| !segment.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
     fn is_chat_completions_path(segments: &[&str]) -> bool {
         matches!(segments, [.., "chat", "completions"])
     }
@@ -176,7 +1508,71 @@ impl Config {
 
 #[cfg(test)]
 mod tests {
-    use super::Config;
+    use super::{Config, Delimiters, TopKMode, UpstreamFormat};
+
+    #[test]
+    fn model_patterns_match_exact_names_and_prefixes() {
+        assert!(Config::model_matches("gpt-4o", "gpt-4o"));
+        assert!(!Config::model_matches("gpt-4o", "gpt-4o-mini"));
+        assert!(Config::model_matches("deepseek/*", "deepseek/deepseek-r1"));
+    }
+
+    #[test]
+    fn numbers_and_flags_reject_bad_values() {
+        // Names only these tests use, so parallel tests don't see them
+        std::env::set_var("TEST_PARSE_NUMBER", " 42 ");
+        std::env::set_var("TEST_PARSE_NUMBER_BAD", "lots");
+        std::env::set_var("TEST_PARSE_FLAG", "TRUE");
+        std::env::set_var("TEST_PARSE_FLAG_BAD", "yes please");
+
+        assert_eq!(
+            Config::parse_number::<u64>("TEST_PARSE_NUMBER").unwrap(),
+            Some(42)
+        );
+        assert!(Config::parse_number::<u64>("TEST_PARSE_NUMBER_BAD").is_err());
+        assert_eq!(
+            Config::parse_number::<u64>("TEST_PARSE_NUMBER_UNSET").unwrap(),
+            None
+        );
+        assert_eq!(Config::parse_flag("TEST_PARSE_FLAG").unwrap(), Some(true));
+        assert!(Config::parse_flag("TEST_PARSE_FLAG_BAD").is_err());
+        assert_eq!(Config::parse_flag("TEST_PARSE_FLAG_UNSET").unwrap(), None);
+    }
+
+    #[test]
+    fn think_tags_are_chosen_per_model() {
+        let mut config = Config::for_tests();
+        config.think_tag_models = vec!["*".to_string()];
+        config.think_tags = vec![(
+            "magistral*".to_string(),
+            Delimiters::parse("[THINK]|[/THINK]").unwrap(),
+        )];
+        assert_eq!(
+            config.think_tags_for("magistral-small").unwrap().close,
+            "[/THINK]"
+        );
+        assert_eq!(
+            config.think_tags_for("qwq-32b"),
+            Some(Delimiters::default())
+        );
+
+        config.think_tag_models.clear();
+        assert_eq!(config.think_tags_for("qwq-32b"), None);
+    }
+
+    #[test]
+    fn top_k_is_withheld_from_openai_in_auto_mode() {
+        let mut config = Config::for_tests();
+        config.base_url = "https://openrouter.ai/api".to_string();
+        assert!(config.forwards_top_k());
+        config.base_url = "https://api.openai.com".to_string();
+        assert!(!config.forwards_top_k());
+        config.top_k = TopKMode::Pass;
+        assert!(config.forwards_top_k());
+        config.top_k = TopKMode::Drop;
+        config.base_url = "http://localhost:11434".to_string();
+        assert!(!config.forwards_top_k());
+    }
 
     #[test]
     fn base_url_without_version_defaults_to_v1_endpoint() {
@@ -199,6 +1595,15 @@ mod tests {
         assert_eq!(url, "https://gateway.example.com/v2/chat/completions");
     }
 
+    #[test]
+    fn comma_separated_base_urls_are_resolved_individually() {
+        let openai = UpstreamFormat::OpenAI;
+        Config::validate_base_url("https://a.example.com, https://b.example.com/v2", openai)
+            .unwrap();
+        assert!(Config::validate_base_url(" , ", openai).is_err());
+        assert!(Config::validate_base_url("https://a.example.com,ftp://b", openai).is_err());
+    }
+
     #[test]
     fn partial_chat_path_is_rejected() {
         let err = Config::resolve_chat_completions_url("https://gateway.example.com/v2/chat")
@@ -216,4 +1621,38 @@ mod tests {
             .to_string()
             .contains("must not include query parameters or fragments"));
     }
+
+    #[test]
+    fn anthropic_base_urls_resolve_to_the_messages_endpoint() {
+        let resolve = |url| Config::resolve_upstream_url(url, UpstreamFormat::Anthropic).unwrap();
+        assert_eq!(
+            resolve("https://api.anthropic.com"),
+            "https://api.anthropic.com/v1/messages"
+        );
+        assert_eq!(
+            resolve("https://gateway.example.com/v1/"),
+            "https://gateway.example.com/v1/messages"
+        );
+        assert_eq!(
+            resolve("https://gateway.example.com/v1/messages"),
+            "https://gateway.example.com/v1/messages"
+        );
+    }
+
+    #[test]
+    fn ollama_urls_point_at_the_native_chat_api() {
+        let resolve = |url| Config::resolve_upstream_url(url, UpstreamFormat::Ollama).unwrap();
+        assert_eq!(
+            resolve("http://localhost:11434"),
+            "http://localhost:11434/api/chat"
+        );
+        assert_eq!(
+            resolve("http://localhost:11434/v1/"),
+            "http://localhost:11434/api/chat"
+        );
+        assert_eq!(
+            resolve("http://gpu-box/ollama/api/chat"),
+            "http://gpu-box/ollama/api/chat"
+        );
+    }
 }
@@ -0,0 +1,296 @@
+use crate::accounting::UsageReport;
+use crate::config::Config;
+use crate::error::ProxyResult;
+use crate::models::openai;
+use crate::proxy::{self, EventStream, RequestContext};
+use crate::secrets::SecretVault;
+use bytes::Bytes;
+use futures::stream::{Stream, StreamExt};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// Instruction appended after the partial output when asking for more
+const CONTINUE_PROMPT: &str =
+    "Your previous reply was cut off. Continue exactly where it stopped, without repeating anything.";
+
+/// Send a non-streaming request, re-issuing it while the output is cut at
+/// max_tokens, and merge the pieces into one response
+pub async fn complete(
+    config: &Config,
+    client: &Client,
+    ctx: &RequestContext,
+    openai_req: &openai::OpenAIRequest,
+) -> ProxyResult<(openai::OpenAIResponse, UsageReport)> {
+    let (mut resp, mut usage) =
+        proxy::send_chat_completion(config, client, ctx, openai_req).await?;
+
+    for round in 1..=config.auto_continue_max {
+        let Some(choice) = resp.choices.first() else {
+            break;
+        };
+        let text = choice.message.content.clone().unwrap_or_default();
+        if choice.finish_reason.as_deref() != Some("length")
+            || choice.message.tool_calls.is_some()
+            || choice.message.function_call.is_some()
+            || text.is_empty()
+        {
+            break;
+        }
+
+        tracing::info!(
+            "Output cut at max_tokens, continuing ({}/{})",
+            round,
+            config.auto_continue_max
+        );
+        let req = continuation_request(config, openai_req, &text);
+        let (next, next_usage) = match proxy::send_chat_completion(config, client, ctx, &req).await
+        {
+            Ok(next) => next,
+            Err(err) => {
+                tracing::warn!(
+                    "Continuation request failed, returning partial output: {}",
+                    err
+                );
+                break;
+            }
+        };
+        let Some(next_choice) = next.choices.into_iter().next() else {
+            break;
+        };
+
+        let choice = &mut resp.choices[0];
+        choice.message.content = Some(text + next_choice.message.content.as_deref().unwrap_or(""));
+        choice.message.tool_calls = next_choice.message.tool_calls;
+        choice.message.function_call = next_choice.message.function_call;
+        choice.finish_reason = next_choice.finish_reason;
+        resp.usage.prompt_tokens += next.usage.prompt_tokens;
+        resp.usage.completion_tokens += next.usage.completion_tokens;
+        resp.usage.total_tokens += next.usage.total_tokens;
+        usage.add(&next_usage);
+    }
+
+    Ok((resp, usage))
+}
+
+/// Wrap a translated event stream so that a text answer cut at max_tokens is
+/// continued by follow-up requests, presented to the client as one message
+pub fn stitch_stream(
+    first: impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static,
+    config: Arc<Config>,
+    client: Client,
+    openai_req: openai::OpenAIRequest,
+    ctx: Arc<RequestContext>,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send {
+    async_stream::stream! {
+        let mut current: EventStream = Box::pin(first);
+        let mut round = 0;
+        let mut index_offset = 0;
+        let mut text = String::new();
+        let mut total_usage: Option<Value> = None;
+
+        loop {
+            let mut held_delta: Option<Bytes> = None;
+            let mut saw_tool_use = false;
+            let mut next_offset = index_offset;
+
+            while let Some(item) = current.next().await {
+                let Ok(bytes) = item else {
+                    yield item;
+                    continue;
+                };
+                // Every item of a translated stream is exactly one SSE event
+                let Some((name, mut data)) = parse_event(&bytes) else {
+                    yield Ok(bytes);
+                    continue;
+                };
+
+                match name.as_str() {
+                    "message_start" if round > 0 => {}
+                    "content_block_start" | "content_block_delta" | "content_block_stop" => {
+                        if let Some(index) = data["index"].as_u64() {
+                            let shifted = index as usize + index_offset;
+                            data["index"] = json!(shifted);
+                            next_offset = next_offset.max(shifted + 1);
+                        }
+                        if data["content_block"]["type"] == "tool_use" {
+                            saw_tool_use = true;
+                        }
+                        if let Some(delta) = data["delta"]["text"].as_str() {
+                            text.push_str(delta);
+                        }
+                        yield Ok(render(&name, &data));
+                    }
+                    "message_delta" => {
+                        let cut = data["delta"]["stop_reason"] == "max_tokens";
+                        if cut && round < config.auto_continue_max && !saw_tool_use && !text.is_empty() {
+                            held_delta = Some(bytes);
+                        } else {
+                            yield Ok(bytes);
+                        }
+                    }
+                    "message_stop" => {
+                        if let Some(usage) = data.get("proxy_usage") {
+                            total_usage = Some(add_usage(total_usage.take(), usage));
+                        }
+                        if held_delta.is_none() {
+                            if let Some(usage) = &total_usage {
+                                data["proxy_usage"] = usage.clone();
+                            }
+                            yield Ok(render(&name, &data));
+                        }
+                    }
+                    _ => yield Ok(bytes),
+                }
+            }
+
+            let Some(held) = held_delta else {
+                break;
+            };
+
+            round += 1;
+            index_offset = next_offset;
+            tracing::info!("Stream cut at max_tokens, continuing ({}/{})", round, config.auto_continue_max);
+            let req = continuation_request(&config, &openai_req, &text);
+            match proxy::send_upstream(&config, &client, &ctx, &req).await {
+                Ok(response) => {
+                    current = Box::pin(proxy::create_sse_stream(
+                        proxy::upstream_events(response),
+                        req.model.clone(),
+                        config.clone(),
+                        ctx.clone(),
+                    ));
+                }
+                Err(err) => {
+                    tracing::warn!("Continuation request failed, ending with partial output: {}", err);
+                    yield Ok(held);
+                    let mut stop = json!({"type": "message_stop"});
+                    if let Some(usage) = &total_usage {
+                        stop["proxy_usage"] = usage.clone();
+                    }
+                    yield Ok(render("message_stop", &stop));
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// The original request followed by the partial answer and a request to go on
+fn continuation_request(
+    config: &Config,
+    openai_req: &openai::OpenAIRequest,
+    partial: &str,
+) -> openai::OpenAIRequest {
+    let mut req = openai_req.clone();
+    for (role, text) in [("assistant", partial), ("user", CONTINUE_PROMPT)] {
+        req.messages.push(openai::Message {
+            role: role.to_string(),
+            content: Some(openai::MessageContent::Text(text.to_string())),
+            tool_calls: None,
+            tool_call_id: None,
+            name: None,
+            reasoning_content: None,
+        });
+    }
+    // Streamed text has already had secrets restored; mask them again. The
+    // placeholders are derived from the secrets, so they match the originals.
+    if let Some(scanner) = &config.secret_scanner {
+        scanner.mask_request(&mut req, &mut SecretVault::default());
+    }
+    req
+}
+
+fn parse_event(bytes: &Bytes) -> Option<(String, Value)> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    let name = text.lines().find_map(|l| l.strip_prefix("event: "))?;
+    let data = text.lines().find_map(|l| l.strip_prefix("data: "))?;
+    Some((name.to_string(), serde_json::from_str(data).ok()?))
+}
+
+fn render(name: &str, data: &Value) -> Bytes {
+    Bytes::from(format!(
+        "event: {}\ndata: {}\n\n",
+        name,
+        serde_json::to_string(data).unwrap_or_default()
+    ))
+}
+
+/// Sum two `proxy_usage` objects, keeping the latest upstream model
+fn add_usage(total: Option<Value>, usage: &Value) -> Value {
+    let Some(total) = total else {
+        return usage.clone();
+    };
+    let sum = |key: &str| total[key].as_u64().unwrap_or(0) + usage[key].as_u64().unwrap_or(0);
+    let cost = match (
+        total["estimated_cost"].as_f64(),
+        usage["estimated_cost"].as_f64(),
+    ) {
+        (Some(a), Some(b)) => json!(a + b),
+        _ => Value::Null,
+    };
+    json!({
+        "input_tokens": sum("input_tokens"),
+        "output_tokens": sum("output_tokens"),
+        "estimated_cost": cost,
+        "upstream_model": usage["upstream_model"],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_event, render, stitch_stream};
+    use crate::config::Config;
+    use crate::proxy::RequestContext;
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn event(name: &str, data: serde_json::Value) -> Result<Bytes, std::io::Error> {
+        Ok(render(name, &data))
+    }
+
+    #[tokio::test]
+    async fn streams_that_finish_normally_pass_through() {
+        let mut config = Config::for_tests();
+        config.auto_continue_max = 2;
+        let events = vec![
+            event("message_start", json!({"type": "message_start"})),
+            event(
+                "content_block_start",
+                json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}),
+            ),
+            event(
+                "content_block_delta",
+                json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "hi"}}),
+            ),
+            event(
+                "content_block_stop",
+                json!({"type": "content_block_stop", "index": 0}),
+            ),
+            event(
+                "message_delta",
+                json!({"type": "message_delta", "delta": {"stop_reason": "end_turn"}}),
+            ),
+            event("message_stop", json!({"type": "message_stop"})),
+        ];
+        let expected: Vec<Bytes> = events.iter().map(|e| e.as_ref().unwrap().clone()).collect();
+        let ctx = Arc::new(RequestContext::detached(&config));
+        let req = serde_json::from_value(json!({"model": "m", "messages": []})).unwrap();
+
+        let out: Vec<Bytes> = stitch_stream(
+            futures::stream::iter(events),
+            Arc::new(config),
+            reqwest::Client::new(),
+            req,
+            ctx,
+        )
+        .map(|item| item.unwrap())
+        .collect()
+        .await;
+
+        assert_eq!(out, expected);
+        assert_eq!(parse_event(&out[2]).unwrap().1["delta"]["text"], "hi");
+    }
+}
@@ -0,0 +1,443 @@
+use crate::accounting::UsageReport;
+use crate::config::Config;
+use crate::error::{ProxyError, ProxyResult};
+use crate::models::openai;
+use crate::proxy::{self, RequestContext};
+use crate::stops;
+use crate::transform;
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+use reqwest::Client;
+use serde_json::json;
+use std::io::Write;
+use std::sync::Arc;
+
+const JUDGE_SYSTEM_PROMPT: &str = "You are judging candidate answers to the same conversation. \
+Pick the candidate that is the most correct, complete and helpful. \
+Reply with only the number of the best candidate.";
+
+/// A single ensemble member's outcome
+struct Candidate {
+    model: String,
+    result: ProxyResult<openai::OpenAIResponse>,
+    usage: Option<UsageReport>,
+}
+
+/// Fan a non-streaming request out to every ensemble model and return the judged winner
+pub async fn handle(
+    config: Arc<Config>,
+    client: Client,
+    openai_req: openai::OpenAIRequest,
+    ctx: RequestContext,
+) -> ProxyResult<Response> {
+    tracing::debug!(
+        "Ensemble request fanned out to {} models",
+        config.ensemble_models.len()
+    );
+
+    let requests = config.ensemble_models.iter().map(|model| {
+        let mut req = openai_req.clone();
+        req.model = model.clone();
+        let config = &config;
+        let client = &client;
+        let ctx = &ctx;
+        async move {
+            let (result, usage) = match proxy::send_chat_completion(config, client, ctx, &req).await
+            {
+                Ok((resp, usage)) => (Ok(resp), Some(usage)),
+                Err(err) => (Err(err), None),
+            };
+            Candidate {
+                model: req.model,
+                result,
+                usage,
+            }
+        }
+    });
+    let mut candidates = futures::future::join_all(requests).await;
+
+    let verdict = judge(&config, &client, &ctx, &openai_req, &candidates)
+        .await
+        .filter(|&i| candidates.get(i).is_some_and(|c| c.result.is_ok()))
+        .or_else(|| heuristic_pick(&candidates, &openai_req));
+    let winner = match verdict {
+        Some(index) => index,
+        None => {
+            let errors: Vec<String> = candidates
+                .iter()
+                .filter_map(|c| c.result.as_ref().err())
+                .map(|e| e.to_string())
+                .collect();
+            return Err(ProxyError::Upstream(format!(
+                "All ensemble candidates failed: {}",
+                errors.join("; ")
+            )));
+        }
+    };
+
+    record_candidates(&config, &candidates, winner);
+
+    // The verdict was checked against the candidates above
+    let candidate = candidates.swap_remove(winner);
+    let openai_resp = candidate.result?;
+    let filtered = proxy::content_filter_outcome(&config, &openai_resp)?;
+    let mut anthropic_resp = transform::openai_to_anthropic(openai_resp, &candidate.model)?;
+    if filtered.is_some() {
+        anthropic_resp.stop_reason = filtered;
+    }
+    proxy::apply_thinking_policy(&config, &candidate.model, &mut anthropic_resp);
+    ctx.tool_names.restore_response(&mut anthropic_resp);
+    stops::apply_to_response(&ctx.watched_stops, &mut anthropic_resp);
+    if let Some(tool) = &ctx.structured_output {
+        transform::wrap_structured_output(&mut anthropic_resp, tool);
+    }
+    proxy::postprocess_response(&config, &ctx, &mut anthropic_resp);
+
+    let headers = candidate.usage.map(|u| u.headers()).unwrap_or_default();
+    Ok((headers, Json(anthropic_resp)).into_response())
+}
+
+/// Pick the winning candidate index, or None when every candidate failed
+async fn judge(
+    config: &Config,
+    client: &Client,
+    ctx: &RequestContext,
+    openai_req: &openai::OpenAIRequest,
+    candidates: &[Candidate],
+) -> Option<usize> {
+    let successful: Vec<usize> = candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.result.is_ok())
+        .map(|(i, _)| i)
+        .collect();
+
+    if successful.len() <= 1 {
+        return successful.first().copied();
+    }
+
+    if let Some(judge_model) = &config.ensemble_judge_model {
+        let verdict = judge_with_model(
+            config,
+            client,
+            ctx,
+            judge_model,
+            openai_req,
+            candidates,
+            &successful,
+        )
+        .await;
+        match verdict.filter(|index| successful.contains(index)) {
+            Some(index) => return Some(index),
+            None => tracing::warn!("Ensemble judge gave no usable verdict, using heuristics"),
+        }
+    }
+
+    heuristic_pick(candidates, openai_req)
+}
+
+/// The successful candidate the heuristics rank highest
+fn heuristic_pick(candidates: &[Candidate], openai_req: &openai::OpenAIRequest) -> Option<usize> {
+    (0..candidates.len())
+        .filter(|&i| candidates[i].result.is_ok())
+        .max_by_key(|&i| heuristic_score(&candidates[i], openai_req))
+}
+
+/// Ask the judge model to pick among the successful candidates
+async fn judge_with_model(
+    config: &Config,
+    client: &Client,
+    ctx: &RequestContext,
+    judge_model: &str,
+    openai_req: &openai::OpenAIRequest,
+    candidates: &[Candidate],
+    successful: &[usize],
+) -> Option<usize> {
+    let mut prompt = format!(
+        "Conversation (last user turn):\n{}\n",
+        last_user_text(openai_req)
+    );
+    for (number, &index) in successful.iter().enumerate() {
+        let resp = candidates[index].result.as_ref().ok()?;
+        prompt.push_str(&format!(
+            "\nCandidate {}:\n{}\n",
+            number + 1,
+            response_text(resp)
+        ));
+    }
+
+    let judge_req = openai::OpenAIRequest {
+        model: judge_model.to_string(),
+        messages: vec![
+            openai::Message {
+                role: "system".to_string(),
+                content: Some(openai::MessageContent::Text(
+                    JUDGE_SYSTEM_PROMPT.to_string(),
+                )),
+                tool_calls: None,
+                tool_call_id: None,
+                name: None,
+                reasoning_content: None,
+            },
+            openai::Message {
+                role: "user".to_string(),
+                content: Some(openai::MessageContent::Text(prompt)),
+                tool_calls: None,
+                tool_call_id: None,
+                name: None,
+                reasoning_content: None,
+            },
+        ],
+        max_tokens: Some(16),
+        temperature: Some(0.0),
+        top_p: None,
+        top_k: None,
+        stop: None,
+        stream: Some(false),
+        tools: None,
+        tool_choice: None,
+        stream_options: None,
+        reasoning: None,
+        reasoning_effort: None,
+        response_format: None,
+        extra: serde_json::Map::new(),
+    };
+
+    let verdict = match proxy::send_chat_completion(config, client, ctx, &judge_req).await {
+        Ok((resp, _)) => resp.choices.first()?.message.content.clone()?,
+        Err(err) => {
+            tracing::warn!("Ensemble judge request failed: {}", err);
+            return None;
+        }
+    };
+
+    let number = parse_verdict(&verdict)?;
+    successful.get(number.checked_sub(1)?).copied()
+}
+
+/// Extract the first candidate number from the judge's reply
+fn parse_verdict(verdict: &str) -> Option<usize> {
+    let digits: String = verdict
+        .chars()
+        .skip_while(|c| !c.is_ascii_digit())
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
+
+/// Score a candidate: clean finishes beat truncation, tool calls must parse,
+/// and longer answers win ties
+fn heuristic_score(candidate: &Candidate, openai_req: &openai::OpenAIRequest) -> (u8, usize) {
+    let Ok(resp) = &candidate.result else {
+        return (0, 0);
+    };
+    let Some(choice) = resp.choices.first() else {
+        return (0, 0);
+    };
+
+    let tool_calls = choice.message.tool_calls.as_deref().unwrap_or_default();
+    let tools_valid = tool_calls
+        .iter()
+        .all(|call| serde_json::from_str::<serde_json::Value>(&call.function.arguments).is_ok());
+
+    let mut rank = match choice.finish_reason.as_deref() {
+        Some("stop") | Some("tool_calls") => 3,
+        Some("length") => 1,
+        _ => 2,
+    };
+    if !tools_valid {
+        rank = 1;
+    } else if openai_req.tools.is_some() && !tool_calls.is_empty() {
+        rank += 1;
+    }
+
+    (rank, response_text(resp).len())
+}
+
+/// Append every candidate and the verdict to the configured JSONL record file
+fn record_candidates(config: &Config, candidates: &[Candidate], winner: usize) {
+    for (i, candidate) in candidates.iter().enumerate() {
+        match &candidate.result {
+            Ok(resp) => tracing::info!(
+                "Ensemble candidate {} ({}): {} chars{}",
+                i + 1,
+                candidate.model,
+                response_text(resp).len(),
+                if i == winner { " [selected]" } else { "" }
+            ),
+            Err(err) => tracing::info!(
+                "Ensemble candidate {} ({}) failed: {}",
+                i + 1,
+                candidate.model,
+                err
+            ),
+        }
+    }
+
+    let Some(path) = &config.ensemble_record_file else {
+        return;
+    };
+
+    let record = json!({
+        "winner": candidates[winner].model,
+        "candidates": candidates.iter().map(|c| match &c.result {
+            Ok(resp) => json!({ "model": c.model, "response": resp }),
+            Err(err) => json!({ "model": c.model, "error": err.to_string() }),
+        }).collect::<Vec<_>>(),
+    });
+
+    let written = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", record));
+    if let Err(err) = written {
+        tracing::warn!(
+            "Failed to record ensemble candidates to {}: {}",
+            path.display(),
+            err
+        );
+    }
+}
+
+/// Flatten a response's text and tool calls into plain text
+fn response_text(resp: &openai::OpenAIResponse) -> String {
+    let Some(choice) = resp.choices.first() else {
+        return String::new();
+    };
+
+    let mut text = choice.message.content.clone().unwrap_or_default();
+    for call in choice.message.tool_calls.iter().flatten() {
+        text.push_str(&format!(
+            "\n[tool call {}({})]",
+            call.function.name, call.function.arguments
+        ));
+    }
+    text
+}
+
+fn last_user_text(openai_req: &openai::OpenAIRequest) -> String {
+    let Some(content) = openai_req
+        .messages
+        .iter()
+        .rev()
+        .find(|m| m.role == "user")
+        .and_then(|m| m.content.as_ref())
+    else {
+        return String::new();
+    };
+
+    match content {
+        openai::MessageContent::Text(text) => text.clone(),
+        openai::MessageContent::Parts(parts) => parts
+            .iter()
+            .filter_map(|part| match part {
+                openai::ContentPart::Text { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{heuristic_pick, heuristic_score, parse_verdict, Candidate};
+    use crate::error::ProxyError;
+    use crate::models::openai;
+
+    fn response(content: &str, finish_reason: &str) -> openai::OpenAIResponse {
+        openai::OpenAIResponse {
+            id: None,
+            object: None,
+            created: None,
+            model: None,
+            choices: vec![openai::Choice {
+                index: 0,
+                message: openai::ChoiceMessage {
+                    role: "assistant".to_string(),
+                    content: Some(content.to_string()),
+                    tool_calls: None,
+                    reasoning: None,
+                    reasoning_content: None,
+                    reasoning_details: None,
+                    function_call: None,
+                },
+                finish_reason: Some(finish_reason.to_string()),
+                stop_reason: None,
+                content_filter_results: None,
+            }],
+            usage: openai::Usage {
+                prompt_tokens: 1,
+                completion_tokens: 1,
+                total_tokens: 2,
+                prompt_tokens_details: None,
+            },
+            system_fingerprint: None,
+        }
+    }
+
+    fn request() -> openai::OpenAIRequest {
+        openai::OpenAIRequest {
+            model: "m".to_string(),
+            messages: vec![],
+            max_tokens: None,
+            temperature: None,
+            top_p: None,
+            top_k: None,
+            stop: None,
+            stream: None,
+            tools: None,
+            tool_choice: None,
+            stream_options: None,
+            reasoning: None,
+            reasoning_effort: None,
+            response_format: None,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    #[test]
+    fn verdict_uses_first_number_in_reply() {
+        assert_eq!(parse_verdict("Candidate 2 is best"), Some(2));
+        assert_eq!(parse_verdict("3"), Some(3));
+        assert_eq!(parse_verdict("none"), None);
+    }
+
+    #[test]
+    fn heuristics_prefer_complete_answers_over_truncated_ones() {
+        let truncated = Candidate {
+            model: "a".to_string(),
+            result: Ok(response("a much longer but truncated answer", "length")),
+            usage: None,
+        };
+        let complete = Candidate {
+            model: "b".to_string(),
+            result: Ok(response("short", "stop")),
+            usage: None,
+        };
+
+        assert!(heuristic_score(&complete, &request()) > heuristic_score(&truncated, &request()));
+    }
+
+    #[test]
+    fn heuristic_pick_skips_failed_candidates() {
+        let candidates = [
+            Candidate {
+                model: "a".to_string(),
+                result: Err(ProxyError::Upstream("down".to_string())),
+                usage: None,
+            },
+            Candidate {
+                model: "b".to_string(),
+                result: Ok(response("short", "length")),
+                usage: None,
+            },
+        ];
+
+        assert_eq!(heuristic_pick(&candidates, &request()), Some(1));
+        assert_eq!(heuristic_pick(&candidates[..1], &request()), None);
+    }
+}
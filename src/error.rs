@@ -1,9 +1,12 @@
 use axum::{
-    http::StatusCode,
+    extract::rejection::JsonRejection,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
+use serde::Serialize;
 use serde_json::json;
+use std::time::Duration;
 use thiserror::Error;
 
 /// Application-specific errors
@@ -13,8 +16,27 @@ pub enum ProxyError {
     Config(String),
 
     #[error("Request transformation error: {0}")]
+    #[allow(dead_code)]
     Transform(String),
 
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
+
+    #[error("Overloaded: {0}")]
+    Overloaded(String),
+
     #[error("Upstream API error: {0}")]
     Upstream(String),
 
@@ -22,37 +44,182 @@ pub enum ProxyError {
     Serialization(#[from] serde_json::Error),
 
     #[error("HTTP error: {0}")]
-    Http(#[from] reqwest::Error),
+    Http(reqwest::Error),
+
+    #[error("Empty upstream response: {0}")]
+    EmptyResponse(String),
+
+    #[error("Blocked by the upstream's content filter: {0}")]
+    ContentFiltered(String),
+
+    #[error("Request timed out: {0}")]
+    Timeout(String),
 
     #[error("Internal error: {0}")]
     Internal(String),
+
+    /// An upstream error along with what the upstream said about it
+    #[error("{error}")]
+    FromUpstream {
+        error: Box<ProxyError>,
+        details: Box<UpstreamDetails>,
+    },
 }
 
-impl IntoResponse for ProxyError {
-    fn into_response(self) -> Response {
-        let (status, error_message) = match self {
+/// The parts of an upstream's error body worth passing on to clients
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct UpstreamDetails {
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub error_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// What the upstream said went wrong, without the proxy's context
+    #[serde(skip)]
+    pub message: String,
+    /// Passed on to clients as a Retry-After header
+    #[serde(skip)]
+    pub retry_after: Option<Duration>,
+}
+
+impl From<reqwest::Error> for ProxyError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            ProxyError::Timeout("upstream did not respond before the request deadline".to_string())
+        } else {
+            ProxyError::Http(err)
+        }
+    }
+}
+
+/// Bodies that aren't valid JSON for the endpoint are the client's mistake,
+/// reported the way Anthropic reports them rather than as axum's plain text
+impl From<JsonRejection> for ProxyError {
+    fn from(rejection: JsonRejection) -> Self {
+        ProxyError::InvalidRequest(rejection.body_text())
+    }
+}
+
+impl ProxyError {
+    /// The `error.type` reported to clients, from Anthropic's error types
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            ProxyError::Transform(_)
+            | ProxyError::InvalidRequest(_)
+            | ProxyError::Serialization(_)
+            | ProxyError::ContentFiltered(_) => "invalid_request_error",
+            ProxyError::Unauthorized(_) => "authentication_error",
+            ProxyError::NotFound(_) => "not_found_error",
+            ProxyError::PermissionDenied(_) => "permission_error",
+            ProxyError::RateLimited(_) => "rate_limit_error",
+            ProxyError::Overloaded(_) => "overloaded_error",
+            ProxyError::Timeout(_) => "timeout_error",
+            ProxyError::Config(_)
+            | ProxyError::Upstream(_)
+            | ProxyError::Http(_)
+            | ProxyError::EmptyResponse(_)
+            | ProxyError::Internal(_) => "api_error",
+            ProxyError::FromUpstream { error, .. } => error.error_type(),
+        }
+    }
+
+    fn into_status_and_message(self) -> (StatusCode, String) {
+        match self {
             ProxyError::Config(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
             ProxyError::Transform(msg) => (StatusCode::BAD_REQUEST, msg),
+            ProxyError::InvalidRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            ProxyError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
+            ProxyError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+            ProxyError::PermissionDenied(msg) => (StatusCode::FORBIDDEN, msg),
+            ProxyError::RateLimited(msg) => (StatusCode::TOO_MANY_REQUESTS, msg),
+            // 529 is Anthropic's own status for an overloaded API
+            ProxyError::Overloaded(msg) => (
+                StatusCode::from_u16(529).unwrap_or(StatusCode::SERVICE_UNAVAILABLE),
+                msg,
+            ),
             ProxyError::Upstream(msg) => (StatusCode::BAD_GATEWAY, msg),
             ProxyError::Serialization(err) => {
                 (StatusCode::BAD_REQUEST, format!("JSON error: {}", err))
             }
-            ProxyError::Http(err) => {
-                (StatusCode::BAD_GATEWAY, format!("HTTP error: {}", err))
-            }
+            ProxyError::Http(err) => (StatusCode::BAD_GATEWAY, format!("HTTP error: {}", err)),
+            ProxyError::EmptyResponse(msg) => (StatusCode::BAD_GATEWAY, msg),
+            ProxyError::Timeout(msg) => (StatusCode::GATEWAY_TIMEOUT, msg),
+            ProxyError::ContentFiltered(msg) => (StatusCode::BAD_REQUEST, msg),
             ProxyError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+            ProxyError::FromUpstream { error, .. } => error.into_status_and_message(),
+        }
+    }
+}
+
+impl IntoResponse for ProxyError {
+    fn into_response(self) -> Response {
+        let error_type = self.error_type();
+
+        let details = match &self {
+            ProxyError::FromUpstream { details, .. } => Some(details.as_ref().clone()),
+            _ => None,
         };
+        let (status, error_message) = self.into_status_and_message();
 
+        let mut error = json!({
+            "type": error_type,
+            "message": &error_message,
+        });
+        if let Some(details) = &details {
+            error["upstream"] = json!(details);
+        }
         let body = Json(json!({
-            "error": {
-                "type": "proxy_error",
-                "message": error_message,
-            }
+            "type": "error",
+            "error": error,
         }));
 
-        (status, body).into_response()
+        let mut response = (status, body).into_response();
+        if let Some(wait) = details.and_then(|details| details.retry_after) {
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+            .extensions_mut()
+            .insert(ErrorMessage(error_message));
+        response
     }
 }
 
+/// The message of an error response, kept for the admin API's recent errors
+#[derive(Debug, Clone)]
+pub struct ErrorMessage(pub String);
+
 /// Result type for proxy operations
 pub type ProxyResult<T> = Result<T, ProxyError>;
+
+#[cfg(test)]
+mod tests {
+    use super::ProxyError;
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn errors_use_anthropic_shapes() {
+        let response = ProxyError::Transform("bad content block".to_string()).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["type"], "error");
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert_eq!(body["error"]["message"], "bad content block");
+
+        let response = ProxyError::Upstream("503".to_string()).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            ProxyError::Internal(String::new()).error_type(),
+            "api_error"
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use std::path::Path;
+
+/// Error types a rule may report, from Anthropic's error types
+const ERROR_TYPES: &[&str] = &[
+    "invalid_request_error",
+    "authentication_error",
+    "permission_error",
+    "not_found_error",
+    "rate_limit_error",
+    "overloaded_error",
+    "timeout_error",
+    "api_error",
+];
+
+/// Error rules as written in the ERROR_RULES_FILE JSON file
+#[derive(Debug, Deserialize)]
+struct RuleFile {
+    rules: Vec<RuleSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RuleSpec {
+    #[serde(default)]
+    status: Option<u16>,
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    error_type: Option<String>,
+    #[serde(default)]
+    retry: Option<bool>,
+}
+
+/// How one kind of upstream error is reported and whether it is retried
+#[derive(Debug, Clone)]
+pub struct ErrorRule {
+    status: Option<u16>,
+    body: Option<Regex>,
+    pub error_type: Option<&'static str>,
+    pub retry: Option<bool>,
+}
+
+/// Overrides for upstreams whose statuses don't say what went wrong, such
+/// as a 500 for an overlong prompt or a 400 for a rate limit
+#[derive(Debug, Clone)]
+pub struct ErrorRules {
+    rules: Vec<ErrorRule>,
+}
+
+impl ErrorRules {
+    pub fn load(path: &Path) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read error rules file {}", path.display()))?;
+        Self::parse(&raw).with_context(|| format!("Invalid error rules file {}", path.display()))
+    }
+
+    fn parse(raw: &str) -> Result<Self> {
+        let file: RuleFile = serde_json::from_str(raw)?;
+        let rules = file
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, spec)| {
+                if spec.status.is_none() && spec.body.is_none() {
+                    bail!("rule {} must match a status, a body pattern or both", i);
+                }
+                if spec.error_type.is_none() && spec.retry.is_none() {
+                    bail!("rule {} must set error_type, retry or both", i);
+                }
+                let error_type = spec
+                    .error_type
+                    .map(|t| {
+                        ERROR_TYPES
+                            .iter()
+                            .find(|&&known| known == t)
+                            .copied()
+                            .with_context(|| format!("rule {}: unknown error_type {}", i, t))
+                    })
+                    .transpose()?;
+                let body = spec
+                    .body
+                    .map(|p| {
+                        Regex::new(&p).with_context(|| format!("rule {}: invalid pattern {}", i, p))
+                    })
+                    .transpose()?;
+                Ok(ErrorRule {
+                    status: spec.status,
+                    body,
+                    error_type,
+                    retry: spec.retry,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// The first rule matching an upstream error response
+    pub fn find(&self, status: u16, body: &str) -> Option<&ErrorRule> {
+        self.rules.iter().find(|rule| {
+            rule.status.is_none_or(|s| s == status)
+                && rule.body.as_ref().is_none_or(|p| p.is_match(body))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ErrorRules;
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = ErrorRules::parse(
+            r#"{"rules": [
+                {"status": 500, "body": "(?i)context length", "error_type": "invalid_request_error", "retry": false},
+                {"status": 400, "body": "(?i)too many requests", "error_type": "rate_limit_error"},
+                {"status": 500, "retry": true}
+            ]}"#,
+        )
+        .unwrap();
+
+        let rule = rules.find(500, "Context length exceeded").unwrap();
+        assert_eq!(rule.error_type, Some("invalid_request_error"));
+        assert_eq!(rule.retry, Some(false));
+        let rule = rules.find(500, "boom").unwrap();
+        assert_eq!((rule.error_type, rule.retry), (None, Some(true)));
+        assert!(rules.find(400, "bad request").is_none());
+
+        assert!(ErrorRules::parse(r#"{"rules": [{"status": 500}]}"#).is_err());
+        assert!(
+            ErrorRules::parse(r#"{"rules": [{"status": 500, "error_type": "oops"}]}"#).is_err()
+        );
+    }
+}
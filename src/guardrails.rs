@@ -0,0 +1,339 @@
+use crate::models::anthropic;
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+
+const DEFAULT_REFUSAL: &str = "I can't provide that response.";
+const DEFAULT_REDACTION: &str = "[REDACTED]";
+const DEFAULT_STREAM_WINDOW: usize = 256;
+
+/// What to do when model output violates a guardrail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GuardrailAction {
+    /// Replace forbidden matches with the redaction marker
+    Redact,
+    /// Cut the output at the first forbidden match
+    Truncate,
+    /// Replace the whole output with the refusal message
+    Refuse,
+}
+
+/// Guardrail rules as written in the OUTPUT_GUARDRAILS_FILE JSON file
+#[derive(Debug, Deserialize)]
+struct GuardrailRules {
+    #[serde(default, alias = "forbidden_patterns")]
+    deny_patterns: Vec<String>,
+    #[serde(default)]
+    required_patterns: Vec<String>,
+    #[serde(default)]
+    max_length: Option<usize>,
+    action: GuardrailAction,
+    #[serde(default)]
+    refusal_message: Option<String>,
+    #[serde(default)]
+    redaction: Option<String>,
+    #[serde(default)]
+    stream_window: Option<usize>,
+}
+
+/// Compiled output policy applied to text returned by the model
+#[derive(Debug, Clone)]
+pub struct Guardrails {
+    deny: Vec<Regex>,
+    required: Vec<Regex>,
+    max_length: Option<usize>,
+    action: GuardrailAction,
+    refusal_message: String,
+    redaction: String,
+    stream_window: usize,
+}
+
+/// A one-line summary for the startup log
+impl fmt::Display for Guardrails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} deny and {} required patterns, action {:?}",
+            self.deny.len(),
+            self.required.len(),
+            self.action
+        )?;
+        if let Some(max) = self.max_length {
+            write!(f, ", max length {}", max)?;
+        }
+        Ok(())
+    }
+}
+
+impl Guardrails {
+    pub fn load(path: &Path) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read guardrails file {}", path.display()))?;
+        Self::parse(&raw).with_context(|| format!("Invalid guardrails file {}", path.display()))
+    }
+
+    fn parse(raw: &str) -> Result<Self> {
+        let rules: GuardrailRules = serde_json::from_str(raw)?;
+
+        let compile = |patterns: &[String]| -> Result<Vec<Regex>> {
+            patterns
+                .iter()
+                .map(|p| Regex::new(p).with_context(|| format!("Invalid pattern: {}", p)))
+                .collect()
+        };
+
+        Ok(Self {
+            deny: compile(&rules.deny_patterns)?,
+            required: compile(&rules.required_patterns)?,
+            max_length: rules.max_length,
+            action: rules.action,
+            refusal_message: rules
+                .refusal_message
+                .unwrap_or_else(|| DEFAULT_REFUSAL.to_string()),
+            redaction: rules
+                .redaction
+                .unwrap_or_else(|| DEFAULT_REDACTION.to_string()),
+            stream_window: rules.stream_window.unwrap_or(DEFAULT_STREAM_WINDOW),
+        })
+    }
+
+    /// Apply the policy to a complete piece of output text
+    pub fn apply(&self, text: &str) -> String {
+        let missing_required = self.required.iter().any(|r| !r.is_match(text));
+        if missing_required {
+            tracing::warn!("Output guardrail: required pattern missing, refusing");
+            return self.refusal_message.clone();
+        }
+
+        match self.action {
+            GuardrailAction::Refuse => {
+                let too_long = self
+                    .max_length
+                    .is_some_and(|max| text.chars().count() > max);
+                if too_long || self.deny.iter().any(|r| r.is_match(text)) {
+                    tracing::warn!("Output guardrail violated, refusing");
+                    return self.refusal_message.clone();
+                }
+                text.to_string()
+            }
+            GuardrailAction::Redact => truncate_chars(&self.redact(text), self.max_length),
+            GuardrailAction::Truncate => {
+                let cut = self.first_match(text).unwrap_or(text.len());
+                truncate_chars(&text[..cut], self.max_length)
+            }
+        }
+    }
+
+    /// Apply the policy to every text block of a non-streaming response
+    pub fn apply_to_response(&self, resp: &mut anthropic::AnthropicResponse) {
+        for block in &mut resp.content {
+            if let anthropic::ResponseContent::Text { text, .. } = block {
+                *text = self.apply(text);
+            }
+        }
+    }
+
+    /// Start guarding a streamed text block
+    pub fn stream(&self) -> StreamGuard<'_> {
+        StreamGuard {
+            rules: self,
+            pending: String::new(),
+            emitted_chars: 0,
+            stopped: false,
+        }
+    }
+
+    /// Whether the whole block has to be seen before anything can be emitted
+    fn needs_full_text(&self) -> bool {
+        self.action == GuardrailAction::Refuse || !self.required.is_empty()
+    }
+
+    fn redact(&self, text: &str) -> String {
+        let mut redacted = text.to_string();
+        for pattern in &self.deny {
+            redacted = pattern
+                .replace_all(&redacted, self.redaction.as_str())
+                .into_owned();
+        }
+        if redacted != text {
+            tracing::warn!("Output guardrail: redacted forbidden content");
+        }
+        redacted
+    }
+
+    fn first_match(&self, text: &str) -> Option<usize> {
+        let start = self
+            .deny
+            .iter()
+            .filter_map(|r| r.find(text).map(|m| m.start()))
+            .min();
+        if start.is_some() {
+            tracing::warn!("Output guardrail: truncating at forbidden content");
+        }
+        start
+    }
+}
+
+/// Incremental guardrail state for one streamed text block.
+///
+/// Redaction and truncation hold back `stream_window` characters so matches
+/// spanning several deltas are still caught; refusal and required patterns
+/// buffer the whole block because they can only be decided at its end.
+pub struct StreamGuard<'a> {
+    rules: &'a Guardrails,
+    pending: String,
+    emitted_chars: usize,
+    stopped: bool,
+}
+
+impl StreamGuard<'_> {
+    /// Feed a delta and return the text that is now safe to emit
+    pub fn push(&mut self, delta: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.pending.push_str(delta);
+
+        if self.rules.needs_full_text() {
+            return String::new();
+        }
+
+        match self.rules.action {
+            GuardrailAction::Redact => self.pending = self.rules.redact(&self.pending),
+            GuardrailAction::Truncate => {
+                if let Some(cut) = self.rules.first_match(&self.pending) {
+                    self.pending.truncate(cut);
+                    self.stopped = true;
+                    let rest = std::mem::take(&mut self.pending);
+                    return self.limit(rest);
+                }
+            }
+            GuardrailAction::Refuse => unreachable!("refusal buffers the full block"),
+        }
+
+        let keep = self.rules.stream_window;
+        let total = self.pending.chars().count();
+        if total <= keep {
+            return String::new();
+        }
+        let split = self
+            .pending
+            .char_indices()
+            .nth(total - keep)
+            .map(|(i, _)| i)
+            .unwrap_or(self.pending.len());
+        let ready: String = self.pending.drain(..split).collect();
+        self.limit(ready)
+    }
+
+    /// Flush whatever is left once the block ends
+    pub fn finish(&mut self) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        let rest = std::mem::take(&mut self.pending);
+        self.stopped = true;
+
+        if self.rules.needs_full_text() {
+            return self.rules.apply(&rest);
+        }
+
+        let rest = match self.rules.action {
+            GuardrailAction::Redact => self.rules.redact(&rest),
+            _ => {
+                let cut = self.rules.first_match(&rest).unwrap_or(rest.len());
+                rest[..cut].to_string()
+            }
+        };
+        self.limit(rest)
+    }
+
+    /// Enforce max_length across everything emitted for the block
+    fn limit(&mut self, text: String) -> String {
+        let Some(max) = self.rules.max_length else {
+            return text;
+        };
+        let remaining = max.saturating_sub(self.emitted_chars);
+        let limited = truncate_chars(&text, Some(remaining));
+        self.emitted_chars += limited.chars().count();
+        if limited.len() < text.len() {
+            tracing::warn!("Output guardrail: truncated at max_length {}", max);
+            self.stopped = true;
+            self.pending.clear();
+        }
+        limited
+    }
+}
+
+fn truncate_chars(text: &str, max: Option<usize>) -> String {
+    match max.and_then(|max| text.char_indices().nth(max)) {
+        Some((cut, _)) => text[..cut].to_string(),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Guardrails;
+
+    fn rules(json: &str) -> Guardrails {
+        Guardrails::parse(json).unwrap()
+    }
+
+    #[test]
+    fn redact_replaces_denied_matches() {
+        let g = rules(r#"{"deny_patterns": ["sk-[a-z0-9]+"], "action": "redact"}"#);
+        assert_eq!(g.apply("key sk-abc123 here"), "key [REDACTED] here");
+    }
+
+    #[test]
+    fn truncate_cuts_at_first_match_and_max_length() {
+        let g = rules(r#"{"deny_patterns": ["SECRET"], "max_length": 5, "action": "truncate"}"#);
+        assert_eq!(g.apply("abc SECRET def"), "abc ");
+        assert_eq!(g.apply("abcdefgh"), "abcde");
+    }
+
+    #[test]
+    fn refuse_replaces_output_on_violation() {
+        let g = rules(
+            r#"{"forbidden_patterns": ["bad"], "action": "refuse", "refusal_message": "no"}"#,
+        );
+        assert_eq!(g.apply("this is bad"), "no");
+        assert_eq!(g.apply("this is fine"), "this is fine");
+    }
+
+    #[test]
+    fn missing_required_pattern_refuses() {
+        let g = rules(r#"{"required_patterns": ["^OK"], "action": "redact"}"#);
+        assert_eq!(g.apply("OK done"), "OK done");
+        assert_eq!(g.apply("done"), "I can't provide that response.");
+    }
+
+    #[test]
+    fn stream_redacts_matches_split_across_deltas() {
+        let g = rules(
+            r#"{"deny_patterns": ["sk-[a-z0-9]{6}"], "action": "redact", "stream_window": 8}"#,
+        );
+        let mut guard = g.stream();
+        let mut out = String::new();
+        for delta in ["hello world, key sk-ab", "c123 and more text after it"] {
+            out.push_str(&guard.push(delta));
+        }
+        out.push_str(&guard.finish());
+        assert_eq!(out, "hello world, key [REDACTED] and more text after it");
+    }
+
+    #[test]
+    fn stream_truncation_stops_output() {
+        let g = rules(r#"{"deny_patterns": ["STOP"], "action": "truncate", "stream_window": 2}"#);
+        let mut guard = g.stream();
+        let mut out = guard.push("go go ST");
+        out.push_str(&guard.push("OP never shown"));
+        out.push_str(&guard.push("more"));
+        out.push_str(&guard.finish());
+        assert_eq!(out, "go go ");
+    }
+}
@@ -0,0 +1,193 @@
+use crate::config::{Config, UpstreamFormat};
+use crate::upstream::UpstreamRegistry;
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a connectivity probe result is reused before probing again
+const PROBE_TTL: Duration = Duration::from_secs(30);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Process start time and the last upstream connectivity probe
+#[derive(Debug)]
+pub struct HealthState {
+    started: Instant,
+    probe: Mutex<Option<Probe>>,
+}
+
+impl Default for HealthState {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            probe: Mutex::new(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Probe {
+    url: String,
+    checked: Instant,
+    latency: Duration,
+    /// The HTTP error, or None if the upstream answered at all
+    error: Option<String>,
+}
+
+impl Probe {
+    fn to_json(&self) -> Value {
+        json!({
+            "url": self.url,
+            "reachable": self.error.is_none(),
+            "latency_ms": self.latency.as_millis() as u64,
+            "checked_secs_ago": self.checked.elapsed().as_secs(),
+            "error": self.error,
+        })
+    }
+}
+
+impl HealthState {
+    /// The cached probe, refreshed if it is older than PROBE_TTL. Concurrent
+    /// callers with a stale cache may both probe; the later result wins.
+    async fn probe(&self, client: &Client, registry: &UpstreamRegistry) -> Probe {
+        let cached = self
+            .probe
+            .lock()
+            .expect("health probe lock poisoned")
+            .clone();
+        if let Some(probe) = cached.filter(|p| p.checked.elapsed() < PROBE_TTL) {
+            return probe;
+        }
+
+        let pool = registry.current();
+        let url = pool.url(pool.select()).to_string();
+        let started = Instant::now();
+        // Any HTTP answer (even 401 or 405) shows the upstream is reachable;
+        // a GET needs no credentials and costs no tokens
+        let error = match client.get(&url).timeout(PROBE_TIMEOUT).send().await {
+            Ok(_) => None,
+            Err(err) => {
+                tracing::warn!("Upstream health probe to {} failed: {}", url, err);
+                Some(err.to_string())
+            }
+        };
+        let probe = Probe {
+            url,
+            checked: Instant::now(),
+            latency: started.elapsed(),
+            error,
+        };
+        *self.probe.lock().expect("health probe lock poisoned") = Some(probe.clone());
+        probe
+    }
+}
+
+/// GET /health: version, uptime, configured upstream and its last probe
+pub async fn health_handler(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<Client>,
+    Extension(registry): Extension<Arc<UpstreamRegistry>>,
+    Extension(state): Extension<Arc<HealthState>>,
+) -> Json<Value> {
+    let probe = state.probe(&client, &registry).await;
+    Json(json!({
+        "status": if probe.error.is_none() { "ok" } else { "degraded" },
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": state.started.elapsed().as_secs(),
+        "upstream": {
+            "base_url": config.base_url,
+            "format": match config.upstream_format {
+                UpstreamFormat::OpenAI => "openai",
+                UpstreamFormat::Anthropic => "anthropic",
+                UpstreamFormat::Ollama => "ollama",
+            },
+            "targets": registry.current().urls(),
+            "probe": probe.to_json(),
+        },
+    }))
+}
+
+/// GET /livez: the process is up and serving requests
+pub async fn livez_handler() -> &'static str {
+    "OK"
+}
+
+/// GET /readyz: 200 once the upstream answers the connectivity probe, 503 otherwise
+pub async fn readyz_handler(
+    Extension(client): Extension<Client>,
+    Extension(registry): Extension<Arc<UpstreamRegistry>>,
+    Extension(state): Extension<Arc<HealthState>>,
+) -> impl IntoResponse {
+    let probe = state.probe(&client, &registry).await;
+    let status = match probe.error {
+        None => StatusCode::OK,
+        Some(_) => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(probe.to_json()))
+}
+
+/// Probe a running proxy's /health, and /readyz when asked, reporting each
+/// endpoint; true when all of them answer with success
+pub async fn check_running(client: &Client, url: &str, ready: bool) -> bool {
+    let mut paths = vec!["/health"];
+    if ready {
+        paths.push("/readyz");
+    }
+
+    for path in paths {
+        let endpoint = format!("{}{}", url.trim_end_matches('/'), path);
+        match client.get(&endpoint).send().await {
+            Ok(response) if response.status().is_success() => {
+                eprintln!("✓ {} ({})", endpoint, response.status());
+            }
+            Ok(response) => {
+                eprintln!("✗ {} ({})", endpoint, response.status());
+                return false;
+            }
+            Err(e) => {
+                eprintln!("✗ {}: {}", endpoint, e);
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_running, HealthState};
+    use crate::upstream::{UpstreamPool, UpstreamRegistry};
+    use axum::{http::StatusCode, routing::get, Router};
+    use reqwest::Client;
+
+    #[tokio::test]
+    async fn unreachable_upstreams_are_reported_and_cached() {
+        let state = HealthState::default();
+        let registry =
+            UpstreamRegistry::new(UpstreamPool::new(vec!["http://127.0.0.1:9/v1".to_string()]));
+
+        let probe = state.probe(&Client::new(), &registry).await;
+        assert!(probe.error.is_some());
+        assert_eq!(probe.to_json()["reachable"], false);
+
+        let cached = state.probe(&Client::new(), &registry).await;
+        assert_eq!(cached.checked, probe.checked);
+    }
+
+    #[tokio::test]
+    async fn running_proxy_checks_follow_the_endpoints() {
+        let app = Router::new()
+            .route("/health", get(|| async { "OK" }))
+            .route("/readyz", get(|| async { StatusCode::SERVICE_UNAVAILABLE }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let url = format!("http://{}/", addr);
+
+        assert!(check_running(&Client::new(), &url, false).await);
+        assert!(!check_running(&Client::new(), &url, true).await);
+        assert!(!check_running(&Client::new(), "http://127.0.0.1:9", false).await);
+    }
+}
@@ -0,0 +1,294 @@
+use crate::accounting::Accounting;
+use crate::config::Config;
+use crate::error::{ProxyError, ProxyResult};
+use crate::models::anthropic;
+use crate::proxy;
+use crate::sse::SseParser;
+use crate::upstream::UpstreamRegistry;
+use crate::vision::CaptionCache;
+use axum::{
+    body::Body,
+    extract::rejection::JsonRejection,
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use bytes::Bytes;
+use futures::StreamExt;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+const HUMAN_PROMPT: &str = "\n\nHuman:";
+const AI_PROMPT: &str = "\n\nAssistant:";
+/// Largest translated non-streaming response the handler will buffer
+const MAX_RESPONSE_BYTES: usize = 16 * 1024 * 1024;
+
+/// Text Completions API request (`POST /v1/complete`)
+#[derive(Debug, Deserialize)]
+pub struct CompletionRequest {
+    model: String,
+    prompt: String,
+    max_tokens_to_sample: u32,
+    #[serde(default)]
+    stop_sequences: Option<Vec<String>>,
+    #[serde(default)]
+    temperature: Option<f32>,
+    #[serde(default)]
+    top_p: Option<f32>,
+    #[serde(default)]
+    top_k: Option<u32>,
+    #[serde(default)]
+    stream: Option<bool>,
+    #[serde(default)]
+    metadata: Option<Value>,
+}
+
+/// Serve the legacy Text Completions API by running the prompt through the
+/// Messages pipeline and translating the result back
+pub async fn complete_handler(
+    config: Extension<Arc<Config>>,
+    client: Extension<Client>,
+    accounting: Extension<Arc<Accounting>>,
+    upstreams: Extension<Arc<UpstreamRegistry>>,
+    captions: Extension<Arc<CaptionCache>>,
+    headers: HeaderMap,
+    payload: Result<Json<CompletionRequest>, JsonRejection>,
+) -> ProxyResult<Response> {
+    let Json(req) = payload?;
+    let model = req.model.clone();
+    let is_streaming = req.stream.unwrap_or(false);
+    let messages_req = to_messages_request(req)?;
+
+    let response = proxy::proxy_handler(
+        config,
+        client,
+        accounting,
+        upstreams,
+        captions,
+        headers,
+        Ok(Json(serde_json::to_value(&messages_req)?)),
+    )
+    .await?;
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    if is_streaming {
+        let stream = completion_stream(body, model);
+        return Ok(Response::from_parts(parts, Body::from_stream(stream)));
+    }
+
+    let bytes = axum::body::to_bytes(body, MAX_RESPONSE_BYTES)
+        .await
+        .map_err(|err| ProxyError::Internal(format!("failed to read response: {}", err)))?;
+    let message: anthropic::AnthropicResponse = serde_json::from_slice(&bytes)?;
+    let completion = to_completion(&message, &model);
+
+    let mut response = (parts.headers, Json(completion)).into_response();
+    response.headers_mut().remove(header::CONTENT_LENGTH);
+    Ok(response)
+}
+
+/// Split a `\n\nHuman: ... \n\nAssistant:` prompt into Messages API turns.
+/// Text before the first turn becomes the system prompt, and the final empty
+/// Assistant turn is the cue for the model to answer.
+fn to_messages_request(req: CompletionRequest) -> ProxyResult<anthropic::AnthropicRequest> {
+    let mut system = String::new();
+    let mut messages: Vec<anthropic::Message> = Vec::new();
+    let mut rest = req.prompt.as_str();
+
+    loop {
+        let next = [(HUMAN_PROMPT, "user"), (AI_PROMPT, "assistant")]
+            .into_iter()
+            .filter_map(|(marker, role)| rest.find(marker).map(|at| (at, marker, role)))
+            .min_by_key(|(at, _, _)| *at);
+
+        let text = match next {
+            Some((at, _, _)) => &rest[..at],
+            None => rest,
+        };
+        match messages.last_mut() {
+            Some(message) => {
+                message.content = anthropic::MessageContent::Text(text.trim().to_string())
+            }
+            None => system.push_str(text.trim()),
+        }
+
+        let Some((at, marker, role)) = next else {
+            break;
+        };
+        messages.push(anthropic::Message {
+            role: role.to_string(),
+            content: anthropic::MessageContent::Text(String::new()),
+        });
+        rest = &rest[at + marker.len()..];
+    }
+
+    if matches!(
+        messages.last(),
+        Some(anthropic::Message { role, content: anthropic::MessageContent::Text(text) })
+            if role == "assistant" && text.is_empty()
+    ) {
+        messages.pop();
+    }
+    if messages.first().map(|m| m.role.as_str()) != Some("user") {
+        return Err(ProxyError::InvalidRequest(
+            "prompt must contain a turn starting with \"\\n\\nHuman:\"".to_string(),
+        ));
+    }
+
+    Ok(anthropic::AnthropicRequest {
+        model: req.model,
+        messages,
+        max_tokens: req.max_tokens_to_sample,
+        system: (!system.is_empty()).then_some(anthropic::SystemPrompt::Single(system)),
+        temperature: req.temperature,
+        top_p: req.top_p,
+        top_k: req.top_k,
+        stop_sequences: req.stop_sequences,
+        stream: req.stream,
+        tools: None,
+        metadata: req.metadata,
+        extra: json!({}),
+    })
+}
+
+fn to_completion(message: &anthropic::AnthropicResponse, model: &str) -> Value {
+    let completion: String = message
+        .content
+        .iter()
+        .filter_map(|block| match block {
+            anthropic::ResponseContent::Text { text, .. } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect();
+    json!({
+        "type": "completion",
+        "id": message.id,
+        "completion": completion,
+        "stop_reason": completion_stop_reason(message.stop_reason.as_deref()),
+        "stop": message.stop_sequence,
+        "model": model,
+    })
+}
+
+fn completion_stop_reason(stop_reason: Option<&str>) -> Option<&'static str> {
+    match stop_reason? {
+        "max_tokens" => Some("max_tokens"),
+        _ => Some("stop_sequence"),
+    }
+}
+
+/// Turn Messages API stream events into `completion` events: one per text
+/// delta, then a final empty one carrying the stop reason
+fn completion_stream(
+    body: Body,
+    model: String,
+) -> impl futures::Stream<Item = Result<Bytes, std::io::Error>> + Send {
+    async_stream::stream! {
+        let mut body = body.into_data_stream();
+        let mut sse = SseParser::new();
+        let mut id = String::from("compl_proxy");
+
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    yield Err(std::io::Error::other(err.to_string()));
+                    break;
+                }
+            };
+            for event in sse.push(&chunk) {
+                let name = event.event.as_deref().unwrap_or_default();
+                let Ok(data) = serde_json::from_str::<Value>(&event.data) else {
+                    continue;
+                };
+
+                let completion = match name {
+                    "message_start" => {
+                        if let Some(message_id) = data["message"]["id"].as_str() {
+                            id = message_id.to_string();
+                        }
+                        continue;
+                    }
+                    "content_block_delta" => match data["delta"]["text"].as_str() {
+                        Some(text) => json!({
+                            "type": "completion",
+                            "id": id,
+                            "completion": text,
+                            "stop_reason": null,
+                            "model": model,
+                        }),
+                        None => continue,
+                    },
+                    "message_delta" => json!({
+                        "type": "completion",
+                        "id": id,
+                        "completion": "",
+                        "stop_reason": completion_stop_reason(data["delta"]["stop_reason"].as_str()),
+                        "stop": data["delta"]["stop_sequence"],
+                        "model": model,
+                    }),
+                    "error" => {
+                        yield Ok(Bytes::from(format!("event: error\ndata: {}\n\n", data)));
+                        continue;
+                    }
+                    _ => continue,
+                };
+                yield Ok(Bytes::from(format!("event: completion\ndata: {}\n\n", completion)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_messages_request, CompletionRequest};
+    use crate::models::anthropic;
+
+    #[test]
+    fn legacy_prompts_become_message_turns() {
+        let req: CompletionRequest = serde_json::from_value(serde_json::json!({
+            "model": "claude-2.1",
+            "prompt": "Be terse.\n\nHuman: Hi\n\nAssistant: Hello!\n\nHuman: What is 2+2?\n\nAssistant:",
+            "max_tokens_to_sample": 100
+        }))
+        .unwrap();
+
+        let messages_req = to_messages_request(req).unwrap();
+        assert!(
+            matches!(messages_req.system, Some(anthropic::SystemPrompt::Single(ref s)) if s == "Be terse.")
+        );
+        let turns: Vec<(&str, &str)> = messages_req
+            .messages
+            .iter()
+            .map(|m| match &m.content {
+                anthropic::MessageContent::Text(text) => (m.role.as_str(), text.as_str()),
+                anthropic::MessageContent::Blocks(_) => panic!("expected text"),
+            })
+            .collect();
+        assert_eq!(
+            turns,
+            [
+                ("user", "Hi"),
+                ("assistant", "Hello!"),
+                ("user", "What is 2+2?")
+            ]
+        );
+        assert_eq!(messages_req.max_tokens, 100);
+    }
+
+    #[test]
+    fn prompts_without_a_human_turn_are_rejected() {
+        let req: CompletionRequest = serde_json::from_value(serde_json::json!({
+            "model": "claude-2.1",
+            "prompt": "Just text",
+            "max_tokens_to_sample": 10
+        }))
+        .unwrap();
+        assert!(to_messages_request(req).is_err());
+    }
+}
@@ -1,11 +1,47 @@
+mod accounting;
+mod admin;
+mod assembly;
+mod batches;
 mod cli;
+mod coalesce;
 mod config;
+mod continuation;
+mod ensemble;
 mod error;
+mod error_rules;
+mod export;
+mod guardrails;
+mod health;
+mod legacy;
+mod model_list;
+mod model_rules;
 mod models;
+mod ollama;
+mod passthrough;
 mod proxy;
+mod quirks;
+mod reverse;
+mod routing;
+mod schema;
+mod secrets;
+mod selftest;
+mod server;
+mod splits;
+mod sse;
+mod stops;
+mod tags;
+mod think_tags;
+mod tokens;
+mod tool_limits;
+mod tool_names;
 mod transform;
+mod translator;
+mod upstream;
+mod validation;
+mod vertex;
+mod vision;
 
-use axum::{routing::post, Extension, Router};
+use axum::{extract::rejection::JsonRejection, routing::post, Extension, Json, Router};
 use clap::Parser;
 use cli::{Cli, Command};
 use config::Config;
@@ -31,6 +67,27 @@ fn main() -> anyhow::Result<()> {
                 check_status(&pid_file)?;
                 return Ok(());
             }
+            Command::Health {
+                url,
+                ready,
+                timeout,
+            } => {
+                if !check_health(&url, ready, std::time::Duration::from_secs(timeout))? {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+            Command::Selftest => {
+                let config = Config::from_env_with_path(cli.config)?;
+                let runtime = tokio::runtime::Runtime::new()?;
+                let failures = runtime.block_on(selftest::run(&config))?;
+                if failures > 0 {
+                    eprintln!("✗ {} fixture(s) did not match", failures);
+                    std::process::exit(1);
+                }
+                eprintln!("✓ All fixtures match");
+                return Ok(());
+            }
         }
     }
 
@@ -90,27 +147,86 @@ async fn async_main(cli: Cli) -> anyhow::Result<()> {
         tracing::Level::INFO
     };
 
+    let (log_filter, log_handle) = tracing_subscriber::reload::Layer::new(
+        tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| format!("anthropic_proxy={}", log_level).into()),
+    );
     tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| format!("anthropic_proxy={}", log_level).into()),
-        )
+        .with(log_filter)
         .with(tracing_subscriber::fmt::layer())
         .init();
 
     tracing::info!("Starting Anthropic Proxy v{}", env!("CARGO_PKG_VERSION"));
     tracing::info!("Port: {}", config.port);
     tracing::info!("Upstream URL: {}", config.base_url);
-    tracing::info!(
-        "Resolved upstream chat completions URL: {}",
-        config.chat_completions_url()
-    );
+    let upstream_urls = config.upstream_urls();
+    for url in &upstream_urls {
+        tracing::info!("Resolved upstream chat completions URL: {}", url);
+    }
+    if upstream_urls.len() > 1 {
+        tracing::info!(
+            "Adaptive routing across {} equivalent upstreams",
+            upstream_urls.len()
+        );
+    }
     if let Some(ref model) = config.reasoning_model {
         tracing::info!("Reasoning Model Override: {}", model);
     }
     if let Some(ref model) = config.completion_model {
         tracing::info!("Completion Model Override: {}", model);
     }
+    if let Some(ref model) = config.small_model {
+        tracing::info!(
+            "Small Model Override: {} (background requests, max_tokens <= {})",
+            model,
+            config.small_model_max_tokens
+        );
+    }
+    for (tier, model) in [
+        ("Haiku", &config.haiku_model),
+        ("Sonnet", &config.sonnet_model),
+        ("Opus", &config.opus_model),
+    ] {
+        if let Some(model) = model {
+            tracing::info!("{} Model Override: {}", tier, model);
+        }
+    }
+    if config.ensemble_enabled() {
+        tracing::info!("Ensemble Models: {}", config.ensemble_models.join(", "));
+        match config.ensemble_judge_model {
+            Some(ref model) => tracing::info!("Ensemble Judge: {}", model),
+            None => tracing::info!("Ensemble Judge: heuristics"),
+        }
+    }
+    if let Some(guardrails) = &config.output_guardrails {
+        tracing::info!("Output Guardrails: {}", guardrails);
+    }
+    if !config.text_only_models.is_empty() {
+        tracing::info!(
+            "Text-only Models: {} (images {})",
+            config.text_only_models.join(", "),
+            match config.image_caption_model {
+                Some(ref model) => format!("captioned by {}", model),
+                None => "omitted".to_string(),
+            }
+        );
+    }
+    if config.secret_scanner.is_some() {
+        tracing::info!("Secret Masking: enabled");
+    }
+    if !config.model_pricing.is_empty() {
+        tracing::info!(
+            "Spend Tracking: {} priced model(s)",
+            config.model_pricing.len()
+        );
+    }
+    if let Some(ref model) = config.budget_downgrade_model {
+        tracing::info!(
+            "Budget Downgrade: {} at {:.0}% of budget",
+            model,
+            config.budget_downgrade_threshold * 100.0
+        );
+    }
     if config.api_key.is_some() {
         tracing::info!("API Key: configured");
     } else {
@@ -123,34 +239,132 @@ async fn async_main(cli: Cli) -> anyhow::Result<()> {
         .pool_max_idle_per_host(10)
         .build()?;
 
+    let admin_state = Arc::new(admin::AdminState::new(
+        log_handle,
+        admin::LogLevel {
+            debug: config.debug,
+            verbose: config.verbose,
+        },
+    ));
     let config = Arc::new(config);
+    let accounting = Arc::new(accounting::Accounting::default());
+
+    if let Some(dir) = &config.usage_export_dir {
+        tracing::info!(
+            "Usage Export: {} every {}s",
+            dir.display(),
+            config.usage_export_interval_secs
+        );
+    }
+    if let Some(target) = &config.usage_export_s3 {
+        tracing::info!(
+            "Usage Export: {} every {}s",
+            target.url,
+            config.usage_export_interval_secs
+        );
+    }
+    if config.usage_export_dir.is_some() || config.usage_export_s3.is_some() {
+        export::spawn_usage_export(accounting.clone(), config.clone(), client.clone());
+    }
 
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
         .allow_headers(Any);
 
-    let app = Router::new()
-        .route("/v1/messages", post(proxy::proxy_handler))
-        .route("/health", axum::routing::get(health_handler))
-        .layer(Extension(config.clone()))
-        .layer(Extension(client))
-        .layer(TraceLayer::new_for_http())
-        .layer(cors);
+    let messages_route = match config.upstream_format {
+        config::UpstreamFormat::OpenAI | config::UpstreamFormat::Ollama => {
+            post(proxy::proxy_handler)
+        }
+        config::UpstreamFormat::Anthropic => {
+            tracing::info!("Passthrough: /v1/messages is forwarded to the upstream unchanged");
+            post(passthrough::messages_handler)
+        }
+    };
+    let mut app = Router::new()
+        .route("/v1/messages", messages_route)
+        .route("/v1/complete", post(legacy::complete_handler))
+        .route("/v1/models", axum::routing::get(model_list::list_models))
+        .route("/health", axum::routing::get(health::health_handler))
+        .route("/livez", axum::routing::get(health::livez_handler))
+        .route("/readyz", axum::routing::get(health::readyz_handler));
+    app = app.merge(batches::router());
+    if config.upstream_format == config::UpstreamFormat::Anthropic {
+        tracing::info!("Reverse Mode: serving /v1/chat/completions from the Anthropic upstream");
+        app = app.route(
+            "/v1/chat/completions",
+            post(reverse::chat_completions_handler),
+        );
+    }
+    if config.debug_endpoints {
+        tracing::warn!("Debug endpoints: enabled at /debug (do not expose publicly)");
+        app = app.route("/debug/transform", post(transform_handler));
+    }
+    app = app.layer(axum::middleware::from_fn(proxy::anthropic_headers));
+
+    let captions = Arc::new(vision::CaptionCache::default());
+    let batches = Arc::new(batches::BatchStore::from_config(&config)?);
+    let health = Arc::new(health::HealthState::default());
+    let upstreams = Arc::new(upstream::UpstreamRegistry::new(
+        upstream::UpstreamPool::new(upstream_urls)
+            .with_circuit_breaker(upstream::CircuitBreaker::from_config(&config))
+            .with_sticky_table(upstream::StickyTable::from_config(&config)),
+    ));
+    let with_state = |router: Router| {
+        router
+            .layer(Extension(config.clone()))
+            .layer(Extension(client.clone()))
+            .layer(Extension(accounting.clone()))
+            .layer(Extension(captions.clone()))
+            .layer(Extension(batches.clone()))
+            .layer(Extension(health.clone()))
+            .layer(Extension(upstreams.clone()))
+            .layer(Extension(admin_state.clone()))
+            .layer(TraceLayer::new_for_http())
+            .layer(cors.clone())
+    };
+
+    if config.admin_token.is_some() {
+        app = app.layer(axum::middleware::from_fn(admin::track_requests));
+        match config.admin_port {
+            Some(port) => {
+                let addr = format!("0.0.0.0:{}", port);
+                let listener = tokio::net::TcpListener::bind(&addr).await?;
+                tracing::info!("Admin API: enabled at /admin on {}", addr);
+                let admin_app = with_state(admin::router());
+                let http2 = config.http2;
+                tokio::spawn(async move {
+                    if let Err(err) = server::serve(listener, admin_app, http2).await {
+                        tracing::error!("Admin listener failed: {}", err);
+                    }
+                });
+            }
+            None => {
+                tracing::info!("Admin API: enabled at /admin");
+                app = app.merge(admin::router());
+            }
+        }
+    }
+    let app = with_state(app);
 
     let addr = format!("0.0.0.0:{}", config.port);
     let listener = tokio::net::TcpListener::bind(&addr).await?;
 
-    tracing::info!("Listening on {}", addr);
+    tracing::info!("Listening on {} (HTTP/2: {:?})", addr, config.http2);
     tracing::info!("Proxy ready to accept requests");
 
-    axum::serve(listener, app).await?;
+    server::serve(listener, app, config.http2).await?;
 
     Ok(())
 }
 
-async fn health_handler() -> &'static str {
-    "OK"
+/// Show the OpenAI request a Messages request translates to, without sending it
+async fn transform_handler(
+    Extension(config): Extension<Arc<Config>>,
+    payload: Result<Json<serde_json::Value>, JsonRejection>,
+) -> error::ProxyResult<Json<models::openai::OpenAIRequest>> {
+    let req = validation::messages_request(payload)?;
+    Ok(Json(transform::anthropic_to_openai(req, &config)?))
 }
 
 fn stop_daemon(pid_file: &std::path::Path) -> anyhow::Result<()> {
@@ -231,3 +445,12 @@ fn check_status(pid_file: &std::path::Path) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Probe a running proxy; Ok(false) when it is unhealthy
+fn check_health(url: &str, ready: bool, timeout: std::time::Duration) -> anyhow::Result<bool> {
+    let client = Client::builder().timeout(timeout).build()?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    Ok(runtime.block_on(health::check_running(&client, url, ready)))
+}
@@ -0,0 +1,186 @@
+use crate::config::{Config, UpstreamFormat};
+use crate::error::ProxyResult;
+use crate::export::format_timestamp;
+use crate::proxy::{self, ANTHROPIC_VERSION};
+use crate::upstream::UpstreamRegistry;
+use axum::{Extension, Json};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+const MODELS_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+struct UpstreamModels {
+    data: Vec<UpstreamModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpstreamModel {
+    id: String,
+    #[serde(default)]
+    created: Option<u64>,
+    #[serde(default, alias = "display_name")]
+    name: Option<String>,
+}
+
+/// GET /v1/models: the upstream model list in Anthropic's format, with the
+/// configured model overrides listed first
+pub async fn list_models(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<Client>,
+    Extension(registry): Extension<Arc<UpstreamRegistry>>,
+) -> ProxyResult<Json<Value>> {
+    let upstream = match fetch_upstream_models(&config, &client, &registry).await {
+        Ok(models) => models,
+        Err(err) if overrides(&config).next().is_some() => {
+            tracing::warn!(
+                "Listing upstream models failed, returning configured models only: {}",
+                err
+            );
+            Vec::new()
+        }
+        Err(err) => return Err(err),
+    };
+
+    Ok(Json(to_anthropic(&config, upstream)))
+}
+
+async fn fetch_upstream_models(
+    config: &Config,
+    client: &Client,
+    registry: &UpstreamRegistry,
+) -> ProxyResult<Vec<UpstreamModel>> {
+    let pool = registry.current();
+    let url = models_url(pool.url(pool.select()));
+    tracing::debug!("Listing models from {}", url);
+
+    let mut req_builder = client.get(&url).timeout(MODELS_TIMEOUT);
+    if let Some(api_key) = &config.api_key {
+        req_builder = match config.upstream_format {
+            UpstreamFormat::OpenAI | UpstreamFormat::Ollama => {
+                req_builder.header("Authorization", format!("Bearer {}", api_key))
+            }
+            UpstreamFormat::Anthropic => req_builder.header("x-api-key", api_key),
+        };
+    }
+    for (name, value) in &config.upstream_headers {
+        req_builder = req_builder.header(name, value);
+    }
+    if config.upstream_format == UpstreamFormat::Anthropic {
+        req_builder = req_builder.header("anthropic-version", ANTHROPIC_VERSION);
+    }
+
+    let response = req_builder.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let retry_after = proxy::retry_after(response.headers());
+        let error_text = response.text().await.unwrap_or_default();
+        let rule = config
+            .error_rules
+            .as_ref()
+            .and_then(|rules| rules.find(status.as_u16(), &error_text));
+        return Err(proxy::upstream_error(
+            status,
+            &url,
+            &error_text,
+            retry_after,
+            rule,
+        ));
+    }
+    let models: UpstreamModels = response.json().await?;
+    Ok(models.data)
+}
+
+/// The `/models` endpoint next to a resolved chat completions or messages URL.
+/// Ollama lists its models in OpenAI's format under `/v1`.
+fn models_url(endpoint_url: &str) -> String {
+    if let Some(base) = endpoint_url.strip_suffix("/api/chat") {
+        return format!("{}/v1/models", base);
+    }
+    let base = endpoint_url
+        .strip_suffix("/chat/completions")
+        .or_else(|| endpoint_url.strip_suffix("/messages"))
+        .unwrap_or(endpoint_url);
+    format!("{}/models", base)
+}
+
+fn overrides(config: &Config) -> impl Iterator<Item = &String> {
+    config
+        .reasoning_model
+        .iter()
+        .chain(config.completion_model.iter())
+        .chain(config.haiku_model.iter())
+        .chain(config.sonnet_model.iter())
+        .chain(config.opus_model.iter())
+        .chain(config.small_model.iter())
+}
+
+fn to_anthropic(config: &Config, upstream: Vec<UpstreamModel>) -> Value {
+    let mut seen = Vec::new();
+    let mut data = Vec::new();
+
+    let configured = overrides(config).map(|id| UpstreamModel {
+        id: id.clone(),
+        created: None,
+        name: None,
+    });
+    for model in configured.chain(upstream) {
+        if seen.contains(&model.id) {
+            continue;
+        }
+        seen.push(model.id.clone());
+        data.push(json!({
+            "type": "model",
+            "id": model.id,
+            "display_name": model.name.unwrap_or_else(|| model.id.clone()),
+            "created_at": format_timestamp(model.created.unwrap_or(0)),
+        }));
+    }
+
+    json!({
+        "data": data,
+        "has_more": false,
+        "first_id": seen.first(),
+        "last_id": seen.last(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{models_url, to_anthropic, UpstreamModel};
+    use crate::config::Config;
+
+    #[test]
+    fn upstream_models_are_listed_after_overrides() {
+        assert_eq!(
+            models_url("https://openrouter.ai/api/v1/chat/completions"),
+            "https://openrouter.ai/api/v1/models"
+        );
+
+        let mut config = Config::for_tests();
+        config.reasoning_model = Some("deepseek/deepseek-r1".to_string());
+        let upstream = vec![
+            UpstreamModel {
+                id: "openai/gpt-4o".to_string(),
+                created: Some(1_715_367_049),
+                name: Some("GPT-4o".to_string()),
+            },
+            UpstreamModel {
+                id: "deepseek/deepseek-r1".to_string(),
+                created: None,
+                name: None,
+            },
+        ];
+
+        let list = to_anthropic(&config, upstream);
+        let data = list["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0]["id"], "deepseek/deepseek-r1");
+        assert_eq!(data[1]["display_name"], "GPT-4o");
+        assert_eq!(data[1]["created_at"], "2024-05-10T18:50:49Z");
+        assert_eq!(list["last_id"], "openai/gpt-4o");
+    }
+}
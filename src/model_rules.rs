@@ -0,0 +1,249 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// Routing rules as written in the ROUTING_RULES_FILE JSON file, and as the
+/// admin API reads and writes them
+#[derive(Debug, Deserialize)]
+pub struct RuleFile {
+    pub rules: Vec<RuleSpec>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleSpec {
+    /// Glob over the requested model, `*` and `?` as wildcards
+    #[serde(default, rename = "match", skip_serializing_if = "Option::is_none")]
+    glob: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    match_regex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thinking: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    map_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upstream: Option<String>,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    params: Map<String, Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reject: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ensemble: Option<bool>,
+}
+
+/// What to do with requests for the models a rule matches
+#[derive(Debug, Clone)]
+pub struct ModelRule {
+    pattern: Regex,
+    /// Only match requests with (true) or without (false) extended thinking
+    thinking: Option<bool>,
+    pub map_model: Option<String>,
+    pub upstream: Option<String>,
+    /// Parameters added to the upstream request, replacing the client's
+    pub params: Map<String, Value>,
+    /// Refuse the request with this message
+    pub reject: Option<String>,
+    /// Answer non-streaming requests with ensemble mode
+    pub ensemble: bool,
+}
+
+/// Ordered rules over incoming model names; the first match decides
+#[derive(Debug, Clone, Default)]
+pub struct ModelRules {
+    rules: Vec<ModelRule>,
+    /// The rules as written, for the admin API to show and edit
+    specs: Vec<RuleSpec>,
+}
+
+impl ModelRules {
+    pub fn load(path: &Path) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read routing rules file {}", path.display()))?;
+        Self::parse(&raw).with_context(|| format!("Invalid routing rules file {}", path.display()))
+    }
+
+    fn parse(raw: &str) -> Result<Self> {
+        let file: RuleFile = serde_json::from_str(raw)?;
+        Self::new(file.rules)
+    }
+
+    pub fn new(specs: Vec<RuleSpec>) -> Result<Self> {
+        let rules = specs
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(i, spec)| {
+                let pattern = match (spec.glob, spec.match_regex) {
+                    (Some(glob), None) => glob_regex(&glob),
+                    (None, Some(regex)) => regex,
+                    _ => bail!("rule {} must set exactly one of match or match_regex", i),
+                };
+                let pattern = Regex::new(&pattern)
+                    .with_context(|| format!("rule {}: invalid pattern {}", i, pattern))?;
+                let ensemble = spec.ensemble.unwrap_or(false);
+                let acts = spec.map_model.is_some()
+                    || spec.upstream.is_some()
+                    || !spec.params.is_empty()
+                    || ensemble;
+                match (acts, spec.reject.is_some()) {
+                    (false, false) => {
+                        bail!(
+                            "rule {} must set map_model, upstream, params, ensemble or reject",
+                            i
+                        )
+                    }
+                    (true, true) => bail!("rule {} can't both reject and route requests", i),
+                    _ => {}
+                }
+                Ok(ModelRule {
+                    pattern,
+                    thinking: spec.thinking,
+                    map_model: spec.map_model,
+                    upstream: spec.upstream,
+                    params: spec.params,
+                    reject: spec.reject,
+                    ensemble,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules, specs })
+    }
+
+    pub fn specs(&self) -> &[RuleSpec] {
+        &self.specs
+    }
+
+    /// The first rule matching a requested model and its thinking setting
+    pub fn find(&self, model: &str, thinking: bool) -> Option<&ModelRule> {
+        self.rules.iter().find(|rule| {
+            rule.pattern.is_match(model) && rule.thinking.is_none_or(|t| t == thinking)
+        })
+    }
+
+    /// Upstream names the rules route to
+    pub fn upstreams(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .filter_map(|rule| rule.upstream.as_deref())
+    }
+}
+
+/// The live routing rules. Requests take a snapshot when they start, and
+/// the admin API swaps in new rules atomically.
+#[derive(Debug, Default)]
+pub struct RuleRegistry {
+    current: RwLock<Arc<ModelRules>>,
+}
+
+impl RuleRegistry {
+    pub fn new(rules: ModelRules) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(rules)),
+        }
+    }
+
+    pub fn current(&self) -> Arc<ModelRules> {
+        self.current
+            .read()
+            .expect("routing rules lock poisoned")
+            .clone()
+    }
+
+    /// Compute new rules from the current ones and swap them in; concurrent
+    /// updates are applied one after another
+    pub fn update<E>(
+        &self,
+        change: impl FnOnce(Vec<RuleSpec>) -> Result<ModelRules, E>,
+    ) -> Result<Arc<ModelRules>, E> {
+        let mut current = self.current.write().expect("routing rules lock poisoned");
+        *current = Arc::new(change(current.specs.clone())?);
+        Ok(current.clone())
+    }
+}
+
+/// An anchored regex for a glob, where `*` matches any run of characters
+/// and `?` any single one
+fn glob_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ModelRules, RuleRegistry};
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = ModelRules::parse(
+            r#"{"rules": [
+                {"match": "claude-2*", "reject": "Claude 2 is retired"},
+                {"match": "claude-*-4-?", "thinking": true, "map_model": "o3", "params": {"reasoning_effort": "high"}},
+                {"match_regex": "^claude-(opus|sonnet)", "map_model": "gpt-4.1", "upstream": "openai"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let rule = rules.find("claude-2.1", false).unwrap();
+        assert_eq!(rule.reject.as_deref(), Some("Claude 2 is retired"));
+        let rule = rules.find("claude-opus-4-1", true).unwrap();
+        assert_eq!(rule.map_model.as_deref(), Some("o3"));
+        assert_eq!(rule.params["reasoning_effort"], "high");
+        let rule = rules.find("claude-opus-4-1", false).unwrap();
+        assert_eq!(rule.upstream.as_deref(), Some("openai"));
+        assert!(rules.find("claude-haiku-4-5", false).is_none());
+        assert!(rules.find("xclaude-2", false).is_none());
+        assert_eq!(rules.upstreams().collect::<Vec<_>>(), ["openai"]);
+
+        assert!(ModelRules::parse(r#"{"rules": [{"match": "x"}]}"#).is_err());
+        assert!(ModelRules::parse(r#"{"rules": [{"map_model": "x"}]}"#).is_err());
+
+        let rules =
+            ModelRules::parse(r#"{"rules": [{"match": "claude-opus-*", "ensemble": true}]}"#)
+                .unwrap();
+        assert!(rules.find("claude-opus-4-1", false).unwrap().ensemble);
+    }
+
+    #[test]
+    fn rule_updates_swap_in_whole_rule_sets() {
+        let registry = RuleRegistry::new(
+            ModelRules::parse(r#"{"rules": [{"match": "claude-*", "map_model": "gpt-4.1"}]}"#)
+                .unwrap(),
+        );
+        let before = registry.current();
+
+        let failed = registry.update(|mut specs| {
+            specs.push(serde_json::from_str(r#"{"match": "x"}"#).unwrap());
+            ModelRules::new(specs)
+        });
+        assert!(failed.is_err());
+        assert_eq!(registry.current().specs().len(), 1);
+
+        registry
+            .update(|mut specs| {
+                specs.insert(
+                    0,
+                    serde_json::from_str(r#"{"match": "claude-2*", "reject": "retired"}"#).unwrap(),
+                );
+                ModelRules::new(specs)
+            })
+            .unwrap();
+        let rule = registry
+            .current()
+            .find("claude-2.1", false)
+            .unwrap()
+            .clone();
+        assert_eq!(rule.reject.as_deref(), Some("retired"));
+        // Snapshots taken earlier keep the rules they started with
+        assert!(before.find("claude-2.1", false).unwrap().reject.is_none());
+    }
+}
@@ -1,4 +1,5 @@
-use serde::{Deserialize, Serialize};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 
 /// OpenAI API request structure
@@ -12,7 +13,14 @@ pub struct OpenAIRequest {
     pub temperature: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_p: Option<f32>,
+    /// Not part of OpenAI's API, but honored by many compatible servers
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "string_or_list"
+    )]
     pub stop: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stream: Option<bool>,
@@ -20,6 +28,25 @@ pub struct OpenAIRequest {
     pub tools: Option<Vec<Tool>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_choice: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+    /// OpenRouter-style reasoning controls, e.g. `{"max_tokens": 4096}`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<Value>,
+    /// OpenAI reasoning models' `low`, `medium` or `high`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<String>,
+    /// Structured output contract, e.g. `{"type": "json_schema", ...}`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<Value>,
+    /// Provider-specific parameters forwarded from the client's request
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamOptions {
+    pub include_usage: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,6 +59,9 @@ pub struct Message {
     pub tool_call_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    /// Earlier reasoning of an assistant turn, for servers that accept it back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,7 +75,12 @@ pub enum MessageContent {
 #[serde(tag = "type")]
 pub enum ContentPart {
     #[serde(rename = "text")]
-    Text { text: String },
+    Text {
+        text: String,
+        /// Anthropic-style prompt caching breakpoint, understood by OpenRouter
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<Value>,
+    },
     #[serde(rename = "image_url")]
     ImageUrl { image_url: ImageUrl },
 }
@@ -82,6 +117,9 @@ pub struct Function {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
     pub parameters: Value,
+    /// OpenAI strict mode: arguments are guaranteed to match `parameters`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
 }
 
 /// OpenAI API response
@@ -95,7 +133,10 @@ pub struct OpenAIResponse {
     pub created: Option<u64>,
     #[serde(default)]
     pub model: Option<String>,
+    /// Flaky backends sometimes answer with no choices, or null ones
+    #[serde(default, deserialize_with = "null_as_default")]
     pub choices: Vec<Choice>,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub usage: Usage,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub system_fingerprint: Option<String>,
@@ -103,26 +144,98 @@ pub struct OpenAIResponse {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Choice {
+    #[serde(default)]
     pub index: usize,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub message: ChoiceMessage,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub finish_reason: Option<String>,
+    /// vLLM's reason for a `stop` finish: the matched stop string, or the
+    /// id of a stop token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_reason: Option<Value>,
+    /// Azure's per-category content filter verdicts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_filter_results: Option<Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Choice {
+    /// The stop sequence the upstream reports it stopped at
+    pub fn stop_sequence(&self) -> Option<&str> {
+        stop_sequence(self.finish_reason.as_deref(), self.stop_reason.as_ref())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ChoiceMessage {
+    #[serde(default)]
     pub role: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        deserialize_with = "content_text",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub content: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Vec<ToolCall>>,
+    /// Deprecated single function call, still sent by some older servers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<FunctionCall>,
+    /// OpenRouter's reasoning text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
+    /// DeepSeek's and vLLM's reasoning text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
+    /// OpenRouter's structured reasoning
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_details: Option<Vec<Value>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl ChoiceMessage {
+    /// The model's reasoning, whichever field the backend put it in
+    pub fn reasoning_text(&self) -> Option<String> {
+        reasoning_text(
+            &self.reasoning,
+            &self.reasoning_content,
+            &self.reasoning_details,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Usage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+impl Usage {
+    /// Fold in the counts from a later chunk. Counts are totals so far, so
+    /// the larger of each wins.
+    pub fn merge(&mut self, later: &Usage) {
+        self.prompt_tokens = self.prompt_tokens.max(later.prompt_tokens);
+        self.completion_tokens = self.completion_tokens.max(later.completion_tokens);
+        self.total_tokens = self.total_tokens.max(later.total_tokens);
+        if later.prompt_tokens_details.is_some() {
+            self.prompt_tokens_details = later.prompt_tokens_details.clone();
+        }
+    }
+
+    /// Prompt tokens served from the provider's prompt cache
+    pub fn cached_tokens(&self) -> u32 {
+        self.prompt_tokens_details
+            .as_ref()
+            .map_or(0, |details| details.cached_tokens)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptTokensDetails {
+    #[serde(default)]
+    pub cached_tokens: u32,
 }
 
 /// Streaming chunk structure
@@ -147,18 +260,137 @@ pub struct StreamChoice {
     pub delta: Delta,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub finish_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_reason: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_filter_results: Option<Value>,
+}
+
+impl StreamChoice {
+    /// The stop sequence the upstream reports it stopped at
+    pub fn stop_sequence(&self) -> Option<&str> {
+        stop_sequence(self.finish_reason.as_deref(), self.stop_reason.as_ref())
+    }
+}
+
+fn stop_sequence<'a>(
+    finish_reason: Option<&str>,
+    stop_reason: Option<&'a Value>,
+) -> Option<&'a str> {
+    stop_reason
+        .and_then(Value::as_str)
+        .filter(|stop| finish_reason == Some("stop") && !stop.is_empty())
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Delta {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub role: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        deserialize_with = "content_text",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub content: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Vec<DeltaToolCall>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<DeltaFunctionCall>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub reasoning: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_details: Option<Vec<Value>>,
+}
+
+impl Delta {
+    /// The tool calls in this delta, with a deprecated `function_call` read as
+    /// the only call; `id` names the call when its name arrives
+    pub fn tool_calls_or_function_call(
+        &self,
+        id: impl FnOnce() -> String,
+    ) -> Option<Vec<DeltaToolCall>> {
+        if self.tool_calls.is_some() {
+            return self.tool_calls.clone();
+        }
+        self.function_call.as_ref().map(|function| {
+            vec![DeltaToolCall {
+                index: 0,
+                id: function.name.as_ref().map(|_| id()),
+                call_type: Some("function".to_string()),
+                function: Some(function.clone()),
+            }]
+        })
+    }
+
+    /// The reasoning in this delta, whichever field the backend put it in
+    pub fn reasoning_text(&self) -> Option<String> {
+        reasoning_text(
+            &self.reasoning,
+            &self.reasoning_content,
+            &self.reasoning_details,
+        )
+    }
+}
+
+/// Normalize the reasoning variants backends use. OpenRouter sends the same
+/// text in both `reasoning` and `reasoning_details`, so the first field
+/// present wins; `reasoning_details` contributes its text and summary
+/// entries, while encrypted entries carry nothing readable.
+fn reasoning_text(
+    reasoning: &Option<String>,
+    reasoning_content: &Option<String>,
+    reasoning_details: &Option<Vec<Value>>,
+) -> Option<String> {
+    let text = match (reasoning, reasoning_content, reasoning_details) {
+        (Some(text), _, _) | (None, Some(text), _) => text.clone(),
+        (None, None, Some(details)) => details
+            .iter()
+            .filter_map(|detail| match detail["type"].as_str() {
+                Some("reasoning.text") => detail["text"].as_str(),
+                Some("reasoning.summary") => detail["summary"].as_str(),
+                _ => None,
+            })
+            .collect(),
+        (None, None, None) => return None,
+    };
+    (!text.is_empty()).then_some(text)
+}
+
+/// Read a field sent as null the same as a missing one
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Read response `content` given either as a string or, as some servers
+/// send it, as an array of parts whose text is joined
+fn content_text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    match Option::<Value>::deserialize(deserializer)? {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(text)) => Ok(Some(text)),
+        Some(Value::Array(parts)) => Ok(Some(
+            parts
+                .iter()
+                .filter_map(|part| match part {
+                    Value::String(text) => Some(text.as_str()),
+                    // Parts of other types, such as images, have no text to keep
+                    _ if matches!(part["type"].as_str(), None | Some("text" | "output_text")) => {
+                        part["text"].as_str()
+                    }
+                    _ => None,
+                })
+                .collect(),
+        )),
+        Some(other) => Err(D::Error::custom(format!(
+            "content must be a string or an array of parts, got {}",
+            other
+        ))),
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -180,3 +412,20 @@ pub struct DeltaFunctionCall {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub arguments: Option<String>,
 }
+
+/// Read `stop` given either as a single string or as a list of them
+fn string_or_list<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        None => None,
+        Some(OneOrMany::One(stop)) => Some(vec![stop]),
+        Some(OneOrMany::Many(stops)) => Some(stops),
+    })
+}
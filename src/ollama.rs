@@ -0,0 +1,540 @@
+use crate::accounting::Accounting;
+use crate::coalesce;
+use crate::config::Config;
+use crate::error::ProxyResult;
+use crate::models::{anthropic, openai};
+use crate::proxy::{self, RequestContext, StreamFailure};
+use crate::tags::RequestTags;
+use crate::transform::{self, TOOL_ERROR_PREFIX};
+use crate::upstream::UpstreamRegistry;
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use reqwest::Client;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Send a request to Ollama's native chat API, translated straight from the
+/// Messages API, and answer in Anthropic's format
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handle(
+    config: Arc<Config>,
+    client: Client,
+    accounting: Arc<Accounting>,
+    upstreams: &UpstreamRegistry,
+    headers: &HeaderMap,
+    req: anthropic::AnthropicRequest,
+    model: Option<&str>,
+    params: Option<&Map<String, Value>>,
+    deadline: Option<Instant>,
+) -> ProxyResult<Response> {
+    let started = Instant::now();
+    let streaming = req.stream.unwrap_or(false);
+    let model = model.map_or_else(|| transform::target_model(&req, &config), String::from);
+    let mut body = to_ollama(&req, &model, &config);
+    for (name, value) in params.into_iter().flatten() {
+        body[name] = value.clone();
+    }
+    if tracing::enabled!(tracing::Level::TRACE) {
+        tracing::trace!(
+            "Ollama request: {}",
+            serde_json::to_string_pretty(&body).unwrap_or_default()
+        );
+    }
+
+    let ctx = RequestContext {
+        deadline,
+        tags: RequestTags::from_request(&config, headers, req.metadata.as_ref()),
+        conversation: config
+            .upstream_affinity
+            .then(|| proxy::conversation_key(&config, headers, &req)),
+        reported_model: config.report_requested_model.then(|| req.model.clone()),
+        ..RequestContext::for_request(accounting, upstreams, headers)
+    };
+    let response = proxy::post_upstream(&config, &client, &ctx, &body, &model, streaming).await?;
+
+    if streaming {
+        let events = proxy::create_sse_stream(
+            openai_chunks(response.bytes_stream()),
+            model.clone(),
+            config.clone(),
+            Arc::new(ctx),
+        );
+        let body = Body::from_stream(coalesce::pace(Box::pin(events), &config, started));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Content-Type",
+            HeaderValue::from_static("text/event-stream"),
+        );
+        headers.insert("Cache-Control", HeaderValue::from_static("no-cache"));
+        headers.insert("Connection", HeaderValue::from_static("keep-alive"));
+        if let Ok(model) = HeaderValue::from_str(&model) {
+            headers.insert("x-proxy-upstream-model", model);
+        }
+        return Ok((headers, body).into_response());
+    }
+
+    let resp: Value = response.json().await?;
+    let openai_resp = to_openai_response(&resp)?;
+    let usage = ctx.record_usage(&config, &model, &openai_resp.usage);
+    let mut anthropic_resp = transform::openai_to_anthropic(openai_resp, &model)?;
+    proxy::apply_thinking_policy(&config, &model, &mut anthropic_resp);
+    proxy::postprocess_response(&config, &ctx, &mut anthropic_resp);
+    Ok((usage.headers(), Json(anthropic_resp)).into_response())
+}
+
+/// Translate a Messages API request into an Ollama `/api/chat` request.
+/// Images stay base64, tool arguments stay objects, and sampling settings go
+/// into `options` along with OLLAMA_OPTIONS.
+pub fn to_ollama(req: &anthropic::AnthropicRequest, model: &str, config: &Config) -> Value {
+    let mut messages = Vec::new();
+    match &req.system {
+        Some(anthropic::SystemPrompt::Single(text)) => {
+            messages.push(json!({"role": "system", "content": text}));
+        }
+        Some(anthropic::SystemPrompt::Multiple(parts)) => {
+            messages.extend(
+                parts
+                    .iter()
+                    .map(|part| json!({"role": "system", "content": part.text})),
+            );
+        }
+        None => {}
+    }
+    // Ollama names the tool a result belongs to rather than the call
+    let mut tool_names = HashMap::new();
+    for message in &req.messages {
+        convert_message(message, &mut tool_names, &mut messages);
+    }
+
+    let mut options = config.ollama_options.clone();
+    options.insert("num_predict".to_string(), json!(req.max_tokens));
+    if let Some(temperature) = req.temperature {
+        options.insert("temperature".to_string(), json!(temperature));
+    }
+    if let Some(top_p) = req.top_p {
+        options.insert("top_p".to_string(), json!(top_p));
+    }
+    if let Some(top_k) = req.top_k {
+        options.insert("top_k".to_string(), json!(top_k));
+    }
+    if let Some(stop) = req.stop_sequences.as_ref().filter(|stop| !stop.is_empty()) {
+        options.insert("stop".to_string(), json!(stop));
+    }
+
+    let mut body = json!({
+        "model": model,
+        "messages": messages,
+        "stream": req.stream.unwrap_or(false),
+        "options": options,
+    });
+    let tools: Vec<Value> = req
+        .tools
+        .iter()
+        .flatten()
+        .filter(|tool| tool.tool_type.as_deref() != Some("BatchTool"))
+        .map(|tool| {
+            json!({
+                "type": "function",
+                "function": {
+                    "name": tool.name,
+                    "description": tool.description,
+                    "parameters": tool.input_schema,
+                }
+            })
+        })
+        .collect();
+    if !tools.is_empty() {
+        body["tools"] = json!(tools);
+    }
+    if transform::thinking_enabled(req) {
+        body["think"] = json!(true);
+    }
+    if let Some(keep_alive) = &config.ollama_keep_alive {
+        body["keep_alive"] = keep_alive.clone();
+    }
+    body
+}
+
+/// Append the Ollama messages for one Anthropic message: tool results as
+/// `tool` messages first, then the rest of the turn
+fn convert_message(
+    message: &anthropic::Message,
+    tool_names: &mut HashMap<String, String>,
+    out: &mut Vec<Value>,
+) {
+    let blocks = match &message.content {
+        anthropic::MessageContent::Text(text) => {
+            out.push(json!({"role": message.role, "content": text}));
+            return;
+        }
+        anthropic::MessageContent::Blocks(blocks) => blocks,
+    };
+
+    let mut text = Vec::new();
+    let mut images = Vec::new();
+    let mut thinking = Vec::new();
+    let mut tool_calls = Vec::new();
+    for block in blocks {
+        match block {
+            anthropic::ContentBlock::Text { text: part, .. } => text.push(part.as_str()),
+            anthropic::ContentBlock::Image { source } => images.extend(image_data(source)),
+            anthropic::ContentBlock::Thinking { thinking: part } => thinking.push(part.as_str()),
+            anthropic::ContentBlock::ToolUse { id, name, input } => {
+                tool_names.insert(id.clone(), name.clone());
+                tool_calls.push(json!({"function": {"name": name, "arguments": input}}));
+            }
+            anthropic::ContentBlock::ToolResult {
+                tool_use_id,
+                content,
+                is_error,
+            } => {
+                let (mut content, result_images) = match content {
+                    anthropic::ToolResultContent::Text(text) => (text.clone(), Vec::new()),
+                    anthropic::ToolResultContent::Blocks(blocks) => {
+                        let mut texts = Vec::new();
+                        let mut images = Vec::new();
+                        for block in blocks {
+                            match block {
+                                anthropic::ContentBlock::Text { text, .. } => {
+                                    texts.push(text.as_str())
+                                }
+                                anthropic::ContentBlock::Image { source } => {
+                                    images.extend(image_data(source))
+                                }
+                                _ => {}
+                            }
+                        }
+                        (texts.join("\n"), images)
+                    }
+                };
+                if *is_error == Some(true) {
+                    content.insert_str(0, TOOL_ERROR_PREFIX);
+                }
+                let mut result = json!({"role": "tool", "content": content});
+                if let Some(name) = tool_names.get(tool_use_id) {
+                    result["tool_name"] = json!(name);
+                }
+                if !result_images.is_empty() {
+                    result["images"] = json!(result_images);
+                }
+                out.push(result);
+            }
+        }
+    }
+
+    if text.is_empty() && images.is_empty() && thinking.is_empty() && tool_calls.is_empty() {
+        return;
+    }
+    let mut converted = json!({"role": message.role, "content": text.join("\n")});
+    if !images.is_empty() {
+        converted["images"] = json!(images);
+    }
+    if !thinking.is_empty() {
+        converted["thinking"] = json!(thinking.join("\n"));
+    }
+    if !tool_calls.is_empty() {
+        converted["tool_calls"] = json!(tool_calls);
+    }
+    out.push(converted);
+}
+
+/// Ollama takes images as bare base64 and can't fetch URLs
+fn image_data(source: &anthropic::ImageSource) -> Option<&str> {
+    match source {
+        anthropic::ImageSource::Base64 { data, .. } => Some(data),
+        anthropic::ImageSource::Url { url } => {
+            tracing::debug!("Dropping image URL {} for an Ollama upstream", url);
+            None
+        }
+    }
+}
+
+/// A complete Ollama chat response as the chat completion it amounts to
+fn to_openai_response(resp: &Value) -> ProxyResult<openai::OpenAIResponse> {
+    let mut tool_calls = 0;
+    let mut message = openai_message(&resp["message"], &mut tool_calls);
+    message["role"] = json!("assistant");
+    Ok(serde_json::from_value(json!({
+        "id": proxy::proxy_id("msg"),
+        "object": "chat.completion",
+        "model": resp["model"],
+        "choices": [{
+            "index": 0,
+            "message": message,
+            "finish_reason": finish_reason(resp, tool_calls > 0),
+        }],
+        "usage": usage(resp),
+    }))?)
+}
+
+/// The content, thinking and tool calls of an Ollama message in OpenAI's
+/// field names; `tool_calls` counts the calls seen so far in the response
+fn openai_message(message: &Value, tool_calls: &mut usize) -> Value {
+    let mut converted = json!({});
+    if let Some(content) = message["content"].as_str().filter(|c| !c.is_empty()) {
+        converted["content"] = json!(content);
+    }
+    if let Some(thinking) = message["thinking"].as_str().filter(|t| !t.is_empty()) {
+        converted["reasoning"] = json!(thinking);
+    }
+    if let Some(calls) = message["tool_calls"].as_array().filter(|c| !c.is_empty()) {
+        let calls: Vec<Value> = calls
+            .iter()
+            .map(|call| {
+                let index = *tool_calls;
+                *tool_calls += 1;
+                let id = call["id"]
+                    .as_str()
+                    .map_or_else(|| proxy::proxy_id("toolu"), String::from);
+                // Arguments arrive as an object, and whole
+                let arguments = match &call["function"]["arguments"] {
+                    Value::String(raw) => raw.clone(),
+                    Value::Null => "{}".to_string(),
+                    arguments => arguments.to_string(),
+                };
+                json!({
+                    "index": index,
+                    "id": id,
+                    "type": "function",
+                    "function": {"name": call["function"]["name"], "arguments": arguments},
+                })
+            })
+            .collect();
+        converted["tool_calls"] = json!(calls);
+    }
+    converted
+}
+
+fn finish_reason(resp: &Value, called_tools: bool) -> &'static str {
+    if called_tools {
+        "tool_calls"
+    } else if resp["done_reason"] == "length" {
+        "length"
+    } else {
+        "stop"
+    }
+}
+
+fn usage(resp: &Value) -> Value {
+    let prompt_tokens = resp["prompt_eval_count"].as_u64().unwrap_or(0);
+    let completion_tokens = resp["eval_count"].as_u64().unwrap_or(0);
+    json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens,
+    })
+}
+
+/// Ollama's newline-delimited JSON stream as the OpenAI stream chunks the
+/// translator reads, failing where Ollama reports an error
+fn openai_chunks(
+    stream: impl Stream<Item = Result<Bytes, reqwest::Error>> + Send + 'static,
+) -> impl Stream<Item = Result<Bytes, StreamFailure>> + Send {
+    let mut adapter = ChunkAdapter::default();
+    stream.flat_map(move |bytes| {
+        let items = match bytes {
+            Ok(bytes) => adapter.push(&bytes),
+            Err(err) => vec![Err(err.into())],
+        };
+        futures::stream::iter(items)
+    })
+}
+
+#[derive(Debug, Default)]
+struct ChunkAdapter {
+    buffer: BytesMut,
+    tool_calls: usize,
+}
+
+impl ChunkAdapter {
+    /// SSE events for the complete lines received so far, and the error of
+    /// a line reporting one after the events before it
+    fn push(&mut self, bytes: &[u8]) -> Vec<Result<Bytes, StreamFailure>> {
+        self.buffer.extend_from_slice(bytes);
+        let mut items = Vec::new();
+        let mut events = BytesMut::new();
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line = self.buffer.split_to(end + 1);
+            let Ok(line) = serde_json::from_slice::<Value>(&line) else {
+                continue;
+            };
+            if let Some(error) = line["error"].as_str() {
+                tracing::error!("Ollama stream error: {}", error);
+                if !events.is_empty() {
+                    items.push(Ok(events.split().freeze()));
+                }
+                items.push(Err(StreamFailure {
+                    error_type: "api_error",
+                    message: error.to_string(),
+                }));
+                continue;
+            }
+            let mut choice = json!({
+                "index": 0,
+                "delta": openai_message(&line["message"], &mut self.tool_calls),
+            });
+            let mut chunk = json!({"model": line["model"]});
+            let done = line["done"] == true;
+            if done {
+                choice["finish_reason"] = json!(finish_reason(&line, self.tool_calls > 0));
+                chunk["usage"] = usage(&line);
+            }
+            chunk["choices"] = json!([choice]);
+            events.extend_from_slice(format!("data: {}\n\n", chunk).as_bytes());
+            if done {
+                events.extend_from_slice(b"data: [DONE]\n\n");
+            }
+        }
+        if !events.is_empty() {
+            items.push(Ok(events.freeze()));
+        }
+        items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_ollama, to_openai_response, ChunkAdapter};
+    use crate::config::Config;
+    use crate::models::anthropic::AnthropicRequest;
+    use serde_json::json;
+
+    #[test]
+    fn requests_use_native_ollama_fields() {
+        let mut config = Config::for_tests();
+        config
+            .ollama_options
+            .insert("num_ctx".to_string(), json!(32768));
+        config.ollama_keep_alive = Some(json!("30m"));
+        let req: AnthropicRequest = serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 1024,
+            "system": "Be brief.",
+            "top_k": 40,
+            "thinking": {"type": "enabled", "budget_tokens": 2000},
+            "tools": [{"name": "read_file", "input_schema": {"type": "object"}}],
+            "messages": [
+                {"role": "user", "content": [
+                    {"type": "text", "text": "What's in the picture?"},
+                    {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0K"}}
+                ]},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "toolu_1", "name": "read_file", "input": {"path": "a.txt"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": "missing", "is_error": true},
+                    {"type": "text", "text": "Go on"}
+                ]}
+            ]
+        }))
+        .unwrap();
+
+        let body = to_ollama(&req, "qwen3:32b", &config);
+        assert_eq!(body["model"], "qwen3:32b");
+        assert_eq!(body["think"], true);
+        assert_eq!(body["keep_alive"], "30m");
+        assert_eq!(
+            body["options"],
+            json!({"num_ctx": 32768, "num_predict": 1024, "top_k": 40})
+        );
+        assert_eq!(body["tools"][0]["function"]["name"], "read_file");
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(
+            messages[0],
+            json!({"role": "system", "content": "Be brief."})
+        );
+        assert_eq!(messages[1]["images"], json!(["iVBORw0K"]));
+        assert_eq!(
+            messages[2]["tool_calls"][0]["function"]["arguments"],
+            json!({"path": "a.txt"})
+        );
+        assert_eq!(messages[3]["role"], "tool");
+        assert_eq!(messages[3]["tool_name"], "read_file");
+        assert_eq!(messages[3]["content"], "[Tool call failed]\nmissing");
+        assert_eq!(messages[4], json!({"role": "user", "content": "Go on"}));
+    }
+
+    #[test]
+    fn responses_read_as_chat_completions() {
+        let resp = to_openai_response(&json!({
+            "model": "qwen3:32b",
+            "message": {
+                "role": "assistant",
+                "content": "",
+                "thinking": "Need the file",
+                "tool_calls": [{"function": {"name": "read_file", "arguments": {"path": "a.txt"}}}]
+            },
+            "done": true,
+            "done_reason": "stop",
+            "prompt_eval_count": 12,
+            "eval_count": 7
+        }))
+        .unwrap();
+        let choice = &resp.choices[0];
+        assert_eq!(choice.finish_reason.as_deref(), Some("tool_calls"));
+        assert_eq!(choice.message.reasoning.as_deref(), Some("Need the file"));
+        let call = &choice.message.tool_calls.as_ref().unwrap()[0];
+        assert_eq!(call.function.arguments, r#"{"path":"a.txt"}"#);
+        assert_eq!(
+            (resp.usage.prompt_tokens, resp.usage.completion_tokens),
+            (12, 7)
+        );
+
+        // Lines may be split anywhere across network reads
+        let mut adapter = ChunkAdapter::default();
+        let first =
+            adapter.push(br#"{"model":"qwen3:32b","message":{"role":"assistant","content":"Hel"#);
+        assert!(first.is_empty());
+        let rest = adapter.push(
+            concat!(
+                r#"lo"},"done":false}"#,
+                "\n",
+                r#"{"model":"qwen3:32b","message":{"role":"assistant","content":""},"done":true,"done_reason":"length","prompt_eval_count":3,"eval_count":2}"#,
+                "\n"
+            )
+            .as_bytes(),
+        );
+        let [Ok(rest)] = &rest[..] else {
+            panic!("expected one batch of events");
+        };
+        let events = String::from_utf8(rest.to_vec()).unwrap();
+        let data: Vec<&str> = events
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .collect();
+        assert_eq!(data.len(), 3);
+        let chunk: serde_json::Value = serde_json::from_str(data[0]).unwrap();
+        assert_eq!(chunk["choices"][0]["delta"]["content"], "Hello");
+        let last: serde_json::Value = serde_json::from_str(data[1]).unwrap();
+        assert_eq!(last["choices"][0]["finish_reason"], "length");
+        assert_eq!(last["usage"]["total_tokens"], 5);
+        assert_eq!(data[2], "[DONE]");
+
+        // Events before an error line still go out, then the stream fails
+        let items = adapter.push(
+            concat!(
+                r#"{"model":"qwen3:32b","message":{"role":"assistant","content":"Hi"},"done":false}"#,
+                "\n",
+                r#"{"error":"model runner has unexpectedly stopped"}"#,
+                "\n"
+            )
+            .as_bytes(),
+        );
+        let [Ok(events), Err(failure)] = &items[..] else {
+            panic!("expected events followed by an error");
+        };
+        assert!(String::from_utf8(events.to_vec())
+            .unwrap()
+            .contains(r#""content":"Hi""#));
+        assert_eq!(failure.error_type, "api_error");
+        assert_eq!(failure.message, "model runner has unexpectedly stopped");
+    }
+}
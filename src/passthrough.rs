@@ -0,0 +1,268 @@
+use crate::accounting::Accounting;
+use crate::config::Config;
+use crate::error::{ProxyError, ProxyResult};
+use crate::models::{anthropic, openai};
+use crate::proxy::{self, RequestContext, ANTHROPIC_VERSION};
+use crate::tags::RequestTags;
+use crate::upstream::UpstreamRegistry;
+use crate::vertex;
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::Response,
+    Extension,
+};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use reqwest::Client;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Headers that describe one hop of the connection rather than the request
+const HOP_HEADERS: &[HeaderName] = &[
+    header::HOST,
+    header::CONNECTION,
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+    header::ACCEPT_ENCODING,
+    header::UPGRADE,
+];
+
+/// POST /v1/messages with UPSTREAM_FORMAT=anthropic: forward the request
+/// verbatim and stream the upstream's answer back unchanged
+pub async fn messages_handler(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<Client>,
+    Extension(accounting): Extension<Arc<Accounting>>,
+    Extension(upstreams): Extension<Arc<UpstreamRegistry>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> ProxyResult<Response> {
+    let deadline = proxy::request_deadline(&headers)?.map(|timeout| Instant::now() + timeout);
+    forward(
+        config, client, accounting, &upstreams, headers, body, deadline,
+    )
+    .await
+}
+
+/// Forward a Messages API body to the Anthropic upstream without
+/// translation, giving up at the caller's `deadline`
+pub(crate) async fn forward(
+    config: Arc<Config>,
+    client: Client,
+    accounting: Arc<Accounting>,
+    upstreams: &UpstreamRegistry,
+    headers: HeaderMap,
+    body: Bytes,
+    deadline: Option<Instant>,
+) -> ProxyResult<Response> {
+    let request: Value = serde_json::from_slice(&body)?;
+    let model = request["model"].as_str().unwrap_or_default().to_string();
+    tracing::debug!("Passing request for model {} through unchanged", model);
+
+    let conversation = config
+        .upstream_affinity
+        .then(|| serde_json::from_value::<anthropic::AnthropicRequest>(request.clone()).ok())
+        .flatten()
+        .map(|req| proxy::conversation_key(&config, &headers, &req));
+    let ctx = RequestContext {
+        deadline,
+        tags: RequestTags::from_request(&config, &headers, request.get("metadata")),
+        conversation,
+        ..RequestContext::for_request(accounting, upstreams, &headers)
+    };
+
+    let target = ctx
+        .upstreams
+        .admit(ctx.upstreams.select_for(ctx.conversation))?;
+    let mut url = ctx.upstreams.url(target).to_string();
+    let mut body = body;
+    let mut outbound = headers;
+    for name in HOP_HEADERS {
+        outbound.remove(name);
+    }
+    if let Some(vertex) = &config.vertex {
+        // Claude on Vertex takes the model in the URL and a Google access token
+        let mut request = request;
+        let streaming = request["stream"].as_bool().unwrap_or(false);
+        let model = vertex::adapt_anthropic(&mut request);
+        url = vertex::anthropic_url(&url, &model, streaming);
+        body = Bytes::from(serde_json::to_vec(&request)?);
+        let token = vertex.token(&client).await?;
+        outbound.remove("x-api-key");
+        outbound.remove("anthropic-version");
+        outbound.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", token)
+                .parse()
+                .map_err(|_| ProxyError::Internal("invalid Vertex AI access token".into()))?,
+        );
+    } else if let Some(api_key) = &config.api_key {
+        outbound.remove(header::AUTHORIZATION);
+        outbound.insert(
+            "x-api-key",
+            api_key
+                .parse()
+                .map_err(|_| ProxyError::Config("UPSTREAM_API_KEY is not a valid header".into()))?,
+        );
+    }
+    for (name, value) in &config.upstream_headers {
+        if let (Ok(name), Ok(value)) = (
+            header::HeaderName::from_bytes(name.as_bytes()),
+            header::HeaderValue::from_str(value),
+        ) {
+            outbound.insert(name, value);
+        }
+    }
+    if config.vertex.is_none() && !outbound.contains_key("anthropic-version") {
+        outbound.insert(
+            "anthropic-version",
+            header::HeaderValue::from_static(ANTHROPIC_VERSION),
+        );
+    }
+
+    let started = Instant::now();
+    let response = client
+        .post(&url)
+        .headers(outbound)
+        .body(body)
+        .timeout(ctx.remaining()?)
+        .send()
+        .await
+        .map_err(|err| {
+            tracing::error!("Failed to pass request through to {}: {:?}", url, err);
+            ctx.upstreams.record(target, started.elapsed(), false);
+            ProxyError::from(err)
+        })?;
+
+    let status = StatusCode::from_u16(response.status().as_u16())
+        .map_err(|err| ProxyError::Internal(err.to_string()))?;
+    let healthy = !(status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS);
+    ctx.upstreams.record(target, started.elapsed(), healthy);
+    if !status.is_success() {
+        tracing::error!("Upstream returned {} from {}", status, url);
+    }
+
+    let mut builder = Response::builder().status(status);
+    for (name, value) in response.headers() {
+        if !HOP_HEADERS.contains(name) {
+            builder = builder.header(name, value);
+        }
+    }
+
+    let upstream = response.bytes_stream();
+    let stream = async_stream::stream! {
+        let mut usage = UsageSniffer::default();
+        tokio::pin!(upstream);
+        while let Some(chunk) = upstream.next().await {
+            match chunk {
+                Ok(bytes) => {
+                    usage.push(&bytes);
+                    yield Ok(bytes);
+                }
+                Err(err) => {
+                    tracing::error!("Passthrough stream error: {}", err);
+                    yield Err(std::io::Error::other(err));
+                    break;
+                }
+            }
+        }
+        if status.is_success() {
+            if let Some((upstream_model, usage)) = usage.finish() {
+                let upstream_model = upstream_model.unwrap_or_else(|| model.clone());
+                ctx.record_usage(&config, &upstream_model, &usage);
+            }
+        }
+    };
+
+    builder
+        .body(Body::from_stream(stream))
+        .map_err(|err| ProxyError::Internal(err.to_string()))
+}
+
+/// Picks the token usage out of a forwarded response, whether it is a single
+/// JSON message or an event stream, without holding back any bytes
+#[derive(Debug, Default)]
+struct UsageSniffer {
+    buffer: BytesMut,
+    model: Option<String>,
+    input_tokens: Option<u64>,
+    output_tokens: Option<u64>,
+}
+
+impl UsageSniffer {
+    fn push(&mut self, bytes: &[u8]) {
+        // Events are only decoded once complete, so characters split across
+        // chunks stay intact
+        self.buffer.extend_from_slice(bytes);
+        while let Some(end) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+            let event = self.buffer.split_to(end + 2);
+            let Some(data) = String::from_utf8_lossy(&event)
+                .lines()
+                .find_map(|l| l.strip_prefix("data: "))
+                .and_then(|d| serde_json::from_str::<Value>(d).ok())
+            else {
+                continue;
+            };
+            match data["type"].as_str() {
+                Some("message_start") => self.observe(&data["message"]),
+                Some("message_delta") => self.observe(&data),
+                _ => {}
+            }
+        }
+    }
+
+    fn observe(&mut self, message: &Value) {
+        if let Some(model) = message["model"].as_str() {
+            self.model = Some(model.to_string());
+        }
+        let usage = &message["usage"];
+        if let Some(input) = usage["input_tokens"].as_u64() {
+            self.input_tokens = Some(input);
+        }
+        if let Some(output) = usage["output_tokens"].as_u64() {
+            self.output_tokens = Some(output);
+        }
+    }
+
+    fn finish(mut self) -> Option<(Option<String>, openai::Usage)> {
+        // A non-streaming response is one JSON message with no event framing
+        if let Ok(message) = serde_json::from_slice::<Value>(&self.buffer) {
+            self.observe(&message);
+        }
+        let input = self.input_tokens.unwrap_or(0) as u32;
+        let output = self.output_tokens? as u32;
+        Some((
+            self.model,
+            openai::Usage {
+                prompt_tokens: input,
+                completion_tokens: output,
+                total_tokens: input + output,
+                prompt_tokens_details: None,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UsageSniffer;
+
+    #[test]
+    fn usage_is_read_from_streams_and_plain_responses() {
+        let mut stream = UsageSniffer::default();
+        stream.push(b"event: message_start\ndata: {\"type\":\"message_start\",\"message\":{\"model\":\"claude-x\",\"usage\":{\"input_tokens\":12,\"output_tokens\":1}}}\n\nevent: message_delta\ndata: {\"type\":\"message_de");
+        stream.push(b"lta\",\"usage\":{\"output_tokens\":30}}\n\n");
+        let (model, usage) = stream.finish().unwrap();
+        assert_eq!(model.as_deref(), Some("claude-x"));
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 30));
+
+        let mut plain = UsageSniffer::default();
+        plain.push(br#"{"model":"claude-x","usage":{"input_tokens":3,"output_tokens":4}}"#);
+        let (_, usage) = plain.finish().unwrap();
+        assert_eq!(usage.total_tokens, 7);
+
+        assert!(UsageSniffer::default().finish().is_none());
+    }
+}
@@ -1,50 +1,725 @@
-use crate::config::Config;
-use crate::error::{ProxyError, ProxyResult};
+use crate::accounting::{Accounting, UsageReport};
+use crate::admin;
+use crate::assembly;
+use crate::coalesce;
+use crate::config::{Config, UpstreamFormat};
+use crate::continuation;
+use crate::ensemble;
+use crate::error::{ProxyError, ProxyResult, UpstreamDetails};
+use crate::error_rules::ErrorRule;
 use crate::models::{anthropic, openai};
+use crate::ollama;
+use crate::passthrough;
+use crate::quirks;
+use crate::routing::{RouteTarget, DEFAULT_UPSTREAM};
+use crate::secrets::SecretVault;
+use crate::sse::SseParser;
+use crate::stops;
+use crate::tags::RequestTags;
+use crate::think_tags;
+use crate::tokens;
+use crate::tool_names::ToolNames;
 use crate::transform;
+use crate::translator::{failed_stream_events, message_start_event, ping_event, StreamTranslator};
+use crate::upstream::{CircuitBreaker, UpstreamPool, UpstreamRegistry};
+use crate::validation;
+use crate::vertex;
+use crate::vision::{self, CaptionCache};
 use axum::{
     body::Body,
-    http::{HeaderMap, HeaderValue},
+    extract::{rejection::JsonRejection, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
     response::{IntoResponse, Response},
     Extension, Json,
 };
 use bytes::Bytes;
 use futures::stream::{Stream, StreamExt};
 use reqwest::Client;
-use serde_json::json;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::Instrument;
+
+/// Messages API version sent to Anthropic upstreams
+pub(crate) const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Beta under which tool input may stream as unvalidated partial JSON
+pub(crate) const FINE_GRAINED_TOOL_STREAMING: &str = "fine-grained-tool-streaming-2025-05-14";
+
+/// Characters per text delta when replaying a response that wasn't streamed
+const SYNTHETIC_DELTA_CHARS: usize = 64;
+
+/// Upper bound on any upstream call, and the default when the client sets no deadline
+const MAX_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(300);
 
 pub async fn proxy_handler(
     Extension(config): Extension<Arc<Config>>,
     Extension(client): Extension<Client>,
-    Json(req): Json<anthropic::AnthropicRequest>,
+    Extension(accounting): Extension<Arc<Accounting>>,
+    Extension(upstreams): Extension<Arc<UpstreamRegistry>>,
+    Extension(captions): Extension<Arc<CaptionCache>>,
+    headers: HeaderMap,
+    payload: Result<Json<Value>, JsonRejection>,
+) -> ProxyResult<Response> {
+    let req = validation::messages_request(payload)?;
+    let deadline = request_deadline(&headers)?.map(|timeout| Instant::now() + timeout);
+    let rules = config.model_rules.current();
+    let rule = rules.find(&req.model, transform::thinking_enabled(&req));
+    if let Some(message) = rule.and_then(|rule| rule.reject.as_deref()) {
+        tracing::debug!("Rejecting request for {}: {}", req.model, message);
+        return Err(ProxyError::InvalidRequest(message.to_string()));
+    }
+    let (model_override, upstream_override) = routing_overrides(&config, &headers)?;
+    let splits = config.model_splits.clone();
+    let split = splits
+        .as_deref()
+        .filter(|_| model_override.is_none() && upstream_override.is_none())
+        .and_then(|splits| splits.assign(&req.model));
+    if let Some(split) = &split {
+        tracing::debug!(
+            "Request for {} assigned to the {} arm",
+            req.model,
+            split.arm()
+        );
+    }
+    let model = model_override
+        .as_deref()
+        .or_else(|| split.as_ref().and_then(|split| split.canary_model()))
+        .or_else(|| rule.and_then(|rule| rule.map_model.as_deref()));
+    let params = rule.map(|rule| &rule.params).filter(|p| !p.is_empty());
+    let ensemble = rule.is_some_and(|rule| rule.ensemble) || ensemble_requested(&headers);
+    let started = Instant::now();
+
+    let routes = config.upstream_routes.clone();
+    let upstream_choice = upstream_override
+        .as_deref()
+        .or_else(|| rule.and_then(|rule| rule.upstream.as_deref()));
+    let targets = match upstream_choice {
+        Some(DEFAULT_UPSTREAM) => None,
+        Some(name) => {
+            let upstream = routes
+                .as_ref()
+                .and_then(|routes| routes.upstream(name))
+                .ok_or_else(|| ProxyError::InvalidRequest(format!("unknown upstream {}", name)))?;
+            Some(vec![RouteTarget {
+                upstream,
+                model: None,
+            }])
+        }
+        None => {
+            let conversation = config
+                .upstream_affinity
+                .then(|| conversation_key(&config, &headers, &req));
+            routes
+                .as_ref()
+                .and_then(|routes| routes.route(&req.model, conversation))
+        }
+    };
+    let result = match targets {
+        None => {
+            let directives = Directives {
+                model,
+                params,
+                ensemble,
+            };
+            handle(
+                config, client, accounting, upstreams, captions, &headers, req, directives,
+                deadline,
+            )
+            .await
+        }
+        Some(targets) => {
+            let mut result = Err(ProxyError::Internal("route has no targets".to_string()));
+            for (i, target) in targets.iter().enumerate() {
+                let upstream = target.upstream;
+                tracing::debug!("Routing {} to upstream {}", req.model, upstream.name);
+                result = handle(
+                    upstream.config.clone(),
+                    client.clone(),
+                    accounting.clone(),
+                    upstream.registry.clone(),
+                    captions.clone(),
+                    &headers,
+                    req.clone(),
+                    Directives {
+                        model: model_override.as_deref().or(target.model).or(model),
+                        params,
+                        ensemble,
+                    },
+                    deadline,
+                )
+                .await;
+                match &mut result {
+                    Ok(response) => {
+                        if let Ok(name) = HeaderValue::from_str(&upstream.name) {
+                            response.headers_mut().insert("x-proxy-upstream", name);
+                        }
+                        break;
+                    }
+                    Err(err) if i + 1 < targets.len() && fails_over(&upstream.config, err) => {
+                        tracing::warn!(
+                            "Upstream {} failed, failing over to {}: {}",
+                            upstream.name,
+                            targets[i + 1].upstream.name,
+                            err
+                        );
+                    }
+                    Err(_) => break,
+                }
+            }
+            result
+        }
+    };
+    if let Some(split) = &split {
+        split.record(started.elapsed(), result.is_ok());
+    }
+    result
+}
+
+/// Model and upstream a client chose for this request with `x-proxy-model`
+/// and `x-proxy-upstream`, if its API key is allowed to
+fn routing_overrides(
+    config: &Config,
+    headers: &HeaderMap,
+) -> ProxyResult<(Option<String>, Option<String>)> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(String::from)
+    };
+    let model = header("x-proxy-model");
+    let upstream = header("x-proxy-upstream");
+    if model.is_none() && upstream.is_none() {
+        return Ok((None, None));
+    }
+
+    let allowed = config.routing_override_keys.iter().any(|k| k == "*")
+        || client_key(headers).is_some_and(|key| {
+            config
+                .routing_override_keys
+                .iter()
+                .any(|allowed| admin::constant_time_eq(allowed, &key))
+        });
+    if !allowed {
+        return Err(ProxyError::PermissionDenied(
+            "routing override headers are not allowed for this API key".to_string(),
+        ));
+    }
+    tracing::info!(
+        "Routing override: model={} upstream={}",
+        model.as_deref().unwrap_or("-"),
+        upstream.as_deref().unwrap_or("-")
+    );
+    Ok((model, upstream))
+}
+
+/// What routing decided about a request beyond which upstream serves it
+#[derive(Debug, Clone, Copy)]
+struct Directives<'a> {
+    /// Model to ask for instead of the usual model choice
+    model: Option<&'a str>,
+    /// Parameters set on the upstream request, replacing the client's
+    params: Option<&'a Map<String, Value>>,
+    /// Answer with ensemble mode, when ENSEMBLE_MODELS is configured
+    ensemble: bool,
+}
+
+/// Set parameters on a request by name, whether it has a field for them or
+/// carries them as extras
+fn apply_params<T: Serialize + DeserializeOwned>(
+    req: &mut T,
+    params: &Map<String, Value>,
+) -> ProxyResult<()> {
+    let mut value = serde_json::to_value(&*req)?;
+    if let Value::Object(fields) = &mut value {
+        fields.extend(params.clone());
+    }
+    *req = serde_json::from_value(value)?;
+    Ok(())
+}
+
+/// Handle a request with one upstream configuration
+#[allow(clippy::too_many_arguments)]
+async fn handle(
+    config: Arc<Config>,
+    client: Client,
+    accounting: Arc<Accounting>,
+    upstreams: Arc<UpstreamRegistry>,
+    captions: Arc<CaptionCache>,
+    headers: &HeaderMap,
+    mut req: anthropic::AnthropicRequest,
+    directives: Directives<'_>,
+    deadline: Option<Instant>,
 ) -> ProxyResult<Response> {
+    // Requests built inside the proxy (legacy completions, batches) reach
+    // Anthropic upstreams through the passthrough as well
+    if config.upstream_format == UpstreamFormat::Anthropic {
+        if let Some(model) = directives.model {
+            req.model = model.to_string();
+        }
+        if let Some(params) = directives.params {
+            apply_params(&mut req, params)?;
+        }
+        let body = Bytes::from(serde_json::to_vec(&req)?);
+        return passthrough::forward(
+            config,
+            client,
+            accounting,
+            &upstreams,
+            headers.clone(),
+            body,
+            deadline,
+        )
+        .await;
+    }
+    if config.upstream_format == UpstreamFormat::Ollama {
+        return ollama::handle(
+            config,
+            client,
+            accounting,
+            &upstreams,
+            headers,
+            req,
+            directives.model,
+            directives.params,
+            deadline,
+        )
+        .await;
+    }
+
     let is_streaming = req.stream.unwrap_or(false);
 
     tracing::debug!("Received request for model: {}", req.model);
     tracing::debug!("Streaming: {}", is_streaming);
 
-    if config.verbose {
+    if tracing::enabled!(tracing::Level::TRACE) {
         tracing::trace!(
             "Incoming Anthropic request: {}",
             serde_json::to_string_pretty(&req).unwrap_or_default()
         );
     }
 
-    let openai_req = transform::anthropic_to_openai(req, &config)?;
+    let conversation = config
+        .upstream_affinity
+        .then(|| conversation_key(&config, headers, &req));
+    let tags = RequestTags::from_request(&config, headers, req.metadata.as_ref());
+    if !tags.is_empty() {
+        tracing::debug!("Request tags: {}", tags);
+    }
+    let tool_names = ToolNames::new(req.tools.as_deref().unwrap_or_default());
+    let reported_model = config.report_requested_model.then(|| req.model.clone());
+    let mut openai_req = transform::anthropic_to_openai(req, &config)?;
+    if let Some(model) = directives.model {
+        tracing::debug!("Model override: {} -> {}", openai_req.model, model);
+        openai_req.model = model.to_string();
+    }
+    if let Some(params) = directives.params {
+        apply_params(&mut openai_req, params)?;
+    }
+
+    let mut ctx = RequestContext {
+        deadline,
+        tags,
+        conversation,
+        structured_output: transform::structured_output_name(&openai_req)
+            .map(|name| tool_names.original(&name)),
+        reported_model,
+        early_message_id: None,
+        tool_names,
+        ..RequestContext::for_request(accounting, &upstreams, headers)
+    };
+    if !ctx.betas.is_empty() {
+        tracing::debug!("Anthropic betas: {}", ctx.betas.join(", "));
+    }
+
+    if let Some(reason) = ctx
+        .accounting
+        .budget_pressure(&config, ctx.client_key.as_deref())
+    {
+        if let Some(cheaper) = &config.budget_downgrade_model {
+            tracing::warn!(
+                "Budget downgrade: {} -> {} ({})",
+                openai_req.model,
+                cheaper,
+                reason
+            );
+            ctx.downgraded_from = Some(std::mem::replace(&mut openai_req.model, cheaper.clone()));
+        }
+    }
+
+    let dropped_stops = stops::limit(&config, &mut openai_req);
+    // Some servers echo the sequence they stopped at, which is cut like the emulated ones
+    ctx.watched_stops = openai_req.stop.clone().unwrap_or_default();
+    if config.emulate_stop_sequences {
+        ctx.watched_stops.extend(dropped_stops);
+    }
+
+    vision::inline_image_urls(&config, &client, &mut openai_req).await;
+    vision::replace_images(&config, &client, &ctx, &captions, &mut openai_req).await;
 
-    if config.verbose {
+    if config.reasoning_limit_upstream {
+        if let Some(limit) = config.reasoning_limit_for(&openai_req.model) {
+            // A smaller thinking budget from the request still applies
+            let requested = openai_req
+                .reasoning
+                .as_ref()
+                .and_then(|r| r["max_tokens"].as_u64());
+            let limit = requested.map_or(limit as u64, |r| r.min(limit as u64));
+            openai_req.reasoning = Some(json!({ "max_tokens": limit }));
+        }
+    }
+
+    check_context_window(&config, &openai_req)?;
+    quirks::apply(&config, &mut openai_req);
+
+    if let Some(scanner) = &config.secret_scanner {
+        scanner.mask_request(&mut openai_req, &mut ctx.secrets);
+    }
+
+    if tracing::enabled!(tracing::Level::TRACE) {
         tracing::trace!(
             "Transformed OpenAI request: {}",
             serde_json::to_string_pretty(&openai_req).unwrap_or_default()
         );
     }
 
-    if is_streaming {
-        handle_streaming(config, client, openai_req).await
+    let downgrade_header = ctx
+        .downgraded_from
+        .as_ref()
+        .and_then(|from| HeaderValue::from_str(&format!("{} -> {}", from, openai_req.model)).ok());
+
+    // Tags become structured fields on every log line emitted while handling the request
+    let span = tracing::info_span!("request", tags = %ctx.tags);
+    let mut response = async {
+        if is_streaming {
+            handle_streaming(config, client, openai_req, ctx).await
+        } else if directives.ensemble && config.ensemble_enabled() {
+            ensemble::handle(config, client, openai_req, ctx).await
+        } else {
+            handle_non_streaming(config, client, openai_req, ctx).await
+        }
+    }
+    .instrument(span)
+    .await?;
+
+    if let Some(value) = downgrade_header {
+        response
+            .headers_mut()
+            .insert("x-proxy-budget-downgrade", value);
+    }
+
+    Ok(response)
+}
+
+/// Betas listed in the client's `anthropic-beta` headers
+pub(crate) fn anthropic_betas(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all("anthropic-beta")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|beta| !beta.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Echo the client's `anthropic-version` and attach a `request-id` to every
+/// response, as Anthropic's API does. Headers already set, such as those of a
+/// passthrough upstream, are kept.
+pub async fn anthropic_headers(request: Request, next: Next) -> Response {
+    let version = request
+        .headers()
+        .get("anthropic-version")
+        .cloned()
+        .unwrap_or_else(|| HeaderValue::from_static(ANTHROPIC_VERSION));
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.entry("anthropic-version").or_insert(version);
+    if let Ok(id) = HeaderValue::from_str(&proxy_id("req")) {
+        headers.entry("request-id").or_insert(id);
+    }
+    response
+}
+
+/// A process-unique id in Anthropic's style, e.g. `req_...` or `msg_...`
+pub(crate) fn proxy_id(prefix: &str) -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis());
+    format!(
+        "{}_proxy_{:x}{:06x}",
+        prefix,
+        millis,
+        NEXT.fetch_add(1, Ordering::Relaxed) & 0xff_ffff
+    )
+}
+
+/// The API key the client authenticated to the proxy with, if any
+pub(crate) fn client_key(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("x-api-key")
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| {
+            headers
+                .get("authorization")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+        })
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(String::from)
+}
+
+/// Whether the client opted into ensemble mode with `x-proxy-ensemble`
+fn ensemble_requested(headers: &HeaderMap) -> bool {
+    headers
+        .get("x-proxy-ensemble")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim() == "1" || v.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Correlation id of the conversation a request belongs to: an explicit
+/// session header, the configured session key, the client's
+/// metadata.user_id, or else the opening of the conversation (system prompt
+/// and first message), which every turn repeats
+pub(crate) fn conversation_key(
+    config: &Config,
+    headers: &HeaderMap,
+    req: &anthropic::AnthropicRequest,
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    };
+    let metadata = |field: &str| {
+        req.metadata
+            .as_ref()
+            .and_then(|m| m.get(field))
+            .and_then(|id| id.as_str())
+            .map(String::from)
+    };
+    let session = header("x-proxy-session-id")
+        .or_else(|| {
+            let key = config.affinity_session_key.as_deref()?;
+            match key.strip_prefix("metadata.") {
+                Some(field) => metadata(field),
+                None => header(key),
+            }
+        })
+        .or_else(|| metadata("user_id"));
+
+    match session {
+        Some(session) => session.hash(&mut hasher),
+        None => {
+            client_key(headers).hash(&mut hasher);
+            serde_json::to_string(&req.system)
+                .unwrap_or_default()
+                .hash(&mut hasher);
+            serde_json::to_string(&req.messages.first())
+                .unwrap_or_default()
+                .hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+/// Client-supplied time budget from `x-proxy-timeout-ms` (milliseconds) or
+/// `Request-Timeout` (seconds), capped at the proxy's own upstream timeout
+pub(crate) fn request_deadline(headers: &HeaderMap) -> ProxyResult<Option<Duration>> {
+    let header = |name: &str| headers.get(name).map(|v| v.to_str().unwrap_or("").trim());
+
+    let timeout = if let Some(raw) = header("x-proxy-timeout-ms") {
+        raw.parse::<u64>()
+            .ok()
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis)
+            .ok_or_else(|| {
+                ProxyError::InvalidRequest(format!(
+                    "x-proxy-timeout-ms must be a positive number of milliseconds, got '{}'",
+                    raw
+                ))
+            })?
+    } else if let Some(raw) = header("request-timeout") {
+        raw.parse::<f64>()
+            .ok()
+            .filter(|secs| secs.is_finite() && *secs > 0.0)
+            .map(|secs| Duration::from_secs_f64(secs.min(MAX_UPSTREAM_TIMEOUT.as_secs_f64())))
+            .ok_or_else(|| {
+                ProxyError::InvalidRequest(format!(
+                    "Request-Timeout must be a positive number of seconds, got '{}'",
+                    raw
+                ))
+            })?
     } else {
-        handle_non_streaming(config, client, openai_req).await
+        return Ok(None);
+    };
+
+    Ok(Some(timeout.min(MAX_UPSTREAM_TIMEOUT)))
+}
+
+/// Reject requests whose prompt plus max_tokens cannot fit the target model,
+/// using the same wording as Anthropic's own invalid_request_error
+fn check_context_window(config: &Config, openai_req: &openai::OpenAIRequest) -> ProxyResult<()> {
+    let Some(limit) = config.context_limit_for(&openai_req.model) else {
+        return Ok(());
+    };
+
+    let input_tokens = tokens::estimate_request_tokens(openai_req);
+    let max_tokens = openai_req.max_tokens.unwrap_or(0);
+
+    if input_tokens.saturating_add(max_tokens) > limit {
+        tracing::warn!(
+            "Rejecting request for {}: {} + {} tokens exceed context limit {}",
+            openai_req.model,
+            input_tokens,
+            max_tokens,
+            limit
+        );
+        return Err(ProxyError::InvalidRequest(format!(
+            "input length and `max_tokens` exceed context limit: {} + {} > {}, \
+             decrease input length or `max_tokens` and try again",
+            input_tokens, max_tokens, limit
+        )));
+    }
+
+    Ok(())
+}
+
+/// Per-request state carried from the handler into the response path
+#[derive(Debug)]
+pub(crate) struct RequestContext {
+    /// Placeholders substituted for secrets in the outbound prompt
+    pub secrets: SecretVault,
+    /// Shared usage and spend accounting
+    pub accounting: Arc<Accounting>,
+    /// Equivalent upstream endpoints with their rolling health
+    pub upstreams: Arc<UpstreamPool>,
+    /// API key the client presented, used for per-client budgets
+    pub client_key: Option<String>,
+    /// Model the request was rerouted away from because of budget pressure
+    pub downgraded_from: Option<String>,
+    /// Point in time by which the client wants the whole response
+    pub deadline: Option<Instant>,
+    /// Client-supplied attribution tags, already filtered to allowed keys
+    pub tags: RequestTags,
+    /// Hash identifying the conversation, set when upstream affinity is on
+    pub conversation: Option<u64>,
+    /// Forced tool requested as structured output, whose JSON answer is
+    /// returned as a call to that tool
+    pub structured_output: Option<String>,
+    /// Betas the client declared in `anthropic-beta`
+    pub betas: Vec<String>,
+    /// Stop sequences the proxy cuts the output at: those sent upstream, in
+    /// case it echoes them, and emulated ones over the upstream's limit
+    pub watched_stops: Vec<String>,
+    /// Model name responses report instead of the upstream's, when the
+    /// client should see the model it asked for
+    pub reported_model: Option<String>,
+    /// Id of the message_start sent before the upstream answered, which the
+    /// translated stream continues instead of starting its own
+    pub early_message_id: Option<String>,
+    /// Client tool names and the sanitized names sent upstream
+    pub tool_names: ToolNames,
+}
+
+impl RequestContext {
+    /// Context for a client request: its API key and betas, on the current
+    /// upstream pool. Callers fill in the rest of what they know.
+    pub fn for_request(
+        accounting: Arc<Accounting>,
+        upstreams: &UpstreamRegistry,
+        headers: &HeaderMap,
+    ) -> Self {
+        Self {
+            client_key: client_key(headers),
+            betas: anthropic_betas(headers),
+            ..Self::new(accounting, upstreams.current())
+        }
+    }
+
+    /// Context for work that isn't tied to a client request, such as self-tests
+    pub fn detached(config: &Config) -> Self {
+        Self::new(
+            Arc::new(Accounting::default()),
+            Arc::new(
+                UpstreamPool::new(config.upstream_urls())
+                    .with_circuit_breaker(CircuitBreaker::from_config(config)),
+            ),
+        )
+    }
+
+    fn new(accounting: Arc<Accounting>, upstreams: Arc<UpstreamPool>) -> Self {
+        Self {
+            secrets: SecretVault::default(),
+            accounting,
+            upstreams,
+            client_key: None,
+            downgraded_from: None,
+            deadline: None,
+            tags: RequestTags::default(),
+            conversation: None,
+            structured_output: None,
+            betas: Vec::new(),
+            watched_stops: Vec::new(),
+            reported_model: None,
+            early_message_id: None,
+            tool_names: ToolNames::default(),
+        }
+    }
+
+    /// Time left for upstream work, or the proxy default without a client deadline
+    pub fn remaining(&self) -> ProxyResult<Duration> {
+        let Some(deadline) = self.deadline else {
+            return Ok(MAX_UPSTREAM_TIMEOUT);
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(ProxyError::Timeout(
+                "request deadline passed before the upstream call".to_string(),
+            ));
+        }
+        Ok(remaining)
+    }
+
+    /// Record the usage of one upstream call against the client's spend
+    pub fn record_usage(&self, config: &Config, model: &str, usage: &openai::Usage) -> UsageReport {
+        let cost = self.accounting.record(
+            config,
+            model,
+            self.client_key.as_deref(),
+            &self.tags,
+            usage.prompt_tokens,
+            usage.completion_tokens,
+        );
+        tracing::debug!(
+            tags = %self.tags,
+            "Usage for {}: {} input, {} output tokens, cost {}",
+            model,
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            cost.map_or_else(|| "unknown".to_string(), |c| format!("${:.6}", c))
+        );
+
+        UsageReport {
+            upstream_model: model.to_string(),
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+            estimated_cost: cost,
+        }
     }
 }
 
@@ -52,97 +727,719 @@ async fn handle_non_streaming(
     config: Arc<Config>,
     client: Client,
     openai_req: openai::OpenAIRequest,
+    ctx: RequestContext,
 ) -> ProxyResult<Response> {
-    let url = config.chat_completions_url();
-    tracing::debug!("Sending non-streaming request to {}", url);
-    tracing::debug!("Request model: {}", openai_req.model);
+    let (openai_resp, usage) = continuation::complete(&config, &client, &ctx, &openai_req).await?;
 
-    let mut req_builder = client
-        .post(&url)
-        .json(&openai_req)
-        .timeout(Duration::from_secs(300));
+    let filtered = content_filter_outcome(&config, &openai_resp)?;
+    let mut anthropic_resp = transform::openai_to_anthropic(openai_resp, &openai_req.model)?;
+    if filtered.is_some() {
+        anthropic_resp.stop_reason = filtered;
+    }
+    apply_thinking_policy(&config, &openai_req.model, &mut anthropic_resp);
+    ctx.tool_names.restore_response(&mut anthropic_resp);
+    stops::apply_to_response(&ctx.watched_stops, &mut anthropic_resp);
+    if let Some(tool) = &ctx.structured_output {
+        transform::wrap_structured_output(&mut anthropic_resp, tool);
+    }
+    postprocess_response(&config, &ctx, &mut anthropic_resp);
 
-    if let Some(api_key) = &config.api_key {
-        req_builder = req_builder.header("Authorization", format!("Bearer {}", api_key));
+    if tracing::enabled!(tracing::Level::TRACE) {
+        tracing::trace!(
+            "Transformed Anthropic response: {}",
+            serde_json::to_string_pretty(&anthropic_resp).unwrap_or_default()
+        );
     }
 
-    let response = req_builder.send().await.map_err(|err| {
-        tracing::error!("Failed to send non-streaming request to {}: {:?}", url, err);
-        ProxyError::Http(err)
-    })?;
+    Ok((usage.headers(), Json(anthropic_resp)).into_response())
+}
+
+/// Stop reason for a response the upstream's content filter stopped, or an
+/// error if `CONTENT_FILTER` says so; None for other responses
+pub(crate) fn content_filter_outcome(
+    config: &Config,
+    resp: &openai::OpenAIResponse,
+) -> ProxyResult<Option<String>> {
+    let Some(choice) = resp
+        .choices
+        .first()
+        .filter(|choice| choice.finish_reason.as_deref() == Some("content_filter"))
+    else {
+        return Ok(None);
+    };
+    transform::content_filter_stop_reason(config, choice.content_filter_results.as_ref())
+        .map(Some)
+        .ok_or_else(|| {
+            ProxyError::ContentFiltered(
+                "the upstream withheld the rest of the response".to_string(),
+            )
+        })
+}
 
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        tracing::error!("Upstream error ({}): {}", status, error_text);
-        return Err(ProxyError::Upstream(format!(
-            "Upstream returned {}: {}",
-            status, error_text
-        )));
+/// Shape the reasoning of a translated non-streaming response the way the
+/// streaming path does: inline tags are extracted, then the model's limit
+/// and hiding rules apply
+pub(crate) fn apply_thinking_policy(
+    config: &Config,
+    model: &str,
+    resp: &mut anthropic::AnthropicResponse,
+) {
+    if let Some(tags) = config.think_tags_for(model) {
+        think_tags::extract_from_response(&tags, resp);
+    }
+    transform::limit_thinking(config, model, resp);
+}
+
+/// Apply proxy-side output policies to a translated non-streaming response
+pub(crate) fn postprocess_response(
+    config: &Config,
+    ctx: &RequestContext,
+    resp: &mut anthropic::AnthropicResponse,
+) {
+    if let Some(model) = &ctx.reported_model {
+        resp.model = model.clone();
+    }
+    ctx.secrets.restore_response(resp);
+    if config.strict_tools {
+        for block in &mut resp.content {
+            if let anthropic::ResponseContent::ToolUse { input, .. } = block {
+                transform::drop_null_fields(input);
+            }
+        }
     }
+    if let Some(guardrails) = &config.output_guardrails {
+        guardrails.apply_to_response(resp);
+    }
+}
 
-    let openai_resp: openai::OpenAIResponse = response.json().await?;
+/// Send a non-streaming chat completion request upstream and decode the response
+pub(crate) async fn send_chat_completion(
+    config: &Config,
+    client: &Client,
+    ctx: &RequestContext,
+    openai_req: &openai::OpenAIRequest,
+) -> ProxyResult<(openai::OpenAIResponse, UsageReport)> {
+    // Streaming keeps long generations from hitting idle timeouts on the way
+    let streamed_req = (config.stream_upstream && openai_req.stream != Some(true)).then(|| {
+        let mut req = openai_req.clone();
+        req.stream = Some(true);
+        req.stream_options = Some(openai::StreamOptions {
+            include_usage: true,
+        });
+        req
+    });
 
-    if config.verbose {
-        tracing::trace!(
-            "Received OpenAI response: {}",
-            serde_json::to_string_pretty(&openai_resp).unwrap_or_default()
+    let mut attempt = 0;
+    loop {
+        let response = send_upstream(
+            config,
+            client,
+            ctx,
+            streamed_req.as_ref().unwrap_or(openai_req),
+        )
+        .await?;
+
+        let openai_resp: openai::OpenAIResponse = if streamed_req.is_some() {
+            assembly::collect(upstream_events(response)).await?
+        } else {
+            response.json().await.map_err(|err| {
+                if err.is_decode() {
+                    ProxyError::Upstream(format!(
+                        "Upstream response is not a chat completion: {}",
+                        err
+                    ))
+                } else {
+                    ProxyError::from(err)
+                }
+            })?
+        };
+        let upstream_model = openai_resp.model.as_deref().unwrap_or(&openai_req.model);
+        let usage = ctx.record_usage(config, upstream_model, &openai_resp.usage);
+
+        if tracing::enabled!(tracing::Level::TRACE) {
+            tracing::trace!(
+                "Received OpenAI response: {}",
+                serde_json::to_string_pretty(&openai_resp).unwrap_or_default()
+            );
+        }
+
+        if !is_empty_response(&openai_resp) {
+            return Ok((openai_resp, usage));
+        }
+        let what = if openai_resp.choices.is_empty() {
+            "no choices"
+        } else {
+            "no content or tool calls"
+        };
+        if attempt >= config.empty_response_retries {
+            return Err(ProxyError::EmptyResponse(format!(
+                "{} returned {} after {} attempt(s)",
+                upstream_model,
+                what,
+                attempt + 1
+            )));
+        }
+        attempt += 1;
+        tracing::warn!(
+            "Upstream returned {}, retrying ({}/{})",
+            what,
+            attempt,
+            config.empty_response_retries
         );
     }
+}
 
-    let anthropic_resp = transform::openai_to_anthropic(openai_resp, &openai_req.model)?;
+/// A response with no choices, or whose first choice has neither text nor tool calls
+fn is_empty_response(resp: &openai::OpenAIResponse) -> bool {
+    resp.choices.first().is_none_or(|choice| {
+        // A filtered response is empty on purpose, and retrying won't change that
+        choice.finish_reason.as_deref() != Some("content_filter")
+            && choice
+                .message
+                .content
+                .as_deref()
+                .is_none_or(|text| text.trim().is_empty())
+            && choice
+                .message
+                .tool_calls
+                .as_ref()
+                .is_none_or(|calls| calls.is_empty())
+            && choice.message.function_call.is_none()
+    })
+}
 
-    if config.verbose {
-        tracing::trace!(
-            "Transformed Anthropic response: {}",
-            serde_json::to_string_pretty(&anthropic_resp).unwrap_or_default()
+/// POST a chat completion request to the selected upstream, recording its
+/// latency and health, and turn non-success statuses into errors. Models the
+/// upstream doesn't know are replaced by the next of FALLBACK_MODELS.
+pub(crate) async fn send_upstream(
+    config: &Config,
+    client: &Client,
+    ctx: &RequestContext,
+    openai_req: &openai::OpenAIRequest,
+) -> ProxyResult<reqwest::Response> {
+    let streaming = openai_req.stream == Some(true);
+    let mut result = post_upstream(
+        config,
+        client,
+        ctx,
+        openai_req,
+        &openai_req.model,
+        streaming,
+    )
+    .await;
+    let mut model = &openai_req.model;
+    let mut fallbacks = config
+        .fallback_models
+        .iter()
+        .filter(|fallback| **fallback != openai_req.model);
+    while result.as_ref().is_err_and(is_model_not_found) {
+        let Some(fallback) = fallbacks.next() else {
+            break;
+        };
+        tracing::warn!(
+            "Model {} is not available upstream, falling back to {}",
+            model,
+            fallback
         );
+        let mut req = openai_req.clone();
+        req.model = fallback.clone();
+        result = post_upstream(config, client, ctx, &req, fallback, streaming).await;
+        model = fallback;
     }
+    result
+}
 
-    Ok(Json(anthropic_resp).into_response())
+/// Whether an error that ended a request on one upstream, after its own
+/// retries, should move the request to the next upstream of a failover chain
+fn fails_over(config: &Config, err: &ProxyError) -> bool {
+    match err {
+        ProxyError::Http(_) | ProxyError::Overloaded(_) | ProxyError::RateLimited(_) => true,
+        ProxyError::FromUpstream { error, details } => {
+            StatusCode::from_u16(details.status)
+                .is_ok_and(|status| RetryPolicy::new(config).retries_status(status))
+                || fails_over(config, error)
+        }
+        _ => false,
+    }
 }
 
-async fn handle_streaming(
-    config: Arc<Config>,
-    client: Client,
-    openai_req: openai::OpenAIRequest,
-) -> ProxyResult<Response> {
-    let url = config.chat_completions_url();
-    tracing::debug!("Sending streaming request to {}", url);
-    tracing::debug!("Request model: {}", openai_req.model);
+/// Whether an upstream error says the requested model doesn't exist, as
+/// OpenRouter does for deprecated models and OpenAI and vLLM for unknown ones
+fn is_model_not_found(err: &ProxyError) -> bool {
+    let ProxyError::FromUpstream { details, .. } = err else {
+        return false;
+    };
+    if !matches!(details.status, 400 | 404) {
+        return false;
+    }
+    if details.code.as_deref() == Some("model_not_found") {
+        return true;
+    }
+    let message = details.message.to_lowercase();
+    message.contains("model")
+        && [
+            "not found",
+            "does not exist",
+            "not a valid model",
+            "no endpoints found",
+            "unknown model",
+            "invalid model",
+        ]
+        .iter()
+        .any(|phrase| message.contains(phrase))
+}
 
-    let mut req_builder = client
-        .post(&url)
-        .json(&openai_req)
-        .timeout(Duration::from_secs(300));
+/// POST any request body to the selected upstream, authenticating the way
+/// the configured upstream format expects
+pub(crate) async fn post_upstream<T: serde::Serialize + ?Sized>(
+    config: &Config,
+    client: &Client,
+    ctx: &RequestContext,
+    body: &T,
+    model: &str,
+    streaming: bool,
+) -> ProxyResult<reqwest::Response> {
+    let kind = if streaming {
+        "streaming"
+    } else {
+        "non-streaming"
+    };
+    // Nothing has reached the client until the upstream answers, streaming
+    // or not, so failures before then can be retried without it noticing
+    let retry = RetryPolicy::new(config);
+    let mut attempt = 0;
+    let mut rate_limited_for = Duration::ZERO;
+    let vertex_request = match &config.vertex {
+        Some(_) => {
+            let mut body = serde_json::to_value(body)?;
+            let claude_model = match config.upstream_format {
+                UpstreamFormat::OpenAI | UpstreamFormat::Ollama => {
+                    vertex::adapt_openai(&mut body);
+                    None
+                }
+                UpstreamFormat::Anthropic => Some(vertex::adapt_anthropic(&mut body)),
+            };
+            Some((body, claude_model))
+        }
+        None => None,
+    };
+    loop {
+        let timeout = ctx.remaining()?;
+        let target = ctx
+            .upstreams
+            .admit(ctx.upstreams.select_for(ctx.conversation))?;
+        let url = match &vertex_request {
+            Some((_, Some(claude_model))) => {
+                vertex::anthropic_url(ctx.upstreams.url(target), claude_model, streaming)
+            }
+            _ => ctx.upstreams.url(target).to_string(),
+        };
+        let url = url.as_str();
+        tracing::debug!("Sending {} request to {}", kind, url);
+        tracing::debug!("Request model: {}", model);
 
-    if let Some(api_key) = &config.api_key {
-        req_builder = req_builder.header("Authorization", format!("Bearer {}", api_key));
-    }
+        let mut req_builder = match &vertex_request {
+            Some((body, _)) => client.post(url).json(body),
+            None => client.post(url).json(body),
+        }
+        .timeout(timeout);
+
+        if let Some(vertex) = &config.vertex {
+            let token = vertex.token(client).await?;
+            req_builder = req_builder.header("Authorization", format!("Bearer {}", token));
+        } else if let Some(api_key) = &config.api_key {
+            req_builder = match config.upstream_format {
+                UpstreamFormat::OpenAI | UpstreamFormat::Ollama => {
+                    req_builder.header("Authorization", format!("Bearer {}", api_key))
+                }
+                UpstreamFormat::Anthropic => req_builder.header("x-api-key", api_key),
+            };
+        }
+        if config.is_openrouter() {
+            for (name, value) in config.openrouter_headers() {
+                req_builder = req_builder.header(name, value);
+            }
+        }
+        for (name, value) in &config.upstream_headers {
+            req_builder = req_builder.header(name, value);
+        }
+        if config.upstream_format == UpstreamFormat::Anthropic && config.vertex.is_none() {
+            req_builder = req_builder.header("anthropic-version", ANTHROPIC_VERSION);
+        }
 
-    let response = req_builder.send().await.map_err(|err| {
-        tracing::error!("Failed to send streaming request to {}: {:?}", url, err);
-        ProxyError::Http(err)
-    })?;
+        let started = Instant::now();
+        let response = match req_builder.send().await {
+            Ok(response) => response,
+            Err(err) => {
+                ctx.upstreams.record(target, started.elapsed(), false);
+                if err.is_connect() {
+                    if let Some(delay) = retry.delay(attempt, ctx) {
+                        attempt += 1;
+                        tracing::warn!(
+                            "Could not connect to {} ({}), retrying in {:?} ({}/{})",
+                            url,
+                            err,
+                            delay,
+                            attempt,
+                            retry.max_retries
+                        );
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                }
+                tracing::error!("Failed to send {} request to {}: {:?}", kind, url, err);
+                return Err(ProxyError::from(err));
+            }
+        };
 
-    if !response.status().is_success() {
         let status = response.status();
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        tracing::error!("Upstream error ({}) from {}: {}", status, url, error_text);
-        return Err(ProxyError::Upstream(format!(
-            "Upstream returned {} from {}: {}",
-            status, url, error_text
-        )));
+        let healthy = !(status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS);
+        ctx.upstreams.record(target, started.elapsed(), healthy);
+
+        if !status.is_success() {
+            let retry_after = retry_after(response.headers());
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            let rule = config
+                .error_rules
+                .as_ref()
+                .and_then(|rules| rules.find(status.as_u16(), &error_text));
+            if upstream_error_type(status, rule) == "rate_limit_error" {
+                if let Some(wait) =
+                    rate_limit_wait(config, ctx, &retry, attempt, retry_after, rate_limited_for)
+                {
+                    rate_limited_for += wait;
+                    attempt += 1;
+                    tracing::warn!(
+                        "Upstream {} is rate limiting, retrying in {:?} ({}/{}): {}",
+                        url,
+                        wait,
+                        attempt,
+                        retry.max_retries,
+                        error_text
+                    );
+                    tokio::time::sleep(wait).await;
+                    continue;
+                }
+            }
+            let retryable = rule
+                .and_then(|rule| rule.retry)
+                .unwrap_or_else(|| retry.retries_status(status));
+            if retryable {
+                if let Some(delay) = retry.delay(attempt, ctx) {
+                    attempt += 1;
+                    tracing::warn!(
+                        "Upstream returned {} from {}, retrying in {:?} ({}/{}): {}",
+                        status,
+                        url,
+                        delay,
+                        attempt,
+                        retry.max_retries,
+                        error_text
+                    );
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            }
+            return Err(upstream_error(status, url, &error_text, retry_after, rule));
+        }
+
+        return Ok(response);
     }
+}
+
+/// Map an upstream's error response onto Anthropic's error types, so client
+/// SDKs back off on rate limits and overload but don't retry bad requests
+pub(crate) fn upstream_error(
+    status: StatusCode,
+    url: &str,
+    body: &str,
+    retry_after: Option<Duration>,
+    rule: Option<&ErrorRule>,
+) -> ProxyError {
+    let (message, mut details) = upstream_error_details(status, body);
+    let message_from_upstream = message.clone();
+    details.retry_after = retry_after;
+    tracing::error!(
+        status = details.status,
+        code = ?details.code,
+        error_type = ?details.error_type,
+        provider = ?details.provider,
+        "Upstream error ({}) from {}: {}",
+        status,
+        url,
+        message
+    );
+    let message = format!("Upstream returned {} from {}: {}", status, url, message);
+    details.message = message_from_upstream;
+    let error = match upstream_error_type(status, rule) {
+        "invalid_request_error" => ProxyError::InvalidRequest(message),
+        "authentication_error" => ProxyError::Unauthorized(message),
+        "permission_error" => ProxyError::PermissionDenied(message),
+        "not_found_error" => ProxyError::NotFound(message),
+        "rate_limit_error" => ProxyError::RateLimited(message),
+        "overloaded_error" => ProxyError::Overloaded(message),
+        "timeout_error" => ProxyError::Timeout(message),
+        _ => ProxyError::Upstream(message),
+    };
+    ProxyError::FromUpstream {
+        error: Box::new(error),
+        details: Box::new(details),
+    }
+}
+
+/// The Anthropic error type for an upstream status, unless a rule from
+/// ERROR_RULES_FILE says otherwise
+pub(crate) fn upstream_error_type(status: StatusCode, rule: Option<&ErrorRule>) -> &'static str {
+    if let Some(error_type) = rule.and_then(|rule| rule.error_type) {
+        return error_type;
+    }
+    match status.as_u16() {
+        400 | 422 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        429 => "rate_limit_error",
+        503 | 529 => "overloaded_error",
+        _ => "api_error",
+    }
+}
 
-    let stream = response.bytes_stream();
-    let sse_stream = create_sse_stream(stream, openai_req.model.clone());
+/// The message, code, type and provider from an OpenAI, OpenRouter or
+/// Anthropic error body; bodies that aren't JSON become the message as-is
+fn upstream_error_details(status: StatusCode, body: &str) -> (String, UpstreamDetails) {
+    let mut details = UpstreamDetails {
+        status: status.as_u16(),
+        ..UpstreamDetails::default()
+    };
+    let Ok(value) = serde_json::from_str::<Value>(body) else {
+        return (body.to_string(), details);
+    };
+    let text = |value: &Value| match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        _ => None,
+    };
+    let error = &value["error"];
+    details.code = text(&error["code"]);
+    details.error_type = text(&error["type"]);
+    details.provider = text(&error["metadata"]["provider_name"]);
+
+    let Some(mut message) = text(&error["message"])
+        .or_else(|| text(error))
+        .or_else(|| text(&value["message"]))
+    else {
+        return (body.to_string(), details);
+    };
+    // OpenRouter puts the provider's own error under metadata.raw, behind a
+    // generic "Provider returned error"
+    if let Some(raw) = text(&error["metadata"]["raw"]).filter(|raw| !raw.is_empty()) {
+        message = format!("{}: {}", message, raw);
+    }
+    (message, details)
+}
+
+/// How long a rate-limited upstream asks to be left alone, from Retry-After,
+/// OpenAI's retry-after-ms or the x-ratelimit-reset headers
+pub(crate) fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.trim().parse::<f64>().ok()) {
+        return seconds(ms / 1000.0);
+    }
+    if let Some(secs) = header("retry-after").and_then(|v| v.trim().parse::<f64>().ok()) {
+        return seconds(secs);
+    }
+    [
+        "x-ratelimit-reset",
+        "x-ratelimit-reset-requests",
+        "x-ratelimit-reset-tokens",
+    ]
+    .into_iter()
+    .filter_map(|name| header(name).and_then(parse_reset))
+    .max()
+}
+
+/// A rate limit reset as a wait: a Unix timestamp in seconds or milliseconds
+/// (OpenRouter), a duration like "6m0s" or "20ms" (OpenAI), or plain seconds
+fn parse_reset(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(number) = value.parse::<f64>() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        let reset = if number > 1e12 {
+            Duration::from_millis(number as u64)
+        } else if number > 1e9 {
+            Duration::from_secs(number as u64)
+        } else {
+            return seconds(number);
+        };
+        return Some(reset.saturating_sub(now));
+    }
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .filter(|&end| end > 0)?;
+        let number: f64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let secs = match &rest[..unit] {
+            "h" => number * 3600.0,
+            "m" => number * 60.0,
+            "s" => number,
+            "ms" => number / 1000.0,
+            _ => return None,
+        };
+        total += seconds(secs)?;
+        rest = &rest[unit..];
+    }
+    Some(total)
+}
+
+/// A header's count of seconds, refusing values that aren't durations
+fn seconds(secs: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(secs.max(0.0)).ok()
+}
+
+/// How long to wait out a rate limit before retrying, or None if the wait
+/// is unknown, the retries are used up, or the wait would exhaust
+/// RATE_LIMIT_RETRY_SECS or run past the deadline. A wait of zero, from a
+/// `Retry-After: 0` or a reset already past, still backs off as a retry would
+fn rate_limit_wait(
+    config: &Config,
+    ctx: &RequestContext,
+    retry: &RetryPolicy,
+    attempt: u32,
+    retry_after: Option<Duration>,
+    waited: Duration,
+) -> Option<Duration> {
+    let budget = Duration::from_secs(config.rate_limit_retry_secs?);
+    if attempt >= retry.max_retries {
+        return None;
+    }
+    let wait = retry_after?.max(retry.backoff(attempt));
+    let remaining = ctx.remaining().ok()?;
+    (waited + wait <= budget && wait < remaining).then_some(wait)
+}
+
+/// When a failed upstream request is sent again: after connection errors
+/// and UPSTREAM_RETRY_STATUSES, with jittered exponential backoff
+pub(crate) struct RetryPolicy {
+    pub max_retries: u32,
+    backoff: Duration,
+    jitter: f64,
+    statuses: Vec<u16>,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_retries: config.upstream_retries,
+            backoff: Duration::from_millis(config.upstream_retry_backoff_ms),
+            jitter: config.upstream_retry_jitter,
+            statuses: config.upstream_retry_statuses.clone(),
+        }
+    }
+
+    pub fn retries_status(&self, status: StatusCode) -> bool {
+        self.statuses.contains(&status.as_u16())
+    }
+
+    /// The wait before retrying after `attempt` retries, or None once they
+    /// are used up or the wait would run past the request deadline
+    pub fn delay(&self, attempt: u32, ctx: &RequestContext) -> Option<Duration> {
+        if attempt >= self.max_retries {
+            return None;
+        }
+        let delay = self.backoff(attempt);
+        let remaining = ctx.remaining().ok()?;
+        (delay < remaining).then_some(delay)
+    }
+
+    /// The jittered exponential backoff before retry number `attempt + 1`
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.backoff.saturating_mul(1 << attempt.min(10));
+        // Spread retries from many clients so they don't hit the upstream at once
+        delay.mul_f64(1.0 + self.jitter * (2.0 * random_fraction() - 1.0))
+    }
+}
+
+/// A number in [0, 1) that differs from call to call, for jitter and
+/// weighted routing
+pub(crate) fn random_fraction() -> f64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = DefaultHasher::new();
+    SystemTime::now().hash(&mut hasher);
+    NEXT.fetch_add(1, Ordering::Relaxed).hash(&mut hasher);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+async fn handle_streaming(
+    config: Arc<Config>,
+    client: Client,
+    openai_req: openai::OpenAIRequest,
+    mut ctx: RequestContext,
+) -> ProxyResult<Response> {
+    let started = Instant::now();
+    let upstream_model = openai_req.model.clone();
+    let events: EventStream = if config.early_message_start {
+        // Acknowledge the request before the upstream answers; upstream
+        // failures then arrive as error events instead of error statuses
+        let id = proxy_id("msg");
+        let input_tokens = tokens::estimate_request_tokens(&openai_req);
+        let estimate = openai::Usage {
+            prompt_tokens: input_tokens,
+            completion_tokens: 0,
+            total_tokens: input_tokens,
+            prompt_tokens_details: None,
+        };
+        let message_start = message_start_event(
+            id.clone(),
+            ctx.reported_model
+                .clone()
+                .unwrap_or_else(|| upstream_model.clone()),
+            Some(&estimate),
+        );
+        ctx.early_message_id = Some(id);
+        let ctx = Arc::new(ctx);
+        let config = config.clone();
+        Box::pin(async_stream::stream! {
+            yield Ok(message_start);
+            let send = send_upstream(&config, &client, &ctx, &openai_req);
+            tokio::pin!(send);
+            let response = loop {
+                let Some((interval, event)) = idle_event(&config, true) else {
+                    break send.await;
+                };
+                match tokio::time::timeout(interval, &mut send).await {
+                    Ok(response) => break response,
+                    Err(_) => yield Ok(event),
+                }
+            };
+            match response {
+                Ok(response) => {
+                    let stream = translate_stream(response, config.clone(), client.clone(), openai_req.clone(), ctx.clone());
+                    for await item in stream {
+                        yield item;
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!("Upstream request failed after message_start: {}", err);
+                    for event in failed_stream_events(None, None, err.error_type(), &err.to_string()) {
+                        yield Ok(event);
+                    }
+                }
+            }
+        })
+    } else {
+        let response = send_upstream(&config, &client, &ctx, &openai_req).await?;
+        translate_stream(response, config.clone(), client, openai_req, Arc::new(ctx))
+    };
+    let body = Body::from_stream(coalesce::pace(events, &config, started));
 
     let mut headers = HeaderMap::new();
     headers.insert(
@@ -151,269 +1448,1195 @@ async fn handle_streaming(
     );
     headers.insert("Cache-Control", HeaderValue::from_static("no-cache"));
     headers.insert("Connection", HeaderValue::from_static("keep-alive"));
+    if let Ok(model) = HeaderValue::from_str(&upstream_model) {
+        headers.insert("x-proxy-upstream-model", model);
+    }
 
-    Ok((headers, Body::from_stream(sse_stream)).into_response())
+    Ok((headers, body).into_response())
 }
 
-fn create_sse_stream(
-    stream: impl Stream<Item = Result<Bytes, reqwest::Error>> + Send + 'static,
-    fallback_model: String,
-) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send {
-    async_stream::stream! {
-        let mut buffer = String::new();
-        let mut message_id = None;
-        let mut current_model = None;
-        let mut content_index = 0;
-        let mut tool_call_id = None;
-        let mut _tool_call_name = None;
-        let mut tool_call_args = String::new();
-        let mut has_sent_message_start = false;
-        let mut current_block_type: Option<String> = None;
+/// Translated events on their way to the client, one SSE event per item
+pub(crate) type EventStream = Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send>>;
 
-        tokio::pin!(stream);
+/// Translate an upstream stream, continuing it past max_tokens if enabled
+fn translate_stream(
+    response: reqwest::Response,
+    config: Arc<Config>,
+    client: Client,
+    openai_req: openai::OpenAIRequest,
+    ctx: Arc<RequestContext>,
+) -> EventStream {
+    let sse_stream = create_sse_stream(
+        upstream_events(response),
+        openai_req.model.clone(),
+        config.clone(),
+        ctx.clone(),
+    );
+    if config.auto_continue_max > 0 {
+        Box::pin(continuation::stitch_stream(
+            sse_stream, config, client, openai_req, ctx,
+        ))
+    } else {
+        Box::pin(sse_stream)
+    }
+}
 
-        while let Some(chunk) = stream.next().await {
-            match chunk {
-                Ok(bytes) => {
-                    let text = String::from_utf8_lossy(&bytes);
-                    buffer.push_str(&text);
+/// The upstream's body as an event stream. Servers that ignore `stream: true`
+/// answer with a single JSON body instead, which is turned into the chunks a
+/// streaming server would have sent.
+pub(crate) fn upstream_events(
+    response: reqwest::Response,
+) -> Pin<Box<dyn Stream<Item = Result<Bytes, reqwest::Error>> + Send>> {
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        return Box::pin(response.bytes_stream());
+    }
 
-                    while let Some(pos) = buffer.find("\n\n") {
-                        let line = buffer[..pos].to_string();
-                        buffer = buffer[pos + 2..].to_string();
+    tracing::debug!(
+        "Upstream answered a streaming request with a JSON body, synthesizing the stream"
+    );
+    Box::pin(futures::stream::once(async move {
+        let body = response.bytes().await?;
+        Ok(
+            match serde_json::from_slice::<openai::OpenAIResponse>(&body) {
+                Ok(resp) => synthesize_chunks(&resp),
+                Err(e) => {
+                    tracing::warn!("Unparseable JSON body for a streaming request: {}", e);
+                    body
+                }
+            },
+        )
+    }))
+}
 
-                        if line.trim().is_empty() {
-                            continue;
-                        }
+/// Replay a complete response as the stream chunks that would have produced
+/// it, with text split into deltas of `SYNTHETIC_DELTA_CHARS`
+fn synthesize_chunks(resp: &openai::OpenAIResponse) -> Bytes {
+    let chunk = |delta: serde_json::Value| {
+        json!({
+            "id": resp.id,
+            "model": resp.model,
+            "choices": [{"index": 0, "delta": delta}]
+        })
+    };
+    let mut chunks = Vec::new();
+    if let Some(choice) = resp.choices.first() {
+        let message = &choice.message;
+        if let Some(reasoning) = message.reasoning_text() {
+            chunks.push(chunk(json!({ "reasoning": reasoning })));
+        }
+        let mut rest = message.content.as_deref().unwrap_or_default();
+        while !rest.is_empty() {
+            let at = rest
+                .char_indices()
+                .nth(SYNTHETIC_DELTA_CHARS)
+                .map_or(rest.len(), |(i, _)| i);
+            let (piece, tail) = rest.split_at(at);
+            chunks.push(chunk(json!({ "content": piece })));
+            rest = tail;
+        }
+        for (index, call) in message.tool_calls.iter().flatten().enumerate() {
+            chunks.push(chunk(json!({
+                "tool_calls": [{
+                    "index": index,
+                    "id": call.id,
+                    "type": call.call_type,
+                    "function": call.function
+                }]
+            })));
+        }
+        if let Some(call) = &message.function_call {
+            chunks.push(chunk(json!({ "function_call": call })));
+        }
+        let mut last = chunk(json!({}));
+        let finish = &mut last["choices"][0];
+        finish["finish_reason"] = json!(choice.finish_reason.as_deref().unwrap_or("stop"));
+        finish["stop_reason"] = json!(choice.stop_reason);
+        finish["content_filter_results"] = json!(choice.content_filter_results);
+        chunks.push(last);
+    }
+    let mut usage = chunk(json!({}));
+    usage["choices"] = json!([]);
+    usage["usage"] = json!(resp.usage);
+    chunks.push(usage);
+
+    let mut body: String = chunks
+        .iter()
+        .map(|chunk| format!("data: {}\n\n", chunk))
+        .collect();
+    body.push_str("data: [DONE]\n\n");
+    Bytes::from(body)
+}
 
-                        for l in line.lines() {
-                            if let Some(data) = l.strip_prefix("data: ") {
-                                if data.trim() == "[DONE]" {
-                                    let event = json!({"type": "message_stop"});
-                                    let sse_data = format!("event: message_stop\ndata: {}\n\n",
-                                        serde_json::to_string(&event).unwrap_or_default());
-                                    yield Ok(Bytes::from(sse_data));
-                                    continue;
-                                }
-
-                                if let Ok(chunk) = serde_json::from_str::<openai::StreamChunk>(data) {
-                                    if message_id.is_none() {
-                                        if let Some(id) = &chunk.id {
-                                            message_id = Some(id.clone());
-                                        }
-                                    }
-                                    if current_model.is_none() {
-                                        if let Some(model) = &chunk.model {
-                                            current_model = Some(model.clone());
-                                        }
-                                    }
-
-                                    if let Some(choice) = chunk.choices.first() {
-
-                                        if !has_sent_message_start {
-                                            let event = anthropic::StreamEvent::MessageStart {
-                                                message: anthropic::MessageStartData {
-                                                    id: message_id.clone().unwrap_or_else(|| "msg_proxy".to_string()),
-                                                    message_type: "message".to_string(),
-                                                    role: "assistant".to_string(),
-                                                    model: current_model.clone().unwrap_or_else(|| fallback_model.clone()),
-                                                    usage: anthropic::Usage {
-                                                        input_tokens: 0,
-                                                        output_tokens: 0,
-                                                    },
-                                                },
-                                            };
-                                            let sse_data = format!("event: message_start\ndata: {}\n\n",
-                                                serde_json::to_string(&event).unwrap_or_default());
-                                            yield Ok(Bytes::from(sse_data));
-                                            has_sent_message_start = true;
-                                        }
-
-                                        if let Some(reasoning) = &choice.delta.reasoning {
-                                            if current_block_type.is_none() {
-                                                let event = json!({
-                                                    "type": "content_block_start",
-                                                    "index": content_index,
-                                                    "content_block": {
-                                                        "type": "thinking",
-                                                        "thinking": ""
-                                                    }
-                                                });
-                                                let sse_data = format!("event: content_block_start\ndata: {}\n\n",
-                                                    serde_json::to_string(&event).unwrap_or_default());
-                                                yield Ok(Bytes::from(sse_data));
-                                                current_block_type = Some("thinking".to_string());
-                                            }
-
-                                            let event = json!({
-                                                "type": "content_block_delta",
-                                                "index": content_index,
-                                                "delta": {
-                                                    "type": "thinking_delta",
-                                                    "thinking": reasoning
-                                                }
-                                            });
-                                            let sse_data = format!("event: content_block_delta\ndata: {}\n\n",
-                                                serde_json::to_string(&event).unwrap_or_default());
-                                            yield Ok(Bytes::from(sse_data));
-                                        }
-
-                                        if let Some(content) = &choice.delta.content {
-                                            if !content.is_empty() {
-                                                if current_block_type.as_deref() != Some("text") {
-                                                    if current_block_type.is_some() {
-                                                        let event = json!({
-                                                            "type": "content_block_stop",
-                                                            "index": content_index
-                                                        });
-                                                        let sse_data = format!("event: content_block_stop\ndata: {}\n\n",
-                                                            serde_json::to_string(&event).unwrap_or_default());
-                                                        yield Ok(Bytes::from(sse_data));
-                                                        content_index += 1;
-                                                    }
-
-                                                    // Start text block
-                                                    let event = json!({
-                                                        "type": "content_block_start",
-                                                        "index": content_index,
-                                                        "content_block": {
-                                                            "type": "text",
-                                                            "text": ""
-                                                        }
-                                                    });
-                                                    let sse_data = format!("event: content_block_start\ndata: {}\n\n",
-                                                        serde_json::to_string(&event).unwrap_or_default());
-                                                    yield Ok(Bytes::from(sse_data));
-                                                    current_block_type = Some("text".to_string());
-                                                }
-
-                                                // Send text delta
-                                                let event = json!({
-                                                    "type": "content_block_delta",
-                                                    "index": content_index,
-                                                    "delta": {
-                                                        "type": "text_delta",
-                                                        "text": content
-                                                    }
-                                                });
-                                                let sse_data = format!("event: content_block_delta\ndata: {}\n\n",
-                                                    serde_json::to_string(&event).unwrap_or_default());
-                                                yield Ok(Bytes::from(sse_data));
-                                            }
-                                        }
-
-                                        // Handle tool calls
-                                        if let Some(tool_calls) = &choice.delta.tool_calls {
-                                            for tool_call in tool_calls {
-                                                if let Some(id) = &tool_call.id {
-                                                    // Start of new tool call
-                                                    if current_block_type.is_some() {
-                                                        let event = json!({
-                                                            "type": "content_block_stop",
-                                                            "index": content_index
-                                                        });
-                                                        let sse_data = format!("event: content_block_stop\ndata: {}\n\n",
-                                                            serde_json::to_string(&event).unwrap_or_default());
-                                                        yield Ok(Bytes::from(sse_data));
-                                                        content_index += 1;
-                                                    }
-
-                                                    tool_call_id = Some(id.clone());
-                                                    tool_call_args.clear();
-                                                }
-
-                                                if let Some(function) = &tool_call.function {
-                                                    if let Some(name) = &function.name {
-                                                        _tool_call_name = Some(name.clone());
-
-                                                        // Start tool_use block
-                                                        let event = json!({
-                                                            "type": "content_block_start",
-                                                            "index": content_index,
-                                                            "content_block": {
-                                                                "type": "tool_use",
-                                                                "id": tool_call_id.clone().unwrap_or_default(),
-                                                                "name": name
-                                                            }
-                                                        });
-                                                        let sse_data = format!("event: content_block_start\ndata: {}\n\n",
-                                                            serde_json::to_string(&event).unwrap_or_default());
-                                                        yield Ok(Bytes::from(sse_data));
-                                                        current_block_type = Some("tool_use".to_string());
-                                                    }
-
-                                                    if let Some(args) = &function.arguments {
-                                                        tool_call_args.push_str(args);
-
-                                                        // Send input_json_delta
-                                                        let event = json!({
-                                                            "type": "content_block_delta",
-                                                            "index": content_index,
-                                                            "delta": {
-                                                                "type": "input_json_delta",
-                                                                "partial_json": args
-                                                            }
-                                                        });
-                                                        let sse_data = format!("event: content_block_delta\ndata: {}\n\n",
-                                                            serde_json::to_string(&event).unwrap_or_default());
-                                                        yield Ok(Bytes::from(sse_data));
-                                                    }
-                                                }
-                                            }
-                                        }
-
-                                        // Handle finish reason
-                                        if let Some(finish_reason) = &choice.finish_reason {
-                                            // Close current content block
-                                            if current_block_type.is_some() {
-                                                let event = json!({
-                                                    "type": "content_block_stop",
-                                                    "index": content_index
-                                                });
-                                                let sse_data = format!("event: content_block_stop\ndata: {}\n\n",
-                                                    serde_json::to_string(&event).unwrap_or_default());
-                                                yield Ok(Bytes::from(sse_data));
-                                            }
-
-                                            // Send message_delta with stop_reason
-                                            let stop_reason = transform::map_stop_reason(Some(finish_reason));
-                                            let event = json!({
-                                                "type": "message_delta",
-                                                "delta": {
-                                                    "stop_reason": stop_reason,
-                                                    "stop_sequence": serde_json::Value::Null
-                                                },
-                                                "usage": chunk.usage.as_ref().map(|u| json!({
-                                                    "output_tokens": u.completion_tokens
-                                                }))
-                                            });
-                                            let sse_data = format!("event: message_delta\ndata: {}\n\n",
-                                                serde_json::to_string(&event).unwrap_or_default());
-                                            yield Ok(Bytes::from(sse_data));
-                                        }
-                                    }
-                                } else {
-                                    tracing::debug!("Ignoring unrecognized upstream stream chunk: {}", data);
-                                }
+/// Read an upstream chat completion stream and translate it into Anthropic
+/// events, sending keep-alives while the upstream is idle
+pub(crate) fn create_sse_stream<E: Into<StreamFailure> + Send + 'static>(
+    stream: impl Stream<Item = Result<Bytes, E>> + Send + 'static,
+    upstream_model: String,
+    config: Arc<Config>,
+    ctx: Arc<RequestContext>,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send {
+    async_stream::stream! {
+        let mut translator = StreamTranslator::new(upstream_model, &config, &ctx);
+        let mut sse = SseParser::new();
+        tokio::pin!(stream);
+
+        // The upstream's body ended
+        let mut eof = false;
+        let mut last_data = Instant::now();
+        while !translator.is_finished() {
+            let next = if eof {
+                None
+            } else {
+                let idle = idle_event(&config, translator.message_started());
+                // Time left before a silent upstream counts as hung
+                let stall = config
+                    .stream_idle_timeout_secs
+                    .map(|secs| (last_data + Duration::from_secs(secs)).saturating_duration_since(Instant::now()));
+                let wait = match (&idle, stall) {
+                    (Some((interval, _)), Some(stall)) => Some((*interval).min(stall)),
+                    (Some((interval, _)), None) => Some(*interval),
+                    (None, stall) => stall,
+                };
+                match wait {
+                    Some(wait) => match tokio::time::timeout(wait, stream.next()).await {
+                        Ok(next) => next.map(|item| item.map_err(Into::into)),
+                        Err(_) if stall.is_some_and(|stall| stall <= wait) => {
+                            tracing::warn!("Upstream stalled mid-stream, giving up");
+                            Some(Err(StreamFailure {
+                                error_type: "timeout_error",
+                                message: format!(
+                                    "Upstream sent nothing for {}s",
+                                    config.stream_idle_timeout_secs.unwrap_or_default()
+                                ),
+                            }))
+                        }
+                        Err(_) => {
+                            if let Some((_, event)) = idle {
+                                yield Ok(event);
                             }
+                            continue;
                         }
+                    },
+                    None => stream.next().await.map(|item| item.map_err(Into::into)),
+                }
+            };
+
+            let mut events = Vec::new();
+            match next {
+                Some(Ok(bytes)) => {
+                    last_data = Instant::now();
+                    for event in sse.push(&bytes) {
+                        events.extend(translator.push_data(&event.data));
                     }
                 }
-                Err(e) => {
-                    tracing::error!("Stream error: {}", e);
-                    let error_event = json!({
-                        "type": "error",
-                        "error": {
-                            "type": "stream_error",
-                            "message": format!("Stream error: {}", e)
-                        }
-                    });
-                    let sse_data = format!("event: error\ndata: {}\n\n",
-                        serde_json::to_string(&error_event).unwrap_or_default());
-                    yield Ok(Bytes::from(sse_data));
-                    break;
+                Some(Err(StreamFailure { error_type, message })) => {
+                    events.extend(translator.fail(error_type, &message));
+                }
+                None if !eof => {
+                    // An event cut off at EOF is still translated
+                    eof = true;
+                    if let Some(event) = sse.finish() {
+                        events.extend(translator.push_data(&event.data));
+                    }
+                }
+                None => events.extend(translator.finish()),
+            }
+
+            // Buffered tool input, interleaved calls and an unfinished event
+            // grow with the upstream's output; stop before they take too much
+            if let Some(max) = config.stream_block_max_bytes {
+                let held = translator.held_bytes() + sse.buffered();
+                if held > max {
+                    tracing::warn!("Stream block of {} bytes exceeds STREAM_BLOCK_MAX_BYTES ({})", held, max);
+                    let message = format!("Content block exceeds the proxy's limit of {} bytes", max);
+                    events.extend(translator.fail("api_error", &message));
+                }
+            }
+
+            for event in events {
+                yield Ok(event);
+            }
+        }
+    }
+}
+
+/// What to send after the upstream has been idle for how long: liveness
+/// pings as Anthropic sends them, or comments keeping the connection open
+/// where pings can't go. Pings only make sense inside a message.
+fn idle_event(config: &Config, message_started: bool) -> Option<(Duration, Bytes)> {
+    match (config.ping_interval_secs, config.heartbeat_interval_secs) {
+        (Some(secs), _) if message_started => Some((Duration::from_secs(secs), ping_event())),
+        (_, Some(secs)) => Some((
+            Duration::from_secs(secs),
+            Bytes::from_static(b": keep-alive\n\n"),
+        )),
+        _ => None,
+    }
+}
+
+/// Why an upstream body failed partway, as the error type and message of
+/// the `error` event that ends the client's stream
+#[derive(Debug)]
+pub(crate) struct StreamFailure {
+    pub error_type: &'static str,
+    pub message: String,
+}
+
+impl From<reqwest::Error> for StreamFailure {
+    fn from(err: reqwest::Error) -> Self {
+        // The upstream request timeout also bounds the body, so a client
+        // deadline surfaces here mid-stream
+        if err.is_timeout() {
+            tracing::warn!("Stream cut off at the request deadline");
+            Self {
+                error_type: "timeout_error",
+                message: "Request deadline exceeded while streaming".to_string(),
+            }
+        } else {
+            tracing::error!("Stream error: {}", err);
+            Self {
+                error_type: "api_error",
+                message: format!("Stream error: {}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        anthropic_betas, apply_params, create_sse_stream, handle_streaming, is_empty_response,
+        post_upstream, proxy_handler, request_deadline, retry_after, send_upstream, upstream_error,
+        upstream_error_details, upstream_events, RequestContext, RetryPolicy, MAX_UPSTREAM_TIMEOUT,
+    };
+    use crate::accounting::Accounting;
+    use crate::config::{Config, ContentFilterMode, ToolInputStreaming};
+    use crate::models::{anthropic, openai};
+    use crate::routing::ModelRoutes;
+    use crate::transform;
+    use crate::upstream::{UpstreamPool, UpstreamRegistry};
+    use crate::vision::CaptionCache;
+    use axum::http::{header, HeaderMap, StatusCode};
+    use axum::response::IntoResponse;
+    use axum::Extension;
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn deadline_headers_are_parsed_and_capped() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(request_deadline(&headers).unwrap(), None);
+
+        headers.insert("request-timeout", "2.5".parse().unwrap());
+        assert_eq!(
+            request_deadline(&headers).unwrap(),
+            Some(Duration::from_millis(2500))
+        );
+
+        headers.insert("x-proxy-timeout-ms", "900000".parse().unwrap());
+        assert_eq!(
+            request_deadline(&headers).unwrap(),
+            Some(MAX_UPSTREAM_TIMEOUT)
+        );
+
+        headers.insert("x-proxy-timeout-ms", "soon".parse().unwrap());
+        assert!(request_deadline(&headers).is_err());
+    }
+
+    /// Translate upstream chunks for model `r1` into the emitted SSE text
+    async fn translate(config: Config, chunks: &[&str]) -> String {
+        let ctx = RequestContext::detached(&config);
+        translate_with(config, chunks, ctx).await
+    }
+
+    async fn translate_with(config: Config, chunks: &[&str], ctx: RequestContext) -> String {
+        let body: String = chunks.iter().map(|c| format!("data: {}\n\n", c)).collect();
+        let ctx = Arc::new(ctx);
+        let output: Vec<Bytes> = create_sse_stream(
+            futures::stream::iter(vec![Ok::<_, reqwest::Error>(Bytes::from(body))]),
+            "r1".to_string(),
+            Arc::new(config),
+            ctx,
+        )
+        .map(|item| item.unwrap())
+        .collect()
+        .await;
+        String::from_utf8(output.concat()).unwrap()
+    }
+
+    const REASONING_CHUNKS: [&str; 3] = [
+        r#"{"choices":[{"index":0,"delta":{"reasoning":"abcdef"}}]}"#,
+        r#"{"choices":[{"index":0,"delta":{"reasoning":"ghijkl"}}]}"#,
+        r#"{"choices":[{"index":0,"delta":{"content":"done"},"finish_reason":"stop"}]}"#,
+    ];
+
+    #[tokio::test]
+    async fn reasoning_is_cut_at_its_token_limit() {
+        let mut config = Config::for_tests();
+        config.reasoning_limits = vec![("r1".to_string(), 2)];
+        let output = translate(config, &REASONING_CHUNKS).await;
+
+        assert!(output.contains(r#""thinking":"gh""#));
+        assert!(!output.contains("ijkl"));
+        assert!(output.contains(r#"{"index":0,"type":"content_block_stop"}"#));
+        assert!(output.contains(r#""text":"done","type":"text_delta"},"index":1"#));
+    }
+
+    #[tokio::test]
+    async fn hidden_reasoning_is_never_emitted() {
+        let mut config = Config::for_tests();
+        config.hide_thinking_models = vec!["*".to_string()];
+        let output = translate(config, &REASONING_CHUNKS).await;
+
+        assert!(!output.contains("thinking"));
+        assert!(output.contains(r#""text":"done","type":"text_delta"},"index":0"#));
+
+        let mut config = Config::for_tests();
+        config.strip_thinking = true;
+        let output = translate(config, &REASONING_CHUNKS).await;
+        assert!(!output.contains("thinking"));
+    }
+
+    #[tokio::test]
+    async fn every_reasoning_field_variant_becomes_thinking() {
+        let chunks = [
+            r#"{"choices":[{"index":0,"delta":{"reasoning_content":"one "}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"reasoning":"two ","reasoning_details":[{"type":"reasoning.text","text":"two "}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"reasoning_details":[{"type":"reasoning.summary","summary":"three"},{"type":"reasoning.encrypted","data":"x"}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":"done"},"finish_reason":"stop"}]}"#,
+        ];
+        let output = translate(Config::for_tests(), &chunks).await;
+
+        let thinking: String = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+            .filter_map(|event| event["delta"]["thinking"].as_str().map(String::from))
+            .collect();
+        assert_eq!(thinking, "one two three");
+    }
+
+    #[tokio::test]
+    async fn inline_think_tags_become_a_thinking_block() {
+        let chunks = [
+            r#"{"choices":[{"index":0,"delta":{"content":"<think>\nadd"}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":" them</think>\n\n4"}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":" <"},"finish_reason":"stop"}]}"#,
+        ];
+        let mut config = Config::for_tests();
+        config.think_tag_models = vec!["r1".to_string()];
+        let output = translate(config, &chunks).await;
+
+        assert!(output.contains(r#""thinking":"add","type":"thinking_delta"},"index":0"#));
+        assert!(output.contains(r#""thinking":" them","type":"thinking_delta"},"index":0"#));
+        assert!(output.contains(r#""text":"4","type":"text_delta"},"index":1"#));
+        assert!(output.contains(r#""text":" <","type":"text_delta"},"index":1"#));
+        assert!(!output.contains("think>"));
+    }
+
+    #[tokio::test]
+    async fn oversized_blocks_end_the_stream() {
+        let mut config = Config::for_tests();
+        config.stream_block_max_bytes = Some(16);
+        let chunks = [
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"c1","function":{"name":"f","arguments":"{\"a\":\"0123456789"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"0123456789\"}"}}]}}]}"#,
+        ];
+        let output = translate(config, &chunks).await;
+
+        assert!(!output.contains("input_json_delta"));
+        assert!(output.contains(r#""type":"api_error""#));
+        assert!(output.contains("exceeds the proxy's limit of 16 bytes"));
+        assert!(output.ends_with("event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n"));
+    }
+
+    #[tokio::test]
+    async fn tool_input_streams_partially_only_under_the_fine_grained_beta() {
+        let chunks = [
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"c1","function":{"name":"f","arguments":"{\"a\":"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"1}"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}]}"#,
+        ];
+
+        let output = translate(Config::for_tests(), &chunks).await;
+        assert!(output.contains(r#""partial_json":"{\"a\":1}""#));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "anthropic-beta",
+            "token-efficient-tools-2025-02-19, fine-grained-tool-streaming-2025-05-14"
+                .parse()
+                .unwrap(),
+        );
+        let config = Config::for_tests();
+        let mut ctx = RequestContext::detached(&config);
+        ctx.betas = anthropic_betas(&headers);
+        let output = translate_with(config, &chunks, ctx).await;
+        assert!(output.contains(r#""partial_json":"{\"a\":""#));
+        assert!(output.contains(r#""partial_json":"1}""#));
+
+        // Clients that can't parse partial input can have it whole regardless
+        let mut config = Config::for_tests();
+        config.tool_input_streaming = ToolInputStreaming::Buffered;
+        let mut ctx = RequestContext::detached(&config);
+        ctx.betas = anthropic_betas(&headers);
+        let output = translate_with(config, &chunks, ctx).await;
+        assert!(output.contains(r#""partial_json":"{\"a\":1}""#));
+    }
+
+    #[tokio::test]
+    async fn streamed_function_calls_become_tool_use() {
+        let chunks = [
+            r#"{"id":"chatcmpl-9","choices":[{"index":0,"delta":{"function_call":{"name":"f","arguments":""}}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"function_call":{"arguments":"{\"a\":1}"}}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{},"finish_reason":"function_call"}]}"#,
+        ];
+        let output = translate(Config::for_tests(), &chunks).await;
+
+        assert!(output.contains(r#""id":"toolu_chatcmpl-9","name":"f","type":"tool_use""#));
+        assert!(output.contains(r#""partial_json":"{\"a\":1}""#));
+        assert!(output.contains(r#""stop_reason":"tool_use""#));
+    }
+
+    #[tokio::test]
+    async fn interleaved_tool_calls_get_their_own_blocks() {
+        let chunks = [
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"c1","function":{"name":"f","arguments":"{\"a\":"}},{"index":1,"id":"c2","function":{"name":"g","arguments":"{\"b\":"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":1,"function":{"arguments":"2}"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"c1","function":{"arguments":"1}"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}]}"#,
+        ];
+        let output = translate(Config::for_tests(), &chunks).await;
+
+        assert!(output.contains(r#""id":"c1","name":"f","type":"tool_use"},"index":0"#));
+        assert!(
+            output.contains(r#""partial_json":"{\"a\":1}","type":"input_json_delta"},"index":0"#)
+        );
+        assert!(output.contains(r#""id":"c2","name":"g","type":"tool_use"},"index":1"#));
+        assert!(
+            output.contains(r#""partial_json":"{\"b\":2}","type":"input_json_delta"},"index":1"#)
+        );
+        assert_eq!(output.matches("content_block_stop").count(), 4);
+    }
+
+    #[tokio::test]
+    async fn the_requested_model_can_be_reported() {
+        let chunks = [
+            r#"{"model":"deepseek/deepseek-r1","choices":[{"index":0,"delta":{"content":"hi"},"finish_reason":"stop"}]}"#,
+        ];
+        let output = translate(Config::for_tests(), &chunks).await;
+        assert!(output.contains(r#""model":"deepseek/deepseek-r1""#));
+
+        let config = Config::for_tests();
+        let mut ctx = RequestContext::detached(&config);
+        ctx.reported_model = Some("claude-sonnet-4-5".to_string());
+        let output = translate_with(config, &chunks, ctx).await;
+        assert!(output.contains(r#""model":"claude-sonnet-4-5""#));
+        assert!(!output.contains("deepseek"));
+    }
+
+    #[tokio::test]
+    async fn usage_sent_after_the_finish_reason_is_reported() {
+        let chunks = [
+            r#"{"choices":[{"index":0,"delta":{"content":"hi"}}],"usage":{"prompt_tokens":12,"completion_tokens":1,"total_tokens":13}}"#,
+            r#"{"choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+            r#"{"choices":[],"usage":{"prompt_tokens":12,"completion_tokens":5,"total_tokens":17}}"#,
+            "[DONE]",
+        ];
+        let output = translate(Config::for_tests(), &chunks).await;
+
+        assert!(output.contains(r#""usage":{"input_tokens":12,"output_tokens":1}"#));
+        assert!(output.contains(
+            r#""stop_reason":"end_turn","stop_sequence":null},"type":"message_delta","usage":{"input_tokens":12,"output_tokens":5}"#
+        ));
+        assert!(output.contains(r#""input_tokens":12,"output_tokens":5,"upstream_model""#));
+    }
+
+    #[tokio::test]
+    async fn failing_upstreams_still_yield_a_whole_message() {
+        let error = reqwest::Client::new()
+            .get("not a url")
+            .send()
+            .await
+            .unwrap_err();
+        let config = Config::for_tests();
+        let ctx = Arc::new(RequestContext::detached(&config));
+        let output: Vec<Bytes> = create_sse_stream(
+            futures::stream::iter(vec![Err(error)]),
+            "r1".to_string(),
+            Arc::new(config),
+            ctx,
+        )
+        .map(|item| item.unwrap())
+        .collect()
+        .await;
+        let output = String::from_utf8(output.concat()).unwrap();
+
+        let events: Vec<&str> = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("event: "))
+            .collect();
+        assert_eq!(events, ["message_start", "error", "message_stop"]);
+        assert!(output.contains(r#""model":"r1""#));
+    }
+
+    #[tokio::test]
+    async fn streams_cut_off_at_eof_are_completed() {
+        let events = |output: &str| -> Vec<String> {
+            output
+                .lines()
+                .filter_map(|line| line.strip_prefix("event: "))
+                .map(String::from)
+                .collect()
+        };
+
+        let chunks = [r#"{"choices":[{"index":0,"delta":{"content":"partial"}}]}"#];
+        let output = translate(Config::for_tests(), &chunks).await;
+        assert_eq!(
+            events(&output),
+            [
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop"
+            ]
+        );
+        assert!(output.contains(r#""stop_reason":"end_turn""#));
+
+        let chunks = [
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"c1","function":{"name":"f","arguments":"{\"a\":1}"}}]}}]}"#,
+        ];
+        let output = translate(Config::for_tests(), &chunks).await;
+        assert!(output.contains(r#""partial_json":"{\"a\":1}""#));
+        assert!(output.contains(r#""stop_reason":"tool_use""#));
+        assert!(output.ends_with("event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n"));
+
+        let output = translate(Config::for_tests(), &[]).await;
+        assert_eq!(events(&output), ["message_start", "error", "message_stop"]);
+    }
+
+    #[tokio::test]
+    async fn json_bodies_for_streaming_requests_are_replayed_as_a_stream() {
+        let text = "word ".repeat(20);
+        let body = serde_json::json!({
+            "id": "chatcmpl-7",
+            "model": "r1",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": text,
+                    "tool_calls": [{"id": "c1", "type": "function", "function": {"name": "f", "arguments": "{}"}}]
+                },
+                "finish_reason": "tool_calls"
+            }],
+            "usage": {"prompt_tokens": 5, "completion_tokens": 30, "total_tokens": 35}
+        });
+        let response = reqwest::Response::from(
+            axum::http::Response::builder()
+                .header("content-type", "application/json")
+                .body(body.to_string())
+                .unwrap(),
+        );
+
+        let config = Config::for_tests();
+        let ctx = Arc::new(RequestContext::detached(&config));
+        let output: Vec<Bytes> = create_sse_stream(
+            upstream_events(response),
+            "r1".to_string(),
+            Arc::new(config),
+            ctx,
+        )
+        .map(|item| item.unwrap())
+        .collect()
+        .await;
+        let output = String::from_utf8(output.concat()).unwrap();
+
+        assert!(output.starts_with("event: message_start"));
+        assert_eq!(output.matches(r#""type":"text_delta""#).count(), 2);
+        assert!(output.contains(r#""id":"c1","name":"f","type":"tool_use""#));
+        assert!(output.contains(
+            r#""stop_reason":"tool_use","stop_sequence":null},"type":"message_delta","usage":{"input_tokens":5,"output_tokens":30}"#
+        ));
+        assert!(output.ends_with("\"type\":\"message_stop\"}\n\n"));
+    }
+
+    #[tokio::test]
+    async fn early_message_start_precedes_upstream_failures() {
+        let mut config = Config::for_tests();
+        config.early_message_start = true;
+        config.base_url = "http://127.0.0.1:1".to_string();
+        let ctx = RequestContext::detached(&config);
+        let req: anthropic::AnthropicRequest = serde_json::from_value(serde_json::json!({
+            "model": "r1",
+            "max_tokens": 100,
+            "stream": true,
+            "messages": [{"role": "user", "content": "Hello there"}]
+        }))
+        .unwrap();
+        let openai_req = transform::anthropic_to_openai(req, &config).unwrap();
+
+        let response = handle_streaming(Arc::new(config), reqwest::Client::new(), openai_req, ctx)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let output: Vec<Bytes> = response
+            .into_body()
+            .into_data_stream()
+            .map(|item| item.unwrap())
+            .collect()
+            .await;
+        let output = String::from_utf8(output.concat()).unwrap();
+
+        let events: Vec<&str> = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("event: "))
+            .collect();
+        assert_eq!(events, ["message_start", "error", "message_stop"]);
+        assert!(output.contains(r#""id":"msg_proxy_"#));
+        assert!(!output.contains(r#""input_tokens":0"#));
+    }
+
+    #[tokio::test]
+    async fn unavailable_upstreams_are_retried_before_streaming() {
+        let attempts = Arc::new(AtomicU64::new(0));
+        let counter = attempts.clone();
+        let app = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(move || {
+                let counter = counter.clone();
+                async move {
+                    if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                    }
+                    let body = concat!(
+                        "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hi\"}}]}\n\n",
+                        "data: {\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n",
+                        "data: [DONE]\n\n",
+                    );
+                    ([(header::CONTENT_TYPE, "text/event-stream")], body).into_response()
                 }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = Config::for_tests();
+        config.base_url = format!("http://{}", addr);
+        config.upstream_retries = 1;
+        let ctx = RequestContext::detached(&config);
+        let req: anthropic::AnthropicRequest = serde_json::from_value(serde_json::json!({
+            "model": "r1",
+            "max_tokens": 100,
+            "stream": true,
+            "messages": [{"role": "user", "content": "Hello there"}]
+        }))
+        .unwrap();
+        let openai_req = transform::anthropic_to_openai(req, &config).unwrap();
+
+        let response = handle_streaming(Arc::new(config), reqwest::Client::new(), openai_req, ctx)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let output: Vec<Bytes> = response
+            .into_body()
+            .into_data_stream()
+            .map(|item| item.unwrap())
+            .collect()
+            .await;
+        let output = String::from_utf8(output.concat()).unwrap();
+        assert!(output.contains(r#""text":"Hi""#));
+        assert!(!output.contains("event: error"));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn upstream_statuses_map_to_anthropic_error_types() {
+        let cases = [
+            (400, "invalid_request_error"),
+            (401, "authentication_error"),
+            (403, "permission_error"),
+            (429, "rate_limit_error"),
+            (500, "api_error"),
+            (503, "overloaded_error"),
+            (529, "overloaded_error"),
+        ];
+        for (status, error_type) in cases {
+            let status = StatusCode::from_u16(status).unwrap();
+            let err = upstream_error(status, "http://upstream", "", None, None);
+            assert_eq!(err.error_type(), error_type, "{}", status);
+        }
+        let response = upstream_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "http://upstream",
+            "",
+            None,
+            None,
+        )
+        .into_response();
+        assert_eq!(response.status().as_u16(), 529);
+    }
+
+    #[test]
+    fn upstream_error_bodies_keep_their_details() {
+        let body = r#"{"error":{"message":"Provider returned error","code":429,"metadata":{"provider_name":"DeepInfra","raw":"slow down"}}}"#;
+        let (message, details) = upstream_error_details(StatusCode::TOO_MANY_REQUESTS, body);
+        assert_eq!(message, "Provider returned error: slow down");
+        assert_eq!(details.status, 429);
+        assert_eq!(details.code.as_deref(), Some("429"));
+        assert_eq!(details.provider.as_deref(), Some("DeepInfra"));
+
+        let body = r#"{"error":{"message":"Bad key","type":"invalid_request_error","code":"invalid_api_key"}}"#;
+        let (message, details) = upstream_error_details(StatusCode::UNAUTHORIZED, body);
+        assert_eq!(message, "Bad key");
+        assert_eq!(details.code.as_deref(), Some("invalid_api_key"));
+        assert_eq!(details.error_type.as_deref(), Some("invalid_request_error"));
+        assert_eq!(details.provider, None);
+
+        let (message, _) = upstream_error_details(StatusCode::BAD_GATEWAY, "<html>502</html>");
+        assert_eq!(message, "<html>502</html>");
+    }
+
+    #[test]
+    fn rate_limit_waits_come_from_any_reset_header() {
+        let headers = |pairs: &[(&'static str, &str)]| {
+            let mut headers = reqwest::header::HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, value.parse().unwrap());
             }
+            headers
+        };
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "3")])),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            retry_after(&headers(&[("retry-after-ms", "250"), ("retry-after", "1")])),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            retry_after(&headers(&[
+                ("x-ratelimit-reset-requests", "1m30s"),
+                ("x-ratelimit-reset-tokens", "20ms")
+            ])),
+            Some(Duration::from_secs(90))
+        );
+        let in_a_minute =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + Duration::from_secs(60);
+        let wait = retry_after(&headers(&[(
+            "x-ratelimit-reset",
+            &in_a_minute.as_millis().to_string(),
+        )]))
+        .unwrap();
+        assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60));
+        assert_eq!(retry_after(&headers(&[("retry-after", "inf")])), None);
+        assert_eq!(retry_after(&headers(&[])), None);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_are_waited_out_within_the_budget() {
+        let attempts = Arc::new(AtomicU64::new(0));
+        let counter = attempts.clone();
+        let app = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(move || {
+                let counter = counter.clone();
+                async move {
+                    if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                        return (
+                            StatusCode::TOO_MANY_REQUESTS,
+                            [(header::RETRY_AFTER, "1")],
+                            "slow down",
+                        )
+                            .into_response();
+                    }
+                    StatusCode::OK.into_response()
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = Config::for_tests();
+        config.base_url = format!("http://{}", addr);
+        let client = reqwest::Client::new();
+        let ctx = RequestContext::detached(&config);
+        let body = serde_json::json!({});
+
+        // Without a budget the client gets the rate limit and when to retry
+        let err = post_upstream(&config, &client, &ctx, &body, "r1", false)
+            .await
+            .unwrap_err();
+        assert_eq!(err.error_type(), "rate_limit_error");
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        attempts.store(0, Ordering::SeqCst);
+        config.rate_limit_retry_secs = Some(2);
+        config.upstream_retries = 1;
+        let ctx = RequestContext::detached(&config);
+        let response = post_upstream(&config, &client, &ctx, &body, "r1", false)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn retry_delays_back_off_with_bounded_jitter() {
+        let mut config = Config::for_tests();
+        config.upstream_retries = 3;
+        config.upstream_retry_backoff_ms = 100;
+        config.upstream_retry_jitter = 0.5;
+        config.upstream_retry_statuses = vec![429, 503];
+        let ctx = RequestContext::detached(&config);
+        let retry = RetryPolicy::new(&config);
+
+        assert!(retry.retries_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!retry.retries_status(StatusCode::BAD_GATEWAY));
+        for (attempt, base) in [(0, 100), (1, 200), (2, 400)] {
+            let delay = retry.delay(attempt, &ctx).unwrap();
+            assert!(delay >= Duration::from_millis(base / 2));
+            assert!(delay <= Duration::from_millis(base * 3 / 2));
+        }
+        assert_eq!(retry.delay(3, &ctx), None);
+    }
+
+    #[tokio::test]
+    async fn immediate_rate_limit_resets_still_use_up_retries() {
+        let attempts = Arc::new(AtomicU64::new(0));
+        let counter = attempts.clone();
+        let app = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async {
+                    (
+                        StatusCode::TOO_MANY_REQUESTS,
+                        [(header::RETRY_AFTER, "0")],
+                        "slow down",
+                    )
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = Config::for_tests();
+        config.base_url = format!("http://{}", addr);
+        config.rate_limit_retry_secs = Some(60);
+        config.upstream_retries = 2;
+        config.upstream_retry_backoff_ms = 10;
+        let client = reqwest::Client::new();
+        let ctx = RequestContext::detached(&config);
+
+        let started = Instant::now();
+        let err = post_upstream(&config, &client, &ctx, &serde_json::json!({}), "r1", false)
+            .await
+            .unwrap_err();
+        assert_eq!(err.error_type(), "rate_limit_error");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[tokio::test]
+    async fn unknown_models_fall_back_in_order() {
+        let app = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(|body: axum::Json<serde_json::Value>| async move {
+                let model = body["model"].as_str().unwrap_or_default().to_string();
+                if model != "r2" {
+                    let error = serde_json::json!({"error": {
+                        "message": format!("The model `{}` does not exist", model),
+                    }});
+                    return (StatusCode::NOT_FOUND, axum::Json(error)).into_response();
+                }
+                StatusCode::OK.into_response()
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = Config::for_tests();
+        config.base_url = format!("http://{}", addr);
+        let client = reqwest::Client::new();
+        let ctx = RequestContext::detached(&config);
+        let req: anthropic::AnthropicRequest = serde_json::from_value(serde_json::json!({
+            "model": "r1",
+            "max_tokens": 100,
+            "messages": [{"role": "user", "content": "Hello there"}]
+        }))
+        .unwrap();
+        let openai_req = transform::anthropic_to_openai(req, &config).unwrap();
+
+        let err = send_upstream(&config, &client, &ctx, &openai_req)
+            .await
+            .unwrap_err();
+        assert_eq!(err.error_type(), "not_found_error");
+
+        config.fallback_models = vec!["r1-old".to_string(), "r2".to_string()];
+        let response = send_upstream(&config, &client, &ctx, &openai_req)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn failover_chains_move_on_after_retryable_errors() {
+        let down = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(|| async { StatusCode::SERVICE_UNAVAILABLE }),
+        );
+        let up = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(|body: axum::Json<serde_json::Value>| async move {
+                axum::Json(serde_json::json!({
+                    "model": body["model"],
+                    "choices": [{"message": {"role": "assistant", "content": "hi"}, "finish_reason": "stop"}],
+                    "usage": {"prompt_tokens": 3, "completion_tokens": 1, "total_tokens": 4}
+                }))
+            }),
+        );
+        let mut addrs = Vec::new();
+        for app in [down, up] {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addrs.push(listener.local_addr().unwrap());
+            tokio::spawn(async move { axum::serve(listener, app).await });
         }
+
+        let mut config = Config::for_tests();
+        let routes = serde_json::json!({
+            "upstreams": {
+                "primary": {"base_url": format!("http://{}", addrs[0])},
+                "backup": {"base_url": format!("http://{}", addrs[1])}
+            },
+            "routes": [{"model": "claude*", "failover": [
+                {"upstream": "primary"},
+                {"upstream": "backup", "model": "backup-model"}
+            ]}]
+        });
+        let routes = ModelRoutes::parse(&routes.to_string(), &config).unwrap();
+        config.upstream_routes = Some(Arc::new(routes));
+        let pool = UpstreamPool::new(config.upstream_urls());
+
+        let response = proxy_handler(
+            Extension(Arc::new(config)),
+            Extension(reqwest::Client::new()),
+            Extension(Arc::new(Accounting::default())),
+            Extension(Arc::new(UpstreamRegistry::new(pool))),
+            Extension(Arc::new(CaptionCache::default())),
+            HeaderMap::new(),
+            Ok(axum::Json(serde_json::json!({
+                "model": "claude-sonnet-4-5",
+                "max_tokens": 100,
+                "messages": [{"role": "user", "content": "Hello"}]
+            }))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.headers()["x-proxy-upstream"], "backup");
+        assert_eq!(response.headers()["x-proxy-upstream-model"], "backup-model");
+    }
+
+    #[test]
+    fn rule_params_replace_fields_and_extras() {
+        let req: anthropic::AnthropicRequest = serde_json::from_value(serde_json::json!({
+            "model": "claude-opus-4-1",
+            "max_tokens": 100,
+            "temperature": 1.0,
+            "messages": [{"role": "user", "content": "Hello"}]
+        }))
+        .unwrap();
+        let mut openai_req = transform::anthropic_to_openai(req, &Config::for_tests()).unwrap();
+        let params = serde_json::json!({"temperature": 0.2, "reasoning_effort": "high"});
+        apply_params(&mut openai_req, params.as_object().unwrap()).unwrap();
+
+        assert_eq!(openai_req.temperature, Some(0.2));
+        let body = serde_json::to_string(&openai_req).unwrap();
+        assert_eq!(body.matches("temperature").count(), 1);
+        assert!(body.contains(r#""reasoning_effort":"high""#));
+    }
+
+    #[tokio::test]
+    async fn override_headers_need_an_allowed_key() {
+        let app = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(|body: axum::Json<serde_json::Value>| async move {
+                axum::Json(serde_json::json!({
+                    "model": body["model"],
+                    "choices": [{"message": {"role": "assistant", "content": "hi"}, "finish_reason": "stop"}]
+                }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = Config::for_tests();
+        config.base_url = format!("http://{}", addr);
+        config.routing_override_keys = vec!["sk-debug".to_string()];
+        let config = Arc::new(config);
+        let send = |key: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-api-key", key.parse().unwrap());
+            headers.insert("x-proxy-model", "other-model".parse().unwrap());
+            proxy_handler(
+                Extension(config.clone()),
+                Extension(reqwest::Client::new()),
+                Extension(Arc::new(Accounting::default())),
+                Extension(Arc::new(UpstreamRegistry::new(UpstreamPool::new(
+                    config.upstream_urls(),
+                )))),
+                Extension(Arc::new(CaptionCache::default())),
+                headers,
+                Ok(axum::Json(serde_json::json!({
+                    "model": "claude-sonnet-4-5",
+                    "max_tokens": 100,
+                    "messages": [{"role": "user", "content": "Hello"}]
+                }))),
+            )
+        };
+
+        let err = send("sk-other").await.unwrap_err();
+        assert_eq!(err.error_type(), "permission_error");
+        let response = send("sk-debug").await.unwrap();
+        assert_eq!(response.headers()["x-proxy-upstream-model"], "other-model");
+    }
+
+    #[tokio::test]
+    async fn idle_upstreams_get_heartbeats_and_pings() {
+        let mut config = Config::for_tests();
+        config.ping_interval_secs = Some(1);
+        config.heartbeat_interval_secs = Some(1);
+        let ctx = Arc::new(RequestContext::detached(&config));
+        let upstream = async_stream::stream! {
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            yield Ok::<_, reqwest::Error>(Bytes::from(
+                "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"slow\"}}]}\n\n",
+            ));
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            yield Ok(Bytes::from(
+                "data: {\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n",
+            ));
+        };
+        let output: Vec<Bytes> =
+            create_sse_stream(upstream, "r1".to_string(), Arc::new(config), ctx)
+                .map(|item| item.unwrap())
+                .collect()
+                .await;
+        let output = String::from_utf8(output.concat()).unwrap();
+
+        // A comment before the message starts; then one ping right after
+        // message_start and one for the idle second
+        assert!(output.starts_with(": keep-alive\n\nevent: message_start"));
+        assert_eq!(output.matches("keep-alive").count(), 1);
+        assert_eq!(output.matches("event: ping").count(), 2);
+        assert!(output.find("event: ping").unwrap() > output.find("message_start").unwrap());
+        assert!(output.contains(r#""stop_reason":"end_turn""#));
+    }
+
+    #[tokio::test]
+    async fn stalled_upstreams_time_out_mid_stream() {
+        let mut config = Config::for_tests();
+        config.stream_idle_timeout_secs = Some(1);
+        let ctx = Arc::new(RequestContext::detached(&config));
+        let upstream = async_stream::stream! {
+            yield Ok::<_, reqwest::Error>(Bytes::from(
+                "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"partial\"}}]}\n\n",
+            ));
+            futures::future::pending::<()>().await;
+        };
+        let output: Vec<Bytes> =
+            create_sse_stream(upstream, "r1".to_string(), Arc::new(config), ctx)
+                .map(|item| item.unwrap())
+                .collect()
+                .await;
+        let output = String::from_utf8(output.concat()).unwrap();
+
+        assert!(output.contains(r#""text":"partial""#));
+        assert!(output.contains(r#""type":"timeout_error""#));
+        assert!(output.ends_with("event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n"));
+    }
+
+    #[tokio::test]
+    async fn emulated_stop_sequences_end_the_stream() {
+        let config = Config::for_tests();
+        let mut ctx = RequestContext::detached(&config);
+        ctx.watched_stops = vec!["DONE".to_string()];
+        let chunks = [
+            r#"{"choices":[{"index":0,"delta":{"content":"one DO"}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":"NE two"}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":" three"},"finish_reason":"stop"}]}"#,
+        ];
+        let output = translate_with(config, &chunks, ctx).await;
+
+        assert!(output.contains(r#""text":"one ","type":"text_delta""#));
+        assert!(!output.contains("two"));
+        assert!(output.contains(r#""stop_reason":"stop_sequence","stop_sequence":"DONE""#));
+        assert_eq!(output.matches("message_stop").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn content_filter_stops_become_refusals_or_errors() {
+        let chunks = [
+            r#"{"choices":[{"index":0,"delta":{"content":"Sure"}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{},"finish_reason":"content_filter","content_filter_results":{"violence":{"filtered":true,"severity":"high"}}}]}"#,
+        ];
+        let output = translate(Config::for_tests(), &chunks).await;
+        assert!(output.contains(r#""stop_reason":"refusal""#));
+
+        let mut config = Config::for_tests();
+        config.content_filter = ContentFilterMode::Error;
+        let output = translate(config, &chunks).await;
+        assert!(output.contains("event: error"));
+        assert!(!output.contains("message_delta"));
+        assert!(output.ends_with("event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n"));
+    }
+
+    #[test]
+    fn responses_without_content_or_tool_calls_are_empty() {
+        let response = |choices: serde_json::Value| {
+            serde_json::from_value(serde_json::json!({
+                "choices": choices,
+                "usage": {"prompt_tokens": 1, "completion_tokens": 0, "total_tokens": 1}
+            }))
+            .unwrap()
+        };
+        let message = |message: serde_json::Value| {
+            response(serde_json::json!([{"index": 0, "message": message, "finish_reason": "stop"}]))
+        };
+
+        assert!(is_empty_response(&response(serde_json::json!([]))));
+        assert!(is_empty_response(&response(serde_json::json!(null))));
+        assert!(is_empty_response(&message(serde_json::json!(null))));
+        let bare: openai::OpenAIResponse =
+            serde_json::from_value(serde_json::json!({"id": "x"})).unwrap();
+        assert!(is_empty_response(&bare));
+        assert!(is_empty_response(&message(
+            serde_json::json!({"role": "assistant", "content": "  "})
+        )));
+        assert!(!is_empty_response(&message(
+            serde_json::json!({"role": "assistant", "content": "pong"})
+        )));
+        assert!(!is_empty_response(&message(serde_json::json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{"id": "c1", "type": "function", "function": {"name": "f", "arguments": "{}"}}]
+        }))));
     }
 }
@@ -0,0 +1,267 @@
+use crate::config::{Config, UpstreamQuirks};
+use crate::models::openai;
+use crate::think_tags::Delimiters;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Request adjustments a model needs beyond its upstream's quirks profile
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ModelCapabilities {
+    /// Takes the output limit as `max_completion_tokens`
+    pub max_completion_tokens: bool,
+    /// Rejects `temperature` and `top_p`
+    pub no_sampling: bool,
+    /// Takes system prompts as `developer` messages
+    pub developer_role: bool,
+}
+
+impl ModelCapabilities {
+    /// OpenAI's o-series reasoning models need all of them
+    pub const REASONING: Self = Self {
+        max_completion_tokens: true,
+        no_sampling: true,
+        developer_role: true,
+    };
+
+    /// Parse `+`-separated flags, e.g. `max_completion_tokens+no_sampling`,
+    /// or `none`
+    pub fn parse(raw: &str) -> Option<Self> {
+        let mut caps = Self::default();
+        for flag in raw.split('+').map(str::trim) {
+            match flag {
+                "max_completion_tokens" => caps.max_completion_tokens = true,
+                "no_sampling" => caps.no_sampling = true,
+                "developer_role" => caps.developer_role = true,
+                "none" => {}
+                _ => return None,
+            }
+        }
+        Some(caps)
+    }
+}
+
+/// Tags Gemini wraps the thought summaries it includes in the text in
+pub fn gemini_thought_tags() -> Delimiters {
+    Delimiters {
+        open: "<thought>".to_string(),
+        close: "</thought>".to_string(),
+    }
+}
+
+/// Adjust a translated request for the upstream's quirks profile
+pub fn apply(config: &Config, req: &mut openai::OpenAIRequest) {
+    match config.quirks() {
+        UpstreamQuirks::Gemini => apply_gemini(req),
+        UpstreamQuirks::Vllm => apply_vllm(config, req),
+        UpstreamQuirks::Auto | UpstreamQuirks::None => {}
+    }
+    apply_capabilities(config.capabilities_for(&req.model), req);
+}
+
+/// vLLM continues a trailing assistant message only when told to, takes
+/// schemas for guided decoding as `guided_json`, and has sampling parameters
+/// beyond OpenAI's
+fn apply_vllm(config: &Config, req: &mut openai::OpenAIRequest) {
+    if req.messages.last().is_some_and(|m| m.role == "assistant") {
+        req.extra
+            .insert("continue_final_message".to_string(), json!(true));
+        req.extra
+            .insert("add_generation_prompt".to_string(), json!(false));
+    }
+
+    // A forced tool sent as structured output is decoded against its schema
+    let schema = req
+        .response_format
+        .as_ref()
+        .filter(|format| format["type"] == "json_schema")
+        .map(|format| format["json_schema"]["schema"].clone());
+    if let Some(schema) = schema {
+        req.response_format = None;
+        req.extra.insert("guided_json".to_string(), schema);
+    }
+
+    let extras = [
+        ("repetition_penalty", config.vllm_repetition_penalty),
+        ("min_p", config.vllm_min_p),
+    ];
+    for (name, value) in extras {
+        if let Some(value) = value {
+            req.extra.entry(name).or_insert_with(|| json!(value));
+        }
+    }
+}
+
+fn apply_capabilities(caps: ModelCapabilities, req: &mut openai::OpenAIRequest) {
+    if caps.max_completion_tokens {
+        if let Some(max_tokens) = req.max_tokens.take() {
+            req.extra
+                .entry("max_completion_tokens")
+                .or_insert_with(|| json!(max_tokens));
+        }
+    }
+    if caps.no_sampling {
+        req.temperature = None;
+        req.top_p = None;
+    }
+    if caps.developer_role {
+        for message in &mut req.messages {
+            if message.role == "system" {
+                message.role = "developer".to_string();
+            }
+        }
+    }
+}
+
+/// Google's OpenAI endpoint takes a thinking budget in its own thinking
+/// config, which can't be combined with `reasoning_effort`, and only knows
+/// the string forms of `tool_choice`
+fn apply_gemini(req: &mut openai::OpenAIRequest) {
+    if let Some(budget) = req.reasoning.take().and_then(|r| r["max_tokens"].as_u64()) {
+        req.reasoning_effort = None;
+        let extra_body = req.extra.entry("extra_body").or_insert_with(|| json!({}));
+        if extra_body.is_object() {
+            extra_body["google"]["thinking_config"] = json!({
+                "thinking_budget": budget,
+                "include_thoughts": true,
+            });
+        }
+    }
+
+    // A named tool is forced by making it the only one, and required
+    if let Some(Value::Object(choice)) = &req.tool_choice {
+        let name = choice
+            .get("function")
+            .and_then(|function| function["name"].as_str())
+            .map(String::from);
+        if let (Some(name), Some(tools)) = (name, req.tools.as_mut()) {
+            tools.retain(|tool| tool.function.name == name);
+        }
+        req.tool_choice = Some(json!("required"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, ModelCapabilities};
+    use crate::config::{Config, UpstreamQuirks};
+    use crate::models::openai::OpenAIRequest;
+    use serde_json::json;
+
+    #[test]
+    fn gemini_requests_use_its_thinking_config_and_tool_choice() {
+        let request = || -> OpenAIRequest {
+            serde_json::from_value(json!({
+                "model": "gemini-2.5-pro",
+                "messages": [{"role": "user", "content": "hi"}],
+                "reasoning": {"max_tokens": 2048},
+                "tools": [
+                    {"type": "function", "function": {"name": "read", "parameters": {}}},
+                    {"type": "function", "function": {"name": "write", "parameters": {}}}
+                ],
+                "tool_choice": {"type": "function", "function": {"name": "write"}}
+            }))
+            .unwrap()
+        };
+        let mut config = Config::for_tests();
+
+        let mut req = request();
+        apply(&config, &mut req);
+        assert!(req.reasoning.is_some());
+
+        config.base_url = "https://generativelanguage.googleapis.com/v1beta/openai".to_string();
+        let mut req = request();
+        apply(&config, &mut req);
+        let req = serde_json::to_value(req).unwrap();
+        assert!(req.get("reasoning").is_none());
+        assert_eq!(
+            req["extra_body"]["google"]["thinking_config"],
+            json!({"thinking_budget": 2048, "include_thoughts": true})
+        );
+        assert_eq!(req["tool_choice"], "required");
+        assert_eq!(req["tools"].as_array().unwrap().len(), 1);
+        assert_eq!(req["tools"][0]["function"]["name"], "write");
+        assert!(!config.forwards_top_k());
+        assert_eq!(
+            config.think_tags_for("gemini-2.5-pro").unwrap().open,
+            "<thought>"
+        );
+    }
+
+    #[test]
+    fn o_series_requests_use_completion_tokens_and_developer_role() {
+        let request = |model: &str| -> OpenAIRequest {
+            serde_json::from_value(json!({
+                "model": model,
+                "messages": [
+                    {"role": "system", "content": "be brief"},
+                    {"role": "user", "content": "hi"}
+                ],
+                "max_tokens": 1024,
+                "temperature": 0.7,
+                "top_p": 0.9
+            }))
+            .unwrap()
+        };
+        let mut config = Config::for_tests();
+
+        let mut req = request("o3-mini");
+        apply(&config, &mut req);
+        let req = serde_json::to_value(req).unwrap();
+        assert_eq!(req["max_completion_tokens"], 1024);
+        assert!(req.get("max_tokens").is_none());
+        assert!(req.get("temperature").is_none() && req.get("top_p").is_none());
+        assert_eq!(req["messages"][0]["role"], "developer");
+
+        let mut req = request("gpt-4.1");
+        apply(&config, &mut req);
+        assert_eq!(req.max_tokens, Some(1024));
+        assert_eq!(req.messages[0].role, "system");
+
+        config.model_capabilities = vec![
+            (
+                "o1-mini".to_string(),
+                ModelCapabilities::parse("max_completion_tokens").unwrap(),
+            ),
+            ("gpt-5*".to_string(), ModelCapabilities::REASONING),
+        ];
+        let mut req = request("o1-mini");
+        apply(&config, &mut req);
+        assert_eq!(req.extra["max_completion_tokens"], 1024);
+        assert_eq!(req.temperature, Some(0.7));
+        assert_eq!(req.messages[0].role, "system");
+        assert_eq!(
+            config.capabilities_for("gpt-5-mini"),
+            ModelCapabilities::REASONING
+        );
+        assert!(ModelCapabilities::parse("no_sampling+fast").is_none());
+    }
+
+    #[test]
+    fn vllm_requests_continue_prefills_and_use_guided_json() {
+        let mut config = Config::for_tests();
+        config.quirks = UpstreamQuirks::Vllm;
+        config.vllm_min_p = Some(0.05);
+        let mut req: OpenAIRequest = serde_json::from_value(json!({
+            "model": "qwen3",
+            "messages": [
+                {"role": "user", "content": "hi"},
+                {"role": "assistant", "content": "{\"answer\":"}
+            ],
+            "response_format": {
+                "type": "json_schema",
+                "json_schema": {"name": "answer", "schema": {"type": "object"}}
+            },
+            "min_p": 0.1
+        }))
+        .unwrap();
+
+        apply(&config, &mut req);
+        let req = serde_json::to_value(req).unwrap();
+        assert_eq!(req["continue_final_message"], true);
+        assert_eq!(req["add_generation_prompt"], false);
+        assert!(req.get("response_format").is_none());
+        assert_eq!(req["guided_json"], json!({"type": "object"}));
+        assert_eq!(req["min_p"], 0.1);
+        assert!(req.get("repetition_penalty").is_none());
+    }
+}
@@ -0,0 +1,584 @@
+use crate::accounting::Accounting;
+use crate::config::Config;
+use crate::error::{ProxyError, ProxyResult};
+use crate::models::openai;
+use crate::proxy::{self, RequestContext};
+use crate::sse::SseParser;
+use crate::tags::RequestTags;
+use crate::upstream::UpstreamRegistry;
+use axum::{
+    body::Body,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use bytes::Bytes;
+use futures::stream::{Stream, StreamExt};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Anthropic requires max_tokens; OpenAI clients often leave it out
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+/// POST /v1/chat/completions: accept an OpenAI request and serve it from an
+/// Anthropic Messages upstream (UPSTREAM_FORMAT=anthropic)
+pub async fn chat_completions_handler(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<Client>,
+    Extension(accounting): Extension<Arc<Accounting>>,
+    Extension(upstreams): Extension<Arc<UpstreamRegistry>>,
+    headers: HeaderMap,
+    Json(req): Json<ChatCompletionRequest>,
+) -> ProxyResult<Response> {
+    let is_streaming = req.request.stream.unwrap_or(false);
+    let include_usage = req
+        .request
+        .stream_options
+        .as_ref()
+        .is_some_and(|o| o.include_usage);
+    let model = req.request.model.clone();
+    tracing::debug!("Received OpenAI request for model: {}", model);
+
+    let ctx = RequestContext {
+        deadline: proxy::request_deadline(&headers)?.map(|timeout| Instant::now() + timeout),
+        tags: RequestTags::from_request(&config, &headers, None),
+        ..RequestContext::for_request(accounting, &upstreams, &headers)
+    };
+
+    let anthropic_req = to_anthropic_request(req)?;
+    if tracing::enabled!(tracing::Level::TRACE) {
+        tracing::trace!(
+            "Transformed Anthropic request: {}",
+            serde_json::to_string_pretty(&anthropic_req).unwrap_or_default()
+        );
+    }
+
+    let response =
+        proxy::post_upstream(&config, &client, &ctx, &anthropic_req, &model, is_streaming).await?;
+
+    if is_streaming {
+        let stream = chunk_stream(response.bytes_stream(), model, config, ctx, include_usage);
+        let headers = [
+            ("Content-Type", "text/event-stream"),
+            ("Cache-Control", "no-cache"),
+        ];
+        return Ok((headers, Body::from_stream(stream)).into_response());
+    }
+
+    let message: Value = response.json().await?;
+    let usage = openai_usage(&message["usage"]);
+    ctx.record_usage(&config, message["model"].as_str().unwrap_or(&model), &usage);
+    Ok(Json(to_openai_response(&message, &model, &usage)).into_response())
+}
+
+/// A chat request as OpenAI clients send it
+#[derive(Deserialize)]
+pub struct ChatCompletionRequest {
+    #[serde(flatten)]
+    request: openai::OpenAIRequest,
+    /// Sent by current OpenAI SDKs in place of max_tokens
+    max_completion_tokens: Option<u32>,
+}
+
+/// Translate an OpenAI chat request into an Anthropic Messages request
+fn to_anthropic_request(req: ChatCompletionRequest) -> ProxyResult<Value> {
+    let ChatCompletionRequest {
+        request: req,
+        max_completion_tokens,
+    } = req;
+    let mut system = Vec::new();
+    let mut messages: Vec<Value> = Vec::new();
+
+    for message in req.messages {
+        let (role, blocks) = match message.role.as_str() {
+            "system" | "developer" => {
+                system.push(content_text(message.content));
+                continue;
+            }
+            "user" => ("user", content_blocks(message.content)),
+            "assistant" => {
+                let mut blocks = content_blocks(message.content);
+                for call in message.tool_calls.unwrap_or_default() {
+                    let input: Value = serde_json::from_str(&call.function.arguments)
+                        .unwrap_or_else(|_| json!({}));
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": call.id,
+                        "name": call.function.name,
+                        "input": input,
+                    }));
+                }
+                ("assistant", blocks)
+            }
+            "tool" => (
+                "user",
+                vec![json!({
+                    "type": "tool_result",
+                    "tool_use_id": message.tool_call_id.unwrap_or_default(),
+                    "content": content_text(message.content),
+                })],
+            ),
+            other => {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "unsupported message role: {}",
+                    other
+                )))
+            }
+        };
+        if blocks.is_empty() {
+            continue;
+        }
+
+        // Anthropic expects alternating turns, so merge consecutive ones
+        match messages.last_mut() {
+            Some(last) if last["role"] == role => {
+                if let Some(content) = last["content"].as_array_mut() {
+                    content.extend(blocks);
+                }
+            }
+            _ => messages.push(json!({ "role": role, "content": blocks })),
+        }
+    }
+
+    let max_tokens = req
+        .max_tokens
+        .or(max_completion_tokens)
+        .unwrap_or(DEFAULT_MAX_TOKENS);
+    let mut anthropic_req = json!({
+        "model": req.model,
+        "messages": messages,
+        "max_tokens": max_tokens,
+    });
+    let fields = anthropic_req
+        .as_object_mut()
+        .expect("request is a JSON object");
+    if !system.is_empty() {
+        fields.insert("system".into(), json!(system.join("\n\n")));
+    }
+    if let Some(temperature) = req.temperature {
+        fields.insert("temperature".into(), json!(temperature));
+    }
+    if let Some(top_p) = req.top_p {
+        fields.insert("top_p".into(), json!(top_p));
+    }
+    if let Some(top_k) = req.top_k {
+        fields.insert("top_k".into(), json!(top_k));
+    }
+    if let Some(stop) = req.stop {
+        fields.insert("stop_sequences".into(), json!(stop));
+    }
+    if let Some(stream) = req.stream {
+        fields.insert("stream".into(), json!(stream));
+    }
+    if let Some(tools) = req.tools {
+        let tools: Vec<Value> = tools
+            .into_iter()
+            .map(|tool| {
+                json!({
+                    "name": tool.function.name,
+                    "description": tool.function.description,
+                    "input_schema": tool.function.parameters,
+                })
+            })
+            .collect();
+        fields.insert("tools".into(), json!(tools));
+    }
+    if let Some(choice) = req.tool_choice.as_ref().and_then(tool_choice) {
+        fields.insert("tool_choice".into(), choice);
+    }
+
+    Ok(anthropic_req)
+}
+
+fn tool_choice(choice: &Value) -> Option<Value> {
+    match choice {
+        Value::String(mode) => match mode.as_str() {
+            "auto" => Some(json!({"type": "auto"})),
+            "required" => Some(json!({"type": "any"})),
+            "none" => Some(json!({"type": "none"})),
+            _ => None,
+        },
+        Value::Object(_) => choice["function"]["name"]
+            .as_str()
+            .map(|name| json!({"type": "tool", "name": name})),
+        _ => None,
+    }
+}
+
+fn content_text(content: Option<openai::MessageContent>) -> String {
+    match content {
+        Some(openai::MessageContent::Text(text)) => text,
+        Some(openai::MessageContent::Parts(parts)) => parts
+            .into_iter()
+            .filter_map(|part| match part {
+                openai::ContentPart::Text { text, .. } => Some(text),
+                openai::ContentPart::ImageUrl { .. } => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => String::new(),
+    }
+}
+
+fn content_blocks(content: Option<openai::MessageContent>) -> Vec<Value> {
+    match content {
+        Some(openai::MessageContent::Text(text)) if text.is_empty() => Vec::new(),
+        Some(openai::MessageContent::Text(text)) => vec![json!({"type": "text", "text": text})],
+        Some(openai::MessageContent::Parts(parts)) => parts
+            .into_iter()
+            .map(|part| match part {
+                openai::ContentPart::Text { text, .. } => json!({"type": "text", "text": text}),
+                openai::ContentPart::ImageUrl { image_url } => image_block(&image_url.url),
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Data URLs become base64 image sources; anything else is passed as a URL source
+fn image_block(url: &str) -> Value {
+    let inline = url
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(";base64,"));
+    match inline {
+        Some((media_type, data)) => json!({
+            "type": "image",
+            "source": {"type": "base64", "media_type": media_type, "data": data},
+        }),
+        None => json!({"type": "image", "source": {"type": "url", "url": url}}),
+    }
+}
+
+fn finish_reason(stop_reason: Option<&str>) -> Option<&'static str> {
+    match stop_reason? {
+        "max_tokens" => Some("length"),
+        "tool_use" => Some("tool_calls"),
+        _ => Some("stop"),
+    }
+}
+
+fn openai_usage(usage: &Value) -> openai::Usage {
+    let cached = usage["cache_read_input_tokens"].as_u64().unwrap_or(0) as u32;
+    let input = usage["input_tokens"].as_u64().unwrap_or(0) as u32 + cached;
+    let output = usage["output_tokens"].as_u64().unwrap_or(0) as u32;
+    openai::Usage {
+        prompt_tokens: input,
+        completion_tokens: output,
+        total_tokens: input + output,
+        prompt_tokens_details: (cached > 0).then_some(openai::PromptTokensDetails {
+            cached_tokens: cached,
+        }),
+    }
+}
+
+fn to_openai_response(message: &Value, model: &str, usage: &openai::Usage) -> Value {
+    let blocks = message["content"].as_array().cloned().unwrap_or_default();
+    let text: String = blocks
+        .iter()
+        .filter_map(|block| block["text"].as_str())
+        .collect();
+    let tool_calls: Vec<Value> = blocks
+        .iter()
+        .filter(|block| block["type"] == "tool_use")
+        .map(|block| {
+            json!({
+                "id": block["id"],
+                "type": "function",
+                "function": {
+                    "name": block["name"],
+                    "arguments": block["input"].to_string(),
+                },
+            })
+        })
+        .collect();
+
+    let mut reply = Map::new();
+    reply.insert("role".into(), json!("assistant"));
+    reply.insert(
+        "content".into(),
+        if text.is_empty() {
+            Value::Null
+        } else {
+            json!(text)
+        },
+    );
+    if !tool_calls.is_empty() {
+        reply.insert("tool_calls".into(), json!(tool_calls));
+    }
+
+    json!({
+        "id": message["id"],
+        "object": "chat.completion",
+        "created": now(),
+        "model": message["model"].as_str().unwrap_or(model),
+        "choices": [{
+            "index": 0,
+            "message": reply,
+            "finish_reason": finish_reason(message["stop_reason"].as_str()),
+        }],
+        "usage": usage,
+    })
+}
+
+/// Translate an Anthropic event stream into OpenAI `chat.completion.chunk`s
+fn chunk_stream(
+    stream: impl Stream<Item = Result<Bytes, reqwest::Error>> + Send + 'static,
+    model: String,
+    config: Arc<Config>,
+    ctx: RequestContext,
+    include_usage: bool,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send {
+    async_stream::stream! {
+        let mut parser = SseParser::new();
+        let mut id = String::from("chatcmpl-proxy");
+        let mut model = model;
+        // Usage from message_start, updated by message_delta
+        let mut usage_so_far = Map::new();
+        // Anthropic content block index -> OpenAI tool call index
+        let mut tool_indices: HashMap<u64, usize> = HashMap::new();
+        let created = now();
+
+        tokio::pin!(stream);
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    tracing::error!("Stream error: {}", err);
+                    yield Ok(sse(&json!({"error": {"type": "api_error", "message": err.to_string()}})));
+                    break;
+                }
+            };
+            for event in parser.push(&chunk) {
+                let Ok(data) = serde_json::from_str::<Value>(&event.data) else {
+                    continue;
+                };
+
+                match data["type"].as_str().unwrap_or_default() {
+                    "message_start" => {
+                        if let Some(message_id) = data["message"]["id"].as_str() {
+                            id = message_id.to_string();
+                        }
+                        if let Some(upstream_model) = data["message"]["model"].as_str() {
+                            model = upstream_model.to_string();
+                        }
+                        if let Some(usage) = data["message"]["usage"].as_object() {
+                            usage_so_far = usage.clone();
+                        }
+                        yield Ok(sse(&completion_chunk(&id, created, &model, json!({"role": "assistant", "content": ""}), None)));
+                    }
+                    "content_block_start" if data["content_block"]["type"] == "tool_use" => {
+                        let tool_index = tool_indices.len();
+                        tool_indices.insert(data["index"].as_u64().unwrap_or(0), tool_index);
+                        yield Ok(sse(&completion_chunk(&id, created, &model, json!({"tool_calls": [{
+                            "index": tool_index,
+                            "id": data["content_block"]["id"],
+                            "type": "function",
+                            "function": {"name": data["content_block"]["name"], "arguments": ""},
+                        }]}), None)));
+                    }
+                    "content_block_delta" => {
+                        let delta = &data["delta"];
+                        let out = match delta["type"].as_str().unwrap_or_default() {
+                            "text_delta" => json!({"content": delta["text"]}),
+                            "thinking_delta" => json!({"reasoning": delta["thinking"]}),
+                            "input_json_delta" => {
+                                let block = data["index"].as_u64().unwrap_or(0);
+                                json!({"tool_calls": [{
+                                    "index": tool_indices.get(&block).copied().unwrap_or(0),
+                                    "function": {"arguments": delta["partial_json"]},
+                                }]})
+                            }
+                            _ => continue,
+                        };
+                        yield Ok(sse(&completion_chunk(&id, created, &model, out, None)));
+                    }
+                    "message_delta" => {
+                        let reason = finish_reason(data["delta"]["stop_reason"].as_str());
+                        yield Ok(sse(&completion_chunk(&id, created, &model, json!({}), reason)));
+
+                        if let Some(usage) = data["usage"].as_object() {
+                            usage_so_far.extend(usage.clone());
+                        }
+                        let usage = openai_usage(&Value::Object(usage_so_far.clone()));
+                        ctx.record_usage(&config, &model, &usage);
+                        if include_usage {
+                            yield Ok(sse(&json!({
+                                "id": id,
+                                "object": "chat.completion.chunk",
+                                "created": created,
+                                "model": model,
+                                "choices": [],
+                                "usage": usage,
+                            })));
+                        }
+                    }
+                    "message_stop" => {
+                        yield Ok(Bytes::from("data: [DONE]\n\n"));
+                    }
+                    "error" => {
+                        yield Ok(sse(&json!({"error": data["error"]})));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn completion_chunk(
+    id: &str,
+    created: u64,
+    model: &str,
+    delta: Value,
+    finish_reason: Option<&str>,
+) -> Value {
+    json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": created,
+        "model": model,
+        "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+    })
+}
+
+fn sse(data: &Value) -> Bytes {
+    Bytes::from(format!("data: {}\n\n", data))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk_stream, openai_usage, to_anthropic_request, to_openai_response};
+    use crate::config::Config;
+    use crate::proxy::RequestContext;
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn openai_requests_become_alternating_anthropic_turns() {
+        let req = serde_json::from_value(json!({
+            "model": "claude-sonnet-4",
+            "messages": [
+                {"role": "system", "content": "Be terse."},
+                {"role": "user", "content": "Weather in Paris?"},
+                {"role": "assistant", "content": null, "tool_calls": [{
+                    "id": "call_1", "type": "function",
+                    "function": {"name": "weather", "arguments": "{\"city\":\"Paris\"}"}
+                }]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "18C"},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "And this?"},
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}}
+                ]}
+            ],
+            "tool_choice": "required"
+        }))
+        .unwrap();
+
+        let anthropic = to_anthropic_request(req).unwrap();
+        assert_eq!(anthropic["system"], "Be terse.");
+        assert_eq!(anthropic["max_tokens"], 4096);
+        assert_eq!(anthropic["tool_choice"], json!({"type": "any"}));
+        let messages = anthropic["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["content"][0]["input"], json!({"city": "Paris"}));
+        // The tool result and the following user turn are merged
+        assert_eq!(messages[2]["content"][0]["type"], "tool_result");
+        assert_eq!(
+            messages[2]["content"][2]["source"]["media_type"],
+            "image/png"
+        );
+    }
+
+    #[test]
+    fn anthropic_responses_become_chat_completions() {
+        let message = json!({
+            "id": "msg_1",
+            "model": "claude-sonnet-4",
+            "content": [
+                {"type": "text", "text": "Checking."},
+                {"type": "tool_use", "id": "toolu_1", "name": "weather", "input": {"city": "Paris"}}
+            ],
+            "stop_reason": "tool_use",
+            "usage": {"input_tokens": 10, "output_tokens": 5}
+        });
+        let usage = openai_usage(&message["usage"]);
+
+        let response = to_openai_response(&message, "claude-sonnet-4", &usage);
+        let choice = &response["choices"][0];
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        assert_eq!(choice["message"]["content"], "Checking.");
+        assert_eq!(
+            choice["message"]["tool_calls"][0]["function"]["arguments"],
+            "{\"city\":\"Paris\"}"
+        );
+        assert_eq!(response["usage"]["total_tokens"], 15);
+    }
+
+    #[test]
+    fn current_sdk_request_fields_are_read() {
+        let req = serde_json::from_value(json!({
+            "model": "claude-sonnet-4",
+            "messages": [{"role": "user", "content": "Count."}],
+            "max_completion_tokens": 300,
+            "stop": "END"
+        }))
+        .unwrap();
+
+        let anthropic = to_anthropic_request(req).unwrap();
+        assert_eq!(anthropic["max_tokens"], 300);
+        assert_eq!(anthropic["stop_sequences"], json!(["END"]));
+    }
+
+    #[tokio::test]
+    async fn streamed_usage_matches_non_streaming_usage() {
+        let usage = json!({"input_tokens": 10, "cache_read_input_tokens": 90, "output_tokens": 1});
+        let events = [
+            json!({"type": "message_start", "message": {"id": "msg_1", "model": "claude", "usage": usage}}),
+            json!({"type": "message_delta", "delta": {"stop_reason": "end_turn"}, "usage": {"output_tokens": 5}}),
+            json!({"type": "message_stop"}),
+        ];
+        let body: String = events
+            .iter()
+            .map(|event| {
+                format!(
+                    "event: {}\ndata: {}\n\n",
+                    event["type"].as_str().unwrap(),
+                    event
+                )
+            })
+            .collect();
+        let config = Arc::new(Config::for_tests());
+        let ctx = RequestContext::detached(&config);
+        let upstream = futures::stream::iter([Ok::<_, reqwest::Error>(Bytes::from(body))]);
+
+        let chunks: Vec<_> = chunk_stream(upstream, "claude".into(), config, ctx, true)
+            .map(|chunk| String::from_utf8(chunk.unwrap().to_vec()).unwrap())
+            .collect()
+            .await;
+        let usage_chunk = chunks
+            .iter()
+            .find(|chunk| chunk.contains("\"usage\""))
+            .unwrap();
+        let usage_chunk: serde_json::Value =
+            serde_json::from_str(usage_chunk.trim().strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(usage_chunk["usage"]["prompt_tokens"], 100);
+        assert_eq!(usage_chunk["usage"]["completion_tokens"], 5);
+        assert_eq!(
+            usage_chunk["usage"]["prompt_tokens_details"]["cached_tokens"],
+            90
+        );
+    }
+}
@@ -0,0 +1,478 @@
+use crate::config::{
+    CacheControlMode, Config, SchemaProfile, ThinkingBudgetParams, TopKMode, UpstreamFormat,
+    UpstreamQuirks,
+};
+use crate::proxy::random_fraction;
+use crate::upstream::{CircuitBreaker, StickyTable, UpstreamPool, UpstreamRegistry};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Name selecting UPSTREAM_BASE_URL where a named upstream could be chosen
+pub const DEFAULT_UPSTREAM: &str = "default";
+
+/// Sticky conversations leave a weighted upstream whose health drops below this
+const STICKY_MIN_HEALTH: f64 = 0.5;
+/// Spreads route indexes over the bits of sticky table keys
+const ROUTE_KEY_MIX: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Upstreams and routes as written in the UPSTREAMS_FILE JSON file
+#[derive(Debug, Deserialize)]
+struct RoutesFile {
+    upstreams: BTreeMap<String, ProfileSpec>,
+    #[serde(default)]
+    routes: Vec<RouteSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileSpec {
+    base_url: String,
+    #[serde(default)]
+    format: Option<UpstreamFormat>,
+    #[serde(default)]
+    api_key: Option<String>,
+    /// Environment variable holding the key, to keep it out of the file
+    #[serde(default)]
+    api_key_env: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    schema_profile: Option<SchemaProfile>,
+    #[serde(default)]
+    cache_control: Option<CacheControlMode>,
+    #[serde(default)]
+    thinking_budget_params: Option<ThinkingBudgetParams>,
+    #[serde(default)]
+    top_k: Option<TopKMode>,
+    /// Mark the upstream as OpenRouter, or not, whatever its URL
+    #[serde(default)]
+    openrouter: Option<bool>,
+    #[serde(default)]
+    quirks: Option<UpstreamQuirks>,
+}
+
+/// A route names one upstream, several with weights, or an ordered
+/// failover chain
+#[derive(Debug, Deserialize)]
+struct RouteSpec {
+    model: String,
+    #[serde(default)]
+    upstream: Option<String>,
+    #[serde(default)]
+    upstreams: Vec<TargetSpec>,
+    #[serde(default)]
+    failover: Vec<HopSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TargetSpec {
+    upstream: String,
+    #[serde(default = "default_weight")]
+    weight: f64,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Deserialize)]
+struct HopSpec {
+    upstream: String,
+    /// Upstream model to ask for instead of the usual choice
+    #[serde(default)]
+    model: Option<String>,
+}
+
+/// A named upstream: the configuration its requests are handled with and
+/// its own pool of endpoints
+#[derive(Debug)]
+pub struct RoutedUpstream {
+    pub name: String,
+    pub config: Arc<Config>,
+    pub registry: Arc<UpstreamRegistry>,
+}
+
+/// Where a routed request is sent, in the order to try
+#[derive(Debug)]
+pub struct RouteTarget<'a> {
+    pub upstream: &'a RoutedUpstream,
+    pub model: Option<&'a str>,
+}
+
+#[derive(Debug)]
+enum Targets {
+    /// Upstream indexes and their weights
+    Weighted(Vec<(usize, f64)>),
+    /// Upstream indexes and model overrides, tried in order
+    Failover(Vec<(usize, Option<String>)>),
+}
+
+#[derive(Debug)]
+struct Route {
+    pattern: String,
+    targets: Targets,
+}
+
+/// Named upstreams and the model patterns routed to them; models no route
+/// matches stay on UPSTREAM_BASE_URL
+#[derive(Debug)]
+pub struct ModelRoutes {
+    upstreams: Vec<RoutedUpstream>,
+    routes: Vec<Route>,
+    /// Which weighted upstream each conversation was sent to
+    sticky: Option<Arc<StickyTable>>,
+}
+
+impl ModelRoutes {
+    pub fn load(path: &Path, base: &Config) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read upstreams file {}", path.display()))?;
+        Self::parse(&raw, base)
+            .with_context(|| format!("Invalid upstreams file {}", path.display()))
+    }
+
+    pub(crate) fn parse(raw: &str, base: &Config) -> Result<Self> {
+        let file: RoutesFile = serde_json::from_str(raw)?;
+        let upstreams = file
+            .upstreams
+            .into_iter()
+            .map(|(name, spec)| {
+                let config =
+                    profile_config(base, spec).with_context(|| format!("upstream {}", name))?;
+                let pool = UpstreamPool::new(config.upstream_urls())
+                    .with_circuit_breaker(CircuitBreaker::from_config(&config))
+                    .with_sticky_table(StickyTable::from_config(&config));
+                Ok(RoutedUpstream {
+                    name,
+                    config: Arc::new(config),
+                    registry: Arc::new(UpstreamRegistry::new(pool)),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let index_of = |model: &str, name: &str| {
+            upstreams
+                .iter()
+                .position(|u| u.name == name)
+                .with_context(|| format!("route for {} names unknown upstream {}", model, name))
+        };
+        let routes = file
+            .routes
+            .into_iter()
+            .map(|route| {
+                let model = route.model.as_str();
+                let targets = match (route.upstream, &route.upstreams[..], &route.failover[..]) {
+                    (Some(upstream), [], []) => {
+                        Targets::Weighted(vec![(index_of(model, &upstream)?, default_weight())])
+                    }
+                    (None, targets, []) if !targets.is_empty() => Targets::Weighted(
+                        targets
+                            .iter()
+                            .map(|target| {
+                                if !(target.weight.is_finite() && target.weight > 0.0) {
+                                    bail!(
+                                        "route for {}: weight of {} must be a positive number",
+                                        model,
+                                        target.upstream
+                                    );
+                                }
+                                Ok((index_of(model, &target.upstream)?, target.weight))
+                            })
+                            .collect::<Result<_>>()?,
+                    ),
+                    (None, [], hops) if !hops.is_empty() => Targets::Failover(
+                        hops.iter()
+                            .map(|hop| Ok((index_of(model, &hop.upstream)?, hop.model.clone())))
+                            .collect::<Result<_>>()?,
+                    ),
+                    _ => bail!(
+                        "route for {} must set exactly one of upstream, upstreams or failover",
+                        model
+                    ),
+                };
+                Ok(Route {
+                    pattern: route.model,
+                    targets,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            upstreams,
+            routes,
+            sticky: StickyTable::from_config(base),
+        })
+    }
+
+    /// Where to send a request for a model, by the first matching route:
+    /// one upstream picked by weight, or a failover chain in order. With
+    /// upstream affinity, a conversation stays on the weighted upstream it
+    /// was first sent to while that upstream stays healthy.
+    pub fn route(&self, model: &str, conversation: Option<u64>) -> Option<Vec<RouteTarget<'_>>> {
+        let (index, route) = self
+            .routes
+            .iter()
+            .enumerate()
+            .find(|(_, route)| Config::model_matches(&route.pattern, model))?;
+        Some(match &route.targets {
+            Targets::Weighted(targets) => {
+                let upstream = match (&self.sticky, conversation) {
+                    (Some(sticky), Some(conversation)) if targets.len() > 1 => {
+                        // Routes have separate pins, so one conversation can
+                        // use several routes without them displacing each other
+                        let key = conversation ^ (index as u64 + 1).wrapping_mul(ROUTE_KEY_MIX);
+                        self.pick_sticky(sticky, key, targets)
+                    }
+                    _ => self.pick(targets, random_fraction()),
+                };
+                vec![RouteTarget {
+                    upstream,
+                    model: None,
+                }]
+            }
+            Targets::Failover(hops) => hops
+                .iter()
+                .map(|(index, model)| RouteTarget {
+                    upstream: &self.upstreams[*index],
+                    model: model.as_deref(),
+                })
+                .collect(),
+        })
+    }
+
+    fn pick_sticky(
+        &self,
+        sticky: &StickyTable,
+        key: u64,
+        targets: &[(usize, f64)],
+    ) -> &RoutedUpstream {
+        let pinned = sticky.get(key).and_then(|name| {
+            targets
+                .iter()
+                .map(|&(index, _)| &self.upstreams[index])
+                .find(|upstream| upstream.name == name)
+        });
+        match pinned {
+            Some(upstream) if upstream.registry.current().health() >= STICKY_MIN_HEALTH => upstream,
+            _ => {
+                let upstream = self.pick(targets, random_fraction());
+                sticky.pin(key, &upstream.name);
+                upstream
+            }
+        }
+    }
+
+    /// Weights are scaled by each upstream's health, so traffic shifts away
+    /// from failing upstreams and stops reaching those whose circuits are
+    /// open. If every target is down the configured weights apply as is.
+    fn pick(&self, targets: &[(usize, f64)], fraction: f64) -> &RoutedUpstream {
+        if let [(index, _)] = targets {
+            return &self.upstreams[*index];
+        }
+        let healthy: Vec<(usize, f64)> = targets
+            .iter()
+            .map(|&(index, weight)| {
+                let health = self.upstreams[index].registry.current().health();
+                (index, weight * health)
+            })
+            .collect();
+        let weighted = if healthy.iter().any(|(_, weight)| *weight > 0.0) {
+            &healthy[..]
+        } else {
+            targets
+        };
+
+        let total: f64 = weighted.iter().map(|(_, weight)| weight).sum();
+        let mut point = fraction * total;
+        for &(index, weight) in weighted {
+            if point < weight {
+                return &self.upstreams[index];
+            }
+            point -= weight;
+        }
+        let (index, _) = weighted[weighted.len() - 1];
+        &self.upstreams[index]
+    }
+
+    pub fn upstream(&self, name: &str) -> Option<&RoutedUpstream> {
+        self.upstreams.iter().find(|upstream| upstream.name == name)
+    }
+
+    /// Named upstreams, in name order
+    pub fn upstreams(&self) -> &[RoutedUpstream] {
+        &self.upstreams
+    }
+
+    /// Route patterns and the upstreams each leads to, in order
+    pub fn describe(&self) -> Value {
+        self.routes
+            .iter()
+            .map(|route| {
+                let name = |index: usize| self.upstreams[index].name.as_str();
+                match &route.targets {
+                    Targets::Weighted(targets) => json!({
+                        "model": route.pattern,
+                        "upstreams": targets
+                            .iter()
+                            .map(|&(index, weight)| json!({ "upstream": name(index), "weight": weight }))
+                            .collect::<Vec<_>>(),
+                    }),
+                    Targets::Failover(hops) => json!({
+                        "model": route.pattern,
+                        "failover": hops
+                            .iter()
+                            .map(|(index, model)| json!({ "upstream": name(*index), "model": model }))
+                            .collect::<Vec<_>>(),
+                    }),
+                }
+            })
+            .collect()
+    }
+}
+
+/// The proxy's configuration with the upstream settings of a profile. The
+/// default API key and headers are never sent to a profile's upstream.
+fn profile_config(base: &Config, spec: ProfileSpec) -> Result<Config> {
+    let format = spec.format.unwrap_or(UpstreamFormat::OpenAI);
+    Config::validate_base_url(&spec.base_url, format)?;
+    let api_key = match (spec.api_key, spec.api_key_env) {
+        (Some(key), _) => Some(key),
+        (None, Some(var)) => {
+            Some(std::env::var(&var).with_context(|| format!("{} is not set", var))?)
+        }
+        (None, None) => None,
+    };
+
+    let mut config = base.clone();
+    config.base_url = spec.base_url;
+    config.upstream_format = format;
+    config.api_key = api_key.filter(|key| !key.is_empty());
+    config.upstream_headers = spec.headers.into_iter().collect();
+    Config::validate_headers(&config.upstream_headers)?;
+    config.upstream_routes = None;
+    config.vertex = None;
+    config.openrouter = spec.openrouter;
+    if let Some(schema_profile) = spec.schema_profile {
+        config.schema_profile = schema_profile;
+    }
+    if let Some(cache_control) = spec.cache_control {
+        config.cache_control = cache_control;
+    }
+    if let Some(thinking_budget_params) = spec.thinking_budget_params {
+        config.thinking_budget_params = thinking_budget_params;
+    }
+    if let Some(top_k) = spec.top_k {
+        config.top_k = top_k;
+    }
+    if let Some(quirks) = spec.quirks {
+        config.quirks = quirks;
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ModelRoutes, Targets};
+    use crate::config::{Config, SchemaProfile};
+    use std::time::Duration;
+
+    #[test]
+    fn models_route_to_their_upstream_profile() {
+        let mut base = Config::for_tests();
+        base.api_key = Some("default-key".to_string());
+        let routes = ModelRoutes::parse(
+            r#"{
+                "upstreams": {
+                    "openrouter": {
+                        "base_url": "https://openrouter.ai/api",
+                        "api_key": "sk-or",
+                        "headers": {"X-Title": "proxy"}
+                    },
+                    "local": {"base_url": "http://localhost:11434", "schema_profile": "gemini"}
+                },
+                "routes": [
+                    {"model": "claude-opus*", "upstream": "openrouter"},
+                    {"model": "claude-haiku*", "upstream": "local"}
+                ]
+            }"#,
+            &base,
+        )
+        .unwrap();
+
+        let route = |model| routes.route(model, None).map(|targets| targets[0].upstream);
+        let opus = route("claude-opus-4-1").unwrap();
+        assert_eq!(opus.name, "openrouter");
+        assert_eq!(opus.config.api_key.as_deref(), Some("sk-or"));
+        assert_eq!(
+            opus.config.upstream_headers,
+            [("X-Title".to_string(), "proxy".to_string())]
+        );
+        assert_eq!(
+            opus.registry.current().urls(),
+            ["https://openrouter.ai/api/v1/chat/completions"]
+        );
+
+        let haiku = route("claude-haiku-4-5").unwrap();
+        assert_eq!(haiku.config.api_key, None);
+        assert_eq!(haiku.config.schema_profile, SchemaProfile::Gemini);
+        assert!(route("claude-sonnet-4-5").is_none());
+
+        let unknown = r#"{"upstreams": {}, "routes": [{"model": "x", "upstream": "nowhere"}]}"#;
+        assert!(ModelRoutes::parse(unknown, &base).is_err());
+    }
+
+    #[test]
+    fn weighted_routes_split_traffic_and_avoid_failing_upstreams() {
+        let routes = ModelRoutes::parse(
+            r#"{
+                "upstreams": {
+                    "a": {"base_url": "http://a:8000"},
+                    "b": {"base_url": "http://b:8000"}
+                },
+                "routes": [{"model": "*", "upstreams": [
+                    {"upstream": "a", "weight": 3},
+                    {"upstream": "b"}
+                ]}]
+            }"#,
+            &Config::for_tests(),
+        )
+        .unwrap();
+        let Targets::Weighted(targets) = &routes.routes[0].targets else {
+            panic!("expected weighted targets");
+        };
+        let pick = |fraction| routes.pick(targets, fraction).name.as_str();
+        assert_eq!(pick(0.0), "a");
+        assert_eq!(pick(0.74), "a");
+        assert_eq!(pick(0.76), "b");
+
+        // Upstream a fails every request: most traffic moves to b
+        let a = routes.upstreams()[0].registry.current();
+        for _ in 0..20 {
+            a.record(0, Duration::from_millis(10), false);
+        }
+        assert_eq!(pick(0.5), "b");
+
+        let mut config = Config::for_tests();
+        config.upstream_affinity = true;
+        let routes = ModelRoutes::parse(
+            r#"{
+                "upstreams": {
+                    "a": {"base_url": "http://a:8000"},
+                    "b": {"base_url": "http://b:8000"}
+                },
+                "routes": [{"model": "*", "upstreams": [{"upstream": "a"}, {"upstream": "b"}]}]
+            }"#,
+            &config,
+        )
+        .unwrap();
+        let first = routes.route("m", Some(7)).unwrap()[0].upstream.name.clone();
+        for _ in 0..20 {
+            assert_eq!(routes.route("m", Some(7)).unwrap()[0].upstream.name, first);
+        }
+
+        let invalid = r#"{"upstreams": {"a": {"base_url": "http://a:8000"}},
+            "routes": [{"model": "*", "upstreams": [{"upstream": "a", "weight": 0}]}]}"#;
+        assert!(ModelRoutes::parse(invalid, &Config::for_tests()).is_err());
+    }
+}
@@ -0,0 +1,452 @@
+use crate::config::SchemaProfile;
+use serde_json::{json, Map, Value};
+
+/// How many schema nodes `$ref`s may inline in total, so definitions that
+/// refer to each other many times can't blow up the schema
+const MAX_INLINED_NODES: usize = 10_000;
+
+/// String formats OpenAI's structured outputs understand
+const STANDARD_FORMATS: &[&str] = &[
+    "date-time",
+    "time",
+    "date",
+    "duration",
+    "email",
+    "hostname",
+    "ipv4",
+    "ipv6",
+    "uuid",
+];
+
+/// String formats Gemini understands
+const GEMINI_FORMATS: &[&str] = &["enum", "date-time"];
+
+/// Schema metadata no upstream needs
+const METADATA_KEYWORDS: &[&str] = &["$schema", "$id", "$comment", "$defs", "definitions"];
+
+/// Keywords Gemini's OpenAI endpoint rejects
+const GEMINI_UNSUPPORTED: &[&str] = &[
+    "additionalProperties",
+    "patternProperties",
+    "unevaluatedProperties",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "default",
+    "examples",
+    "not",
+];
+
+/// Keywords whose value is a map of name to schema
+const SCHEMA_MAPS: &[&str] = &["properties", "patternProperties"];
+
+/// Keywords whose value is a schema, or for `items` possibly a list of them
+const SCHEMA_VALUES: &[&str] = &[
+    "items",
+    "additionalProperties",
+    "unevaluatedProperties",
+    "not",
+    "contains",
+];
+
+/// Keywords whose value is a list of schemas
+const SCHEMA_LISTS: &[&str] = &["anyOf", "oneOf", "allOf", "prefixItems"];
+
+/// Clean a tool's input schema for the upstream according to the profile
+pub fn clean(schema: Value, profile: SchemaProfile) -> Value {
+    if profile == SchemaProfile::Off {
+        return schema;
+    }
+    let mut defs = Map::new();
+    for key in ["$defs", "definitions"] {
+        if let Some(Value::Object(found)) = schema.get(key) {
+            for (name, def) in found {
+                defs.insert(format!("#/{}/{}", key, name), def.clone());
+            }
+        }
+    }
+    Cleaner {
+        profile,
+        defs,
+        resolving: Vec::new(),
+        inlined: 0,
+    }
+    .walk(schema)
+}
+
+struct Cleaner {
+    profile: SchemaProfile,
+    /// Root definitions by the `$ref` that points at them
+    defs: Map<String, Value>,
+    /// The `$ref`s being inlined on the path to the current schema
+    resolving: Vec<String>,
+    /// How many nodes `$ref`s have inlined so far
+    inlined: usize,
+}
+
+impl Cleaner {
+    fn walk(&mut self, schema: Value) -> Value {
+        let Value::Object(mut obj) = schema else {
+            return schema;
+        };
+
+        if let Some(Value::String(target)) = obj.remove("$ref") {
+            match self.defs.get(&target) {
+                Some(Value::Object(def)) if !self.resolving.contains(&target) => {
+                    let def_nodes = count_nodes(def);
+                    if self.inlined + def_nodes <= MAX_INLINED_NODES {
+                        self.inlined += def_nodes;
+                        // Keywords next to the `$ref`, like a description, win
+                        let mut resolved = def.clone();
+                        resolved.extend(obj);
+                        self.resolving.push(target);
+                        let cleaned = self.walk(Value::Object(resolved));
+                        self.resolving.pop();
+                        return cleaned;
+                    }
+                    tracing::debug!("Dropping schema $ref {} past the inlining limit", target);
+                }
+                _ => tracing::debug!("Dropping unresolvable or recursive schema $ref {}", target),
+            }
+        }
+
+        for key in METADATA_KEYWORDS {
+            obj.remove(*key);
+        }
+
+        let formats = match self.profile {
+            SchemaProfile::Gemini => GEMINI_FORMATS,
+            _ => STANDARD_FORMATS,
+        };
+        if obj
+            .get("format")
+            .and_then(|f| f.as_str())
+            .is_some_and(|f| !formats.contains(&f))
+        {
+            obj.remove("format");
+        }
+
+        for key in SCHEMA_MAPS {
+            if let Some(Value::Object(map)) = obj.get_mut(*key) {
+                for value in map.values_mut() {
+                    *value = self.walk(value.take());
+                }
+            }
+        }
+        for key in SCHEMA_VALUES {
+            match obj.get_mut(*key) {
+                Some(Value::Array(list)) => {
+                    for value in list.iter_mut() {
+                        *value = self.walk(value.take());
+                    }
+                }
+                Some(value @ Value::Object(_)) => *value = self.walk(value.take()),
+                _ => {}
+            }
+        }
+        for key in SCHEMA_LISTS {
+            if let Some(Value::Array(list)) = obj.get_mut(*key) {
+                for value in list.iter_mut() {
+                    *value = self.walk(value.take());
+                }
+            }
+        }
+
+        if matches!(self.profile, SchemaProfile::OpenAI | SchemaProfile::Gemini) {
+            if !obj.contains_key("anyOf") {
+                if let Some(one_of) = obj.remove("oneOf") {
+                    obj.insert("anyOf".to_string(), one_of);
+                }
+            }
+            merge_all_of(&mut obj);
+        }
+        if self.profile == SchemaProfile::Gemini {
+            for key in GEMINI_UNSUPPORTED {
+                obj.remove(*key);
+            }
+            if let Some(value) = obj.remove("const") {
+                obj.insert("enum".to_string(), Value::Array(vec![value]));
+            }
+            describe_non_string_enum(&mut obj);
+        }
+
+        Value::Object(obj)
+    }
+}
+
+/// How many JSON values a schema is made of
+fn count_nodes(obj: &Map<String, Value>) -> usize {
+    fn count(value: &Value) -> usize {
+        match value {
+            Value::Object(obj) => count_nodes(obj),
+            Value::Array(list) => 1 + list.iter().map(count).sum::<usize>(),
+            _ => 1,
+        }
+    }
+    1 + obj.values().map(count).sum::<usize>()
+}
+
+/// Rewrite a cleaned schema to satisfy OpenAI's strict mode: every object
+/// lists all its properties as required and allows no others, and optional
+/// properties become nullable instead. Returns None for schemas strict mode
+/// can't express, such as objects with open-ended keys.
+pub fn strict(schema: &Value) -> Option<Value> {
+    let mut schema = schema.clone();
+    make_strict(&mut schema).then_some(schema)
+}
+
+fn make_strict(schema: &mut Value) -> bool {
+    let Value::Object(obj) = schema else {
+        return true;
+    };
+    if obj.contains_key("patternProperties")
+        || obj
+            .get("additionalProperties")
+            .is_some_and(|extra| extra != &Value::Bool(false))
+    {
+        return false;
+    }
+
+    let is_object = obj.get("type").and_then(|t| t.as_str()) == Some("object")
+        || obj.contains_key("properties");
+    if is_object {
+        let required: Vec<Value> = match obj.get("required") {
+            Some(Value::Array(required)) => required.clone(),
+            _ => Vec::new(),
+        };
+        let mut names = Vec::new();
+        if let Some(Value::Object(properties)) = obj.get_mut("properties") {
+            for (name, property) in properties.iter_mut() {
+                let name = Value::String(name.clone());
+                if !required.contains(&name) {
+                    make_nullable(property);
+                }
+                names.push(name);
+            }
+        }
+        obj.insert("required".to_string(), Value::Array(names));
+        obj.insert("additionalProperties".to_string(), Value::Bool(false));
+    }
+
+    let mut nested: Vec<&mut Value> = Vec::new();
+    for (key, value) in obj.iter_mut() {
+        match (key.as_str(), value) {
+            ("properties", Value::Object(properties)) => nested.extend(properties.values_mut()),
+            ("items" | "anyOf" | "prefixItems", Value::Array(list)) => {
+                nested.extend(list.iter_mut())
+            }
+            ("items", value) => nested.push(value),
+            _ => {}
+        }
+    }
+    nested.into_iter().all(make_strict)
+}
+
+/// Let an optional property also be null, which is how strict mode
+/// expresses that it may be left out
+fn make_nullable(property: &mut Value) {
+    let Value::Object(obj) = property else {
+        return;
+    };
+    match obj.get_mut("type") {
+        Some(Value::String(kind)) if kind != "null" => {
+            let kind = Value::String(std::mem::take(kind));
+            obj.insert("type".to_string(), json!([kind, "null"]));
+        }
+        Some(Value::Array(kinds)) => {
+            if !kinds.iter().any(|k| k == "null") {
+                kinds.push(json!("null"));
+            }
+        }
+        Some(_) => {}
+        None => {
+            if let Some(Value::Array(options)) = obj.get_mut("anyOf") {
+                options.push(json!({"type": "null"}));
+            }
+        }
+    }
+    if let Some(Value::Array(values)) = obj.get_mut("enum") {
+        if !values.contains(&Value::Null) {
+            values.push(Value::Null);
+        }
+    }
+}
+
+/// Fold `allOf` object schemas into the parent: properties and required
+/// fields are combined, other keywords kept where the parent has none
+fn merge_all_of(obj: &mut Map<String, Value>) {
+    let Some(Value::Array(parts)) = obj.get("allOf") else {
+        return;
+    };
+    if !parts.iter().all(Value::is_object) {
+        return;
+    }
+    let Some(Value::Array(parts)) = obj.remove("allOf") else {
+        return;
+    };
+
+    for part in parts {
+        let Value::Object(part) = part else { continue };
+        for (key, value) in part {
+            match (key.as_str(), obj.get_mut(&key), value) {
+                ("properties", Some(Value::Object(existing)), Value::Object(more)) => {
+                    existing.extend(more)
+                }
+                ("required", Some(Value::Array(existing)), Value::Array(more)) => {
+                    for name in more {
+                        if !existing.contains(&name) {
+                            existing.push(name);
+                        }
+                    }
+                }
+                (_, Some(_), _) => {}
+                (_, None, value) => {
+                    obj.insert(key, value);
+                }
+            }
+        }
+    }
+}
+
+/// Gemini only takes string enums; other values move into the description
+fn describe_non_string_enum(obj: &mut Map<String, Value>) {
+    let Some(Value::Array(values)) = obj.get("enum") else {
+        return;
+    };
+    if values.iter().all(Value::is_string) {
+        return;
+    }
+    let allowed = values
+        .iter()
+        .map(Value::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    obj.remove("enum");
+    let description = match obj.get("description").and_then(|d| d.as_str()) {
+        Some(description) => format!("{} (one of: {})", description, allowed),
+        None => format!("One of: {}", allowed),
+    };
+    obj.insert("description".to_string(), Value::String(description));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clean, strict};
+    use crate::config::SchemaProfile;
+    use serde_json::json;
+
+    fn schema() -> serde_json::Value {
+        json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "object",
+            "properties": {
+                "site": {"type": "string", "format": "uri"},
+                "when": {"type": "string", "format": "date-time"},
+                "target": {"$ref": "#/$defs/target", "description": "Where to go"},
+                "level": {"type": "integer", "enum": [1, 2, 3]},
+                "mode": {"oneOf": [{"const": "fast"}, {"const": "safe"}]}
+            },
+            "allOf": [
+                {"properties": {"extra": {"type": "boolean"}}, "required": ["extra"]}
+            ],
+            "required": ["site"],
+            "$defs": {
+                "target": {
+                    "type": "object",
+                    "properties": {"next": {"$ref": "#/$defs/target"}}
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn standard_profile_inlines_refs_and_drops_unknown_formats() {
+        let cleaned = clean(schema(), SchemaProfile::Standard);
+
+        assert!(cleaned.get("$schema").is_none() && cleaned.get("$defs").is_none());
+        assert!(cleaned["properties"]["site"].get("format").is_none());
+        assert_eq!(cleaned["properties"]["when"]["format"], "date-time");
+        let target = &cleaned["properties"]["target"];
+        assert_eq!(target["type"], "object");
+        assert_eq!(target["description"], "Where to go");
+        assert!(!cleaned.to_string().contains("$ref"));
+        assert_eq!(cleaned["properties"]["level"]["enum"], json!([1, 2, 3]));
+        assert!(cleaned["properties"]["mode"].get("oneOf").is_some());
+    }
+
+    #[test]
+    fn self_referencing_definitions_are_inlined_once() {
+        let branches: serde_json::Map<_, _> = (0..8)
+            .map(|i| (format!("b{}", i), json!({"$ref": "#/$defs/node"})))
+            .collect();
+        let schema = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {"node": {"type": "object", "properties": branches}}
+        });
+
+        let cleaned = clean(schema, SchemaProfile::Standard);
+        assert_eq!(cleaned["type"], "object");
+        assert_eq!(cleaned["properties"]["b0"], json!({}));
+        assert!(!cleaned.to_string().contains("$ref"));
+
+        // Definitions that each use the next one twice double at every level
+        let mut defs = serde_json::Map::new();
+        for level in 0..30 {
+            let next = json!({"$ref": format!("#/$defs/d{}", level + 1)});
+            defs.insert(
+                format!("d{}", level),
+                json!({"type": "object", "properties": {"a": next, "b": next}}),
+            );
+        }
+        let schema = json!({"$ref": "#/$defs/d0", "$defs": defs});
+        let cleaned = clean(schema, SchemaProfile::Standard);
+        assert!(cleaned.to_string().len() < 1_000_000);
+    }
+
+    #[test]
+    fn gemini_profile_applies_provider_restrictions() {
+        let cleaned = clean(schema(), SchemaProfile::Gemini);
+
+        let mode = &cleaned["properties"]["mode"];
+        assert_eq!(mode["anyOf"][0]["enum"], json!(["fast"]));
+        assert!(cleaned.get("allOf").is_none());
+        assert_eq!(cleaned["properties"]["extra"]["type"], "boolean");
+        assert_eq!(cleaned["required"], json!(["site", "extra"]));
+        let level = &cleaned["properties"]["level"];
+        assert!(level.get("enum").is_none());
+        assert_eq!(level["description"], "One of: 1, 2, 3");
+    }
+
+    #[test]
+    fn strict_schemas_require_every_property() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "path": {"type": "string"},
+                "limit": {"type": "integer"},
+                "filter": {
+                    "type": "object",
+                    "properties": {"glob": {"type": "string", "enum": ["*.rs", "*.md"]}}
+                }
+            },
+            "required": ["path"]
+        });
+
+        let strict = strict(&schema).unwrap();
+        assert_eq!(strict["additionalProperties"], false);
+        assert_eq!(strict["required"], json!(["filter", "limit", "path"]));
+        assert_eq!(strict["properties"]["path"]["type"], "string");
+        assert_eq!(
+            strict["properties"]["limit"]["type"],
+            json!(["integer", "null"])
+        );
+        let filter = &strict["properties"]["filter"];
+        assert_eq!(filter["additionalProperties"], false);
+        assert_eq!(
+            filter["properties"]["glob"]["enum"],
+            json!(["*.rs", "*.md", null])
+        );
+
+        let open = json!({"type": "object", "additionalProperties": {"type": "string"}});
+        assert!(super::strict(&open).is_none());
+    }
+}
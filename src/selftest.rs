@@ -0,0 +1,220 @@
+use crate::config::Config;
+use crate::models::openai;
+use crate::proxy::{self, RequestContext};
+use crate::transform;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Upstream bytes are replayed in pieces this small so event reassembly
+/// across network reads is exercised too
+const REPLAY_CHUNK_SIZE: usize = 7;
+
+/// Recorded upstream outputs paired with the Anthropic output they must produce
+const FIXTURES: &[(&str, &str)] = &[
+    (
+        "text_response",
+        include_str!("../fixtures/selftest/text_response.json"),
+    ),
+    (
+        "tool_call_response",
+        include_str!("../fixtures/selftest/tool_call_response.json"),
+    ),
+    (
+        "text_stream",
+        include_str!("../fixtures/selftest/text_stream.json"),
+    ),
+    (
+        "reasoning_stream",
+        include_str!("../fixtures/selftest/reasoning_stream.json"),
+    ),
+    (
+        "tool_call_stream",
+        include_str!("../fixtures/selftest/tool_call_stream.json"),
+    ),
+    (
+        "reasoning_content_stream",
+        include_str!("../fixtures/selftest/reasoning_content_stream.json"),
+    ),
+    (
+        "parallel_tool_calls_stream",
+        include_str!("../fixtures/selftest/parallel_tool_calls_stream.json"),
+    ),
+    (
+        "length_stream",
+        include_str!("../fixtures/selftest/length_stream.json"),
+    ),
+    (
+        "truncated_stream",
+        include_str!("../fixtures/selftest/truncated_stream.json"),
+    ),
+];
+
+#[derive(Debug, Deserialize)]
+struct Fixture {
+    /// Model the request was sent for, used when the upstream omits it
+    model: String,
+    #[serde(default)]
+    openai_response: Option<Value>,
+    #[serde(default)]
+    openai_stream: Option<Vec<Value>>,
+    #[serde(default)]
+    expected: Option<Value>,
+    #[serde(default)]
+    expected_events: Option<Vec<Value>>,
+}
+
+/// Run every fixture through the transforms, print a report and return the
+/// number of mismatches
+pub async fn run(config: &Config) -> Result<usize> {
+    let config = Arc::new(neutralize(config));
+    let mut failures = 0;
+
+    for (name, raw) in FIXTURES {
+        match check_fixture(&config, raw).await {
+            Ok(None) => eprintln!("✓ {}", name),
+            Ok(Some(mismatch)) => {
+                failures += 1;
+                eprintln!("✗ {}: {}", name, mismatch);
+            }
+            Err(err) => {
+                failures += 1;
+                eprintln!("✗ {}: {:#}", name, err);
+            }
+        }
+    }
+
+    Ok(failures)
+}
+
+/// Turn off features that deliberately rewrite output, so fixtures only
+/// depend on the translation itself
+fn neutralize(config: &Config) -> Config {
+    let mut config = config.clone();
+    config.output_guardrails = None;
+    config.secret_scanner = None;
+    config.model_pricing.clear();
+    config
+}
+
+async fn check_fixture(config: &Arc<Config>, raw: &str) -> Result<Option<String>> {
+    let fixture: Fixture = serde_json::from_str(raw).context("invalid fixture")?;
+
+    if let (Some(response), Some(expected)) = (&fixture.openai_response, &fixture.expected) {
+        let response: openai::OpenAIResponse = serde_json::from_value(response.clone())
+            .context("fixture response is not a valid OpenAI response")?;
+        let actual = transform::openai_to_anthropic(response, &fixture.model)?;
+        return Ok(compare(
+            expected,
+            &serde_json::to_value(actual)?,
+            "response",
+        ));
+    }
+
+    if let (Some(chunks), Some(expected)) = (&fixture.openai_stream, &fixture.expected_events) {
+        let actual = replay_stream(config, &fixture.model, chunks).await;
+        if actual.len() != expected.len() {
+            return Ok(Some(format!(
+                "expected {} events, got {}:\n{}",
+                expected.len(),
+                actual.len(),
+                actual
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            )));
+        }
+        for (i, (expected, actual)) in expected.iter().zip(&actual).enumerate() {
+            if let Some(mismatch) = compare(expected, actual, &format!("event {}", i)) {
+                return Ok(Some(mismatch));
+            }
+        }
+        return Ok(None);
+    }
+
+    anyhow::bail!("fixture needs openai_response + expected or openai_stream + expected_events")
+}
+
+/// Feed recorded chunks through the streaming translator and parse what it emits
+async fn replay_stream(config: &Arc<Config>, model: &str, chunks: &[Value]) -> Vec<Value> {
+    let mut body = String::new();
+    for chunk in chunks {
+        let data = match chunk {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        body.push_str(&format!("data: {}\n\n", data));
+    }
+    let pieces: Vec<Result<Bytes, reqwest::Error>> = body
+        .as_bytes()
+        .chunks(REPLAY_CHUNK_SIZE)
+        .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+        .collect();
+
+    let ctx = Arc::new(RequestContext::detached(config));
+    let output: Vec<u8> = proxy::create_sse_stream(
+        futures::stream::iter(pieces),
+        model.to_string(),
+        config.clone(),
+        ctx,
+    )
+    .filter_map(|item| async move { item.ok() })
+    .collect::<Vec<Bytes>>()
+    .await
+    .concat();
+
+    String::from_utf8_lossy(&output)
+        .split("\n\n")
+        .filter(|event| !event.trim().is_empty())
+        .map(|event| {
+            let name = event
+                .lines()
+                .find_map(|l| l.strip_prefix("event: "))
+                .unwrap_or_default();
+            let data = event
+                .lines()
+                .find_map(|l| l.strip_prefix("data: "))
+                .and_then(|d| serde_json::from_str(d).ok())
+                .unwrap_or(Value::Null);
+            serde_json::json!({ "event": name, "data": data })
+        })
+        .collect()
+}
+
+/// Describe the first difference between two JSON values, if any
+fn compare(expected: &Value, actual: &Value, path: &str) -> Option<String> {
+    match (expected, actual) {
+        (Value::Object(e), Value::Object(a)) => e
+            .keys()
+            .chain(a.keys().filter(|k| !e.contains_key(*k)))
+            .find_map(|key| {
+                compare(
+                    e.get(key).unwrap_or(&Value::Null),
+                    a.get(key).unwrap_or(&Value::Null),
+                    &format!("{}.{}", path, key),
+                )
+            }),
+        (Value::Array(e), Value::Array(a)) if e.len() == a.len() => e
+            .iter()
+            .zip(a)
+            .enumerate()
+            .find_map(|(i, (e, a))| compare(e, a, &format!("{}[{}]", path, i))),
+        _ if expected == actual => None,
+        _ => Some(format!("{}: expected {}, got {}", path, expected, actual)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run;
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn embedded_fixtures_pass() {
+        assert_eq!(run(&Config::for_tests()).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,87 @@
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Interval of HTTP/2 PING frames, keeping idle SSE connections alive
+/// through load balancers that drop quiet streams
+const HTTP2_KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// Which HTTP versions the listener speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Http2Mode {
+    /// HTTP/1.1, plus cleartext HTTP/2 (h2c) for clients that start with the HTTP/2 preface
+    Auto,
+    /// HTTP/1.1 only
+    Off,
+    /// Cleartext HTTP/2 (h2c) only
+    Only,
+}
+
+impl Http2Mode {
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw.trim().to_lowercase().as_str() {
+            "auto" => Some(Self::Auto),
+            "off" | "false" | "0" => Some(Self::Off),
+            "only" => Some(Self::Only),
+            _ => None,
+        }
+    }
+}
+
+/// Accept connections and serve the app on each, with the configured protocols
+pub async fn serve(listener: TcpListener, app: Router, mode: Http2Mode) -> std::io::Result<()> {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http2()
+        .keep_alive_interval(HTTP2_KEEP_ALIVE)
+        .timer(hyper_util::rt::TokioTimer::new());
+    let builder = match mode {
+        Http2Mode::Auto => builder,
+        Http2Mode::Off => builder.http1_only(),
+        Http2Mode::Only => builder.http2_only(),
+    };
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                // Running out of file descriptors and similar errors are transient
+                tracing::error!("Failed to accept connection: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        // Events are small and latency-sensitive; don't let Nagle hold them back
+        if let Err(err) = stream.set_nodelay(true) {
+            tracing::debug!("Could not set TCP_NODELAY for {}: {}", peer, err);
+        }
+
+        let builder = builder.clone();
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            if let Err(err) = builder
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Connection from {} ended with error: {}", peer, err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Http2Mode;
+
+    #[test]
+    fn modes_parse_case_insensitively() {
+        assert_eq!(Http2Mode::parse("AUTO"), Some(Http2Mode::Auto));
+        assert_eq!(Http2Mode::parse("off"), Some(Http2Mode::Off));
+        assert_eq!(Http2Mode::parse("only"), Some(Http2Mode::Only));
+        assert_eq!(Http2Mode::parse("h3"), None);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::config::Config;
+use crate::proxy::random_fraction;
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Requests, failures and latency of one arm of a split
+#[derive(Debug, Default)]
+struct ArmStats {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    latency_ms: AtomicU64,
+}
+
+impl ArmStats {
+    fn record(&self, latency: Duration, success: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_ms
+            .fetch_add(latency.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn to_json(&self) -> Value {
+        let requests = self.requests.load(Ordering::Relaxed);
+        let errors = self.errors.load(Ordering::Relaxed);
+        let latency_ms = self.latency_ms.load(Ordering::Relaxed);
+        json!({
+            "requests": requests,
+            "errors": errors,
+            "error_rate": (requests > 0).then(|| errors as f64 / requests as f64),
+            "avg_latency_ms": (requests > 0).then(|| latency_ms / requests),
+        })
+    }
+}
+
+/// Sends a share of the requests for matching models to a canary model,
+/// leaving the rest (the control arm) to the usual model selection
+#[derive(Debug)]
+pub struct ModelSplit {
+    pattern: String,
+    canary_model: String,
+    /// Share of requests sent to the canary, in percent
+    percent: f64,
+    control: ArmStats,
+    canary: ArmStats,
+}
+
+/// The arm a request was assigned to
+#[derive(Debug)]
+pub struct Assignment<'a> {
+    split: &'a ModelSplit,
+    canary: bool,
+}
+
+impl Assignment<'_> {
+    /// The model replacing the usual choice, for requests on the canary arm
+    pub fn canary_model(&self) -> Option<&str> {
+        self.canary.then_some(self.split.canary_model.as_str())
+    }
+
+    pub fn arm(&self) -> &'static str {
+        if self.canary {
+            "canary"
+        } else {
+            "control"
+        }
+    }
+
+    /// Count the request, its time to response headers and whether it failed
+    pub fn record(&self, latency: Duration, success: bool) {
+        let stats = if self.canary {
+            &self.split.canary
+        } else {
+            &self.split.control
+        };
+        stats.record(latency, success);
+    }
+}
+
+/// Percentage-based traffic splits from MODEL_SPLITS, first match wins
+#[derive(Debug)]
+pub struct ModelSplits {
+    splits: Vec<ModelSplit>,
+}
+
+impl ModelSplits {
+    /// Parse `pattern=model:percent` pairs, e.g. `claude-sonnet*=gpt-5:5`
+    pub fn parse(pairs: Vec<(String, String)>) -> Result<Self> {
+        let splits = pairs
+            .into_iter()
+            .map(|(pattern, target)| {
+                let Some((model, percent)) = target.rsplit_once(':') else {
+                    bail!(
+                        "MODEL_SPLITS entries must look like pattern=model:percent: {}={}",
+                        pattern,
+                        target
+                    );
+                };
+                let percent: f64 = match percent.trim().parse() {
+                    Ok(percent) if percent > 0.0 && percent < 100.0 => percent,
+                    _ => bail!(
+                        "MODEL_SPLITS percentage for {} must be between 0 and 100",
+                        pattern
+                    ),
+                };
+                Ok(ModelSplit {
+                    pattern,
+                    canary_model: model.trim().to_string(),
+                    percent,
+                    control: ArmStats::default(),
+                    canary: ArmStats::default(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { splits })
+    }
+
+    /// Assign a request for a model to an arm of the first matching split
+    pub fn assign(&self, model: &str) -> Option<Assignment<'_>> {
+        self.assign_with(model, random_fraction())
+    }
+
+    fn assign_with(&self, model: &str, fraction: f64) -> Option<Assignment<'_>> {
+        let split = self
+            .splits
+            .iter()
+            .find(|split| Config::model_matches(&split.pattern, model))?;
+        Some(Assignment {
+            split,
+            canary: fraction * 100.0 < split.percent,
+        })
+    }
+
+    /// Every split with the metrics of both arms
+    pub fn to_json(&self) -> Value {
+        self.splits
+            .iter()
+            .map(|split| {
+                json!({
+                    "model": split.pattern,
+                    "canary_model": split.canary_model,
+                    "percent": split.percent,
+                    "control": split.control.to_json(),
+                    "canary": split.canary.to_json(),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ModelSplits;
+    use std::time::Duration;
+
+    #[test]
+    fn a_share_of_requests_goes_to_the_canary() {
+        let splits = ModelSplits::parse(vec![(
+            "claude-sonnet*".to_string(),
+            "qwen3:32b:5".to_string(),
+        )])
+        .unwrap();
+
+        let canary = splits.assign_with("claude-sonnet-4-5", 0.04).unwrap();
+        assert_eq!(canary.canary_model(), Some("qwen3:32b"));
+        canary.record(Duration::from_millis(300), false);
+        let control = splits.assign_with("claude-sonnet-4-5", 0.5).unwrap();
+        assert_eq!((control.arm(), control.canary_model()), ("control", None));
+        control.record(Duration::from_millis(100), true);
+        assert!(splits.assign_with("claude-opus-4-1", 0.0).is_none());
+
+        let stats = splits.to_json();
+        assert_eq!(stats[0]["canary"]["errors"], 1);
+        assert_eq!(stats[0]["canary"]["avg_latency_ms"], 300);
+        assert_eq!(stats[0]["control"]["error_rate"], 0.0);
+
+        let invalid = vec![("claude-sonnet*".to_string(), "gpt-5:100".to_string())];
+        assert!(ModelSplits::parse(invalid).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use bytes::{Buf, BytesMut};
+
+/// One dispatched server-sent event
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    /// The `event` field, if the event named a type
+    pub event: Option<String>,
+    /// The `data` fields, joined with newlines
+    pub data: String,
+    /// The last `id` field seen so far in the stream
+    pub id: Option<String>,
+}
+
+/// Incremental parser for `text/event-stream` bodies, following the WHATWG
+/// event stream format.
+///
+/// Bytes are buffered until a full line is available, so events and
+/// characters split across network chunks come out whole. Lines may end in
+/// CRLF, LF or CR; comment lines and `retry` fields are ignored.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buffer: BytesMut,
+    /// Leading bytes of the buffer already searched for a line ending
+    scanned: usize,
+    started: bool,
+    event: Option<String>,
+    data: String,
+    has_data: bool,
+    id: Option<String>,
+}
+
+impl SseParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed a chunk of the body and return the events it completed
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(bytes);
+        if !self.started {
+            // A byte order mark may only start the stream
+            if self.buffer.len() < 3 && b"\xEF\xBB\xBF".starts_with(&self.buffer) {
+                return Vec::new();
+            }
+            if self.buffer.starts_with(b"\xEF\xBB\xBF") {
+                self.buffer.advance(3);
+            }
+            self.started = true;
+        }
+
+        let mut events = Vec::new();
+        while let Some((line_len, ending_len)) = self.next_line() {
+            let line = self.buffer.split_to(line_len);
+            self.buffer.advance(ending_len);
+            self.scanned = 0;
+            if let Some(event) = self.process_line(&line) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    /// Bytes held for the event in progress: its data so far and the
+    /// incomplete line after it
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() + self.data.len()
+    }
+
+    /// Flush the end of the body. Unlike the spec, which discards an event
+    /// that is not followed by a blank line, a final event cut off at EOF is
+    /// still returned: some servers end the stream right after the last data
+    /// line.
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        let rest = std::mem::take(&mut self.buffer);
+        self.scanned = 0;
+        let rest = rest.strip_suffix(b"\r").unwrap_or(&rest);
+        let event = if rest.is_empty() {
+            None
+        } else {
+            self.process_line(rest)
+        };
+        event.or_else(|| self.dispatch())
+    }
+
+    /// Length of the next complete line and of its line ending. A CR at the
+    /// end of the buffer waits for the next chunk, which may start with LF.
+    /// Bytes searched before aren't searched again, so a long line arriving
+    /// in many chunks is only scanned once.
+    fn next_line(&mut self) -> Option<(usize, usize)> {
+        let Some(at) = self.buffer[self.scanned..]
+            .iter()
+            .position(|&b| b == b'\n' || b == b'\r')
+            .map(|at| self.scanned + at)
+        else {
+            self.scanned = self.buffer.len();
+            return None;
+        };
+        match (self.buffer[at], self.buffer.get(at + 1)) {
+            (b'\r', Some(b'\n')) => Some((at, 2)),
+            (b'\r', None) => {
+                self.scanned = at;
+                None
+            }
+            _ => Some((at, 1)),
+        }
+    }
+
+    fn process_line(&mut self, line: &[u8]) -> Option<SseEvent> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line[0] == b':' {
+            return None;
+        }
+
+        let line = String::from_utf8_lossy(line);
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_ref(), ""),
+        };
+        match field {
+            "data" => {
+                if self.has_data {
+                    self.data.push('\n');
+                }
+                self.data.push_str(value);
+                self.has_data = true;
+            }
+            "event" => self.event = Some(value.to_string()),
+            "id" if !value.contains('\0') => self.id = Some(value.to_string()),
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        if !std::mem::take(&mut self.has_data) {
+            return None;
+        }
+        Some(SseEvent {
+            event,
+            data: std::mem::take(&mut self.data),
+            id: self.id.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SseEvent, SseParser};
+
+    fn parse(chunks: &[&[u8]]) -> Vec<SseEvent> {
+        let mut parser = SseParser::new();
+        let mut events: Vec<SseEvent> = chunks.iter().flat_map(|c| parser.push(c)).collect();
+        events.extend(parser.finish());
+        events
+    }
+
+    fn data(events: &[SseEvent]) -> Vec<&str> {
+        events.iter().map(|e| e.data.as_str()).collect()
+    }
+
+    #[test]
+    fn line_endings_and_chunk_boundaries_are_handled() {
+        let events = parse(&[b"data: one\r", b"\n\r\ndata: two\r\rdata: three\n", b"\n"]);
+        assert_eq!(data(&events), ["one", "two", "three"]);
+
+        let events = parse(&[b"\xEF\xBB", b"\xBFdata: a\n\n"]);
+        assert_eq!(data(&events), ["a"]);
+    }
+
+    #[test]
+    fn characters_split_across_chunks_stay_intact() {
+        let emoji = "🦀".as_bytes();
+        let events = parse(&[
+            b"data: caf\xC3",
+            b"\xA9 ",
+            &emoji[..1],
+            &emoji[1..],
+            b"\n\n",
+        ]);
+        assert_eq!(data(&events), ["café 🦀"]);
+    }
+
+    #[test]
+    fn fields_follow_the_event_stream_format() {
+        let events = parse(&[
+            b": keep-alive\n\n",
+            b"event: ping\nid: 7\ndata: first\ndata:second\n\n",
+            b"data\n\n",
+            b"retry: 100\nevent: nothing\n\n",
+            b"data: {\"a\":1}",
+        ]);
+
+        assert_eq!(data(&events), ["first\nsecond", "", "{\"a\":1}"]);
+        assert_eq!(events[0].event.as_deref(), Some("ping"));
+        assert_eq!(events[1].event, None);
+        assert_eq!(events[2].id.as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn long_lines_are_buffered_until_complete() {
+        let mut parser = SseParser::new();
+        assert!(parser.push(b"data: ab").is_empty());
+        assert!(parser.push(b"cd\r").is_empty());
+        assert_eq!(parser.buffered(), 11);
+        let events = parser.push(b"\ndata: ef\n\n");
+        assert_eq!(data(&events), ["abcd\nef"]);
+        assert_eq!(parser.buffered(), 0);
+    }
+}
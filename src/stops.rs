@@ -0,0 +1,187 @@
+use crate::config::Config;
+use crate::models::{anthropic, openai};
+
+/// Fit a request's stop sequences within the upstream's limit and return the
+/// ones that had to be dropped.
+///
+/// Duplicates go first, then sequences that contain another one, since the
+/// shorter sequence already stops generation no later. Anything still over
+/// the limit is dropped from the end.
+pub fn limit(config: &Config, req: &mut openai::OpenAIRequest) -> Vec<String> {
+    let (Some(max), Some(stops)) = (config.stop_sequences_max(), req.stop.as_mut()) else {
+        return Vec::new();
+    };
+    if stops.len() <= max {
+        return Vec::new();
+    }
+
+    let mut unique: Vec<String> = Vec::new();
+    for stop in stops.drain(..) {
+        if !stop.is_empty() && !unique.contains(&stop) {
+            unique.push(stop);
+        }
+    }
+    let merged: Vec<String> = unique
+        .iter()
+        .filter(|stop| {
+            !unique
+                .iter()
+                .any(|other| other != *stop && stop.contains(other.as_str()))
+        })
+        .cloned()
+        .collect();
+    if merged.len() < unique.len() {
+        tracing::debug!(
+            "Merged {} stop sequences covered by shorter ones",
+            unique.len() - merged.len()
+        );
+    }
+
+    let mut kept = merged;
+    let dropped = if kept.len() > max {
+        kept.split_off(max)
+    } else {
+        Vec::new()
+    };
+    if !dropped.is_empty() {
+        tracing::warn!(
+            "Upstream accepts {} stop sequences, dropping {:?}{}",
+            max,
+            dropped,
+            if config.emulate_stop_sequences {
+                " (emulated by the proxy)"
+            } else {
+                ""
+            }
+        );
+    }
+    *stops = kept;
+    dropped
+}
+
+/// Cut a non-streaming response at the first watched stop sequence, the way
+/// Anthropic would have
+pub fn apply_to_response(stops: &[String], resp: &mut anthropic::AnthropicResponse) {
+    if stops.is_empty() {
+        return;
+    }
+    let hit = resp
+        .content
+        .iter()
+        .enumerate()
+        .find_map(|(i, block)| match block {
+            anthropic::ResponseContent::Text { text, .. } => {
+                first_match(stops, text).map(|(at, stop)| (i, at, stop))
+            }
+            _ => None,
+        });
+    let Some((index, at, stop)) = hit else {
+        return;
+    };
+
+    resp.content.truncate(index + 1);
+    if let Some(anthropic::ResponseContent::Text { text, .. }) = resp.content.last_mut() {
+        text.truncate(at);
+    }
+    resp.stop_reason = Some("stop_sequence".to_string());
+    resp.stop_sequence = Some(stop.to_string());
+}
+
+/// Earliest occurrence of any stop sequence in the text
+fn first_match<'a>(stops: &'a [String], text: &str) -> Option<(usize, &'a str)> {
+    stops
+        .iter()
+        .filter_map(|stop| text.find(stop.as_str()).map(|at| (at, stop.as_str())))
+        .min_by_key(|(at, _)| *at)
+}
+
+/// Incremental stop sequence detection for one streamed text block.
+///
+/// Text that could be the start of a stop sequence is held back until the
+/// next delta decides it; once a sequence matches, nothing more is emitted.
+pub struct StopScanner<'a> {
+    stops: &'a [String],
+    pending: String,
+    matched: Option<&'a str>,
+}
+
+impl<'a> StopScanner<'a> {
+    pub fn new(stops: &'a [String]) -> Self {
+        Self {
+            stops,
+            pending: String::new(),
+            matched: None,
+        }
+    }
+
+    /// Feed a delta and return the text that is now safe to emit
+    pub fn push(&mut self, delta: &str) -> String {
+        if self.matched.is_some() {
+            return String::new();
+        }
+        self.pending.push_str(delta);
+
+        if let Some((at, stop)) = first_match(self.stops, &self.pending) {
+            self.matched = Some(stop);
+            self.pending.truncate(at);
+            return std::mem::take(&mut self.pending);
+        }
+
+        // Hold back the longest tail that is still a prefix of some sequence
+        let held = self
+            .pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                let tail = &self.pending[i..];
+                self.stops.iter().any(|stop| stop.starts_with(tail))
+            })
+            .unwrap_or(self.pending.len());
+        self.pending.drain(..held).collect()
+    }
+
+    /// Flush whatever is left once the block ends
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// The stop sequence that ended the block, if any
+    pub fn matched(&self) -> Option<&'a str> {
+        self.matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{limit, StopScanner};
+    use crate::config::Config;
+    use crate::models::openai;
+
+    #[test]
+    fn stop_sequences_are_merged_before_being_dropped() {
+        let mut config = Config::for_tests();
+        config.base_url = "https://api.openai.com".to_string();
+        let mut req: openai::OpenAIRequest = serde_json::from_value(serde_json::json!({
+            "model": "gpt-4o",
+            "messages": [],
+            "stop": ["Human:", "\n\nHuman:", "END", "END", "###", "STOP", "DONE"]
+        }))
+        .unwrap();
+
+        let dropped = limit(&config, &mut req);
+        assert_eq!(req.stop.unwrap(), ["Human:", "END", "###", "STOP"]);
+        assert_eq!(dropped, ["DONE"]);
+    }
+
+    #[test]
+    fn scanner_catches_sequences_split_across_deltas() {
+        let stops = vec!["STOP".to_string()];
+        let mut scanner = StopScanner::new(&stops);
+
+        assert_eq!(scanner.push("one ST"), "one ");
+        assert_eq!(scanner.push("ray two S"), "STray two ");
+        assert_eq!(scanner.push("TOP three"), "");
+        assert_eq!(scanner.matched(), Some("STOP"));
+        assert_eq!(scanner.finish(), "");
+    }
+}
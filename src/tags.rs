@@ -0,0 +1,164 @@
+use crate::config::Config;
+use axum::http::HeaderMap;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+
+/// Longest tag value kept; longer values are cut
+const MAX_VALUE_LEN: usize = 64;
+/// Value recorded once a tag key has seen too many distinct values
+const OVERFLOW_VALUE: &str = "other";
+
+/// Attribution tags of one request, sorted by key
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestTags(Vec<(String, String)>);
+
+impl RequestTags {
+    /// Collect tags from `metadata.tags` and the configured tag header.
+    /// Only keys listed in REQUEST_TAG_KEYS are kept; the header wins on conflicts.
+    pub fn from_request(config: &Config, headers: &HeaderMap, metadata: Option<&Value>) -> Self {
+        if config.request_tag_keys.is_empty() {
+            return Self::default();
+        }
+
+        let mut pairs: Vec<(String, String)> = Vec::new();
+        match metadata.and_then(|m| m.get("tags")) {
+            Some(Value::Object(map)) => {
+                for (key, value) in map {
+                    let value = match value {
+                        Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    };
+                    pairs.push((key.clone(), value));
+                }
+            }
+            Some(Value::Array(items)) => {
+                pairs.extend(items.iter().filter_map(|item| parse_pair(item.as_str()?)));
+            }
+            _ => {}
+        }
+        if let Some(raw) = headers
+            .get(config.request_tag_header.as_str())
+            .and_then(|v| v.to_str().ok())
+        {
+            pairs.extend(raw.split(',').filter_map(parse_pair));
+        }
+
+        let mut tags: Vec<(String, String)> = Vec::new();
+        for (key, value) in pairs {
+            let key = key.trim().to_lowercase();
+            if !config.request_tag_keys.contains(&key) {
+                continue;
+            }
+            let Some(value) = sanitize_value(&value) else {
+                continue;
+            };
+            tags.retain(|(k, _)| *k != key);
+            tags.push((key, value));
+        }
+        tags.sort();
+        Self(tags)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for RequestTags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Caps the distinct values recorded per tag key so usage aggregates stay bounded
+#[derive(Debug, Default)]
+pub struct TagLimiter {
+    seen: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+impl TagLimiter {
+    /// Replace values beyond the first `max_values` seen for a key with `other`
+    pub fn bound(&self, tags: &RequestTags, max_values: usize) -> RequestTags {
+        let mut seen = self.seen.lock().expect("tag limiter lock poisoned");
+        RequestTags(
+            tags.0
+                .iter()
+                .map(|(key, value)| {
+                    let values = seen.entry(key.clone()).or_default();
+                    if values.contains(value) || values.len() < max_values {
+                        values.insert(value.clone());
+                        (key.clone(), value.clone())
+                    } else {
+                        (key.clone(), OVERFLOW_VALUE.to_string())
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
+fn parse_pair(raw: &str) -> Option<(String, String)> {
+    let (key, value) = raw.split_once('=')?;
+    Some((key.to_string(), value.to_string()))
+}
+
+/// Keep label-safe characters only, so tags can't break log or CSV formats
+fn sanitize_value(value: &str) -> Option<String> {
+    let clean: String = value
+        .trim()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/' | ':'))
+        .take(MAX_VALUE_LEN)
+        .collect();
+    (!clean.is_empty()).then_some(clean)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sanitize_value, RequestTags, TagLimiter};
+
+    fn tags(pairs: &[(&str, &str)]) -> RequestTags {
+        RequestTags(
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn values_are_sanitized_and_bounded() {
+        assert_eq!(sanitize_value(" web app;rm "), Some("webapprm".to_string()));
+        assert_eq!(sanitize_value("\"\""), None);
+        assert_eq!(sanitize_value(&"x".repeat(100)).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn limiter_folds_excess_values_into_other() {
+        let limiter = TagLimiter::default();
+        assert_eq!(
+            limiter.bound(&tags(&[("project", "a")]), 2).to_string(),
+            "project=a"
+        );
+        assert_eq!(
+            limiter.bound(&tags(&[("project", "b")]), 2).to_string(),
+            "project=b"
+        );
+        assert_eq!(
+            limiter.bound(&tags(&[("project", "c")]), 2).to_string(),
+            "project=other"
+        );
+        assert_eq!(
+            limiter.bound(&tags(&[("project", "a")]), 2).to_string(),
+            "project=a"
+        );
+    }
+}
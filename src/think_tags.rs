@@ -0,0 +1,182 @@
+use crate::models::anthropic;
+
+/// Tags a model wraps its inline reasoning in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delimiters {
+    pub open: String,
+    pub close: String,
+}
+
+impl Default for Delimiters {
+    fn default() -> Self {
+        Self {
+            open: "<think>".to_string(),
+            close: "</think>".to_string(),
+        }
+    }
+}
+
+impl Delimiters {
+    /// Parse `open|close`, e.g. `[THINK]|[/THINK]`
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (open, close) = spec.split_once('|')?;
+        let (open, close) = (open.trim(), close.trim());
+        (!open.is_empty() && !close.is_empty()).then(|| Self {
+            open: open.to_string(),
+            close: close.to_string(),
+        })
+    }
+}
+
+/// Incremental splitting of streamed content into reasoning and text.
+///
+/// Text that could be the start of a tag is held back until the next delta
+/// decides it. Whitespace right after a tag is dropped, since models put a
+/// newline or two there that would otherwise open the block.
+pub struct ThinkTagParser {
+    tags: Delimiters,
+    inside: bool,
+    trim_start: bool,
+    pending: String,
+}
+
+impl ThinkTagParser {
+    pub fn new(tags: Delimiters) -> Self {
+        Self {
+            tags,
+            inside: false,
+            trim_start: false,
+            pending: String::new(),
+        }
+    }
+
+    /// Feed a content delta and return the reasoning and the text that are now
+    /// safe to emit. Reasoning is returned first even when the delta had text
+    /// before an opening tag.
+    pub fn push(&mut self, delta: &str) -> (String, String) {
+        self.pending.push_str(delta);
+        let mut reasoning = String::new();
+        let mut text = String::new();
+
+        loop {
+            let tag = if self.inside {
+                &self.tags.close
+            } else {
+                &self.tags.open
+            };
+            let (part, found) = match self.pending.find(tag.as_str()) {
+                Some(at) => {
+                    let part: String = self.pending.drain(..at).collect();
+                    self.pending.drain(..tag.len());
+                    (part, true)
+                }
+                None => {
+                    // Hold back the longest tail that is still a prefix of the tag
+                    let held = self
+                        .pending
+                        .char_indices()
+                        .map(|(i, _)| i)
+                        .find(|&i| tag.starts_with(&self.pending[i..]))
+                        .unwrap_or(self.pending.len());
+                    (self.pending.drain(..held).collect(), false)
+                }
+            };
+
+            let out = if self.inside {
+                &mut reasoning
+            } else {
+                &mut text
+            };
+            self.emit(out, &part);
+            if !found {
+                return (reasoning, text);
+            }
+            self.inside = !self.inside;
+            self.trim_start = true;
+        }
+    }
+
+    /// Flush whatever is left once the content ends
+    pub fn finish(&mut self) -> (String, String) {
+        let rest = std::mem::take(&mut self.pending);
+        let mut out = String::new();
+        self.emit(&mut out, &rest);
+        if self.inside {
+            (out, String::new())
+        } else {
+            (String::new(), out)
+        }
+    }
+
+    fn emit(&mut self, out: &mut String, part: &str) {
+        let part = if self.trim_start {
+            part.trim_start()
+        } else {
+            part
+        };
+        if !part.is_empty() {
+            self.trim_start = false;
+            out.push_str(part);
+        }
+    }
+}
+
+/// Move inline reasoning out of a non-streaming response's text into a
+/// leading thinking block
+pub fn extract_from_response(tags: &Delimiters, resp: &mut anthropic::AnthropicResponse) {
+    let mut reasoning = String::new();
+    for block in &mut resp.content {
+        if let anthropic::ResponseContent::Text { text, .. } = block {
+            let mut parser = ThinkTagParser::new(tags.clone());
+            let (thinking, mut rest) = parser.push(text);
+            let (tail_thinking, tail) = parser.finish();
+            reasoning.push_str(&thinking);
+            reasoning.push_str(&tail_thinking);
+            rest.push_str(&tail);
+            *text = rest;
+        }
+    }
+    if reasoning.is_empty() {
+        return;
+    }
+
+    resp.content.retain(
+        |block| !matches!(block, anthropic::ResponseContent::Text { text, .. } if text.is_empty()),
+    );
+    match resp.content.first_mut() {
+        Some(anthropic::ResponseContent::Thinking { thinking, .. }) => {
+            thinking.push_str("\n\n");
+            thinking.push_str(&reasoning);
+        }
+        _ => resp.content.insert(
+            0,
+            anthropic::ResponseContent::Thinking {
+                content_type: "thinking".to_string(),
+                thinking: reasoning,
+            },
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Delimiters, ThinkTagParser};
+
+    #[test]
+    fn tags_split_across_deltas_are_found() {
+        let mut parser = ThinkTagParser::new(Delimiters::default());
+        let mut reasoning = String::new();
+        let mut text = String::new();
+        for delta in ["<thi", "nk>\n2+2", " is 4</th", "ink>\n\nIt's ", "4 <b>"] {
+            let (r, t) = parser.push(delta);
+            reasoning.push_str(&r);
+            text.push_str(&t);
+        }
+        let (r, t) = parser.finish();
+        reasoning.push_str(&r);
+        text.push_str(&t);
+
+        assert_eq!(reasoning, "2+2 is 4");
+        assert_eq!(text, "It's 4 <b>");
+    }
+}
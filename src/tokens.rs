@@ -0,0 +1,82 @@
+use crate::models::openai;
+
+/// Rough characters-per-token ratio for English text and code
+const CHARS_PER_TOKEN: usize = 4;
+/// Fixed cost of message framing (role, separators)
+const TOKENS_PER_MESSAGE: u32 = 4;
+/// Flat estimate for an image, close to a mid-sized image on most providers
+const TOKENS_PER_IMAGE: u32 = 1600;
+
+/// Characters of text that roughly make up the given number of tokens
+pub fn chars_for_tokens(tokens: u32) -> usize {
+    tokens as usize * CHARS_PER_TOKEN
+}
+
+/// Estimate the prompt tokens of an outbound request without a tokenizer
+pub fn estimate_request_tokens(req: &openai::OpenAIRequest) -> u32 {
+    let mut chars = 0;
+    let mut tokens = 0;
+
+    for message in &req.messages {
+        tokens += TOKENS_PER_MESSAGE;
+        match &message.content {
+            Some(openai::MessageContent::Text(text)) => chars += text.chars().count(),
+            Some(openai::MessageContent::Parts(parts)) => {
+                for part in parts {
+                    match part {
+                        openai::ContentPart::Text { text, .. } => chars += text.chars().count(),
+                        openai::ContentPart::ImageUrl { .. } => tokens += TOKENS_PER_IMAGE,
+                    }
+                }
+            }
+            None => {}
+        }
+        for call in message.tool_calls.iter().flatten() {
+            chars += call.function.name.len() + call.function.arguments.chars().count();
+        }
+    }
+
+    for tool in req.tools.iter().flatten() {
+        chars += tool.function.name.len();
+        chars += tool.function.description.as_deref().map_or(0, str::len);
+        chars += tool.function.parameters.to_string().len();
+    }
+
+    tokens + chars.div_ceil(CHARS_PER_TOKEN) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::estimate_request_tokens;
+    use crate::models::openai;
+
+    #[test]
+    fn estimate_counts_text_and_message_overhead() {
+        let req = openai::OpenAIRequest {
+            model: "m".to_string(),
+            messages: vec![openai::Message {
+                role: "user".to_string(),
+                content: Some(openai::MessageContent::Text("a".repeat(400))),
+                tool_calls: None,
+                tool_call_id: None,
+                name: None,
+                reasoning_content: None,
+            }],
+            max_tokens: None,
+            temperature: None,
+            top_p: None,
+            top_k: None,
+            stop: None,
+            stream: None,
+            tools: None,
+            tool_choice: None,
+            stream_options: None,
+            reasoning: None,
+            reasoning_effort: None,
+            response_format: None,
+            extra: serde_json::Map::new(),
+        };
+
+        assert_eq!(estimate_request_tokens(&req), 104);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::config::Config;
+use crate::models::openai;
+use serde_json::Value;
+
+/// Appended to a description that was cut short
+const TRIM_MARKER: &str = "...";
+
+/// Fit tool definitions within the configured per-tool and total size caps.
+///
+/// Each tool over its allowance loses, in order and only as far as needed,
+/// schema `examples`, the tail of its description, and the descriptions of
+/// its parameters. The total cap is shared out so small tools stay whole
+/// and the largest ones give up the difference.
+pub fn apply(config: &Config, tools: &mut [openai::Tool]) {
+    if config.tool_schema_max_chars.is_none() && config.tools_max_chars.is_none() {
+        return;
+    }
+    let allowances = allowances(config, &tools.iter().map(size).collect::<Vec<_>>());
+    for (tool, allowance) in tools.iter_mut().zip(allowances) {
+        if let Some(allowance) = allowance {
+            trim(tool, allowance);
+        }
+    }
+}
+
+/// Characters each tool may use, or None when it is within every cap.
+/// Tools are served smallest first, each getting an even share of what the
+/// smaller ones left over.
+fn allowances(config: &Config, sizes: &[usize]) -> Vec<Option<usize>> {
+    let per_tool = config.tool_schema_max_chars.unwrap_or(usize::MAX);
+    let mut allowances: Vec<Option<usize>> = sizes
+        .iter()
+        .map(|&size| (size > per_tool).then_some(per_tool))
+        .collect();
+
+    let Some(total) = config.tools_max_chars else {
+        return allowances;
+    };
+    let capped = |i: usize| sizes[i].min(per_tool);
+    if (0..sizes.len()).map(capped).sum::<usize>() <= total {
+        return allowances;
+    }
+
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| (capped(i), i));
+    let mut remaining = total;
+    for (served, &i) in order.iter().enumerate() {
+        let share = remaining / (order.len() - served);
+        if capped(i) <= share {
+            remaining -= capped(i);
+        } else {
+            allowances[i] = Some(share);
+            remaining -= share;
+        }
+    }
+    allowances
+}
+
+fn size(tool: &openai::Tool) -> usize {
+    serde_json::to_string(&tool.function).map_or(0, |json| json.chars().count())
+}
+
+/// Trim one tool down to the allowance, logging what was removed
+fn trim(tool: &mut openai::Tool, allowance: usize) {
+    let original = size(tool);
+    let mut removed = Vec::new();
+
+    if strip_keyword(&mut tool.function.parameters, "examples") {
+        removed.push("parameter examples".to_string());
+    }
+
+    let excess = size(tool).saturating_sub(allowance);
+    if excess > 0 {
+        if let Some(description) = &mut tool.function.description {
+            let length = description.chars().count();
+            let keep = length.saturating_sub(excess + TRIM_MARKER.len());
+            if keep < length {
+                *description = description.chars().take(keep).collect::<String>() + TRIM_MARKER;
+                removed.push(format!("{} description chars", length - keep));
+            }
+        }
+    }
+
+    if size(tool) > allowance {
+        if let Some(Value::Object(properties)) = tool.function.parameters.get_mut("properties") {
+            let mut stripped = false;
+            for property in properties.values_mut() {
+                stripped |= strip_keyword(property, "description");
+            }
+            if stripped {
+                removed.push("parameter descriptions".to_string());
+            }
+        }
+    }
+
+    let trimmed = size(tool);
+    tracing::warn!(
+        "Tool {} trimmed from {} to {} chars (limit {}): removed {}",
+        tool.function.name,
+        original,
+        trimmed,
+        allowance,
+        if removed.is_empty() {
+            "nothing".to_string()
+        } else {
+            removed.join(", ")
+        }
+    );
+}
+
+/// Remove a keyword from a schema and every schema nested in it
+fn strip_keyword(schema: &mut Value, keyword: &str) -> bool {
+    match schema {
+        Value::Object(obj) => {
+            let mut stripped = obj.remove(keyword).is_some();
+            for (key, value) in obj.iter_mut() {
+                // Property names are not keywords, but their schemas may hold some
+                if key == "properties" {
+                    if let Value::Object(properties) = value {
+                        for property in properties.values_mut() {
+                            stripped |= strip_keyword(property, keyword);
+                        }
+                    }
+                } else {
+                    stripped |= strip_keyword(value, keyword);
+                }
+            }
+            stripped
+        }
+        Value::Array(items) => items.iter_mut().fold(false, |stripped, item| {
+            strip_keyword(item, keyword) | stripped
+        }),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{allowances, apply, size};
+    use crate::config::Config;
+    use crate::models::openai;
+    use serde_json::json;
+
+    fn tool(name: &str, description: &str) -> openai::Tool {
+        openai::Tool {
+            tool_type: "function".to_string(),
+            function: openai::Function {
+                name: name.to_string(),
+                description: Some(description.to_string()),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {"type": "string", "description": "File to read", "examples": ["a.rs"]}
+                    }
+                }),
+                strict: None,
+            },
+        }
+    }
+
+    #[test]
+    fn total_cap_is_taken_from_the_largest_tools() {
+        let mut config = Config::for_tests();
+        config.tools_max_chars = Some(300);
+        assert_eq!(
+            allowances(&config, &[50, 400, 200]),
+            [None, Some(125), Some(125)]
+        );
+
+        config.tool_schema_max_chars = Some(100);
+        assert_eq!(
+            allowances(&config, &[50, 400, 200]),
+            [None, Some(100), Some(100)]
+        );
+    }
+
+    #[test]
+    fn oversized_tools_lose_examples_then_description() {
+        let mut config = Config::for_tests();
+        config.tool_schema_max_chars = Some(200);
+        let mut tools = vec![tool("small", "Reads"), tool("big", &"x".repeat(500))];
+        let small_before = size(&tools[0]);
+        apply(&config, &mut tools);
+
+        assert_eq!(size(&tools[0]), small_before);
+        assert!(size(&tools[1]) <= 200);
+        assert!(!tools[1]
+            .function
+            .parameters
+            .to_string()
+            .contains("examples"));
+        assert!(tools[1]
+            .function
+            .parameters
+            .to_string()
+            .contains("File to read"));
+        assert!(tools[1]
+            .function
+            .description
+            .as_deref()
+            .unwrap()
+            .ends_with("..."));
+    }
+}
@@ -0,0 +1,173 @@
+use crate::models::{anthropic, openai};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+/// Longest function name OpenAI-compatible backends accept
+const MAX_NAME_LEN: usize = 64;
+
+/// Per-request mapping between the client's tool names and the names sent
+/// upstream, which must match `^[a-zA-Z0-9_-]{1,64}$`. Names that already
+/// fit are kept as they are.
+#[derive(Debug, Clone, Default)]
+pub struct ToolNames {
+    upstream: HashMap<String, String>,
+    original: HashMap<String, String>,
+}
+
+impl ToolNames {
+    /// Assign an upstream name to every tool the request defines, keeping
+    /// sanitized names distinct
+    pub fn new(tools: &[anthropic::Tool]) -> Self {
+        let mut names = Self::default();
+        // Names that already fit are kept, so sanitized ones must avoid them
+        let (valid, invalid): (Vec<_>, Vec<_>) = tools
+            .iter()
+            .partition(|tool| sanitize(&tool.name) == tool.name);
+        for tool in valid {
+            names.insert(tool.name.clone(), tool.name.clone());
+        }
+        for tool in invalid {
+            if names.upstream.contains_key(&tool.name) {
+                continue;
+            }
+            let mut name = sanitize(&tool.name);
+            if names.original.contains_key(&name) {
+                name = with_hash(&name, &tool.name);
+            }
+            tracing::debug!("Tool {:?} is sent upstream as {}", tool.name, name);
+            names.insert(tool.name.clone(), name);
+        }
+        names
+    }
+
+    fn insert(&mut self, original: String, upstream: String) {
+        self.original.insert(upstream.clone(), original.clone());
+        self.upstream.insert(original, upstream);
+    }
+
+    /// Name to send upstream for a client tool name
+    pub fn upstream(&self, name: &str) -> String {
+        self.upstream
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| sanitize(name))
+    }
+
+    /// Client tool name for a name the upstream used
+    pub fn original(&self, name: &str) -> String {
+        self.original
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    /// Rename tools, earlier tool calls and any structured output schema
+    pub fn sanitize_request(&self, req: &mut openai::OpenAIRequest) {
+        for tool in req.tools.iter_mut().flatten() {
+            tool.function.name = self.upstream(&tool.function.name);
+        }
+        for message in &mut req.messages {
+            for call in message.tool_calls.iter_mut().flatten() {
+                call.function.name = self.upstream(&call.function.name);
+            }
+        }
+        if let Some(name) = req
+            .response_format
+            .as_mut()
+            .and_then(|format| format.pointer_mut("/json_schema/name"))
+        {
+            if let Some(upstream) = name.as_str().map(|n| self.upstream(n)) {
+                *name = upstream.into();
+            }
+        }
+    }
+
+    /// Restore the client's names in a non-streaming response
+    pub fn restore_response(&self, resp: &mut anthropic::AnthropicResponse) {
+        for block in &mut resp.content {
+            if let anthropic::ResponseContent::ToolUse { name, .. } = block {
+                *name = self.original(name);
+            }
+        }
+    }
+}
+
+/// Replace characters backends reject and shorten overlong names
+fn sanitize(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if cleaned.is_empty() {
+        "tool".to_string()
+    } else if cleaned.len() > MAX_NAME_LEN {
+        with_hash(&cleaned, name)
+    } else {
+        cleaned
+    }
+}
+
+/// Make a name unique to its original by ending it with a hash of the original
+fn with_hash(name: &str, original: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    original.hash(&mut hasher);
+    let suffix = format!("_{:08x}", hasher.finish() as u32);
+    let keep = name.len().min(MAX_NAME_LEN - suffix.len());
+    format!("{}{}", &name[..keep], suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ToolNames;
+    use crate::models::anthropic;
+
+    fn tool(name: &str) -> anthropic::Tool {
+        anthropic::Tool {
+            name: name.to_string(),
+            description: None,
+            input_schema: serde_json::json!({"type": "object"}),
+            tool_type: None,
+        }
+    }
+
+    #[test]
+    fn invalid_names_are_sanitized_and_mapped_back() {
+        let long = "x".repeat(80);
+        let names = ToolNames::new(&[
+            tool("read_file"),
+            tool("fs.read"),
+            tool("fs_read"),
+            tool("fs read"),
+            tool("météo"),
+            tool(&long),
+        ]);
+
+        assert_eq!(names.upstream("read_file"), "read_file");
+        assert_eq!(names.upstream("fs_read"), "fs_read");
+        let renamed = [names.upstream("fs.read"), names.upstream("fs read")];
+        assert!(renamed
+            .iter()
+            .all(|name| name.starts_with("fs_read_") && name.len() == 16));
+        assert_ne!(renamed[0], renamed[1]);
+        assert_eq!(names.upstream("météo"), "m_t_o");
+        assert_eq!(names.upstream(&long).len(), 64);
+
+        for original in [
+            "read_file",
+            "fs.read",
+            "fs_read",
+            "fs read",
+            "météo",
+            long.as_str(),
+        ] {
+            assert_eq!(names.original(&names.upstream(original)), original);
+        }
+    }
+}
@@ -1,32 +1,143 @@
-use crate::config::Config;
+use crate::config::{Config, ContentFilterMode, ThinkingBudgetParams, ThinkingHistory};
 use crate::error::{ProxyError, ProxyResult};
 use crate::models::{anthropic, openai};
+use crate::schema;
+use crate::tokens;
+use crate::tool_limits;
+use crate::tool_names::ToolNames;
 use serde_json::{json, Value};
 
+/// Marks a tool result the client flagged with `is_error`
+pub(crate) const TOOL_ERROR_PREFIX: &str = "[Tool call failed]\n";
+
+/// Parameters the proxy translates or sets itself, never forwarded as extras
+const PROXY_PARAMS: &[&str] = &[
+    "thinking",
+    "tool_choice",
+    "stream_options",
+    "reasoning",
+    "reasoning_effort",
+    "response_format",
+];
+
+/// Whether a request enables extended thinking
+pub fn thinking_enabled(req: &anthropic::AnthropicRequest) -> bool {
+    req.extra
+        .get("thinking")
+        .and_then(|v| v.as_object())
+        .map(|o| o.get("type").and_then(|t| t.as_str()) == Some("enabled"))
+        .unwrap_or(false)
+}
+
+/// Lightweight requests such as title generation and summaries: no thinking,
+/// no tools, and either a Haiku model or a small token budget
+fn is_background(req: &anthropic::AnthropicRequest, config: &Config, has_thinking: bool) -> bool {
+    if has_thinking || req.tools.as_ref().is_some_and(|tools| !tools.is_empty()) {
+        return false;
+    }
+    req.model.to_ascii_lowercase().contains("haiku")
+        || req.max_tokens <= config.small_model_max_tokens
+}
+
+/// The upstream model for a request: the small model for background work,
+/// the model configured for the request's Claude tier, then the
+/// thinking/non-thinking overrides, then the model from the request
+pub fn target_model(req: &anthropic::AnthropicRequest, config: &Config) -> String {
+    let has_thinking = thinking_enabled(req);
+    let small_model = config
+        .small_model
+        .as_deref()
+        .filter(|_| is_background(req, config, has_thinking));
+    if let Some(model) = small_model {
+        tracing::debug!("Sending background request for {} to {}", req.model, model);
+        model.to_string()
+    } else if let Some(model) = config.tier_model(&req.model) {
+        model.to_string()
+    } else if has_thinking {
+        config
+            .reasoning_model
+            .clone()
+            .unwrap_or_else(|| req.model.clone())
+    } else {
+        config
+            .completion_model
+            .clone()
+            .unwrap_or_else(|| req.model.clone())
+    }
+}
+
 /// Transform Anthropic request to OpenAI format
 pub fn anthropic_to_openai(
     req: anthropic::AnthropicRequest,
     config: &Config,
 ) -> ProxyResult<openai::OpenAIRequest> {
-    // Determine model based on thinking parameter
-    let has_thinking = req
+    let has_thinking = thinking_enabled(&req);
+    let model = target_model(&req, config);
+
+    let tool_names = ToolNames::new(req.tools.as_deref().unwrap_or_default());
+
+    // Let the thinking budget steer the upstream's reasoning, not just the model choice
+    let thinking_budget = req
         .extra
         .get("thinking")
-        .and_then(|v| v.as_object())
-        .map(|o| o.get("type").and_then(|t| t.as_str()) == Some("enabled"))
-        .unwrap_or(false);
+        .and_then(|t| t.get("budget_tokens"))
+        .and_then(|b| b.as_u64())
+        .filter(|_| has_thinking);
+    let (reasoning, reasoning_effort) = match (thinking_budget, config.thinking_budget_params()) {
+        (Some(budget), ThinkingBudgetParams::Effort) => {
+            (None, Some(effort_for_budget(budget).to_string()))
+        }
+        (Some(budget), ThinkingBudgetParams::MaxTokens) => {
+            (Some(json!({ "max_tokens": budget })), None)
+        }
+        _ => (None, None),
+    };
 
-    // Use configured model or fall back to the model from the request
-    let model = if has_thinking {
-        config.reasoning_model.clone()
-            .or_else(|| Some(req.model.clone()))
-            .unwrap_or_else(|| req.model.clone())
-    } else {
-        config.completion_model.clone()
-            .or_else(|| Some(req.model.clone()))
-            .unwrap_or_else(|| req.model.clone())
+    let top_k = match req.top_k {
+        Some(top_k) if !config.forwards_top_k() => {
+            tracing::debug!("Dropping top_k={} for an upstream that rejects it", top_k);
+            None
+        }
+        top_k => top_k,
     };
 
+    // A forced tool with an object schema can be asked for as structured output
+    let structured_tool = config
+        .structured_output_tools
+        .then(|| structured_output_tool(&req))
+        .flatten();
+    let response_format = structured_tool.map(|tool| {
+        tracing::debug!("Requesting forced tool {} as structured output", tool.name);
+        json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": tool.name,
+                "schema": schema::clean(tool.input_schema.clone(), config.schema_profile()),
+            }
+        })
+    });
+
+    // Tunnel provider-specific parameters the client opted into
+    let mut extra = serde_json::Map::new();
+    if let Value::Object(fields) = &req.extra {
+        for (name, value) in fields {
+            if PROXY_PARAMS.contains(&name.as_str()) {
+                continue;
+            }
+            if config.forwards_extra_param(name) {
+                extra.insert(name.clone(), value.clone());
+            } else {
+                tracing::debug!("Dropping unrecognized request parameter {}", name);
+            }
+        }
+    }
+    // OpenRouter's routing extensions, unless the client sent its own
+    if config.is_openrouter() {
+        for (name, value) in config.openrouter_params() {
+            extra.entry(name).or_insert(value);
+        }
+    }
+
     // Convert messages
     let mut openai_messages = Vec::new();
 
@@ -40,16 +151,27 @@ pub fn anthropic_to_openai(
                     tool_calls: None,
                     tool_call_id: None,
                     name: None,
+                    reasoning_content: None,
                 });
             }
             anthropic::SystemPrompt::Multiple(messages) => {
                 for msg in messages {
+                    let content = match msg.cache_control {
+                        Some(cache_control) if config.forwards_cache_control() => {
+                            openai::MessageContent::Parts(vec![openai::ContentPart::Text {
+                                text: msg.text,
+                                cache_control: Some(cache_control),
+                            }])
+                        }
+                        _ => openai::MessageContent::Text(msg.text),
+                    };
                     openai_messages.push(openai::Message {
                         role: "system".to_string(),
-                        content: Some(openai::MessageContent::Text(msg.text)),
+                        content: Some(content),
                         tool_calls: None,
                         tool_call_id: None,
                         name: None,
+                        reasoning_content: None,
                     });
                 }
             }
@@ -58,51 +180,155 @@ pub fn anthropic_to_openai(
 
     // Convert user/assistant messages
     for msg in req.messages {
-        let converted = convert_message(msg)?;
+        let converted = convert_message(msg, config)?;
         openai_messages.extend(converted);
     }
 
     // Convert tools
-    let tools = req.tools.and_then(|tools| {
-        let filtered: Vec<_> = tools
-            .into_iter()
-            .filter(|t| t.tool_type.as_deref() != Some("BatchTool"))
-            .collect();
+    let mut tools: Option<Vec<openai::Tool>> = req
+        .tools
+        .filter(|_| response_format.is_none())
+        .and_then(|tools| {
+            let filtered: Vec<_> = tools
+                .into_iter()
+                .filter(|t| t.tool_type.as_deref() != Some("BatchTool"))
+                .collect();
 
-        if filtered.is_empty() {
-            None
-        } else {
-            Some(
-                filtered
-                    .into_iter()
-                    .map(|t| openai::Tool {
-                        tool_type: "function".to_string(),
-                        function: openai::Function {
-                            name: t.name,
-                            description: t.description,
-                            parameters: clean_schema(t.input_schema),
-                        },
-                    })
-                    .collect(),
-            )
-        }
-    });
+            if filtered.is_empty() {
+                None
+            } else {
+                Some(
+                    filtered
+                        .into_iter()
+                        .map(|t| {
+                            let parameters = schema::clean(t.input_schema, config.schema_profile());
+                            let strict = config
+                                .strict_tools
+                                .then(|| schema::strict(&parameters))
+                                .flatten();
+                            if config.strict_tools && strict.is_none() {
+                                tracing::debug!("Tool {} can't use strict mode", t.name);
+                            }
+                            openai::Tool {
+                                tool_type: "function".to_string(),
+                                function: openai::Function {
+                                    name: t.name,
+                                    description: t.description,
+                                    strict: strict.is_some().then_some(true),
+                                    parameters: strict.unwrap_or(parameters),
+                                },
+                            }
+                        })
+                        .collect(),
+                )
+            }
+        });
 
-    Ok(openai::OpenAIRequest {
+    if let Some(tools) = tools.as_mut() {
+        tool_limits::apply(config, tools);
+    }
+
+    let mut openai_req = openai::OpenAIRequest {
         model,
         messages: openai_messages,
         max_tokens: Some(req.max_tokens),
         temperature: req.temperature,
         top_p: req.top_p,
+        top_k,
         stop: req.stop_sequences,
         stream: req.stream,
         tools,
         tool_choice: None,
-    })
+        // Most providers only report usage in a stream when asked to
+        stream_options: (req.stream == Some(true)).then_some(openai::StreamOptions {
+            include_usage: true,
+        }),
+        reasoning,
+        reasoning_effort,
+        response_format,
+        extra,
+    };
+    tool_names.sanitize_request(&mut openai_req);
+    Ok(openai_req)
+}
+
+/// The tool a request forces with `tool_choice`, when its schema is an object
+/// and so can serve as a JSON output contract
+fn structured_output_tool(req: &anthropic::AnthropicRequest) -> Option<&anthropic::Tool> {
+    let choice = req.extra.get("tool_choice")?;
+    if choice.get("type").and_then(|t| t.as_str()) != Some("tool") {
+        return None;
+    }
+    let name = choice.get("name").and_then(|n| n.as_str())?;
+    req.tools
+        .as_ref()?
+        .iter()
+        .find(|t| t.name == name)
+        .filter(|t| t.input_schema.get("type").and_then(|t| t.as_str()) == Some("object"))
+}
+
+/// Name of the tool a translated request asks for as structured output
+pub fn structured_output_name(openai_req: &openai::OpenAIRequest) -> Option<String> {
+    let format = openai_req.response_format.as_ref()?;
+    if format.get("type").and_then(|t| t.as_str()) != Some("json_schema") {
+        return None;
+    }
+    format["json_schema"]["name"].as_str().map(str::to_string)
+}
+
+/// Id for a tool_use block the upstream gave no id, from a structured output
+/// or a legacy `function_call`, derived from the message id so it is unique
+/// per response
+pub fn synthesized_tool_use_id(message_id: &str) -> String {
+    format!("toolu_{}", message_id.trim_start_matches("msg_"))
+}
+
+/// Turn the JSON text of a structured output response back into the forced tool's call
+pub fn wrap_structured_output(resp: &mut anthropic::AnthropicResponse, tool_name: &str) {
+    let text: String = resp
+        .content
+        .iter()
+        .filter_map(|block| match block {
+            anthropic::ResponseContent::Text { text, .. } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect();
+    let input = match serde_json::from_str::<Value>(&text) {
+        Ok(input) => input,
+        Err(err) => {
+            tracing::warn!(
+                "Structured output for {} is not valid JSON: {}",
+                tool_name,
+                err
+            );
+            return;
+        }
+    };
+
+    resp.content
+        .retain(|block| !matches!(block, anthropic::ResponseContent::Text { .. }));
+    resp.content.push(anthropic::ResponseContent::ToolUse {
+        content_type: "tool_use".to_string(),
+        id: synthesized_tool_use_id(&resp.id),
+        name: tool_name.to_string(),
+        input,
+    });
+    if resp.stop_reason.as_deref() == Some("end_turn") {
+        resp.stop_reason = Some("tool_use".to_string());
+    }
+}
+
+/// Bucket a thinking budget into OpenAI's reasoning effort levels
+fn effort_for_budget(budget_tokens: u64) -> &'static str {
+    match budget_tokens {
+        0..=4_095 => "low",
+        4_096..=16_383 => "medium",
+        _ => "high",
+    }
 }
 
 /// Convert a single Anthropic message to one or more OpenAI messages
-fn convert_message(msg: anthropic::Message) -> ProxyResult<Vec<openai::Message>> {
+fn convert_message(msg: anthropic::Message, config: &Config) -> ProxyResult<Vec<openai::Message>> {
     let mut result = Vec::new();
 
     match msg.content {
@@ -113,25 +339,28 @@ fn convert_message(msg: anthropic::Message) -> ProxyResult<Vec<openai::Message>>
                 tool_calls: None,
                 tool_call_id: None,
                 name: None,
+                reasoning_content: None,
             });
         }
         anthropic::MessageContent::Blocks(blocks) => {
             let mut current_content_parts = Vec::new();
             let mut tool_calls = Vec::new();
+            let mut reasoning = Vec::new();
 
             for block in blocks {
                 match block {
-                    anthropic::ContentBlock::Text { text, .. } => {
-                        current_content_parts.push(openai::ContentPart::Text { text });
+                    anthropic::ContentBlock::Text {
+                        text,
+                        cache_control,
+                    } => {
+                        current_content_parts.push(openai::ContentPart::Text {
+                            text,
+                            cache_control: cache_control
+                                .filter(|_| config.forwards_cache_control()),
+                        });
                     }
                     anthropic::ContentBlock::Image { source } => {
-                        let data_url = format!(
-                            "data:{};base64,{}",
-                            source.media_type, source.data
-                        );
-                        current_content_parts.push(openai::ContentPart::ImageUrl {
-                            image_url: openai::ImageUrl { url: data_url },
-                        });
+                        current_content_parts.push(image_part(&source));
                     }
                     anthropic::ContentBlock::ToolUse { id, name, input } => {
                         tool_calls.push(openai::ToolCall {
@@ -140,39 +369,86 @@ fn convert_message(msg: anthropic::Message) -> ProxyResult<Vec<openai::Message>>
                             function: openai::FunctionCall {
                                 name,
                                 arguments: serde_json::to_string(&input)
-                                    .map_err(|e| ProxyError::Serialization(e))?,
+                                    .map_err(ProxyError::Serialization)?,
                             },
                         });
                     }
                     anthropic::ContentBlock::ToolResult {
                         tool_use_id,
                         content,
-                        ..
+                        is_error,
                     } => {
                         // Tool results become separate messages with role "tool"
+                        let (mut content, images) = flatten_tool_result(content);
+                        if !images.is_empty() {
+                            // Tool messages are text-only, so images follow in the user message
+                            content.push_str(&format!(
+                                "\n[{} image(s) from this result are attached to the next message]",
+                                images.len()
+                            ));
+                            current_content_parts.push(openai::ContentPart::Text {
+                                text: format!("Images returned by tool call {}:", tool_use_id),
+                                cache_control: None,
+                            });
+                            current_content_parts.extend(images);
+                        }
+                        let mut content = match config.tool_result_max_chars {
+                            Some(max) => truncate_tool_result(content, max),
+                            None => content,
+                        };
+                        // OpenAI tool messages have no error flag, so say it in the text
+                        if is_error == Some(true) {
+                            content.insert_str(0, TOOL_ERROR_PREFIX);
+                        }
                         result.push(openai::Message {
                             role: "tool".to_string(),
                             content: Some(openai::MessageContent::Text(content)),
                             tool_calls: None,
                             tool_call_id: Some(tool_use_id),
                             name: None,
+                            reasoning_content: None,
                         });
                     }
-                    anthropic::ContentBlock::Thinking { .. } => {
-                        // Skip thinking blocks in request
+                    anthropic::ContentBlock::Thinking { thinking } => {
+                        if config.thinking_history != ThinkingHistory::Drop {
+                            reasoning.push(thinking);
+                        }
                     }
                 }
             }
 
+            let reasoning = (!reasoning.is_empty()).then(|| reasoning.join("\n\n"));
+            let reasoning = match (reasoning, config.thinking_history) {
+                (Some(thinking), ThinkingHistory::Text) => {
+                    let folded = format!("<thinking>\n{}\n</thinking>", thinking);
+                    match current_content_parts.first_mut() {
+                        Some(openai::ContentPart::Text { text, .. }) => {
+                            *text = format!("{}\n\n{}", folded, text);
+                        }
+                        _ => current_content_parts.insert(
+                            0,
+                            openai::ContentPart::Text {
+                                text: folded,
+                                cache_control: None,
+                            },
+                        ),
+                    }
+                    None
+                }
+                (reasoning, _) => reasoning,
+            };
+
             // Add message with content and/or tool calls
             if !current_content_parts.is_empty() || !tool_calls.is_empty() {
                 let content = if current_content_parts.is_empty() {
                     None
                 } else if current_content_parts.len() == 1 {
                     match &current_content_parts[0] {
-                        openai::ContentPart::Text { text } => {
-                            Some(openai::MessageContent::Text(text.clone()))
-                        }
+                        // A caching breakpoint needs the part form to travel with the text
+                        openai::ContentPart::Text {
+                            text,
+                            cache_control: None,
+                        } => Some(openai::MessageContent::Text(text.clone())),
                         _ => Some(openai::MessageContent::Parts(current_content_parts)),
                     }
                 } else {
@@ -189,6 +465,7 @@ fn convert_message(msg: anthropic::Message) -> ProxyResult<Vec<openai::Message>>
                     },
                     tool_call_id: None,
                     name: None,
+                    reasoning_content: reasoning,
                 });
             }
         }
@@ -197,27 +474,64 @@ fn convert_message(msg: anthropic::Message) -> ProxyResult<Vec<openai::Message>>
     Ok(result)
 }
 
-/// Clean JSON schema by removing unsupported formats
-fn clean_schema(mut schema: Value) -> Value {
-    if let Some(obj) = schema.as_object_mut() {
-        // Remove "format": "uri"
-        if obj.get("format").and_then(|v| v.as_str()) == Some("uri") {
-            obj.remove("format");
+fn image_part(source: &anthropic::ImageSource) -> openai::ContentPart {
+    let url = match source {
+        anthropic::ImageSource::Base64 { media_type, data } => {
+            format!("data:{};base64,{}", media_type, data)
         }
+        anthropic::ImageSource::Url { url } => url.clone(),
+    };
+    openai::ContentPart::ImageUrl {
+        image_url: openai::ImageUrl { url },
+    }
+}
 
-        // Recursively clean nested schemas
-        if let Some(properties) = obj.get_mut("properties").and_then(|v| v.as_object_mut()) {
-            for (_, value) in properties.iter_mut() {
-                *value = clean_schema(value.clone());
-            }
-        }
+/// Split tool result content into its text, with blocks joined by newlines,
+/// and its images
+fn flatten_tool_result(
+    content: anthropic::ToolResultContent,
+) -> (String, Vec<openai::ContentPart>) {
+    let blocks = match content {
+        anthropic::ToolResultContent::Text(text) => return (text, Vec::new()),
+        anthropic::ToolResultContent::Blocks(blocks) => blocks,
+    };
 
-        if let Some(items) = obj.get_mut("items") {
-            *items = clean_schema(items.clone());
+    let mut texts = Vec::new();
+    let mut images = Vec::new();
+    for block in blocks {
+        match block {
+            anthropic::ContentBlock::Text { text, .. } => texts.push(text),
+            anthropic::ContentBlock::Image { source } => images.push(image_part(&source)),
+            other => tracing::debug!("Ignoring unsupported block in tool result: {:?}", other),
         }
     }
+    (texts.join("\n"), images)
+}
+
+/// Keep the head and tail of an oversized tool result, with a marker noting
+/// how much was cut from the middle
+fn truncate_tool_result(content: String, max_chars: usize) -> String {
+    let total = content.chars().count();
+    if total <= max_chars {
+        return content;
+    }
+    // Errors and summaries tend to sit at the end of command output
+    let head = max_chars * 2 / 3;
+    let tail = max_chars - head;
+    let omitted = total - head - tail;
+    tracing::debug!(
+        "Truncated tool result from {} to {} characters",
+        total,
+        max_chars
+    );
 
-    schema
+    let mut truncated: String = content.chars().take(head).collect();
+    truncated.push_str(&format!(
+        "\n\n[... {} characters omitted by the proxy ...]\n\n",
+        omitted
+    ));
+    truncated.extend(content.chars().skip(total - tail));
+    truncated
 }
 
 /// Transform OpenAI response to Anthropic format
@@ -228,10 +542,18 @@ pub fn openai_to_anthropic(
     let choice = resp
         .choices
         .first()
-        .ok_or_else(|| ProxyError::Transform("No choices in response".to_string()))?;
+        .ok_or_else(|| ProxyError::EmptyResponse("upstream response has no choices".to_string()))?;
 
     let mut content = Vec::new();
 
+    // Reasoning comes first, as Anthropic puts thinking before the answer
+    if let Some(reasoning) = choice.message.reasoning_text() {
+        content.push(anthropic::ResponseContent::Thinking {
+            content_type: "thinking".to_string(),
+            thinking: reasoning,
+        });
+    }
+
     // Add text content if present
     if let Some(text) = &choice.message.content {
         if !text.is_empty() {
@@ -242,11 +564,26 @@ pub fn openai_to_anthropic(
         }
     }
 
+    // Older servers answer with a single deprecated function_call instead
+    if let Some(call) = choice
+        .message
+        .function_call
+        .as_ref()
+        .filter(|_| choice.message.tool_calls.is_none())
+    {
+        content.push(anthropic::ResponseContent::ToolUse {
+            content_type: "tool_use".to_string(),
+            id: synthesized_tool_use_id(resp.id.as_deref().unwrap_or("msg_proxy")),
+            name: call.name.clone(),
+            input: parse_tool_arguments(&call.name, &call.arguments),
+        });
+    }
+
     // Add tool calls if present
     if let Some(tool_calls) = &choice.message.tool_calls {
         for tool_call in tool_calls {
-            let input: Value = serde_json::from_str(&tool_call.function.arguments)
-                .unwrap_or_else(|_| json!({}));
+            let input =
+                parse_tool_arguments(&tool_call.function.name, &tool_call.function.arguments);
 
             content.push(anthropic::ResponseContent::ToolUse {
                 content_type: "tool_use".to_string(),
@@ -257,16 +594,11 @@ pub fn openai_to_anthropic(
         }
     }
 
-    let stop_reason = choice
-        .finish_reason
-        .as_ref()
-        .map(|r| match r.as_str() {
-            "tool_calls" => "tool_use",
-            "stop" => "end_turn",
-            "length" => "max_tokens",
-            _ => "end_turn",
-        })
-        .map(String::from);
+    let stop_sequence = choice.stop_sequence().map(String::from);
+    let stop_reason = match stop_sequence {
+        Some(_) => Some("stop_sequence".to_string()),
+        None => map_stop_reason(choice.finish_reason.as_deref()),
+    };
 
     Ok(anthropic::AnthropicResponse {
         id: resp.id.unwrap_or_else(|| "msg_proxy".to_string()),
@@ -275,27 +607,253 @@ pub fn openai_to_anthropic(
         content,
         model: resp.model.unwrap_or_else(|| fallback_model.to_string()),
         stop_reason,
-        stop_sequence: None,
-        usage: anthropic::Usage {
-            input_tokens: resp.usage.prompt_tokens,
-            output_tokens: resp.usage.completion_tokens,
-        },
+        stop_sequence,
+        usage: anthropic_usage(&resp.usage),
     })
 }
 
+/// Apply the model's thinking policy to a non-streaming response: hidden
+/// reasoning is removed and the rest is cut at the model's reasoning limit
+pub fn limit_thinking(config: &Config, model: &str, resp: &mut anthropic::AnthropicResponse) {
+    let hidden = config.hides_thinking(model);
+    let max_chars = config
+        .reasoning_limit_for(model)
+        .map(tokens::chars_for_tokens);
+    resp.content.retain_mut(|block| match block {
+        anthropic::ResponseContent::Thinking { thinking, .. } => {
+            if let Some(max) = max_chars.filter(|&max| thinking.chars().count() > max) {
+                *thinking = thinking.chars().take(max).collect();
+            }
+            !hidden && !thinking.is_empty()
+        }
+        _ => true,
+    });
+}
+
+/// Parse the arguments of an upstream tool call. Empty arguments mean `{}`,
+/// near-valid JSON is repaired, and anything else is logged and replaced by `{}`.
+pub fn parse_tool_arguments(name: &str, raw: &str) -> Value {
+    if raw.trim().is_empty() {
+        return json!({});
+    }
+    if let Ok(input @ Value::Object(_)) = serde_json::from_str(raw) {
+        return input;
+    }
+    match repair_json(raw) {
+        Some(input) => {
+            tracing::debug!(
+                tool = name,
+                raw = raw,
+                "Repaired malformed tool call arguments"
+            );
+            input
+        }
+        None => {
+            tracing::warn!(
+                tool = name,
+                raw = raw,
+                "Unparseable tool call arguments replaced by {{}}"
+            );
+            json!({})
+        }
+    }
+}
+
+/// Tool call arguments as they should be sent to the client: the raw text
+/// when it is already a JSON object, otherwise the normalized input
+pub fn normalize_tool_arguments(name: &str, raw: &str) -> String {
+    match serde_json::from_str(raw) {
+        Ok(Value::Object(_)) => raw.to_string(),
+        _ => parse_tool_arguments(name, raw).to_string(),
+    }
+}
+
+/// Recover a JSON object from near-valid text: code fences, text around the
+/// object, trailing garbage, an object cut off before its end, or the
+/// mistakes fixed by `fix_syntax`
+fn repair_json(raw: &str) -> Option<Value> {
+    let start = raw.find('{')?;
+    let text = fix_syntax(raw[start..].trim_end().trim_end_matches("```"));
+    let text = text.as_str();
+
+    // The first complete value wins; whatever follows it is ignored
+    let mut values = serde_json::Deserializer::from_str(text).into_iter::<Value>();
+    if let Some(Ok(value @ Value::Object(_))) = values.next() {
+        return Some(value);
+    }
+
+    // Close whatever strings, arrays and objects were left open
+    let mut closers = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in text.chars() {
+        match (in_string, c) {
+            (true, _) if escaped => escaped = false,
+            (true, '\\') => escaped = true,
+            (true, '"') => in_string = false,
+            (true, _) => {}
+            (false, '"') => in_string = true,
+            (false, '{') => closers.push('}'),
+            (false, '[') => closers.push(']'),
+            (false, '}' | ']') => {
+                closers.pop();
+            }
+            _ => {}
+        }
+    }
+    let mut repaired = text.to_string();
+    if in_string {
+        repaired.push('"');
+    }
+    let trimmed = repaired.trim_end().trim_end_matches(',').len();
+    repaired.truncate(trimmed);
+    repaired.extend(closers.into_iter().rev());
+    match serde_json::from_str(&repaired) {
+        Ok(value @ Value::Object(_)) => Some(value),
+        _ => None,
+    }
+}
+
+/// Fix the JSON mistakes local models make most: single-quoted strings,
+/// trailing commas, Python's `True`, `False` and `None`, and raw control
+/// characters inside strings
+fn fix_syntax(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut quote: Option<char> = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        match quote {
+            Some(q) => match c {
+                '\\' => match chars.get(i) {
+                    // `\'` is only needed in single-quoted strings, and invalid JSON
+                    Some('\'') => {
+                        out.push('\'');
+                        i += 1;
+                    }
+                    Some(&next) => {
+                        out.push('\\');
+                        out.push(next);
+                        i += 1;
+                    }
+                    None => {}
+                },
+                _ if c == q => {
+                    out.push('"');
+                    quote = None;
+                }
+                '"' => out.push_str("\\\""),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c),
+            },
+            None => match c {
+                '"' | '\'' => {
+                    out.push('"');
+                    quote = Some(c);
+                }
+                ',' => {
+                    let next = chars[i..].iter().find(|c| !c.is_whitespace());
+                    if !matches!(next, Some('}' | ']')) {
+                        out.push(',');
+                    }
+                }
+                c if c.is_ascii_alphabetic() => {
+                    let end = chars[i..]
+                        .iter()
+                        .position(|c| !c.is_ascii_alphanumeric())
+                        .map_or(chars.len(), |n| i + n);
+                    let word: String = chars[i - 1..end].iter().collect();
+                    out.push_str(match word.as_str() {
+                        "True" => "true",
+                        "False" => "false",
+                        "None" => "null",
+                        word => word,
+                    });
+                    i = end;
+                }
+                c => out.push(c),
+            },
+        }
+    }
+    out
+}
+
+/// Remove null object fields from tool input. Strict mode makes optional
+/// parameters nullable, and clients expect them left out instead.
+pub fn drop_null_fields(input: &mut Value) {
+    match input {
+        Value::Object(fields) => {
+            fields.retain(|_, value| !value.is_null());
+            fields.values_mut().for_each(drop_null_fields);
+        }
+        Value::Array(items) => items.iter_mut().for_each(drop_null_fields),
+        _ => {}
+    }
+}
+
+/// OpenAI counts cached tokens inside prompt_tokens, Anthropic separately
+pub fn anthropic_usage(usage: &openai::Usage) -> anthropic::Usage {
+    let cached = usage.cached_tokens();
+    anthropic::Usage {
+        input_tokens: usage.prompt_tokens.saturating_sub(cached),
+        output_tokens: usage.completion_tokens,
+        cache_read_input_tokens: (cached > 0).then_some(cached),
+    }
+}
+
+/// Stop reason for a response the upstream's content filter stopped, or
+/// None when `CONTENT_FILTER` makes it an error. The upstream's filter
+/// verdicts are logged either way.
+pub fn content_filter_stop_reason(config: &Config, details: Option<&Value>) -> Option<String> {
+    let categories: Vec<&str> = details
+        .and_then(Value::as_object)
+        .map(|results| {
+            results
+                .iter()
+                .filter(|(_, verdict)| verdict["filtered"].as_bool() == Some(true))
+                .map(|(category, _)| category.as_str())
+                .collect()
+        })
+        .unwrap_or_default();
+    let details = details.map(Value::to_string).unwrap_or_default();
+    tracing::warn!(
+        categories = ?categories,
+        details = details,
+        "Upstream content filter stopped the response"
+    );
+    match config.content_filter {
+        ContentFilterMode::Refusal => Some("refusal".to_string()),
+        ContentFilterMode::EndTurn => Some("end_turn".to_string()),
+        ContentFilterMode::Error => None,
+    }
+}
+
 /// Map OpenAI finish reason to Anthropic stop reason
 pub fn map_stop_reason(finish_reason: Option<&str>) -> Option<String> {
-    finish_reason.map(|r| match r {
-        "tool_calls" => "tool_use",
-        "stop" => "end_turn",
-        "length" => "max_tokens",
-        _ => "end_turn",
-    }.to_string())
+    finish_reason.map(|r| {
+        match r {
+            "tool_calls" | "function_call" => "tool_use",
+            "stop" => "end_turn",
+            "length" => "max_tokens",
+            "content_filter" => "refusal",
+            _ => "end_turn",
+        }
+        .to_string()
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use super::openai_to_anthropic;
+    use super::{
+        anthropic_to_openai, convert_message, limit_thinking, openai_to_anthropic,
+        truncate_tool_result,
+    };
+    use crate::config::{CacheControlMode, Config, ThinkingBudgetParams, ThinkingHistory};
     use crate::models::openai;
 
     #[test]
@@ -311,13 +869,20 @@ mod tests {
                     role: "assistant".to_string(),
                     content: Some("pong".to_string()),
                     tool_calls: None,
+                    reasoning: None,
+                    reasoning_content: None,
+                    reasoning_details: None,
+                    function_call: None,
                 },
                 finish_reason: Some("stop".to_string()),
+                stop_reason: None,
+                content_filter_results: None,
             }],
             usage: openai::Usage {
                 prompt_tokens: 10,
                 completion_tokens: 2,
                 total_tokens: 12,
+                prompt_tokens_details: None,
             },
             system_fingerprint: None,
         };
@@ -343,13 +908,20 @@ mod tests {
                     role: "assistant".to_string(),
                     content: Some("hello".to_string()),
                     tool_calls: None,
+                    reasoning: None,
+                    reasoning_content: None,
+                    reasoning_details: None,
+                    function_call: None,
                 },
                 finish_reason: Some("stop".to_string()),
+                stop_reason: None,
+                content_filter_results: None,
             }],
             usage: openai::Usage {
                 prompt_tokens: 5,
                 completion_tokens: 1,
                 total_tokens: 6,
+                prompt_tokens_details: None,
             },
             system_fingerprint: None,
         };
@@ -359,4 +931,533 @@ mod tests {
         assert_eq!(anthropic.id, "chatcmpl-abc123");
         assert_eq!(anthropic.model, "gpt-4o");
     }
+
+    #[test]
+    fn reasoning_becomes_a_leading_thinking_block() {
+        let response: openai::OpenAIResponse = serde_json::from_value(serde_json::json!({
+            "model": "deepseek-reasoner",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "4", "reasoning_content": "2+2 is 4"},
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8}
+        }))
+        .unwrap();
+        let anthropic = openai_to_anthropic(response, "fallback-model").unwrap();
+        let blocks = serde_json::to_value(&anthropic.content).unwrap();
+        assert_eq!(
+            blocks,
+            serde_json::json!([
+                {"type": "thinking", "thinking": "2+2 is 4"},
+                {"type": "text", "text": "4"}
+            ])
+        );
+
+        let mut config = Config::for_tests();
+        config.reasoning_limits = vec![("deepseek*".to_string(), 1)];
+        let mut limited = anthropic.clone();
+        limit_thinking(&config, "deepseek-reasoner", &mut limited);
+        assert_eq!(
+            serde_json::to_value(&limited.content).unwrap()[0]["thinking"],
+            "2+2 "
+        );
+
+        config.hide_thinking_models = vec!["*".to_string()];
+        let mut hidden = anthropic;
+        limit_thinking(&config, "deepseek-reasoner", &mut hidden);
+        assert_eq!(hidden.content.len(), 1);
+    }
+
+    #[test]
+    fn array_content_is_joined_into_text() {
+        let response: openai::OpenAIResponse = serde_json::from_value(serde_json::json!({
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": [
+                        {"type": "text", "text": "Hello, "},
+                        {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}},
+                        {"type": "text", "text": "world"}
+                    ]
+                },
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7}
+        }))
+        .unwrap();
+        let anthropic = openai_to_anthropic(response, "m").unwrap();
+        assert_eq!(
+            serde_json::to_value(&anthropic.content).unwrap(),
+            serde_json::json!([{"type": "text", "text": "Hello, world"}])
+        );
+    }
+
+    #[test]
+    fn legacy_function_calls_become_tool_use() {
+        let response: openai::OpenAIResponse = serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-7",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "function_call": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}
+                },
+                "finish_reason": "function_call"
+            }],
+            "usage": {"prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7}
+        }))
+        .unwrap();
+        let anthropic = openai_to_anthropic(response, "m").unwrap();
+        assert_eq!(anthropic.stop_reason.as_deref(), Some("tool_use"));
+        assert_eq!(
+            serde_json::to_value(&anthropic.content).unwrap(),
+            serde_json::json!([{
+                "type": "tool_use",
+                "id": "toolu_chatcmpl-7",
+                "name": "get_weather",
+                "input": {"city": "Paris"}
+            }])
+        );
+    }
+
+    #[test]
+    fn vllm_stop_reasons_are_reported_as_stop_sequences() {
+        let response = |stop_reason: serde_json::Value| {
+            serde_json::from_value::<openai::OpenAIResponse>(serde_json::json!({
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "Hi"},
+                    "finish_reason": "stop",
+                    "stop_reason": stop_reason
+                }],
+                "usage": {"prompt_tokens": 5, "completion_tokens": 1, "total_tokens": 6}
+            }))
+            .unwrap()
+        };
+
+        let matched = openai_to_anthropic(response("\n\nHuman:".into()), "m").unwrap();
+        assert_eq!(matched.stop_reason.as_deref(), Some("stop_sequence"));
+        assert_eq!(matched.stop_sequence.as_deref(), Some("\n\nHuman:"));
+
+        let eos = openai_to_anthropic(response(151643.into()), "m").unwrap();
+        assert_eq!(eos.stop_reason.as_deref(), Some("end_turn"));
+        assert!(eos.stop_sequence.is_none());
+    }
+
+    #[test]
+    fn oversized_tool_results_keep_head_and_tail() {
+        assert_eq!(truncate_tool_result("short".to_string(), 10), "short");
+
+        let content = format!("{}{}", "a".repeat(100), "z".repeat(100));
+        let truncated = truncate_tool_result(content, 30);
+        assert!(truncated.starts_with(&"a".repeat(20)));
+        assert!(truncated.ends_with(&"z".repeat(10)));
+        assert!(truncated.contains("[... 170 characters omitted by the proxy ...]"));
+    }
+
+    #[test]
+    fn thinking_budgets_become_upstream_reasoning_params() {
+        let request = || {
+            serde_json::from_value(serde_json::json!({
+                "model": "claude-sonnet-4",
+                "max_tokens": 32000,
+                "thinking": {"type": "enabled", "budget_tokens": 10000},
+                "messages": [{"role": "user", "content": "hi"}]
+            }))
+            .unwrap()
+        };
+        let mut config = Config::for_tests();
+
+        config.base_url = "https://api.openai.com".to_string();
+        let req = anthropic_to_openai(request(), &config).unwrap();
+        assert_eq!(req.reasoning_effort.as_deref(), Some("medium"));
+        assert!(req.reasoning.is_none());
+
+        config.base_url = "https://openrouter.ai/api".to_string();
+        let req = anthropic_to_openai(request(), &config).unwrap();
+        assert_eq!(req.reasoning.unwrap()["max_tokens"], 10000);
+
+        config.thinking_budget_params = ThinkingBudgetParams::Off;
+        let req = anthropic_to_openai(request(), &config).unwrap();
+        assert!(req.reasoning.is_none() && req.reasoning_effort.is_none());
+    }
+
+    #[test]
+    fn claude_tiers_map_to_their_own_models() {
+        let request = |model: &str| {
+            serde_json::from_value(serde_json::json!({
+                "model": model,
+                "max_tokens": 1024,
+                "messages": [{"role": "user", "content": "hi"}]
+            }))
+            .unwrap()
+        };
+        let mut config = Config::for_tests();
+        config.completion_model = Some("gpt-4o".to_string());
+        config.haiku_model = Some("gpt-4o-mini".to_string());
+        config.opus_model = Some("o3".to_string());
+
+        let model = |name: &str| anthropic_to_openai(request(name), &config).unwrap().model;
+        assert_eq!(model("claude-3-5-haiku-20241022"), "gpt-4o-mini");
+        assert_eq!(model("claude-opus-4-1"), "o3");
+        // Tiers without a model keep the existing overrides
+        assert_eq!(model("claude-sonnet-4-5"), "gpt-4o");
+    }
+
+    #[test]
+    fn background_requests_use_the_small_model() {
+        let request = |model: &str, max_tokens: u32, tools: bool| {
+            let mut body = serde_json::json!({
+                "model": model,
+                "max_tokens": max_tokens,
+                "messages": [{"role": "user", "content": "Write a title"}]
+            });
+            if tools {
+                body["tools"] = serde_json::json!([
+                    {"name": "ls", "input_schema": {"type": "object"}}
+                ]);
+            }
+            serde_json::from_value(body).unwrap()
+        };
+        let mut config = Config::for_tests();
+        config.small_model = Some("gpt-4o-mini".to_string());
+        config.haiku_model = Some("gpt-4.1-mini".to_string());
+
+        let model = |name: &str, max_tokens: u32, tools: bool| {
+            anthropic_to_openai(request(name, max_tokens, tools), &config)
+                .unwrap()
+                .model
+        };
+        assert_eq!(model("claude-3-5-haiku", 8192, false), "gpt-4o-mini");
+        assert_eq!(model("claude-sonnet-4-5", 512, false), "gpt-4o-mini");
+        assert_eq!(model("claude-3-5-haiku", 8192, true), "gpt-4.1-mini");
+        assert_eq!(model("claude-sonnet-4-5", 8192, false), "claude-sonnet-4-5");
+    }
+
+    #[test]
+    fn earlier_thinking_follows_the_history_strategy() {
+        let message = || {
+            serde_json::from_value(serde_json::json!({
+                "role": "assistant",
+                "content": [
+                    {"type": "thinking", "thinking": "2+2 is 4", "signature": "sig"},
+                    {"type": "text", "text": "4"}
+                ]
+            }))
+            .unwrap()
+        };
+        let text = |msg: &openai::Message| match &msg.content {
+            Some(openai::MessageContent::Text(text)) => text.clone(),
+            other => panic!("expected text, got {:?}", other),
+        };
+        let mut config = Config::for_tests();
+
+        let dropped = convert_message(message(), &config).unwrap();
+        assert_eq!(text(&dropped[0]), "4");
+        assert!(dropped[0].reasoning_content.is_none());
+
+        config.thinking_history = ThinkingHistory::Reasoning;
+        let forwarded = convert_message(message(), &config).unwrap();
+        assert_eq!(text(&forwarded[0]), "4");
+        assert_eq!(forwarded[0].reasoning_content.as_deref(), Some("2+2 is 4"));
+
+        config.thinking_history = ThinkingHistory::Text;
+        let folded = convert_message(message(), &config).unwrap();
+        assert_eq!(text(&folded[0]), "<thinking>\n2+2 is 4\n</thinking>\n\n4");
+        assert!(folded[0].reasoning_content.is_none());
+    }
+
+    #[test]
+    fn structured_tool_results_are_flattened_and_images_lifted_out() {
+        let message = serde_json::from_value(serde_json::json!({
+            "role": "user",
+            "content": [{
+                "type": "tool_result",
+                "tool_use_id": "toolu_1",
+                "content": [
+                    {"type": "text", "text": "line one"},
+                    {"type": "text", "text": "line two"},
+                    {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBOR"}}
+                ]
+            }]
+        }))
+        .unwrap();
+
+        let converted = convert_message(message, &Config::for_tests()).unwrap();
+        assert_eq!(converted.len(), 2);
+        assert_eq!(converted[0].role, "tool");
+        match &converted[0].content {
+            Some(openai::MessageContent::Text(text)) => {
+                assert!(text.starts_with("line one\nline two\n"));
+            }
+            other => panic!("expected text, got {:?}", other),
+        }
+        match &converted[1].content {
+            Some(openai::MessageContent::Parts(parts)) => assert!(matches!(
+                &parts[1],
+                openai::ContentPart::ImageUrl { image_url } if image_url.url == "data:image/png;base64,iVBOR"
+            )),
+            other => panic!("expected parts, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn failed_tool_results_are_marked() {
+        let message = serde_json::from_value(serde_json::json!({
+            "role": "user",
+            "content": [
+                {"type": "tool_result", "tool_use_id": "toolu_1", "content": "No such file", "is_error": true},
+                {"type": "tool_result", "tool_use_id": "toolu_2", "content": "ok", "is_error": false}
+            ]
+        }))
+        .unwrap();
+
+        let converted = convert_message(message, &Config::for_tests()).unwrap();
+        let texts: Vec<_> = converted
+            .iter()
+            .map(|msg| match &msg.content {
+                Some(openai::MessageContent::Text(text)) => text.as_str(),
+                other => panic!("expected text, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(texts, ["[Tool call failed]\nNo such file", "ok"]);
+    }
+
+    #[test]
+    fn url_image_sources_are_sent_by_reference() {
+        let message = serde_json::from_value(serde_json::json!({
+            "role": "user",
+            "content": [
+                {"type": "text", "text": "what is this?"},
+                {"type": "image", "source": {"type": "url", "url": "https://example.com/cat.png"}}
+            ]
+        }))
+        .unwrap();
+
+        let converted = convert_message(message, &Config::for_tests()).unwrap();
+        match &converted[0].content {
+            Some(openai::MessageContent::Parts(parts)) => assert!(matches!(
+                &parts[1],
+                openai::ContentPart::ImageUrl { image_url } if image_url.url == "https://example.com/cat.png"
+            )),
+            other => panic!("expected parts, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cache_breakpoints_are_forwarded_only_when_enabled() {
+        let request = || {
+            serde_json::from_value(serde_json::json!({
+                "model": "anthropic/claude-sonnet-4",
+                "max_tokens": 100,
+                "system": [{"type": "text", "text": "You are terse.", "cache_control": {"type": "ephemeral"}}],
+                "messages": [{"role": "user", "content": [
+                    {"type": "text", "text": "hi", "cache_control": {"type": "ephemeral"}}
+                ]}]
+            }))
+            .unwrap()
+        };
+        let mut config = Config::for_tests();
+
+        config.cache_control = CacheControlMode::Strip;
+        let stripped =
+            serde_json::to_value(anthropic_to_openai(request(), &config).unwrap()).unwrap();
+        assert_eq!(stripped["messages"][0]["content"], "You are terse.");
+        assert_eq!(stripped["messages"][1]["content"], "hi");
+
+        config.cache_control = CacheControlMode::Pass;
+        let passed =
+            serde_json::to_value(anthropic_to_openai(request(), &config).unwrap()).unwrap();
+        for message in passed["messages"].as_array().unwrap() {
+            assert_eq!(message["content"][0]["cache_control"]["type"], "ephemeral");
+        }
+    }
+
+    #[test]
+    fn cached_prompt_tokens_are_reported_separately() {
+        let usage: openai::Usage = serde_json::from_value(serde_json::json!({
+            "prompt_tokens": 1000,
+            "completion_tokens": 20,
+            "total_tokens": 1020,
+            "prompt_tokens_details": {"cached_tokens": 800}
+        }))
+        .unwrap();
+        let usage = super::anthropic_usage(&usage);
+        assert_eq!(usage.input_tokens, 200);
+        assert_eq!(usage.cache_read_input_tokens, Some(800));
+    }
+
+    #[test]
+    fn forced_tool_round_trips_through_structured_output() {
+        let request = || {
+            serde_json::from_value(serde_json::json!({
+                "model": "claude-sonnet-4",
+                "max_tokens": 1024,
+                "messages": [{"role": "user", "content": "Extract the city"}],
+                "tools": [{
+                    "name": "record_city",
+                    "input_schema": {"type": "object", "properties": {"city": {"type": "string"}}}
+                }],
+                "tool_choice": {"type": "tool", "name": "record_city"}
+            }))
+            .unwrap()
+        };
+        let mut config = Config::for_tests();
+
+        let req = anthropic_to_openai(request(), &config).unwrap();
+        assert!(req.response_format.is_none() && req.tools.is_some());
+
+        config.structured_output_tools = true;
+        let req = anthropic_to_openai(request(), &config).unwrap();
+        assert!(req.tools.is_none());
+        assert_eq!(
+            super::structured_output_name(&req).as_deref(),
+            Some("record_city")
+        );
+
+        let response = openai::OpenAIResponse {
+            id: Some("msg_1".to_string()),
+            object: None,
+            created: None,
+            model: None,
+            choices: vec![openai::Choice {
+                index: 0,
+                message: openai::ChoiceMessage {
+                    role: "assistant".to_string(),
+                    content: Some(r#"{"city": "Paris"}"#.to_string()),
+                    tool_calls: None,
+                    reasoning: None,
+                    reasoning_content: None,
+                    reasoning_details: None,
+                    function_call: None,
+                },
+                finish_reason: Some("stop".to_string()),
+                stop_reason: None,
+                content_filter_results: None,
+            }],
+            usage: openai::Usage {
+                prompt_tokens: 5,
+                completion_tokens: 5,
+                total_tokens: 10,
+                prompt_tokens_details: None,
+            },
+            system_fingerprint: None,
+        };
+        let mut anthropic = openai_to_anthropic(response, "gpt-4o").unwrap();
+        super::wrap_structured_output(&mut anthropic, "record_city");
+
+        let anthropic = serde_json::to_value(anthropic).unwrap();
+        assert_eq!(anthropic["stop_reason"], "tool_use");
+        assert_eq!(anthropic["content"].as_array().unwrap().len(), 1);
+        assert_eq!(anthropic["content"][0]["type"], "tool_use");
+        assert_eq!(anthropic["content"][0]["id"], "toolu_1");
+        assert_eq!(anthropic["content"][0]["input"]["city"], "Paris");
+    }
+
+    #[test]
+    fn extra_params_follow_the_allow_and_deny_lists() {
+        let request = || {
+            serde_json::from_value(serde_json::json!({
+                "model": "claude-sonnet-4",
+                "max_tokens": 1024,
+                "messages": [{"role": "user", "content": "hi"}],
+                "seed": 7,
+                "provider": {"order": ["groq"]},
+                "thinking": {"type": "disabled"}
+            }))
+            .unwrap()
+        };
+        let mut config = Config::for_tests();
+
+        let req = serde_json::to_value(anthropic_to_openai(request(), &config).unwrap()).unwrap();
+        assert!(req.get("seed").is_none() && req.get("provider").is_none());
+
+        config.extra_params = vec!["seed".to_string()];
+        let req = serde_json::to_value(anthropic_to_openai(request(), &config).unwrap()).unwrap();
+        assert_eq!(req["seed"], 7);
+        assert!(req.get("provider").is_none());
+
+        config.extra_params = vec!["*".to_string()];
+        config.extra_params_deny = vec!["seed".to_string()];
+        let req = serde_json::to_value(anthropic_to_openai(request(), &config).unwrap()).unwrap();
+        assert!(req.get("seed").is_none());
+        assert_eq!(req["provider"]["order"][0], "groq");
+        assert!(req.get("thinking").is_none());
+    }
+
+    #[test]
+    fn openrouter_extensions_fill_in_what_the_client_left_out() {
+        let request = || {
+            serde_json::from_value(serde_json::json!({
+                "model": "anthropic/claude-sonnet-4",
+                "max_tokens": 1024,
+                "messages": [{"role": "user", "content": "hi"}],
+                "provider": {"order": ["groq"]}
+            }))
+            .unwrap()
+        };
+        let mut config = Config::for_tests();
+        config.extra_params = vec!["provider".to_string()];
+        config.openrouter_provider = Some(serde_json::json!({"sort": "throughput"}));
+        config.openrouter_models = vec!["openai/gpt-4.1".to_string()];
+        config.openrouter_transforms = vec!["middle-out".to_string()];
+
+        let req = serde_json::to_value(anthropic_to_openai(request(), &config).unwrap()).unwrap();
+        assert!(req.get("models").is_none() && req.get("transforms").is_none());
+
+        config.base_url = "https://openrouter.ai/api".to_string();
+        let req = serde_json::to_value(anthropic_to_openai(request(), &config).unwrap()).unwrap();
+        assert_eq!(req["provider"], serde_json::json!({"order": ["groq"]}));
+        assert_eq!(req["models"], serde_json::json!(["openai/gpt-4.1"]));
+        assert_eq!(req["transforms"], serde_json::json!(["middle-out"]));
+
+        config.openrouter = Some(false);
+        let req = serde_json::to_value(anthropic_to_openai(request(), &config).unwrap()).unwrap();
+        assert!(req.get("models").is_none());
+    }
+
+    #[test]
+    fn malformed_tool_arguments_are_normalized() {
+        use super::parse_tool_arguments;
+
+        assert_eq!(parse_tool_arguments("f", ""), serde_json::json!({}));
+        assert_eq!(
+            parse_tool_arguments("f", r#"{"a": 1}</tool_call>"#),
+            serde_json::json!({"a": 1})
+        );
+        assert_eq!(
+            parse_tool_arguments("f", "```json\n{\"a\": [1, 2]}\n```"),
+            serde_json::json!({"a": [1, 2]})
+        );
+        assert_eq!(
+            parse_tool_arguments("f", r#"{"path": "src/ma"#),
+            serde_json::json!({"path": "src/ma"})
+        );
+        assert_eq!(
+            parse_tool_arguments("f", r#"{"a": [1, 2,"#),
+            serde_json::json!({"a": [1, 2]})
+        );
+        assert_eq!(parse_tool_arguments("f", "not json"), serde_json::json!({}));
+    }
+
+    #[test]
+    fn common_json_mistakes_are_fixed() {
+        use super::parse_tool_arguments;
+
+        assert_eq!(
+            parse_tool_arguments("f", "{'path': 'it\\'s \"here\"', 'lines': [1, 2,],}"),
+            serde_json::json!({"path": "it's \"here\"", "lines": [1, 2]})
+        );
+        assert_eq!(
+            parse_tool_arguments("f", "{\"a\": True, \"b\": None, \"c\": \"x\ny\"}"),
+            serde_json::json!({"a": true, "b": null, "c": "x\ny"})
+        );
+        assert_eq!(
+            parse_tool_arguments("f", "{'cmd': 'ls -la"),
+            serde_json::json!({"cmd": "ls -la"})
+        );
+    }
 }
@@ -0,0 +1,824 @@
+use crate::accounting::UsageReport;
+use crate::config::{Config, ToolInputStreaming};
+use crate::guardrails::StreamGuard;
+use crate::models::{anthropic, openai};
+use crate::proxy::{RequestContext, FINE_GRAINED_TOOL_STREAMING};
+use crate::secrets::StreamRestorer;
+use crate::stops::StopScanner;
+use crate::think_tags::ThinkTagParser;
+use crate::tokens;
+use crate::transform;
+use bytes::{BufMut, Bytes, BytesMut};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Translates an OpenAI chat completion stream into Anthropic Messages
+/// events, one upstream chunk at a time.
+///
+/// The translator does no I/O: it is fed the upstream's events and returns
+/// the SSE events to send, each item of the returned vectors being exactly
+/// one event. Reading the upstream, timeouts and keep-alives are left to the
+/// caller.
+pub struct StreamTranslator<'a> {
+    config: &'a Config,
+    ctx: &'a RequestContext,
+    upstream_model: String,
+    usage_report: Option<UsageReport>,
+    /// Token counts so far; providers send them with every chunk, split
+    /// across chunks or only in a last chunk after the finish reason
+    usage: Option<openai::Usage>,
+    /// The closing message_delta, held until the usage is complete
+    final_delta: Option<Value>,
+    restorer: Option<StreamRestorer<'a>>,
+    text_guard: Option<StreamGuard<'a>>,
+    stop_scanner: Option<StopScanner<'a>>,
+    message_id: Option<String>,
+    current_model: Option<String>,
+    content_index: usize,
+    tool_call_id: Option<String>,
+    /// Index of the tool call streaming in the open block; calls with other
+    /// indices are collected by index until the end of the message
+    live_tool: Option<usize>,
+    queued_tools: BTreeMap<usize, (String, String, String)>,
+    message_started: bool,
+    current_block_type: Option<String>,
+    /// Characters of thinking still allowed before the block is cut
+    thinking_budget: Option<usize>,
+    /// Reasoning is still billed upstream and counted in usage, just not shown
+    hide_thinking: bool,
+    /// Reasoning some models inline in their text, between tags
+    think_tags: Option<ThinkTagParser>,
+    /// Without fine-grained tool streaming, tool input is sent whole once the
+    /// call is complete, as Anthropic does
+    buffer_tool_input: bool,
+    /// Name and input of the buffered tool call
+    pending_tool: Option<(String, String)>,
+    /// Structured output JSON streams as the input of the forced tool's call
+    content_block: &'static str,
+    saw_done: bool,
+    finished: bool,
+}
+
+impl<'a> StreamTranslator<'a> {
+    pub fn new(upstream_model: String, config: &'a Config, ctx: &'a RequestContext) -> Self {
+        let buffer_tool_input = match config.tool_input_streaming {
+            ToolInputStreaming::Auto => !ctx.betas.iter().any(|b| b == FINE_GRAINED_TOOL_STREAMING),
+            ToolInputStreaming::Buffered => true,
+            ToolInputStreaming::Streamed => false,
+        };
+        Self {
+            usage_report: None,
+            usage: None,
+            final_delta: None,
+            restorer: None,
+            text_guard: None,
+            stop_scanner: None,
+            message_id: ctx.early_message_id.clone(),
+            current_model: None,
+            content_index: 0,
+            tool_call_id: None,
+            live_tool: None,
+            queued_tools: BTreeMap::new(),
+            message_started: ctx.early_message_id.is_some(),
+            current_block_type: None,
+            thinking_budget: config
+                .reasoning_limit_for(&upstream_model)
+                .map(tokens::chars_for_tokens),
+            hide_thinking: config.hides_thinking(&upstream_model),
+            think_tags: config
+                .think_tags_for(&upstream_model)
+                .map(ThinkTagParser::new),
+            buffer_tool_input,
+            pending_tool: None,
+            content_block: if ctx.structured_output.is_some() {
+                "tool_use"
+            } else {
+                "text"
+            },
+            saw_done: false,
+            finished: false,
+            upstream_model,
+            config,
+            ctx,
+        }
+    }
+
+    /// Whether message_start has been sent
+    pub fn message_started(&self) -> bool {
+        self.message_started
+    }
+
+    /// Whether the message has ended for good; nothing the upstream sends
+    /// after this is translated
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Bytes held back until their block is complete: buffered tool input
+    /// and the largest interleaved tool call
+    pub fn held_bytes(&self) -> usize {
+        self.pending_tool
+            .as_ref()
+            .map_or(0, |(_, input)| input.len())
+            + self
+                .queued_tools
+                .values()
+                .map(|(_, _, args)| args.len())
+                .max()
+                .unwrap_or(0)
+    }
+
+    /// Translate the data of one upstream event: a JSON chunk or `[DONE]`
+    pub fn push_data(&mut self, data: &str) -> Vec<Bytes> {
+        if data.trim() == "[DONE]" {
+            return self.push_done();
+        }
+        match serde_json::from_str::<openai::StreamChunk>(data) {
+            Ok(chunk) => self.push_chunk(chunk),
+            Err(_) => {
+                tracing::debug!("Ignoring unrecognized upstream stream chunk: {}", data);
+                Vec::new()
+            }
+        }
+    }
+
+    /// The upstream said `[DONE]`: send the held message_delta with the
+    /// final usage, and message_stop
+    pub fn push_done(&mut self) -> Vec<Bytes> {
+        if self.finished {
+            return Vec::new();
+        }
+        self.saw_done = true;
+        let mut events = Vec::new();
+        if let Some(event) = self.final_delta.take() {
+            events.push(message_delta_event(event, self.usage.as_ref()));
+        }
+        self.settle_usage();
+        events.push(message_stop_event(self.usage_report.as_ref()));
+        events
+    }
+
+    /// Translate one upstream chunk into the SSE events it completes. They
+    /// come back encoded rather than as `anthropic::StreamEvent`s: some carry
+    /// fields that type doesn't model, like the proxy's usage report on
+    /// message_stop, and each is serialized once, straight into its bytes.
+    pub fn push_chunk(&mut self, chunk: openai::StreamChunk) -> Vec<Bytes> {
+        let mut events = Vec::new();
+        if self.finished {
+            return events;
+        }
+        if let Some(chunk_usage) = &chunk.usage {
+            match self.usage.as_mut() {
+                Some(usage) => usage.merge(chunk_usage),
+                None => self.usage = Some(chunk_usage.clone()),
+            }
+        }
+        if self.message_id.is_none() {
+            self.message_id = chunk.id.clone();
+        }
+        if self.current_model.is_none() {
+            self.current_model = chunk.model.clone();
+        }
+
+        let Some(choice) = chunk.choices.first() else {
+            return events;
+        };
+        if !self.message_started {
+            events.push(self.message_start());
+            self.message_started = true;
+            if self.config.ping_interval_secs.is_some() {
+                events.push(ping_event());
+            }
+        }
+
+        let mut reasoning = choice.delta.reasoning_text();
+        let mut content = choice.delta.content.clone();
+        if let Some(parser) = self.think_tags.as_mut() {
+            let (mut inline, mut text) = parser.push(content.as_deref().unwrap_or_default());
+            if choice.finish_reason.is_some() {
+                let (rest_inline, rest_text) = parser.finish();
+                inline.push_str(&rest_inline);
+                text.push_str(&rest_text);
+            }
+            if !inline.is_empty() {
+                reasoning = Some(reasoning.unwrap_or_default() + &inline);
+            }
+            content = Some(text);
+        }
+
+        let reasoning =
+            reasoning.filter(|_| !self.hide_thinking && self.thinking_budget != Some(0));
+        if let Some(reasoning) = reasoning {
+            self.push_reasoning(reasoning, &mut events);
+        }
+
+        if let Some(content) = content.filter(|content| !content.is_empty()) {
+            self.push_content(&content, &mut events);
+            // A stop sequence the upstream couldn't take, or echoed, ends the message here
+            if let Some(stop) = self.stop_scanner.as_ref().and_then(|s| s.matched()) {
+                tracing::debug!("Stop sequence {:?} matched in the output", stop);
+                let held = self.flush_held();
+                if !held.is_empty() {
+                    events.push(delta_event(self.content_index, self.content_block, &held));
+                }
+                events.push(block_stop_event(self.content_index));
+                let delta = json!({
+                    "type": "message_delta",
+                    "delta": {
+                        "stop_reason": "stop_sequence",
+                        "stop_sequence": stop
+                    }
+                });
+                events.push(message_delta_event(delta, self.usage.as_ref()));
+                self.settle_usage();
+                events.push(message_stop_event(self.usage_report.as_ref()));
+                self.finished = true;
+                return events;
+            }
+        }
+
+        let tool_calls = choice.delta.tool_calls_or_function_call(|| {
+            transform::synthesized_tool_use_id(self.message_id.as_deref().unwrap_or("msg_proxy"))
+        });
+        for tool_call in tool_calls.iter().flatten() {
+            self.push_tool_call(tool_call, &mut events);
+        }
+
+        if let Some(finish_reason) = &choice.finish_reason {
+            self.close_block(&mut events);
+            self.push_queued_tools(&mut events);
+
+            let mut stop_reason = transform::map_stop_reason(Some(finish_reason));
+            if self.ctx.structured_output.is_some() && stop_reason.as_deref() == Some("end_turn") {
+                stop_reason = Some("tool_use".to_string());
+            }
+            let stop_sequence = choice.stop_sequence();
+            if stop_sequence.is_some() {
+                stop_reason = Some("stop_sequence".to_string());
+            }
+            if finish_reason == "content_filter" {
+                stop_reason = transform::content_filter_stop_reason(
+                    self.config,
+                    choice.content_filter_results.as_ref(),
+                );
+                if stop_reason.is_none() {
+                    events.extend(failed_stream_events(
+                        None,
+                        None,
+                        "invalid_request_error",
+                        "Blocked by the upstream's content filter: the upstream withheld the rest of the response",
+                    ));
+                    self.settle_usage();
+                    self.finished = true;
+                    return events;
+                }
+            }
+            self.final_delta = Some(json!({
+                "type": "message_delta",
+                "delta": {
+                    "stop_reason": stop_reason,
+                    "stop_sequence": stop_sequence
+                }
+            }));
+        }
+        events
+    }
+
+    /// The upstream failed mid-stream. The error is wrapped in a well-formed
+    /// message, however much of it was sent; a failure after the upstream
+    /// already finished the message only loses the end of the stream.
+    pub fn fail(&mut self, error_type: &str, message: &str) -> Vec<Bytes> {
+        if self.finished {
+            return Vec::new();
+        }
+        self.finished = true;
+        self.settle_usage();
+        if let Some(event) = self.final_delta.take() {
+            return vec![
+                message_delta_event(event, self.usage.as_ref()),
+                message_stop_event(self.usage_report.as_ref()),
+            ];
+        }
+        let start = (!self.message_started).then(|| self.message_start());
+        let open_block = self
+            .current_block_type
+            .is_some()
+            .then_some(self.content_index);
+        failed_stream_events(start, open_block, error_type, message)
+    }
+
+    /// The upstream's body ended. Without `[DONE]`, the message is completed
+    /// as the upstream should have: the open block is closed and the stop
+    /// reason is tool_use for a tool call in progress, end_turn otherwise.
+    pub fn finish(&mut self) -> Vec<Bytes> {
+        if self.finished {
+            return Vec::new();
+        }
+        if self.saw_done {
+            self.finished = true;
+            return Vec::new();
+        }
+        if !self.message_started && self.final_delta.is_none() {
+            tracing::warn!("Upstream closed the stream without a response");
+            let start = self.message_start();
+            self.settle_usage();
+            self.finished = true;
+            return failed_stream_events(
+                Some(start),
+                None,
+                "api_error",
+                "Upstream closed the stream without a response",
+            );
+        }
+
+        let mut events = Vec::new();
+        if self.final_delta.is_none() {
+            tracing::warn!("Upstream closed the stream before finishing the message");
+            let finish_reason = if self.live_tool.is_some() || !self.queued_tools.is_empty() {
+                "tool_calls"
+            } else {
+                "stop"
+            };
+            events.extend(self.push_data(
+                &json!({"choices": [{"index": 0, "delta": {}, "finish_reason": finish_reason}]})
+                    .to_string(),
+            ));
+        }
+        events.extend(self.push_done());
+        self.finished = true;
+        events
+    }
+
+    fn push_reasoning(&mut self, reasoning: String, events: &mut Vec<Bytes>) {
+        let (reasoning, exhausted) = match self.thinking_budget.as_mut() {
+            Some(budget) => {
+                let allowed: String = reasoning.chars().take(*budget).collect();
+                *budget -= allowed.chars().count();
+                (allowed, *budget == 0)
+            }
+            None => (reasoning, false),
+        };
+        if self.current_block_type.as_deref() != Some("thinking") {
+            // Close a text block reasoning interrupts
+            self.close_block(events);
+            events.push(block_start_event(
+                self.content_index,
+                json!({"type": "thinking", "thinking": ""}),
+            ));
+            self.current_block_type = Some("thinking".to_string());
+            self.restorer = Some(self.ctx.secrets.stream());
+        }
+
+        let reasoning = match self.restorer.as_mut() {
+            Some(r) => r.push(&reasoning),
+            None => reasoning,
+        };
+        if !reasoning.is_empty() {
+            events.push(delta_event(self.content_index, "thinking", &reasoning));
+        }
+
+        if exhausted {
+            // Close the thinking block cleanly; further reasoning is dropped
+            tracing::info!(
+                "Reasoning of {} cut at its token limit",
+                self.upstream_model
+            );
+            self.close_block(events);
+        }
+    }
+
+    fn push_content(&mut self, content: &str, events: &mut Vec<Bytes>) {
+        if self.current_block_type.as_deref() != Some(self.content_block) {
+            self.close_block(events);
+
+            // Start text block, or the forced tool's block for structured output
+            let content_block = match &self.ctx.structured_output {
+                Some(tool) => json!({
+                    "type": "tool_use",
+                    "id": transform::synthesized_tool_use_id(self.message_id.as_deref().unwrap_or("msg_proxy")),
+                    "name": tool
+                }),
+                None => json!({"type": "text", "text": ""}),
+            };
+            events.push(block_start_event(self.content_index, content_block));
+            self.current_block_type = Some(self.content_block.to_string());
+            if self.ctx.structured_output.is_some() {
+                self.restorer = Some(self.ctx.secrets.stream_json());
+            } else {
+                self.restorer = Some(self.ctx.secrets.stream());
+                self.text_guard = self.config.output_guardrails.as_ref().map(|g| g.stream());
+                self.stop_scanner = (!self.ctx.watched_stops.is_empty())
+                    .then(|| StopScanner::new(&self.ctx.watched_stops));
+            }
+        }
+
+        // Send text delta, held back by stop sequence emulation, secret
+        // restoration and output guardrails
+        let content = match self.stop_scanner.as_mut() {
+            Some(scanner) => scanner.push(content),
+            None => content.to_string(),
+        };
+        let mut text = match self.restorer.as_mut() {
+            Some(r) => r.push(&content),
+            None => content,
+        };
+        if let Some(guard) = self.text_guard.as_mut() {
+            text = guard.push(&text);
+        }
+        if !text.is_empty() {
+            events.push(delta_event(self.content_index, self.content_block, &text));
+        }
+    }
+
+    fn push_tool_call(&mut self, tool_call: &openai::DeltaToolCall, events: &mut Vec<Bytes>) {
+        let function = tool_call.function.as_ref();
+        if self.live_tool.is_some_and(|live| live != tool_call.index) {
+            // Another call interleaved with the open one: keep it whole and
+            // emit it as its own block at the end
+            let queued = self.queued_tools.entry(tool_call.index).or_default();
+            if let Some(id) = &tool_call.id {
+                queued.0 = id.clone();
+            }
+            if let Some(name) = function.and_then(|f| f.name.as_ref()) {
+                queued.1 = name.clone();
+            }
+            if let Some(args) = function.and_then(|f| f.arguments.as_ref()) {
+                queued.2.push_str(args);
+            }
+            return;
+        }
+
+        if self.live_tool.is_none() {
+            // Start of a new tool call
+            self.close_block(events);
+            self.live_tool = Some(tool_call.index);
+        }
+        if let Some(id) = &tool_call.id {
+            self.tool_call_id = Some(id.clone());
+        }
+        let Some(function) = function else {
+            return;
+        };
+
+        // Some backends repeat the name on every delta
+        if let (Some(name), None) = (&function.name, self.current_block_type.as_deref()) {
+            events.push(block_start_event(
+                self.content_index,
+                json!({
+                    "type": "tool_use",
+                    "id": self.tool_call_id.clone().unwrap_or_default(),
+                    "name": self.ctx.tool_names.original(name)
+                }),
+            ));
+            self.current_block_type = Some("tool_use".to_string());
+            self.restorer = Some(self.ctx.secrets.stream_json());
+            if self.buffer_tool_input {
+                self.pending_tool = Some((name.clone(), String::new()));
+            }
+        }
+
+        if let Some(args) = &function.arguments {
+            let args = match self.restorer.as_mut() {
+                Some(r) => r.push(args),
+                None => args.clone(),
+            };
+            if let Some((_, input)) = self.pending_tool.as_mut() {
+                input.push_str(&args);
+            } else if !args.is_empty() {
+                events.push(delta_event(self.content_index, "tool_use", &args));
+            }
+        }
+    }
+
+    /// Emit the interleaved tool calls, one block each in index order
+    fn push_queued_tools(&mut self, events: &mut Vec<Bytes>) {
+        for (id, name, args) in std::mem::take(&mut self.queued_tools).into_values() {
+            events.push(block_start_event(
+                self.content_index,
+                json!({
+                    "type": "tool_use",
+                    "id": id,
+                    "name": self.ctx.tool_names.original(&name)
+                }),
+            ));
+
+            let mut restorer = self.ctx.secrets.stream_json();
+            let input = restorer.push(&args);
+            let input = flush_held(
+                &mut Some((name, input)),
+                &mut None,
+                &mut Some(restorer),
+                &mut None,
+                self.config.strict_tools,
+            );
+            if !input.is_empty() {
+                events.push(delta_event(self.content_index, "tool_use", &input));
+            }
+            events.push(block_stop_event(self.content_index));
+            self.content_index += 1;
+        }
+    }
+
+    /// Close the open block, flushing the text held back for it first
+    fn close_block(&mut self, events: &mut Vec<Bytes>) {
+        let held = self.flush_held();
+        let Some(block_type) = self.current_block_type.take() else {
+            return;
+        };
+        if !held.is_empty() {
+            events.push(delta_event(self.content_index, &block_type, &held));
+        }
+        events.push(block_stop_event(self.content_index));
+        self.content_index += 1;
+    }
+
+    fn flush_held(&mut self) -> String {
+        flush_held(
+            &mut self.pending_tool,
+            &mut self.stop_scanner,
+            &mut self.restorer,
+            &mut self.text_guard,
+            self.config.strict_tools,
+        )
+    }
+
+    fn message_start(&self) -> Bytes {
+        message_start_event(
+            self.message_id
+                .clone()
+                .unwrap_or_else(|| "msg_proxy".to_string()),
+            self.ctx
+                .reported_model
+                .clone()
+                .or_else(|| self.current_model.clone())
+                .unwrap_or_else(|| self.upstream_model.clone()),
+            self.usage.as_ref(),
+        )
+    }
+
+    /// Record the stream's usage with accounting, once
+    fn settle_usage(&mut self) {
+        if let (None, Some(usage)) = (self.usage_report.as_ref(), self.usage.as_ref()) {
+            let model = self
+                .current_model
+                .as_deref()
+                .unwrap_or(&self.upstream_model);
+            self.usage_report = Some(self.ctx.record_usage(self.config, model, usage));
+        }
+    }
+}
+
+/// Text held back for the open block: buffered tool input, normalized once
+/// complete, and the tails kept by stop sequence emulation, secret
+/// restoration and output guardrails
+fn flush_held(
+    pending_tool: &mut Option<(String, String)>,
+    stop_scanner: &mut Option<StopScanner>,
+    restorer: &mut Option<StreamRestorer>,
+    text_guard: &mut Option<StreamGuard>,
+    strict_tools: bool,
+) -> String {
+    let (tool, mut held) = match pending_tool.take() {
+        Some((name, input)) => (Some(name), input),
+        None => (None, String::new()),
+    };
+    let tail = stop_scanner
+        .take()
+        .map(|mut scanner| scanner.finish())
+        .unwrap_or_default();
+    match restorer.take() {
+        Some(mut r) => {
+            held.push_str(&r.push(&tail));
+            held.push_str(&r.finish());
+        }
+        None => held.push_str(&tail),
+    }
+    if let Some(name) = tool {
+        held = transform::normalize_tool_arguments(&name, &held);
+        if strict_tools {
+            if let Ok(mut input) = serde_json::from_str(&held) {
+                transform::drop_null_fields(&mut input);
+                held = input.to_string();
+            }
+        }
+    }
+    if let Some(mut guard) = text_guard.take() {
+        held = guard.push(&held);
+        held.push_str(&guard.finish());
+    }
+    held
+}
+
+/// Build the message_start event; input tokens are only known when the
+/// upstream reported usage with its first chunk
+pub(crate) fn message_start_event(
+    id: String,
+    model: String,
+    usage: Option<&openai::Usage>,
+) -> Bytes {
+    let event = anthropic::StreamEvent::MessageStart {
+        message: anthropic::MessageStartData {
+            id,
+            message_type: "message".to_string(),
+            role: "assistant".to_string(),
+            model,
+            usage: usage
+                .map(transform::anthropic_usage)
+                .unwrap_or(anthropic::Usage {
+                    input_tokens: 0,
+                    output_tokens: 0,
+                    cache_read_input_tokens: None,
+                }),
+        },
+    };
+    Bytes::from(format!(
+        "event: message_start\ndata: {}\n\n",
+        serde_json::to_string(&event).unwrap_or_default()
+    ))
+}
+
+/// Build the message_stop event, with the proxy's usage report once known
+fn message_stop_event(usage_report: Option<&UsageReport>) -> Bytes {
+    let mut event = json!({"type": "message_stop"});
+    if let Some(usage) = usage_report {
+        event["proxy_usage"] = usage.to_json();
+    }
+    Bytes::from(format!(
+        "event: message_stop\ndata: {}\n\n",
+        serde_json::to_string(&event).unwrap_or_default()
+    ))
+}
+
+/// Events ending a stream that failed, so strict clients still see a
+/// well-formed message: message_start if it wasn't sent yet, the open block's
+/// content_block_stop, the error and message_stop
+pub(crate) fn failed_stream_events(
+    message_start: Option<Bytes>,
+    open_block: Option<usize>,
+    error_type: &str,
+    message: &str,
+) -> Vec<Bytes> {
+    let mut events: Vec<Bytes> = message_start.into_iter().collect();
+    if let Some(index) = open_block {
+        events.push(block_stop_event(index));
+    }
+    let error = json!({
+        "type": "error",
+        "error": {
+            "type": error_type,
+            "message": message
+        }
+    });
+    events.push(Bytes::from(format!(
+        "event: error\ndata: {}\n\n",
+        serde_json::to_string(&error).unwrap_or_default()
+    )));
+    events.push(message_stop_event(None));
+    events
+}
+
+/// Complete the closing message_delta with the final token counts
+fn message_delta_event(mut event: Value, usage: Option<&openai::Usage>) -> Bytes {
+    event["usage"] = json!(usage.map(transform::anthropic_usage));
+    Bytes::from(format!(
+        "event: message_delta\ndata: {}\n\n",
+        serde_json::to_string(&event).unwrap_or_default()
+    ))
+}
+
+/// Build a ping event
+pub(crate) fn ping_event() -> Bytes {
+    Bytes::from("event: ping\ndata: {\"type\": \"ping\"}\n\n")
+}
+
+fn block_start_event(index: usize, content_block: Value) -> Bytes {
+    let event = json!({
+        "type": "content_block_start",
+        "index": index,
+        "content_block": content_block
+    });
+    Bytes::from(format!(
+        "event: content_block_start\ndata: {}\n\n",
+        serde_json::to_string(&event).unwrap_or_default()
+    ))
+}
+
+fn block_stop_event(index: usize) -> Bytes {
+    let event = json!({"type": "content_block_stop", "index": index});
+    Bytes::from(format!(
+        "event: content_block_stop\ndata: {}\n\n",
+        serde_json::to_string(&event).unwrap_or_default()
+    ))
+}
+
+/// Build a content_block_delta event of the kind matching the open block
+pub(crate) fn delta_event(index: usize, block_type: &str, text: &str) -> Bytes {
+    let delta = match block_type {
+        "thinking" => json!({ "type": "thinking_delta", "thinking": text }),
+        "tool_use" => json!({ "type": "input_json_delta", "partial_json": text }),
+        _ => json!({ "type": "text_delta", "text": text }),
+    };
+    let event = json!({
+        "type": "content_block_delta",
+        "index": index,
+        "delta": delta
+    });
+    // Deltas are most of a stream; serialize each straight into its event
+    let mut buf = BytesMut::with_capacity(text.len() + 96);
+    buf.extend_from_slice(b"event: content_block_delta\ndata: ");
+    let _ = serde_json::to_writer((&mut buf).writer(), &event);
+    buf.extend_from_slice(b"\n\n");
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StreamTranslator;
+    use crate::config::Config;
+    use crate::proxy::RequestContext;
+    use bytes::Bytes;
+
+    fn names(events: &[Bytes]) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| {
+                let event = std::str::from_utf8(event).unwrap();
+                event.lines().next().unwrap()["event: ".len()..].to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunks_translate_one_at_a_time() {
+        let config = Config::for_tests();
+        let ctx = RequestContext::detached(&config);
+        let mut translator = StreamTranslator::new("r1".to_string(), &config, &ctx);
+
+        let events =
+            translator.push_data(r#"{"id":"c1","choices":[{"index":0,"delta":{"content":"Hi"}}]}"#);
+        assert_eq!(
+            names(&events),
+            [
+                "message_start",
+                "content_block_start",
+                "content_block_delta"
+            ]
+        );
+        assert!(translator.message_started());
+
+        // The closing message_delta waits for usage that may follow the finish reason
+        let events =
+            translator.push_data(r#"{"choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#);
+        assert_eq!(names(&events), ["content_block_stop"]);
+        let events = translator.push_data(
+            r#"{"choices":[],"usage":{"prompt_tokens":5,"completion_tokens":1,"total_tokens":6}}"#,
+        );
+        assert!(events.is_empty());
+
+        let events = translator.push_data("[DONE]");
+        assert_eq!(names(&events), ["message_delta", "message_stop"]);
+        assert!(std::str::from_utf8(&events[0])
+            .unwrap()
+            .contains(r#""output_tokens":1"#));
+        assert!(translator.finish().is_empty());
+        assert!(translator.is_finished());
+    }
+
+    #[test]
+    fn failures_and_cut_off_streams_end_the_message() {
+        let config = Config::for_tests();
+        let ctx = RequestContext::detached(&config);
+
+        let mut translator = StreamTranslator::new("r1".to_string(), &config, &ctx);
+        translator.push_data(r#"{"choices":[{"index":0,"delta":{"reasoning":"hmm"}}]}"#);
+        let events = translator.fail("api_error", "connection reset");
+        assert_eq!(
+            names(&events),
+            ["content_block_stop", "error", "message_stop"]
+        );
+        assert!(translator.is_finished());
+        assert!(translator.push_data("[DONE]").is_empty());
+
+        let mut translator = StreamTranslator::new("r1".to_string(), &config, &ctx);
+        translator.push_data(
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"c1","function":{"name":"f","arguments":"{}"}}]}}]}"#,
+        );
+        let events = translator.finish();
+        assert_eq!(
+            names(&events),
+            [
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop"
+            ]
+        );
+        assert!(std::str::from_utf8(&events[2])
+            .unwrap()
+            .contains(r#""stop_reason":"tool_use""#));
+
+        let mut translator = StreamTranslator::new("r1".to_string(), &config, &ctx);
+        let events = translator.finish();
+        assert_eq!(names(&events), ["message_start", "error", "message_stop"]);
+    }
+}
@@ -0,0 +1,632 @@
+use crate::config::Config;
+use crate::error::{ProxyError, ProxyResult};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Weight of the newest sample in the rolling averages
+const EWMA_ALPHA: f64 = 0.2;
+/// A challenger must beat the preferred target by this fraction to take over
+const HYSTERESIS: f64 = 0.2;
+/// Samples a target needs before it can become preferred
+const MIN_SAMPLES: u64 = 5;
+/// Every Nth request probes a non-preferred target to keep its stats fresh
+const PROBE_EVERY: u64 = 20;
+/// How strongly the error rate inflates a target's latency score
+const ERROR_PENALTY: f64 = 4.0;
+/// Sticky conversations leave a target whose error rate exceeds this
+const STICKY_MAX_ERROR_RATE: f64 = 0.5;
+/// Least health of a target whose circuit is closed, however often it failed
+const MIN_HEALTH: f64 = 0.05;
+/// How often idle conversations are dropped from a sticky table
+const STICKY_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Rolling health of one upstream target
+#[derive(Debug, Clone, Default)]
+pub struct TargetStats {
+    /// Average time to response headers, in milliseconds
+    pub latency_ms: Option<f64>,
+    /// Average share of failed requests (0.0 - 1.0)
+    pub error_rate: f64,
+    pub samples: u64,
+    /// Failures since the last success
+    pub consecutive_failures: u32,
+    /// Set while the circuit is open: requests fail fast until then, after
+    /// which one probe request at a time is let through
+    pub open_until: Option<Instant>,
+    /// When the probe request in flight was let through
+    probe_since: Option<Instant>,
+}
+
+impl TargetStats {
+    /// Lower is better; targets without samples score None
+    fn score(&self) -> Option<f64> {
+        self.latency_ms
+            .map(|latency| latency * (1.0 + ERROR_PENALTY * self.error_rate))
+    }
+}
+
+/// Stop sending requests to a target after this many failures in a row
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreaker {
+    pub failures: u32,
+    pub cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn from_config(config: &Config) -> Option<Self> {
+        config
+            .circuit_breaker_failures
+            .map(|failures| CircuitBreaker {
+                failures,
+                cooldown: Duration::from_secs(config.circuit_breaker_cooldown_secs),
+            })
+    }
+}
+
+/// Remembers where each conversation was sent, so later turns keep going
+/// there while it stays healthy, until the conversation has been idle for
+/// the TTL
+#[derive(Debug)]
+pub struct StickyTable {
+    ttl: Duration,
+    entries: Mutex<StickyEntries>,
+}
+
+#[derive(Debug)]
+struct StickyEntries {
+    /// Conversation key to target and when the conversation was last seen
+    targets: HashMap<u64, (String, Instant)>,
+    pruned: Instant,
+}
+
+impl StickyTable {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(StickyEntries {
+                targets: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Option<Arc<Self>> {
+        config
+            .upstream_affinity
+            .then(|| Arc::new(Self::new(Duration::from_secs(config.affinity_ttl_secs))))
+    }
+
+    /// The target a conversation is pinned to, keeping the pin alive
+    pub fn get(&self, key: u64) -> Option<String> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("sticky table lock poisoned");
+        let (target, seen) = entries.targets.get_mut(&key)?;
+        if now.duration_since(*seen) > self.ttl {
+            entries.targets.remove(&key);
+            return None;
+        }
+        *seen = now;
+        Some(target.clone())
+    }
+
+    pub fn pin(&self, key: u64, target: &str) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("sticky table lock poisoned");
+        if now.duration_since(entries.pruned) > STICKY_PRUNE_INTERVAL {
+            let ttl = self.ttl;
+            entries
+                .targets
+                .retain(|_, (_, seen)| now.duration_since(*seen) <= ttl);
+            entries.pruned = now;
+        }
+        entries.targets.insert(key, (target.to_string(), now));
+    }
+
+    /// Conversations currently pinned, including idle ones not yet pruned
+    pub fn conversations(&self) -> usize {
+        self.entries
+            .lock()
+            .expect("sticky table lock poisoned")
+            .targets
+            .len()
+    }
+}
+
+#[derive(Debug)]
+struct Target {
+    url: String,
+    stats: Mutex<TargetStats>,
+}
+
+/// Equivalent upstream endpoints, preferring the fastest and healthiest one
+#[derive(Debug)]
+pub struct UpstreamPool {
+    targets: Vec<Target>,
+    preferred: AtomicUsize,
+    requests: AtomicU64,
+    breaker: Option<CircuitBreaker>,
+    sticky: Option<Arc<StickyTable>>,
+}
+
+impl UpstreamPool {
+    pub fn new(urls: Vec<String>) -> Self {
+        assert!(!urls.is_empty(), "upstream pool needs at least one target");
+        Self {
+            targets: urls
+                .into_iter()
+                .map(|url| Target {
+                    url,
+                    stats: Mutex::new(TargetStats::default()),
+                })
+                .collect(),
+            preferred: AtomicUsize::new(0),
+            requests: AtomicU64::new(0),
+            breaker: None,
+            sticky: None,
+        }
+    }
+
+    pub fn with_circuit_breaker(mut self, breaker: Option<CircuitBreaker>) -> Self {
+        self.breaker = breaker;
+        self
+    }
+
+    pub fn with_sticky_table(mut self, sticky: Option<Arc<StickyTable>>) -> Self {
+        self.sticky = sticky;
+        self
+    }
+
+    pub fn sticky_table(&self) -> Option<&StickyTable> {
+        self.sticky.as_deref()
+    }
+
+    /// Pick the target for the next request
+    pub fn select(&self) -> usize {
+        let preferred = self.preferred.load(Ordering::Relaxed);
+        if self.targets.len() == 1 {
+            return preferred;
+        }
+
+        let n = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        if n.is_multiple_of(PROBE_EVERY) {
+            let others = self.targets.len() - 1;
+            let offset = 1 + (n / PROBE_EVERY) as usize % others;
+            return (preferred + offset) % self.targets.len();
+        }
+        preferred
+    }
+
+    /// Pick the target for a request, keeping every turn of one conversation
+    /// on the same target while that target stays healthy
+    pub fn select_for(&self, conversation: Option<u64>) -> usize {
+        let Some(conversation) = conversation else {
+            return self.select();
+        };
+        if self.targets.len() == 1 {
+            return 0;
+        }
+
+        let pinned = self
+            .sticky
+            .as_ref()
+            .and_then(|sticky| sticky.get(conversation))
+            .and_then(|url| self.targets.iter().position(|t| t.url == url))
+            .filter(|&i| self.keeps_conversations(i));
+        if let Some(index) = pinned {
+            return index;
+        }
+
+        // Rendezvous hashing: adding or removing a target only moves the
+        // conversations that were pinned to it
+        let index = self
+            .targets
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| self.keeps_conversations(i))
+            .max_by_key(|(_, t)| {
+                let mut hasher = DefaultHasher::new();
+                conversation.hash(&mut hasher);
+                t.url.hash(&mut hasher);
+                hasher.finish()
+            })
+            .map(|(i, _)| i)
+            .unwrap_or_else(|| self.select());
+        if let Some(sticky) = &self.sticky {
+            sticky.pin(conversation, self.url(index));
+        }
+        index
+    }
+
+    /// Whether a target is healthy enough for conversations to stay on it
+    fn keeps_conversations(&self, index: usize) -> bool {
+        let stats = self.targets[index]
+            .stats
+            .lock()
+            .expect("stats lock poisoned");
+        stats.samples < MIN_SAMPLES || stats.error_rate <= STICKY_MAX_ERROR_RATE
+    }
+
+    /// The target a request selected, or the next one whose circuit isn't
+    /// open. With every circuit open the request fails fast instead of
+    /// waiting on an upstream that is down.
+    pub fn admit(&self, selected: usize) -> ProxyResult<usize> {
+        let Some(breaker) = self.breaker else {
+            return Ok(selected);
+        };
+        let now = Instant::now();
+        let mut reopens = breaker.cooldown;
+        for offset in 0..self.targets.len() {
+            let index = (selected + offset) % self.targets.len();
+            let mut stats = self.targets[index]
+                .stats
+                .lock()
+                .expect("stats lock poisoned");
+            let Some(open_until) = stats.open_until else {
+                return Ok(index);
+            };
+            if now < open_until {
+                reopens = reopens.min(open_until - now);
+                continue;
+            }
+            // Half-open: one probe at a time, and another if it never reported back
+            if stats
+                .probe_since
+                .is_none_or(|since| now.duration_since(since) > breaker.cooldown)
+            {
+                stats.probe_since = Some(now);
+                tracing::info!(
+                    "Probing upstream {} after its circuit opened",
+                    self.url(index)
+                );
+                return Ok(index);
+            }
+        }
+        Err(ProxyError::Overloaded(format!(
+            "Upstream is failing and requests are paused; retry in {}s",
+            reopens.as_secs().max(1)
+        )))
+    }
+
+    /// How much traffic the pool can take, from 0.0 (every circuit open) to
+    /// 1.0: the success rate of its healthiest target. Targets that have
+    /// failed keep a small share so their stats can recover.
+    pub fn health(&self) -> f64 {
+        let now = Instant::now();
+        self.targets
+            .iter()
+            .map(|t| {
+                let stats = t.stats.lock().expect("stats lock poisoned");
+                if stats.open_until.is_some_and(|until| now < until) {
+                    0.0
+                } else if stats.samples < MIN_SAMPLES {
+                    1.0
+                } else {
+                    (1.0 - stats.error_rate).max(MIN_HEALTH)
+                }
+            })
+            .fold(0.0, f64::max)
+    }
+
+    pub fn url(&self, index: usize) -> &str {
+        &self.targets[index].url
+    }
+
+    pub fn urls(&self) -> Vec<String> {
+        self.targets.iter().map(|t| t.url.clone()).collect()
+    }
+
+    /// A pool over a new set of targets that keeps the statistics and the
+    /// preference of targets present in both
+    pub fn rebuild(&self, urls: Vec<String>) -> Self {
+        let pool = Self::new(urls)
+            .with_circuit_breaker(self.breaker)
+            .with_sticky_table(self.sticky.clone());
+        for target in &pool.targets {
+            if let Some(old) = self.targets.iter().find(|t| t.url == target.url) {
+                let stats = old.stats.lock().expect("stats lock poisoned").clone();
+                *target.stats.lock().expect("stats lock poisoned") = stats;
+            }
+        }
+        let preferred = self.url(self.preferred.load(Ordering::Relaxed));
+        if let Some(index) = pool.targets.iter().position(|t| t.url == preferred) {
+            pool.preferred.store(index, Ordering::Relaxed);
+        }
+        pool
+    }
+
+    /// Record the outcome of a request and re-evaluate the preferred target
+    pub fn record(&self, index: usize, latency: Duration, success: bool) {
+        {
+            let mut stats = self.targets[index]
+                .stats
+                .lock()
+                .expect("stats lock poisoned");
+            let latency_ms = latency.as_secs_f64() * 1000.0;
+            stats.latency_ms = Some(match stats.latency_ms {
+                Some(avg) => avg + EWMA_ALPHA * (latency_ms - avg),
+                None => latency_ms,
+            });
+            let failed = if success { 0.0 } else { 1.0 };
+            stats.error_rate += EWMA_ALPHA * (failed - stats.error_rate);
+            stats.samples += 1;
+            self.trip(index, &mut stats, success);
+        }
+
+        if self.targets.len() > 1 {
+            self.rebalance();
+        }
+    }
+
+    /// Open the target's circuit after enough failures in a row, or a
+    /// failed probe, and close it again on the first success
+    fn trip(&self, index: usize, stats: &mut TargetStats, success: bool) {
+        if success {
+            stats.consecutive_failures = 0;
+            stats.probe_since = None;
+            if stats.open_until.take().is_some() {
+                tracing::info!("Upstream {} recovered, circuit closed", self.url(index));
+            }
+            return;
+        }
+        stats.consecutive_failures += 1;
+        let Some(breaker) = self.breaker else {
+            return;
+        };
+        let probe_failed = stats.probe_since.take().is_some();
+        if probe_failed
+            || (stats.open_until.is_none() && stats.consecutive_failures >= breaker.failures)
+        {
+            stats.open_until = Some(Instant::now() + breaker.cooldown);
+            tracing::warn!(
+                "Upstream {} failed {} times in a row, circuit open for {:?}",
+                self.url(index),
+                stats.consecutive_failures,
+                breaker.cooldown
+            );
+        }
+    }
+
+    pub fn stats(&self) -> Vec<(String, TargetStats)> {
+        self.targets
+            .iter()
+            .map(|t| {
+                let stats = t.stats.lock().expect("stats lock poisoned").clone();
+                (t.url.clone(), stats)
+            })
+            .collect()
+    }
+
+    fn rebalance(&self) {
+        let stats: Vec<TargetStats> = self.stats().into_iter().map(|(_, s)| s).collect();
+        let preferred = self.preferred.load(Ordering::Relaxed);
+
+        let Some((best, best_score)) = stats
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.samples >= MIN_SAMPLES)
+            .filter_map(|(i, s)| s.score().map(|score| (i, score)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+        else {
+            return;
+        };
+
+        if best == preferred {
+            return;
+        }
+
+        let should_switch = match stats[preferred].score() {
+            Some(current) => best_score * (1.0 + HYSTERESIS) < current,
+            None => true,
+        };
+        if should_switch
+            && self
+                .preferred
+                .compare_exchange(preferred, best, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            tracing::info!(
+                "Preferred upstream switched from {} to {} ({:.0}ms score vs {:.0}ms)",
+                self.targets[preferred].url,
+                self.targets[best].url,
+                best_score,
+                stats[preferred].score().unwrap_or(f64::INFINITY)
+            );
+        }
+    }
+}
+
+/// The live upstream pool. Requests take a snapshot when they start, so
+/// swapping the pool never disturbs requests or streams already in flight.
+#[derive(Debug)]
+pub struct UpstreamRegistry {
+    current: RwLock<Arc<UpstreamPool>>,
+}
+
+impl UpstreamRegistry {
+    pub fn new(pool: UpstreamPool) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(pool)),
+        }
+    }
+
+    pub fn current(&self) -> Arc<UpstreamPool> {
+        self.current
+            .read()
+            .expect("upstream registry lock poisoned")
+            .clone()
+    }
+
+    /// Compute a new target list from the current one and swap it in
+    /// atomically; concurrent updates are applied one after another
+    pub fn update<E>(
+        &self,
+        change: impl FnOnce(Vec<String>) -> Result<Vec<String>, E>,
+    ) -> Result<Arc<UpstreamPool>, E> {
+        let mut current = self
+            .current
+            .write()
+            .expect("upstream registry lock poisoned");
+        let urls = change(current.urls())?;
+        *current = Arc::new(current.rebuild(urls));
+        Ok(current.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CircuitBreaker, StickyTable, UpstreamPool, UpstreamRegistry, MIN_SAMPLES, PROBE_EVERY,
+    };
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn pool() -> UpstreamPool {
+        UpstreamPool::new(vec!["a".to_string(), "b".to_string()])
+    }
+
+    #[test]
+    fn switches_to_a_clearly_faster_target() {
+        let pool = pool();
+        for _ in 0..MIN_SAMPLES {
+            pool.record(0, Duration::from_millis(900), true);
+            pool.record(1, Duration::from_millis(300), true);
+        }
+        assert_eq!(pool.preferred.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn small_differences_do_not_flap() {
+        let pool = pool();
+        for _ in 0..MIN_SAMPLES {
+            pool.record(0, Duration::from_millis(500), true);
+            pool.record(1, Duration::from_millis(450), true);
+        }
+        assert_eq!(pool.preferred.load(std::sync::atomic::Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn errors_push_traffic_away() {
+        let pool = pool();
+        for _ in 0..MIN_SAMPLES {
+            pool.record(0, Duration::from_millis(300), false);
+            pool.record(1, Duration::from_millis(400), true);
+        }
+        assert_eq!(pool.preferred.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn probes_other_targets_periodically() {
+        let pool = pool();
+        let picks: Vec<usize> = (0..PROBE_EVERY).map(|_| pool.select()).collect();
+        assert_eq!(picks.iter().filter(|&&i| i == 1).count(), 1);
+    }
+
+    #[test]
+    fn swapping_keeps_stats_and_in_flight_snapshots() {
+        let registry = UpstreamRegistry::new(pool());
+        let before = registry.current();
+        before.record(1, Duration::from_millis(250), true);
+
+        registry
+            .update(|mut urls| {
+                urls.retain(|u| u != "a");
+                urls.push("c".to_string());
+                Ok::<_, ()>(urls)
+            })
+            .unwrap();
+
+        let after = registry.current();
+        assert_eq!(after.urls(), vec!["b", "c"]);
+        assert_eq!(after.stats()[0].1.samples, 1);
+        assert_eq!(before.urls(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn circuits_open_after_repeated_failures_and_close_after_a_probe() {
+        let breaker = CircuitBreaker {
+            failures: 3,
+            cooldown: Duration::from_millis(50),
+        };
+        let pool = UpstreamPool::new(vec!["a".to_string()]).with_circuit_breaker(Some(breaker));
+        for _ in 0..3 {
+            assert_eq!(pool.admit(0).unwrap(), 0);
+            pool.record(0, Duration::from_millis(10), false);
+        }
+        let err = pool.admit(0).unwrap_err();
+        assert_eq!(err.error_type(), "overloaded_error");
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(pool.admit(0).unwrap(), 0);
+        // Only one probe at a time while half-open
+        assert!(pool.admit(0).is_err());
+        pool.record(0, Duration::from_millis(10), true);
+        assert_eq!(pool.admit(0).unwrap(), 0);
+        assert_eq!(pool.stats()[0].1.open_until, None);
+    }
+
+    #[test]
+    fn open_circuits_send_requests_to_other_targets() {
+        let breaker = CircuitBreaker {
+            failures: 1,
+            cooldown: Duration::from_secs(60),
+        };
+        let pool = pool().with_circuit_breaker(Some(breaker));
+        pool.record(0, Duration::from_millis(10), false);
+        assert_eq!(pool.admit(0).unwrap(), 1);
+    }
+
+    #[test]
+    fn health_follows_the_healthiest_target() {
+        let breaker = CircuitBreaker {
+            failures: 10,
+            cooldown: Duration::from_secs(60),
+        };
+        let pool = UpstreamPool::new(vec!["a".to_string()]).with_circuit_breaker(Some(breaker));
+        assert_eq!(pool.health(), 1.0);
+        for _ in 0..9 {
+            pool.record(0, Duration::from_millis(10), false);
+        }
+        assert!(pool.health() > 0.0 && pool.health() < 0.2);
+        pool.record(0, Duration::from_millis(10), false);
+        assert_eq!(pool.health(), 0.0);
+    }
+
+    #[test]
+    fn conversations_stick_to_one_target_until_it_fails() {
+        let pool = pool();
+        let picks: Vec<usize> = (0..PROBE_EVERY)
+            .map(|_| pool.select_for(Some(42)))
+            .collect();
+        assert!(picks.iter().all(|&i| i == picks[0]));
+
+        let pinned = picks[0];
+        for _ in 0..MIN_SAMPLES {
+            pool.record(pinned, Duration::from_millis(300), false);
+        }
+        assert_ne!(pool.select_for(Some(42)), pinned);
+    }
+
+    #[test]
+    fn sticky_tables_remember_targets_across_pool_changes() {
+        let sticky = Arc::new(StickyTable::new(Duration::from_secs(60)));
+        let pool = pool().with_sticky_table(Some(sticky.clone()));
+        let pinned = pool.select_for(Some(42));
+        let url = pool.url(pinned).to_string();
+        assert_eq!(sticky.get(42).as_deref(), Some(url.as_str()));
+
+        // A new target that rendezvous hashing would prefer doesn't take the
+        // conversation over
+        let urls: Vec<String> = (0..20).map(|i| format!("http://c{}", i)).collect();
+        let pool = pool.rebuild([vec![url.clone()], urls].concat());
+        assert_eq!(pool.url(pool.select_for(Some(42))), url);
+
+        let expired = StickyTable::new(Duration::ZERO);
+        expired.pin(42, "a");
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(expired.get(42), None);
+    }
+}
@@ -0,0 +1,268 @@
+use crate::error::{ProxyError, ProxyResult};
+use crate::models::anthropic::AnthropicRequest;
+use axum::{extract::rejection::JsonRejection, Json};
+use serde_json::{Map, Value};
+
+const ROLES: &[&str] = &["user", "assistant"];
+const BLOCK_TYPES: &[&str] = &["text", "image", "tool_use", "tool_result", "thinking"];
+
+/// Check a Messages request body before anything is sent upstream, naming
+/// the offending field the way Anthropic does ("messages.1.role: ...")
+pub fn messages_request(
+    payload: Result<Json<Value>, JsonRejection>,
+) -> ProxyResult<AnthropicRequest> {
+    let Json(body) = payload?;
+    check(&body).map_err(ProxyError::InvalidRequest)?;
+    serde_json::from_value(body).map_err(|err| ProxyError::InvalidRequest(err.to_string()))
+}
+
+fn check(body: &Value) -> Result<(), String> {
+    let body = body
+        .as_object()
+        .ok_or("request body must be a JSON object")?;
+
+    match body.get("model") {
+        Some(Value::String(model)) if !model.is_empty() => {}
+        Some(Value::String(_)) => return Err("model: must not be empty".to_string()),
+        Some(_) => return Err(expected("model", "a string")),
+        None => return Err(required("model")),
+    }
+    match body.get("max_tokens") {
+        Some(value)
+            if value
+                .as_u64()
+                .is_some_and(|n| n >= 1 && n <= u32::MAX as u64) => {}
+        Some(_) => return Err(expected("max_tokens", "a positive integer")),
+        None => return Err(required("max_tokens")),
+    }
+
+    let messages = match body.get("messages") {
+        Some(Value::Array(messages)) => messages,
+        Some(_) => return Err(expected("messages", "an array")),
+        None => return Err(required("messages")),
+    };
+    if messages.is_empty() {
+        return Err("messages: at least one message is required".to_string());
+    }
+    for (i, message) in messages.iter().enumerate() {
+        check_message(&format!("messages.{}", i), message)?;
+    }
+
+    match body.get("system") {
+        None | Some(Value::Null) | Some(Value::String(_)) => {}
+        Some(Value::Array(blocks)) => {
+            for (i, block) in blocks.iter().enumerate() {
+                let path = format!("system.{}", i);
+                let block = object(&path, block)?;
+                string(&path, block, "type")?;
+                string(&path, block, "text")?;
+            }
+        }
+        Some(_) => return Err(expected("system", "a string or an array of text blocks")),
+    }
+
+    for key in ["temperature", "top_p"] {
+        if body
+            .get(key)
+            .is_some_and(|v| !v.is_null() && !v.is_number())
+        {
+            return Err(expected(key, "a number"));
+        }
+    }
+    if body
+        .get("top_k")
+        .is_some_and(|v| !v.is_null() && v.as_u64().is_none())
+    {
+        return Err(expected("top_k", "a non-negative integer"));
+    }
+    if body
+        .get("stream")
+        .is_some_and(|v| !v.is_null() && !v.is_boolean())
+    {
+        return Err(expected("stream", "a boolean"));
+    }
+    match body.get("stop_sequences") {
+        None | Some(Value::Null) => {}
+        Some(Value::Array(stops)) if stops.iter().all(Value::is_string) => {}
+        Some(_) => return Err(expected("stop_sequences", "an array of strings")),
+    }
+    match body.get("tools") {
+        None | Some(Value::Null) => {}
+        Some(Value::Array(tools)) => {
+            for (i, tool) in tools.iter().enumerate() {
+                let path = format!("tools.{}", i);
+                let tool = object(&path, tool)?;
+                string(&path, tool, "name")?;
+                match tool.get("input_schema") {
+                    Some(Value::Object(_)) => {}
+                    Some(_) => {
+                        return Err(expected(&format!("{}.input_schema", path), "an object"))
+                    }
+                    None => return Err(required(&format!("{}.input_schema", path))),
+                }
+            }
+        }
+        Some(_) => return Err(expected("tools", "an array")),
+    }
+    Ok(())
+}
+
+fn check_message(path: &str, message: &Value) -> Result<(), String> {
+    let message = object(path, message)?;
+    let role = string(path, message, "role")?;
+    if !ROLES.contains(&role) {
+        return Err(format!(
+            "{}.role: unexpected role \"{}\", expected one of {}",
+            path,
+            role,
+            ROLES.join(", ")
+        ));
+    }
+    match message.get("content") {
+        Some(Value::String(_)) => Ok(()),
+        Some(Value::Array(blocks)) => {
+            for (i, block) in blocks.iter().enumerate() {
+                check_block(&format!("{}.content.{}", path, i), block)?;
+            }
+            Ok(())
+        }
+        Some(_) => Err(expected(
+            &format!("{}.content", path),
+            "a string or an array of content blocks",
+        )),
+        None => Err(required(&format!("{}.content", path))),
+    }
+}
+
+fn check_block(path: &str, block: &Value) -> Result<(), String> {
+    let block = object(path, block)?;
+    let block_type = string(path, block, "type")?;
+    match block_type {
+        "text" => string(path, block, "text").map(drop),
+        "thinking" => string(path, block, "thinking").map(drop),
+        "tool_use" => {
+            string(path, block, "id")?;
+            string(path, block, "name")?;
+            match block.get("input") {
+                Some(Value::Object(_)) => Ok(()),
+                Some(_) => Err(expected(&format!("{}.input", path), "an object")),
+                None => Err(required(&format!("{}.input", path))),
+            }
+        }
+        "tool_result" => {
+            string(path, block, "tool_use_id")?;
+            match block.get("content") {
+                None | Some(Value::String(_)) => Ok(()),
+                Some(Value::Array(blocks)) => {
+                    for (i, inner) in blocks.iter().enumerate() {
+                        check_block(&format!("{}.content.{}", path, i), inner)?;
+                    }
+                    Ok(())
+                }
+                Some(_) => Err(expected(
+                    &format!("{}.content", path),
+                    "a string or an array of content blocks",
+                )),
+            }
+        }
+        "image" => {
+            let source_path = format!("{}.source", path);
+            let source = match block.get("source") {
+                Some(source) => object(&source_path, source)?,
+                None => return Err(required(&source_path)),
+            };
+            match string(&source_path, source, "type")? {
+                "base64" => {
+                    string(&source_path, source, "media_type")?;
+                    string(&source_path, source, "data").map(drop)
+                }
+                "url" => string(&source_path, source, "url").map(drop),
+                other => Err(format!(
+                    "{}.type: unsupported image source \"{}\", expected base64 or url",
+                    source_path, other
+                )),
+            }
+        }
+        other => Err(format!(
+            "{}.type: unsupported content block type \"{}\", expected one of {}",
+            path,
+            other,
+            BLOCK_TYPES.join(", ")
+        )),
+    }
+}
+
+fn object<'a>(path: &str, value: &'a Value) -> Result<&'a Map<String, Value>, String> {
+    value.as_object().ok_or_else(|| expected(path, "an object"))
+}
+
+fn string<'a>(path: &str, object: &'a Map<String, Value>, key: &str) -> Result<&'a str, String> {
+    let path = format!("{}.{}", path, key);
+    match object.get(key) {
+        Some(Value::String(value)) => Ok(value),
+        Some(_) => Err(expected(&path, "a string")),
+        None => Err(required(&path)),
+    }
+}
+
+fn required(path: &str) -> String {
+    format!("{}: Field required", path)
+}
+
+fn expected(path: &str, what: &str) -> String {
+    format!("{}: expected {}", path, what)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use serde_json::json;
+
+    #[test]
+    fn errors_name_the_offending_field() {
+        let valid = json!({
+            "model": "claude",
+            "max_tokens": 100,
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "t1", "name": "ls", "input": {}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "t1", "content": [{"type": "text", "text": "a"}]}
+                ]}
+            ]
+        });
+        assert_eq!(check(&valid), Ok(()));
+
+        let cases = [
+            ("/max_tokens", json!(null), "max_tokens: expected a positive integer"),
+            ("/max_tokens", json!(0), "max_tokens: expected a positive integer"),
+            ("/messages", json!([]), "messages: at least one message is required"),
+            (
+                "/messages/0/role",
+                json!("system"),
+                "messages.0.role: unexpected role \"system\", expected one of user, assistant",
+            ),
+            (
+                "/messages/1/content/0/type",
+                json!("video"),
+                "messages.1.content.0.type: unsupported content block type \"video\", expected one of text, image, tool_use, tool_result, thinking",
+            ),
+            (
+                "/messages/2/content/0/content/0/text",
+                json!(1),
+                "messages.2.content.0.content.0.text: expected a string",
+            ),
+        ];
+        for (pointer, value, message) in cases {
+            let mut body = valid.clone();
+            *body.pointer_mut(pointer).unwrap() = value;
+            assert_eq!(check(&body), Err(message.to_string()));
+        }
+
+        let mut body = valid.clone();
+        body.as_object_mut().unwrap().remove("max_tokens");
+        assert_eq!(check(&body), Err("max_tokens: Field required".to_string()));
+    }
+}
@@ -0,0 +1,435 @@
+use crate::config::UpstreamFormat;
+use crate::error::{ProxyError, ProxyResult};
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use reqwest::Client;
+use ring::rand::SystemRandom;
+use ring::signature::{RsaKeyPair, RSA_PKCS1_SHA256};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+const SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+/// Vertex takes the Messages API version in the body instead of a header
+const ANTHROPIC_VERSION: &str = "vertex-2023-10-16";
+/// Tokens are refreshed this long before Google says they expire
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// A credentials file as written by `gcloud auth application-default login`
+/// or downloaded for a service account
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CredentialsFile {
+    ServiceAccount {
+        client_email: String,
+        private_key: String,
+        #[serde(default = "default_token_uri")]
+        token_uri: String,
+    },
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+        #[serde(default = "default_token_uri")]
+        token_uri: String,
+    },
+}
+
+fn default_token_uri() -> String {
+    TOKEN_URI.to_string()
+}
+
+/// Where access tokens come from
+enum Credentials {
+    /// Signs its own token requests with the account's private key
+    ServiceAccount {
+        client_email: String,
+        key: RsaKeyPair,
+        token_uri: String,
+    },
+    /// Trades a user's refresh token for access tokens
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+        token_uri: String,
+    },
+    /// The metadata server of the GCE, GKE or Cloud Run instance we run on
+    Metadata,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::ServiceAccount { client_email, .. } => {
+                write!(f, "ServiceAccount({})", client_email)
+            }
+            Credentials::AuthorizedUser { .. } => write!(f, "AuthorizedUser"),
+            Credentials::Metadata => write!(f, "Metadata"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// A Vertex AI project and region, with the OAuth credentials to reach it
+pub struct Vertex {
+    pub project: String,
+    pub location: String,
+    credentials: Credentials,
+    /// The cached token, held while one is fetched so concurrent requests
+    /// wait for that fetch instead of starting their own
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl fmt::Debug for Vertex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Vertex")
+            .field("project", &self.project)
+            .field("location", &self.location)
+            .field("credentials", &self.credentials)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Vertex {
+    /// Credentials from `path`, GOOGLE_APPLICATION_CREDENTIALS or the gcloud
+    /// application default credentials, falling back to the metadata server
+    pub fn new(project: String, location: String, path: Option<PathBuf>) -> Result<Self> {
+        let path = path
+            .or_else(|| {
+                std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS")
+                    .filter(|p| !p.is_empty())
+                    .map(PathBuf::from)
+            })
+            .or_else(|| {
+                let home = std::env::var_os("HOME")?;
+                let adc =
+                    Path::new(&home).join(".config/gcloud/application_default_credentials.json");
+                adc.exists().then_some(adc)
+            });
+        let credentials = match path {
+            Some(path) => {
+                let raw = std::fs::read_to_string(&path).with_context(|| {
+                    format!("Failed to read Google credentials file {}", path.display())
+                })?;
+                Self::parse_credentials(&raw).with_context(|| {
+                    format!("Invalid Google credentials file {}", path.display())
+                })?
+            }
+            None => Credentials::Metadata,
+        };
+        Ok(Self {
+            project,
+            location,
+            credentials,
+            token: Mutex::new(None),
+        })
+    }
+
+    fn parse_credentials(raw: &str) -> Result<Credentials> {
+        Ok(match serde_json::from_str(raw)? {
+            CredentialsFile::ServiceAccount {
+                client_email,
+                private_key,
+                token_uri,
+            } => {
+                let der: String = private_key
+                    .lines()
+                    .filter(|line| !line.starts_with("-----"))
+                    .collect();
+                let der = STANDARD
+                    .decode(der.trim())
+                    .context("private_key is not a PEM encoded key")?;
+                let key = match RsaKeyPair::from_pkcs8(&der) {
+                    Ok(key) => key,
+                    Err(err) => bail!("private_key is not a PKCS#8 RSA key: {}", err),
+                };
+                Credentials::ServiceAccount {
+                    client_email,
+                    key,
+                    token_uri,
+                }
+            }
+            CredentialsFile::AuthorizedUser {
+                client_id,
+                client_secret,
+                refresh_token,
+                token_uri,
+            } => Credentials::AuthorizedUser {
+                client_id,
+                client_secret,
+                refresh_token,
+                token_uri,
+            },
+        })
+    }
+
+    /// The API host for the region; global models have no regional host
+    pub fn default_base_url(location: &str) -> String {
+        if location == "global" {
+            "https://aiplatform.googleapis.com".to_string()
+        } else {
+            format!("https://{}-aiplatform.googleapis.com", location)
+        }
+    }
+
+    /// The endpoint under an API host: the OpenAI-compatible chat completions
+    /// endpoint for Gemini and other Model Garden models, or the collection
+    /// of Anthropic publisher models that Claude requests are posted to
+    pub fn endpoint(&self, base_url: &str, format: UpstreamFormat) -> String {
+        let base = format!(
+            "{}/v1/projects/{}/locations/{}",
+            base_url.trim().trim_end_matches('/'),
+            self.project,
+            self.location
+        );
+        match format {
+            UpstreamFormat::OpenAI | UpstreamFormat::Ollama => {
+                format!("{}/endpoints/openapi/chat/completions", base)
+            }
+            UpstreamFormat::Anthropic => format!("{}/publishers/anthropic/models", base),
+        }
+    }
+
+    /// A valid access token, fetching a new one when the cached one is about
+    /// to expire
+    pub async fn token(&self, client: &Client) -> ProxyResult<String> {
+        let mut cached = self.token.lock().await;
+        if let Some((token, expires)) = &*cached {
+            if Instant::now() + REFRESH_MARGIN < *expires {
+                return Ok(token.clone());
+            }
+        }
+
+        let request = match &self.credentials {
+            Credentials::ServiceAccount {
+                client_email,
+                key,
+                token_uri,
+            } => client.post(token_uri).form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &sign_assertion(key, client_email, token_uri)?),
+            ]),
+            Credentials::AuthorizedUser {
+                client_id,
+                client_secret,
+                refresh_token,
+                token_uri,
+            } => client.post(token_uri).form(&[
+                ("grant_type", "refresh_token"),
+                ("client_id", client_id),
+                ("client_secret", client_secret),
+                ("refresh_token", refresh_token),
+            ]),
+            Credentials::Metadata => client
+                .get(METADATA_TOKEN_URL)
+                .header("Metadata-Flavor", "Google"),
+        };
+        let response = request.timeout(Duration::from_secs(30)).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            tracing::error!(
+                "Fetching a Vertex AI access token failed with {}: {}",
+                status,
+                body
+            );
+            return Err(ProxyError::Upstream(format!(
+                "could not get a Vertex AI access token ({})",
+                status
+            )));
+        }
+        let response: TokenResponse = response.json().await?;
+        tracing::debug!(
+            "Fetched a Vertex AI access token valid for {}s",
+            response.expires_in
+        );
+        let expires = Instant::now() + Duration::from_secs(response.expires_in);
+        *cached = Some((response.access_token.clone(), expires));
+        Ok(response.access_token)
+    }
+}
+
+/// A self-signed JWT asking the token endpoint for a cloud-platform token
+fn sign_assertion(key: &RsaKeyPair, client_email: &str, token_uri: &str) -> ProxyResult<String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let header = URL_SAFE_NO_PAD.encode(json!({"alg": "RS256", "typ": "JWT"}).to_string());
+    let claims = URL_SAFE_NO_PAD.encode(
+        json!({
+            "iss": client_email,
+            "scope": SCOPE,
+            "aud": token_uri,
+            "iat": now,
+            "exp": now + 3600,
+        })
+        .to_string(),
+    );
+    let message = format!("{}.{}", header, claims);
+    let mut signature = vec![0; key.public().modulus_len()];
+    key.sign(
+        &RSA_PKCS1_SHA256,
+        &SystemRandom::new(),
+        message.as_bytes(),
+        &mut signature,
+    )
+    .map_err(|_| ProxyError::Internal("could not sign the Vertex AI token request".into()))?;
+    Ok(format!("{}.{}", message, URL_SAFE_NO_PAD.encode(signature)))
+}
+
+/// Adapt a Messages API body for Claude on Vertex, where the model is part of
+/// the URL and the API version part of the body. Returns the model.
+pub fn adapt_anthropic(body: &mut Value) -> String {
+    let model = body
+        .as_object_mut()
+        .and_then(|body| body.remove("model"))
+        .and_then(|model| model.as_str().map(String::from))
+        .unwrap_or_default();
+    if body.get("anthropic_version").is_none() {
+        body["anthropic_version"] = json!(ANTHROPIC_VERSION);
+    }
+    model
+}
+
+/// The URL a Claude request is posted to under the publisher models endpoint
+pub fn anthropic_url(endpoint: &str, model: &str, streaming: bool) -> String {
+    let method = if streaming {
+        "streamRawPredict"
+    } else {
+        "rawPredict"
+    };
+    format!("{}/{}:{}", endpoint, model, method)
+}
+
+/// The OpenAI-compatible endpoint names models by publisher; bare names are
+/// Google's own
+pub fn adapt_openai(body: &mut Value) {
+    if let Some(model) = body["model"].as_str() {
+        if !model.contains('/') {
+            body["model"] = json!(format!("google/{}", model));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adapt_anthropic, adapt_openai, anthropic_url, Vertex};
+    use crate::config::UpstreamFormat;
+    use axum::{routing::post, Form, Json, Router};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn requests_are_shaped_for_vertex_endpoints() {
+        let vertex = Vertex::new(
+            "my-project".to_string(),
+            "us-east5".to_string(),
+            Some(std::path::PathBuf::from("/nonexistent")),
+        );
+        assert!(vertex.is_err());
+
+        let vertex = Vertex {
+            project: "my-project".to_string(),
+            location: "us-east5".to_string(),
+            credentials: super::Credentials::Metadata,
+            token: Default::default(),
+        };
+        let base = Vertex::default_base_url("us-east5");
+        let endpoint = vertex.endpoint(&base, UpstreamFormat::Anthropic);
+        assert_eq!(
+            anthropic_url(&endpoint, "claude-sonnet-4-5@20250929", true),
+            "https://us-east5-aiplatform.googleapis.com/v1/projects/my-project/locations/us-east5/publishers/anthropic/models/claude-sonnet-4-5@20250929:streamRawPredict"
+        );
+        assert_eq!(
+            vertex.endpoint(&Vertex::default_base_url("global"), UpstreamFormat::OpenAI),
+            "https://aiplatform.googleapis.com/v1/projects/my-project/locations/us-east5/endpoints/openapi/chat/completions"
+        );
+
+        let mut body = json!({"model": "claude-opus-4-1", "max_tokens": 10});
+        assert_eq!(adapt_anthropic(&mut body), "claude-opus-4-1");
+        assert_eq!(
+            body,
+            json!({"max_tokens": 10, "anthropic_version": "vertex-2023-10-16"})
+        );
+
+        let mut body = json!({"model": "gemini-2.5-pro"});
+        adapt_openai(&mut body);
+        assert_eq!(body["model"], "google/gemini-2.5-pro");
+        let mut body = json!({"model": "meta/llama-4-maverick"});
+        adapt_openai(&mut body);
+        assert_eq!(body["model"], "meta/llama-4-maverick");
+    }
+
+    #[tokio::test]
+    async fn access_tokens_are_refreshed_only_when_expiring() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let app = Router::new().route(
+            "/token",
+            post(move |Form(form): Form<HashMap<String, String>>| {
+                let calls = counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    assert_eq!(form["grant_type"], "refresh_token");
+                    assert_eq!(form["refresh_token"], "1//refresh");
+                    // The second token is already inside the refresh margin
+                    let expires_in = if calls == 0 { 3600 } else { 30 };
+                    Json::<Value>(json!({
+                        "access_token": format!("ya29.{}", calls),
+                        "expires_in": expires_in,
+                        "token_type": "Bearer",
+                    }))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let credentials = Vertex::parse_credentials(
+            &json!({
+                "type": "authorized_user",
+                "client_id": "id",
+                "client_secret": "secret",
+                "refresh_token": "1//refresh",
+                "token_uri": format!("http://{}/token", addr),
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let vertex = Vertex {
+            project: "p".to_string(),
+            location: "global".to_string(),
+            credentials,
+            token: Default::default(),
+        };
+        let client = reqwest::Client::new();
+
+        // Concurrent requests share one fetch
+        let (first, second) = tokio::join!(vertex.token(&client), vertex.token(&client));
+        assert_eq!(first.unwrap(), "ya29.0");
+        assert_eq!(second.unwrap(), "ya29.0");
+        assert_eq!(vertex.token(&client).await.unwrap(), "ya29.0");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        *vertex.token.lock().await = None;
+        assert_eq!(vertex.token(&client).await.unwrap(), "ya29.1");
+        assert_eq!(vertex.token(&client).await.unwrap(), "ya29.2");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
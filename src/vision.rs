@@ -0,0 +1,315 @@
+use crate::config::Config;
+use crate::models::openai;
+use crate::proxy::{self, RequestContext};
+use base64::Engine;
+use futures::future::join_all;
+use futures::StreamExt;
+use reqwest::Client;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+const DEFAULT_CAPTION_PROMPT: &str = "Transcribe all text visible in this image verbatim, \
+preserving code, error messages and layout where possible. Then briefly describe anything \
+else in the image that matters for understanding it. Reply with the transcription and \
+description only.";
+const CAPTION_MAX_TOKENS: u32 = 1024;
+/// Captions kept in memory; the cache is cleared when it fills up
+const MAX_CACHED_CAPTIONS: usize = 256;
+const OMITTED_IMAGE: &str = "[Image omitted: the model cannot view images]";
+/// Largest remote image downloaded for inlining; Anthropic's own limit is 5 MB
+const MAX_INLINE_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+const IMAGE_DOWNLOAD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Captions of images already seen. Clients resend the same screenshots on
+/// every turn of a conversation, so each one is only captioned once.
+#[derive(Debug, Default)]
+pub struct CaptionCache {
+    captions: Mutex<HashMap<u64, String>>,
+}
+
+impl CaptionCache {
+    fn get(&self, key: u64) -> Option<String> {
+        self.captions
+            .lock()
+            .expect("caption cache lock poisoned")
+            .get(&key)
+            .cloned()
+    }
+
+    fn insert(&self, key: u64, caption: String) {
+        let mut captions = self.captions.lock().expect("caption cache lock poisoned");
+        if captions.len() >= MAX_CACHED_CAPTIONS {
+            captions.clear();
+        }
+        captions.insert(key, caption);
+    }
+}
+
+/// Replace images with text when the target model cannot view them: a caption
+/// from IMAGE_CAPTION_MODEL when configured, otherwise a short placeholder
+pub async fn replace_images(
+    config: &Config,
+    client: &Client,
+    ctx: &RequestContext,
+    cache: &CaptionCache,
+    req: &mut openai::OpenAIRequest,
+) {
+    if !config.is_text_only(&req.model) {
+        return;
+    }
+
+    let images: Vec<String> = image_parts(req)
+        .map(|image_url| image_url.url.clone())
+        .collect();
+    if images.is_empty() {
+        return;
+    }
+
+    let captions: Vec<String> = match &config.image_caption_model {
+        Some(model) => {
+            join_all(
+                images
+                    .iter()
+                    .map(|url| caption(config, client, ctx, cache, model, url)),
+            )
+            .await
+        }
+        None => vec![OMITTED_IMAGE.to_string(); images.len()],
+    };
+    tracing::info!(
+        "Replaced {} image(s) with text for text-only model {}",
+        images.len(),
+        req.model
+    );
+
+    let mut captions = captions.into_iter();
+    for message in &mut req.messages {
+        if let Some(openai::MessageContent::Parts(parts)) = &mut message.content {
+            for part in parts.iter_mut() {
+                if matches!(part, openai::ContentPart::ImageUrl { .. }) {
+                    let text = captions.next().unwrap_or_else(|| OMITTED_IMAGE.to_string());
+                    *part = openai::ContentPart::Text {
+                        text,
+                        cache_control: None,
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Download images given by URL and send them as data URLs instead, for
+/// upstreams that only accept inline images. Images that can't be fetched
+/// keep their URL.
+pub async fn inline_image_urls(config: &Config, client: &Client, req: &mut openai::OpenAIRequest) {
+    if !config.inline_image_urls {
+        return;
+    }
+
+    let urls: Vec<String> = image_parts(req)
+        .map(|image_url| image_url.url.clone())
+        .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+        .collect();
+    if urls.is_empty() {
+        return;
+    }
+    let inlined: HashMap<String, String> = join_all(urls.into_iter().map(|url| async move {
+        let data_url = download_as_data_url(client, &url).await;
+        (url, data_url)
+    }))
+    .await
+    .into_iter()
+    .filter_map(|(url, data_url)| match data_url {
+        Ok(data_url) => Some((url, data_url)),
+        Err(err) => {
+            tracing::warn!("Could not inline image {}: {}", url, err);
+            None
+        }
+    })
+    .collect();
+    tracing::debug!("Inlined {} remote image(s)", inlined.len());
+
+    for message in &mut req.messages {
+        if let Some(openai::MessageContent::Parts(parts)) = &mut message.content {
+            for part in parts.iter_mut() {
+                if let openai::ContentPart::ImageUrl { image_url } = part {
+                    if let Some(data_url) = inlined.get(&image_url.url) {
+                        image_url.url = data_url.clone();
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn download_as_data_url(client: &Client, url: &str) -> Result<String, String> {
+    let response = client
+        .get(url)
+        .timeout(IMAGE_DOWNLOAD_TIMEOUT)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("server returned {}", response.status()));
+    }
+    let media_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_string())
+        .filter(|v| v.starts_with("image/"))
+        .ok_or("response is not an image")?;
+
+    let mut body = Vec::new();
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk.map_err(|err| err.to_string())?);
+        if body.len() > MAX_INLINE_IMAGE_BYTES {
+            return Err(format!(
+                "image is larger than {} bytes",
+                MAX_INLINE_IMAGE_BYTES
+            ));
+        }
+    }
+    Ok(data_url(&media_type, &body))
+}
+
+fn data_url(media_type: &str, bytes: &[u8]) -> String {
+    format!(
+        "data:{};base64,{}",
+        media_type,
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    )
+}
+
+fn image_parts(req: &openai::OpenAIRequest) -> impl Iterator<Item = &openai::ImageUrl> {
+    req.messages
+        .iter()
+        .filter_map(|m| match &m.content {
+            Some(openai::MessageContent::Parts(parts)) => Some(parts),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|part| match part {
+            openai::ContentPart::ImageUrl { image_url } => Some(image_url),
+            openai::ContentPart::Text { .. } => None,
+        })
+}
+
+async fn caption(
+    config: &Config,
+    client: &Client,
+    ctx: &RequestContext,
+    cache: &CaptionCache,
+    model: &str,
+    url: &str,
+) -> String {
+    let mut hasher = DefaultHasher::new();
+    (model, url).hash(&mut hasher);
+    let key = hasher.finish();
+    if let Some(caption) = cache.get(key) {
+        return caption;
+    }
+
+    let prompt = config
+        .image_caption_prompt
+        .as_deref()
+        .unwrap_or(DEFAULT_CAPTION_PROMPT);
+    let req = openai::OpenAIRequest {
+        model: model.to_string(),
+        messages: vec![openai::Message {
+            role: "user".to_string(),
+            content: Some(openai::MessageContent::Parts(vec![
+                openai::ContentPart::Text {
+                    text: prompt.to_string(),
+                    cache_control: None,
+                },
+                openai::ContentPart::ImageUrl {
+                    image_url: openai::ImageUrl {
+                        url: url.to_string(),
+                    },
+                },
+            ])),
+            tool_calls: None,
+            tool_call_id: None,
+            name: None,
+            reasoning_content: None,
+        }],
+        max_tokens: Some(CAPTION_MAX_TOKENS),
+        temperature: Some(0.0),
+        top_p: None,
+        top_k: None,
+        stop: None,
+        stream: Some(false),
+        tools: None,
+        tool_choice: None,
+        stream_options: None,
+        reasoning: None,
+        reasoning_effort: None,
+        response_format: None,
+        extra: serde_json::Map::new(),
+    };
+
+    let text = match proxy::send_chat_completion(config, client, ctx, &req).await {
+        Ok((resp, _)) => resp
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .filter(|text| !text.trim().is_empty()),
+        Err(err) => {
+            tracing::warn!("Image captioning with {} failed: {}", model, err);
+            None
+        }
+    };
+
+    match text {
+        Some(text) => {
+            let caption = format!("[Image, described by {}]\n{}", model, text.trim());
+            cache.insert(key, caption.clone());
+            caption
+        }
+        None => OMITTED_IMAGE.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{data_url, replace_images, CaptionCache, OMITTED_IMAGE};
+    use crate::config::Config;
+    use crate::models::openai;
+    use crate::proxy::RequestContext;
+
+    #[tokio::test]
+    async fn images_become_placeholders_without_a_caption_model() {
+        let mut config = Config::for_tests();
+        config.text_only_models = vec!["local/*".to_string()];
+        let ctx = RequestContext::detached(&config);
+        let mut req: openai::OpenAIRequest = serde_json::from_value(serde_json::json!({
+            "model": "local/llama",
+            "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "what does this say?"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}}
+            ]}]
+        }))
+        .unwrap();
+
+        let client = reqwest::Client::new();
+        replace_images(&config, &client, &ctx, &CaptionCache::default(), &mut req).await;
+
+        let Some(openai::MessageContent::Parts(parts)) = &req.messages[0].content else {
+            panic!("expected content parts");
+        };
+        assert!(
+            matches!(&parts[1], openai::ContentPart::Text { text, .. } if text == OMITTED_IMAGE)
+        );
+    }
+
+    #[test]
+    fn downloaded_images_are_encoded_as_data_urls() {
+        assert_eq!(data_url("image/png", b"hi!"), "data:image/png;base64,aGkh");
+    }
+}